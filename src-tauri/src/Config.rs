@@ -1,5 +1,9 @@
+use crate::DataProcessor::WriteFile;
+use crate::TES_Err::{TESErr, TESErrorS, TESResult};
 use serde::de::Error;
 use serde::{Deserialize, Deserializer, Serialize};
+use std::fs::File;
+use std::path::Path;
 
 fn float_to_u32<'de, D>(deserializer: D) -> Result<u32, D::Error>
 where
@@ -18,10 +22,35 @@ where
     }
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
+/// フィルタ設計やフィットを行う数値計算バックエンド
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
+#[serde(tag = "Type")]
+pub enum BackendConfig {
+    #[default]
+    Native,
+    Http {
+        Url: String,
+    },
+    Mock,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub struct TESAnalysisConfig {
     pub R_sh: f64,
     pub LinerFitSample: u32,
+    #[serde(default)]
+    pub Backend: BackendConfig,
+    #[serde(default)]
+    pub Thermal: ThermalConfig,
+    /// 既知の読み出しゲイン (uA/V)。無ければ超伝導側の傾きから求め、寄生抵抗は 0 とする
+    #[serde(default)]
+    pub Eta: Option<f64>,
+    #[serde(default)]
+    pub Branch: BranchConfig,
+    #[serde(default)]
+    pub FluxJump: FluxJumpConfig,
+    #[serde(default)]
+    pub Electrothermal: ElectrothermalConfig,
 }
 
 impl TESAnalysisConfig {
@@ -29,11 +58,251 @@ impl TESAnalysisConfig {
         Self {
             R_sh: 3.9,
             LinerFitSample: 10,
+            Backend: BackendConfig::Native,
+            Thermal: ThermalConfig::default(),
+            Eta: None,
+            Branch: BranchConfig::default(),
+            FluxJump: FluxJumpConfig::default(),
+            Electrothermal: ElectrothermalConfig::default(),
+        }
+    }
+
+    /// データの隣に置いた設定 (IVConfig.json など) を読む。無ければ None
+    pub fn Load(path: &Path) -> TESResult<Option<Self>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let file = File::open(path).map_err(|e| TESErrorS::Open(path, &e))?;
+        serde_json::from_reader(file).map(Some).map_err(|e| {
+            TESErrorS::AtPath(TESErr::JsonParseErr, path, format!("Failed to parse config\n{}", e))
+        })
+    }
+
+    pub fn Save(&self, path: &Path) -> TESResult<()> {
+        let json = serde_json::to_string_pretty(self)
+            .map_err(|e| TESErrorS::new(TESErr::SerializeErr, format!("Failed to serialize config: {}", e)))?;
+        WriteFile(path, json)
+    }
+}
+
+/// IV を超伝導・転移・常伝導に分ける設定
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(default)]
+pub struct BranchConfig {
+    /// 局所的な傾きを取る区間数
+    #[serde(deserialize_with = "float_to_u32")]
+    pub Window: u32,
+    /// 枝の傾きからのずれの許容値 (傾きに対する割合)
+    pub SlopeTolerance: f64,
+}
+
+impl Default for BranchConfig {
+    fn default() -> Self {
+        Self {
+            Window: 5,
+            SlopeTolerance: 0.2,
+        }
+    }
+}
+
+/// 磁束の飛びを自動で探す設定
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(default)]
+pub struct FluxJumpConfig {
+    /// 周りの傾きを取る前後の区間数
+    #[serde(deserialize_with = "float_to_u32")]
+    pub Window: u32,
+    /// 飛びと見なす外れ方 (MAD から求めた標準偏差の何倍か)
+    pub Threshold: f64,
+    /// 飛びと見なす最小の傾きの変化 (周りの傾きに対する割合)
+    pub MinSlopeChange: f64,
+    /// 飛びの前後の傾きのずれの許容値 (傾きに対する割合)
+    pub SlopeTolerance: f64,
+}
+
+impl Default for FluxJumpConfig {
+    fn default() -> Self {
+        Self {
+            Window: 5,
+            Threshold: 6.0,
+            MinSlopeChange: 1.0,
+            SlopeTolerance: 0.2,
+        }
+    }
+}
+
+/// ループゲインと時定数を求める設定 (None なら P_sat のフィットの値を使う)
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Default)]
+#[serde(default)]
+pub struct ElectrothermalConfig {
+    /// 熱伝導度 (pW/K)
+    pub G: Option<f64>,
+    /// 転移温度 (mK)
+    pub Tc: Option<f64>,
+    /// 熱容量 (pJ/K, 無ければ時定数は τ_eff / τ0 だけ出す)
+    pub C: Option<f64>,
+}
+
+/// IV から P_sat と熱伝導度を求める設定
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(default)]
+pub struct ThermalConfig {
+    /// P_sat を取る R_TES / R_n
+    pub PsatFraction: f64,
+}
+
+impl Default for ThermalConfig {
+    fn default() -> Self {
+        Self { PsatFraction: 0.5 }
+    }
+}
+/// パルス解析前に掛けるフィルタ (IIR は CutoffFrequency を使う)
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "Type")]
+pub enum PulseFilterConfig {
+    None,
+    Bessel { Order: usize },
+    Butterworth { Order: usize },
+    Chebyshev { Order: usize, RippleDb: f64 },
+    MovingAverage { Window: usize },
+    SavitzkyGolay { Window: usize, PolyOrder: usize },
+}
+
+impl Default for PulseFilterConfig {
+    fn default() -> Self {
+        PulseFilterConfig::Bessel { Order: 2 }
+    }
+}
+
+/// 最適フィルタ (テンプレートは PeakAverage が範囲内のパルスから作る)
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct OptimalFilterConfig {
+    pub Enable: bool,
+    pub TemplateMinHeight: f64,
+    /// TemplateMinHeight 以下なら上限なし
+    pub TemplateMaxHeight: f64,
+    #[serde(deserialize_with = "float_to_u32")]
+    pub WindowStart: u32,
+    /// 0 なら記録の最後まで (CH{n}_noise が無ければプリトリガーの長さで、WindowStart が 0 ならトリガーを真ん中にする)
+    /// 窓は必ず PreSample (トリガー) を含む
+    #[serde(deserialize_with = "float_to_u32")]
+    pub WindowLength: u32,
+    #[serde(deserialize_with = "float_to_u32")]
+    pub MaxShift: u32,
+}
+
+impl Default for OptimalFilterConfig {
+    fn default() -> Self {
+        Self {
+            Enable: false,
+            TemplateMinHeight: 0.0,
+            TemplateMaxHeight: 0.0,
+            WindowStart: 0,
+            WindowLength: 0,
+            MaxShift: 0,
+        }
+    }
+}
+
+/// 閾値を横切る位置のサンプル間補間
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Default)]
+pub enum CrossingInterpolation {
+    #[default]
+    None,
+    Linear,
+    /// 前後 4 点の Catmull-Rom スプライン
+    Spline,
+}
+
+/// Fraction·y[i] - y[i-Delay] のゼロクロスを立ち上がりとする (Delay が 0 なら使わない)
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct CFDConfig {
+    pub Fraction: f64,
+    #[serde(deserialize_with = "float_to_u32")]
+    pub Delay: u32,
+}
+
+impl Default for CFDConfig {
+    fn default() -> Self {
+        Self {
+            Fraction: 0.3,
+            Delay: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Default)]
+pub enum TriggerMethod {
+    /// x[i+DerivativeStep] - x[i]
+    #[default]
+    Derivative,
+    /// RiseTau, DecayTau (秒) のテンプレートとの相関
+    MatchedFilter,
+}
+
+/// パイルアップ検出 (Threshold はプリトリガーの揺らぎの何σか)
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct PileupConfig {
+    pub Enable: bool,
+    pub Method: TriggerMethod,
+    pub Threshold: f64,
+    #[serde(deserialize_with = "float_to_u32")]
+    pub DerivativeStep: u32,
+    #[serde(deserialize_with = "float_to_u32")]
+    pub DeadTime: u32,
+    pub RiseTau: f64,
+    pub DecayTau: f64,
+    /// 各パルスの高さも求める
+    pub EstimateSubPulses: bool,
+}
+
+impl Default for PileupConfig {
+    fn default() -> Self {
+        Self {
+            Enable: false,
+            Method: TriggerMethod::Derivative,
+            Threshold: 5.0,
+            DerivativeStep: 10,
+            DeadTime: 100,
+            RiseTau: 0.0,
+            DecayTau: 0.0,
+            EstimateSubPulses: false,
         }
     }
 }
+
+/// パルス波形のフィット関数 (初期値の並び)
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Default)]
+pub enum PulseFitFunction {
+    #[default]
+    None,
+    /// [A, tau_decay]
+    MonoExp,
+    /// [A, t0, tau_rise, tau_decay]
+    DoubleExp,
+    /// [A1, A2, t0, tau_rise, tau_decay1, tau_decay2]
+    RiseTwoDecay,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
+pub struct PulseFitConfig {
+    pub Function: PulseFitFunction,
+    #[serde(deserialize_with = "float_to_u32")]
+    pub WindowStart: u32,
+    /// 0 なら記録の最後まで
+    #[serde(deserialize_with = "float_to_u32")]
+    pub WindowLength: u32,
+    /// 空なら閾値解析の結果から推定する
+    #[serde(default)]
+    pub InitialParams: Vec<f64>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct PulseAnalysisConfig {
+    #[serde(default)]
+    pub Filter: PulseFilterConfig,
     pub CutoffFrequency: f64,
     #[serde(deserialize_with = "float_to_u32")]
     pub BaseLinePreSample: u32,
@@ -49,11 +318,27 @@ pub struct PulseAnalysisConfig {
     pub RiseLowRatio: f64,
     pub DecayHighRatio: f64,
     pub DecayLowRatio: f64,
+    #[serde(default, deserialize_with = "float_to_u32")]
+    pub AreaStart: u32,
+    /// 0 なら記録の最後まで
+    #[serde(default, deserialize_with = "float_to_u32")]
+    pub AreaLength: u32,
+    #[serde(default)]
+    pub OptimalFilter: OptimalFilterConfig,
+    #[serde(default)]
+    pub Fit: PulseFitConfig,
+    #[serde(default)]
+    pub Pileup: PileupConfig,
+    #[serde(default)]
+    pub Interpolation: CrossingInterpolation,
+    #[serde(default)]
+    pub CFD: CFDConfig,
 }
 
 impl PulseAnalysisConfig {
     pub fn new() -> Self {
         Self {
+            Filter: PulseFilterConfig::default(),
             CutoffFrequency: 0.0,
             BaseLinePreSample: 0,
             BaseLinePostSample: 0,
@@ -64,6 +349,13 @@ impl PulseAnalysisConfig {
             RiseLowRatio: 0.0,
             DecayHighRatio: 0.0,
             DecayLowRatio: 0.0,
+            AreaStart: 0,
+            AreaLength: 0,
+            OptimalFilter: OptimalFilterConfig::default(),
+            Fit: PulseFitConfig::default(),
+            Pileup: PileupConfig::default(),
+            Interpolation: CrossingInterpolation::None,
+            CFD: CFDConfig::default(),
         }
     }
 }
@@ -87,8 +379,183 @@ impl PulseReadoutConfig {
     }
 }
 
+/// PulseInfoS の任意のフィールドのヒストグラム (Min >= Max ならデータの範囲)
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct SpectrumConfig {
+    pub Field: String,
+    #[serde(deserialize_with = "float_to_u32")]
+    pub Bins: u32,
+    #[serde(default)]
+    pub Min: f64,
+    #[serde(default)]
+    pub Max: f64,
+    /// PulseConfig.json のカットを通過したパルスだけを使う
+    #[serde(default)]
+    pub UseCuts: bool,
+}
+
+/// ヒストグラムの Min..Max にガウシアン (Tail なら低エネルギー側テール付き) をフィットする
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct PeakFitConfig {
+    pub Min: f64,
+    pub Max: f64,
+    #[serde(default)]
+    pub Tail: bool,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum CalibrationModel {
+    Linear,
+    Quadratic,
+    PowerLaw,
+}
+
+/// Min..Max のピークに割り当てる輝線 (Energy を省略すると名前から引く)
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct CalibrationLineConfig {
+    pub Name: String,
+    #[serde(default)]
+    pub Energy: Option<f64>,
+    pub Min: f64,
+    pub Max: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct EnergyCalibrationConfig {
+    pub Spectrum: SpectrumConfig,
+    pub Model: CalibrationModel,
+    #[serde(default)]
+    pub Tail: bool,
+    pub Lines: Vec<CalibrationLineConfig>,
+}
+
+/// PulseInfoS のフィールドに対するカット (例: "PeakAverage > 0.03 && FitError == 0")
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct PulseCutConfig {
+    pub Name: String,
+    pub Expression: String,
+    /// 空なら全チャンネルに掛ける
+    #[serde(default)]
+    pub Channels: Vec<u32>,
+}
+
+/// 同じ key のパルスをチャンネル間で突き合わせる
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct CoincidenceConfig {
+    /// 空なら解析済みの全チャンネル
+    pub Channels: Vec<u32>,
+    /// 信号の大きさに使うフィールド
+    pub AmplitudeField: String,
+    /// 立ち上がり時刻に使うフィールド (秒)
+    pub TimeField: String,
+    /// AmplitudeField がこれ以上ならそのチャンネルにパルスがあるとみなす
+    pub Threshold: f64,
+    /// 最初にパルスが来たチャンネルからこの時間 (秒) 以内なら同時とみなす
+    pub TimingWindow: f64,
+    /// カットを通過しないパルスはヒットとみなさない
+    pub UseCuts: bool,
+}
+
+impl Default for CoincidenceConfig {
+    fn default() -> Self {
+        Self {
+            Channels: Vec::new(),
+            AmplitudeField: "PeakAverage".to_string(),
+            TimeField: "RiseLowTime".to_string(),
+            Threshold: 0.0,
+            TimingWindow: 0.0,
+            UseCuts: false,
+        }
+    }
+}
+
+/// Welch 法の各区間に掛ける窓関数
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Default)]
+pub enum PSDWindow {
+    #[default]
+    Hann,
+    Rectangular,
+}
+
+/// 雑音解析 (CH{n}_noise の波形から PSD を求める)
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct NoiseConfig {
+    /// 区間の長さ (0 ならテンプレートかレコードの長さ)
+    #[serde(deserialize_with = "float_to_u32")]
+    pub SegmentLength: u32,
+    /// 区間の重なりの割合 (0..1)
+    pub Overlap: f64,
+    pub Window: PSDWindow,
+    /// 標準偏差が中央値の RejectSigma 倍を超える区間は捨てる (0 なら捨てない)
+    pub RejectSigma: f64,
+    /// 読み出しの電流換算係数 A/V (0 なら電流雑音を出さない)
+    pub CurrentGain: f64,
+    /// テンプレートのパルスのエネルギー keV (0 なら NEP と分解能を出さない)
+    pub TemplateEnergy: f64,
+}
+
+impl Default for NoiseConfig {
+    fn default() -> Self {
+        Self {
+            SegmentLength: 0,
+            Overlap: 0.5,
+            Window: PSDWindow::Hann,
+            RejectSigma: 0.0,
+            CurrentGain: 0.0,
+            TemplateEnergy: 0.0,
+        }
+    }
+}
+
+/// 平均パルスに使うパルスの選び方
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
+#[serde(tag = "Type")]
+pub enum PulseSelectionConfig {
+    #[default]
+    All,
+    /// PulseConfig.json のカットを通過したもの
+    Cuts,
+    Keys {
+        Keys: Vec<u32>,
+    },
+    /// Field が Min..=Max のもの (例: Energy の窓)
+    Window {
+        Field: String,
+        Min: f64,
+        Max: f64,
+        #[serde(default)]
+        UseCuts: bool,
+    },
+}
+
+/// 平均する前にパルスを揃える目印
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Default)]
+pub enum TemplateAlign {
+    #[default]
+    None,
+    PeakIndex,
+    CFD,
+    RiseLow,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct TemplateConfig {
+    pub Selection: PulseSelectionConfig,
+    #[serde(default)]
+    pub Align: TemplateAlign,
+    /// 解析と同じフィルタを掛けた波形を平均する
+    #[serde(default)]
+    pub Filtered: bool,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct PulseProcessorConfig {
     pub Readout: PulseReadoutConfig,
     pub Analysis: PulseAnalysisConfig,
+    #[serde(default)]
+    pub Backend: BackendConfig,
+    #[serde(default)]
+    pub Cuts: Vec<PulseCutConfig>,
 }
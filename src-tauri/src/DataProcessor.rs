@@ -1,4 +1,5 @@
 #![allow(non_snake_case)]
+use crate::TES_Err::{TESErr, TESErrorS, TESResult};
 use ndarray::Array1;
 use std::convert::TryInto;
 use std::fmt::Display;
@@ -14,14 +15,15 @@ pub struct DataProcessorS {
 }
 
 pub trait DataProcessorT {
-    fn AnalyzeFolder(&mut self) -> Result<(), String>;
+    fn AnalyzeFolder(&mut self) -> TESResult<()>;
 }
 
-pub(crate) fn SaveTxt<T: Display>(path: &Path, data: &[T]) -> Result<(), String> {
+pub(crate) fn SaveTxt<T: Display>(path: &Path, data: &[T]) -> TESResult<()> {
     // 必要なディレクトリを作成（中間ディレクトリも含む）
     if let Some(parent_dir) = path.parent() {
-        fs::create_dir_all(parent_dir)
-            .map_err(|e| format!("Failed to create {:?}.\n{}", parent_dir, e))?;
+        fs::create_dir_all(parent_dir).map_err(|e| {
+            TESErrorS::AtPath(TESErr::FolderCreateErr, parent_dir, format!("Failed to create folder\n{}", e))
+        })?;
     }
 
     let content = data
@@ -31,29 +33,47 @@ pub(crate) fn SaveTxt<T: Display>(path: &Path, data: &[T]) -> Result<(), String>
         .join("\n");
 
     // ファイルを書き込む
-    fs::write(path, content).map_err(|e| format!("Failed to write {:?}.\n{}", path.display(), e))
+    fs::write(path, content)
+        .map_err(|e| TESErrorS::AtPath(TESErr::FileWriteErr, path, format!("Failed to write file\n{}", e)))
+}
+
+/// ファイルに書き出す (失敗したらパス付きのエラー)
+pub(crate) fn WriteFile(path: &Path, contents: impl AsRef<[u8]>) -> TESResult<()> {
+    fs::write(path, contents)
+        .map_err(|e| TESErrorS::AtPath(TESErr::FileWriteErr, path, format!("Failed to write file\n{}", e)))
+}
+
+/// カンマや引用符を含む文字列を CSV の 1 項目にする
+pub fn CsvField(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
 }
 
 /// バイナリファイルを読み込むメソッド
-pub(crate) fn LoadBi(file_path: &Path) -> Result<Array1<f64>, String> {
+pub(crate) fn LoadBi(file_path: &Path) -> TESResult<Array1<f64>> {
     // ファイルをバイナリモードで開く
-    let mut file =
-        File::open(file_path).map_err(|e| format!("Failed to open {:?}\n{}", file_path, e))?;
+    let mut file = File::open(file_path).map_err(|e| TESErrorS::Open(file_path, &e))?;
 
     // 最初の4バイトをスキップ
-    file.seek(SeekFrom::Start(4))
-        .map_err(|e| format!("Failed in seeking binary\n{}", e))?;
+    file.seek(SeekFrom::Start(4)).map_err(|e| {
+        TESErrorS::AtPath(TESErr::BinaryReadErr, file_path, format!("Failed in seeking binary\n{}", e))
+    })?;
 
     // ファイルの内容をバイナリデータとして読み込む
     let mut buffer = Vec::new();
-    file.read_to_end(&mut buffer)
-        .map_err(|e| format!("Failed to read {}.\n{}", file_path.display(), e))?;
+    file.read_to_end(&mut buffer).map_err(|e| {
+        TESErrorS::AtPath(TESErr::BinaryReadErr, file_path, format!("Failed to read file\n{}", e))
+    })?;
 
     // バッファサイズを確認
     if buffer.len() % size_of::<f64>() != 0 {
-        return Err(format!(
-            "{} is not a multiple of 64 floating point number.",
-            file_path.display()
+        return Err(TESErrorS::AtPath(
+            TESErr::BinaryBufferSizeErr,
+            file_path,
+            "File is not a multiple of 64 floating point number.",
         ));
     }
 
@@ -68,11 +88,22 @@ pub(crate) fn LoadBi(file_path: &Path) -> Result<Array1<f64>, String> {
     Ok(Array1::from(result))
 }
 
+/// LoadBi で読める形式で書き出す (先頭4バイトはサンプル数)
+pub(crate) fn SaveBi(file_path: &Path, data: &[f64]) -> TESResult<()> {
+    let mut buffer = Vec::with_capacity(4 + std::mem::size_of_val(data));
+    buffer.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    for v in data {
+        buffer.extend_from_slice(&v.to_le_bytes());
+    }
+    fs::write(file_path, buffer).map_err(|e| {
+        TESErrorS::AtPath(TESErr::FileWriteErr, file_path, format!("Failed to write file\n{}", e))
+    })
+}
+
 /// テキストファイルを読み込むメソッド
 
-pub(crate) fn LoadTxt(file_path: &Path) -> Result<Array1<f64>, String> {
-    let file =
-        File::open(file_path).map_err(|e| format!("Failed to open {:?}\n{}", file_path, e))?;
+pub(crate) fn LoadTxt(file_path: &Path) -> TESResult<Array1<f64>> {
+    let file = File::open(file_path).map_err(|e| TESErrorS::Open(file_path, &e))?;
     let reader = BufReader::new(file);
     let mut result = Vec::new();
 
@@ -99,10 +130,11 @@ impl DataProcessorS {
     }
 
     /// ファイルパスを設定
-    pub(crate) fn SetDataPath(&mut self, path: &Path) {
-        self.DataPath = path.to_path_buf();
-        if !self.DataPath.exists() {
-            panic!("File not found: {}", self.DataPath.display());
+    pub(crate) fn SetDataPath(&mut self, path: &Path) -> TESResult<()> {
+        if !path.is_dir() {
+            return Err(TESErrorS::AtPath(TESErr::FolderNotFound, path, "Data folder not found"));
         }
+        self.DataPath = path.to_path_buf();
+        Ok(())
     }
 }
@@ -0,0 +1,110 @@
+#![allow(non_snake_case)]
+use num_complex::Complex64;
+use std::f64::consts::PI;
+
+/// 2 のべき長の基数2 FFT (in-place)
+fn Radix2(x: &mut [Complex64], inverse: bool) {
+    let n = x.len();
+    if n <= 1 {
+        return;
+    }
+    // ビット反転並べ替え
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            x.swap(i, j);
+        }
+    }
+
+    let sign = if inverse { 1.0 } else { -1.0 };
+    let mut len = 2;
+    while len <= n {
+        let w = Complex64::from_polar(1.0, sign * 2.0 * PI / len as f64);
+        for start in (0..n).step_by(len) {
+            let mut wk = Complex64::new(1.0, 0.0);
+            for k in 0..len / 2 {
+                let u = x[start + k];
+                let v = x[start + k + len / 2] * wk;
+                x[start + k] = u + v;
+                x[start + k + len / 2] = u - v;
+                wk *= w;
+            }
+        }
+        len <<= 1;
+    }
+}
+
+/// 任意長は Bluestein のアルゴリズムで 2 のべきの畳み込みに直す
+fn Bluestein(x: &[Complex64], inverse: bool) -> Vec<Complex64> {
+    let n = x.len();
+    let m = (2 * n - 1).next_power_of_two();
+    let sign = if inverse { 1.0 } else { -1.0 };
+    // k^2 が大きくなるので 2n で剰余をとって位相誤差を抑える
+    let chirp: Vec<Complex64> = (0..n)
+        .map(|k| {
+            let k2 = (k as u128 * k as u128 % (2 * n as u128)) as f64;
+            Complex64::from_polar(1.0, sign * PI * k2 / n as f64)
+        })
+        .collect();
+
+    let mut a = vec![Complex64::new(0.0, 0.0); m];
+    for k in 0..n {
+        a[k] = x[k] * chirp[k];
+    }
+    let mut b = vec![Complex64::new(0.0, 0.0); m];
+    b[0] = chirp[0].conj();
+    for k in 1..n {
+        b[k] = chirp[k].conj();
+        b[m - k] = chirp[k].conj();
+    }
+
+    Radix2(&mut a, false);
+    Radix2(&mut b, false);
+    for (av, bv) in a.iter_mut().zip(&b) {
+        *av *= bv;
+    }
+    Radix2(&mut a, true);
+
+    (0..n).map(|k| a[k] / m as f64 * chirp[k]).collect()
+}
+
+/// 離散フーリエ変換 X_k = Σ x_n exp(-2πikn/N)
+pub fn FFT(x: &[Complex64]) -> Vec<Complex64> {
+    if x.len().is_power_of_two() {
+        let mut y = x.to_vec();
+        Radix2(&mut y, false);
+        y
+    } else if x.is_empty() {
+        Vec::new()
+    } else {
+        Bluestein(x, false)
+    }
+}
+
+/// 逆離散フーリエ変換 (1/N で規格化)
+pub fn IFFT(x: &[Complex64]) -> Vec<Complex64> {
+    let n = x.len() as f64;
+    let y = if x.len().is_power_of_two() {
+        let mut y = x.to_vec();
+        Radix2(&mut y, true);
+        y
+    } else if x.is_empty() {
+        Vec::new()
+    } else {
+        Bluestein(x, true)
+    };
+    y.into_iter().map(|v| v / n).collect()
+}
+
+/// 実数列の離散フーリエ変換
+pub fn RealFFT(x: &[f64]) -> Vec<Complex64> {
+    let c: Vec<Complex64> = x.iter().map(|&v| Complex64::new(v, 0.0)).collect();
+    FFT(&c)
+}
+
@@ -1,9 +1,18 @@
 #![allow(non_snake_case)]
 
 use std::cmp::max;
-use crate::Config::{PulseAnalysisConfig, PulseProcessorConfig, PulseReadoutConfig};
-use crate::DataProcessor::{DataProcessorS, LoadBi};
-use crate::PyMod::BesselCoefficients;
+use crate::Config::{
+    BackendConfig, CoincidenceConfig, CrossingInterpolation, EnergyCalibrationConfig, NoiseConfig, PeakFitConfig, PulseAnalysisConfig, PulseCutConfig,
+    PSDWindow, PulseFilterConfig, PulseFitFunction, PulseProcessorConfig, PulseReadoutConfig, PulseSelectionConfig,
+    SpectrumConfig, TemplateAlign, TemplateConfig,
+};
+use crate::DataProcessor::{CsvField, DataProcessorS, LoadBi, SaveBi, WriteFile};
+use crate::FilterDesign::{
+    FilterKind, MovingAverageCoefficients, SavitzkyGolayCoefficients, Section,
+};
+use crate::PyMod::{CreateBackend, NumericBackend};
+use crate::TESAnalyzer::LinerFit;
+use crate::TES_Err::{ContextT, TESErr, TESErrorS, TESResult};
 use glob::glob;
 use ndarray::{s, Array1};
 use regex::Regex;
@@ -11,24 +20,216 @@ use serde::Serialize;
 use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::io::Write;
+use std::mem::size_of;
 use std::path::{Path, PathBuf};
 use rayon::prelude::*;
-use std::sync::{Mutex, atomic::{AtomicUsize, Ordering}, Arc};
-use biquad::{Biquad, Coefficients, DirectForm1};
+use std::sync::{mpsc, atomic::{AtomicBool, AtomicUsize, Ordering}, Arc};
+use std::str::FromStr;
+
+pub mod Cache;
+pub mod Calibration;
+pub mod Coincidence;
+pub mod Cut;
+pub mod Noise;
+pub mod OptimalFilter;
+pub mod PulseFit;
+pub mod Reject;
+pub mod Spectrum;
+pub mod Template;
+pub mod Timing;
+pub mod Trigger;
+use Cache::{ConfigHash, FileCacheS, FileEntryS, CACHE_CHUNK};
+use Calibration::{CalibrationLineS, EnergyCalibrationS, KnownLineEnergy};
+use Coincidence::{ChannelEventsT, CoincidenceS};
+use Cut::CutExprS;
+use Noise::{NEPAndResolution, NoiseSpectrumS, WelchPSD};
+use OptimalFilter::OptimalFilterS;
+use PulseFit::{FitPulse, PulseFitGuessS};
+use Reject::{RejectReason, RejectSummaryS, RejectedPulseS, RejectionS};
+use Spectrum::{FitPeak, Histogram, HistogramS};
+use Template::{AveragePulse, AveragePulseS};
+use Timing::{CFDCrossing, Crossing};
+use Trigger::{FindPulses, SubPulseS};
+
+/// 端を奇関数で拡張する (scipy の odd_ext)
+fn OddExtension(x: &[f64], left: usize, right: usize) -> Vec<f64> {
+    let n = x.len();
+    let mut ext = Vec::with_capacity(n + left + right);
+    ext.extend((1..=left).rev().map(|i| 2.0 * x[0] - x[i]));
+    ext.extend_from_slice(x);
+    ext.extend((1..=right).map(|i| 2.0 * x[n - 1] - x[n - 1 - i]));
+    ext
+}
+
+/// 各セクションのステップ応答が定常になる初期状態 (scipy の sosfilt_zi)
+fn sosfilt_zi(sos: &[Section]) -> Vec<[f64; 2]> {
+    let mut scale = 1.0;
+    sos.iter()
+        .map(|s| {
+            let (b0, b1, b2) = (s[0] / s[3], s[1] / s[3], s[2] / s[3]);
+            let (a1, a2) = (s[4] / s[3], s[5] / s[3]);
+            let gain = (b0 + b1 + b2) / (1.0 + a1 + a2);
+            let z2 = b2 - a2 * gain;
+            let z1 = b1 - a1 * gain + z2;
+            let zi = [z1 * scale, z2 * scale];
+            scale *= gain;
+            zi
+        })
+        .collect()
+}
+
+/// 2次セクションを転置直接II型で順に適用する
+fn sosfilt(sos: &[Section], x: &[f64], zi: &[[f64; 2]], x0: f64) -> Vec<f64> {
+    let mut y = x.to_vec();
+    for (s, z0) in sos.iter().zip(zi) {
+        let (b0, b1, b2) = (s[0] / s[3], s[1] / s[3], s[2] / s[3]);
+        let (a1, a2) = (s[4] / s[3], s[5] / s[3]);
+        let mut z = [z0[0] * x0, z0[1] * x0];
+        for v in y.iter_mut() {
+            let xn = *v;
+            let yn = b0 * xn + z[0];
+            z[0] = b1 * xn - a1 * yn + z[1];
+            z[1] = b2 * xn - a2 * yn;
+            *v = yn;
+        }
+    }
+    y
+}
 
-pub fn filtfilt(b:&Vec<f64>,a:&Vec<f64>,pulse:&Array1<f64>)->Result<Vec<f64>,String>{
-    let coeffs = Coefficients::<f64> { b0:b[0], b1:b[1], b2:b[2], a1:a[1], a2:a[2] };
+/// ゼロ位相フィルタ (scipy の sosfiltfilt と同じパディングと初期状態)
+pub fn filtfilt(sos: &[Section], pulse: &Array1<f64>) -> Result<Vec<f64>, String> {
+    let x = pulse.to_vec();
+    if x.len() < 2 {
+        return Err("Data length is too short to filter".to_string());
+    }
+    let zero_b2 = sos.iter().filter(|s| s[2] == 0.0).count();
+    let zero_a2 = sos.iter().filter(|s| s[5] == 0.0).count();
+    let padlen = 3 * (2 * sos.len() + 1 - zero_b2.min(zero_a2));
+    let edge = padlen.min(x.len() - 1);
+
+    let ext = OddExtension(&x, edge, edge);
+    let zi = sosfilt_zi(sos);
     // 正方向フィルタ
-    let mut filter_fwd = DirectForm1::<f64>::new(coeffs);
-    let filtered_fwd: Vec<f64> = pulse.iter().map(|&x| filter_fwd.run(x)).collect();
+    let mut y = sosfilt(sos, &ext, &zi, ext[0]);
     // 逆方向フィルタ
-    let mut filter_bwd = DirectForm1::<f64>::new(coeffs);
-    let mut reversed = filtered_fwd.clone();
-    reversed.reverse();
-    let mut filtered_bwd: Vec<f64> = reversed.iter().map(|&x| filter_bwd.run(x)).collect();
-    filtered_bwd.reverse(); // 元の向きに戻す
+    y.reverse();
+    let y0 = y[0];
+    let mut y = sosfilt(sos, &y, &zi, y0);
+    y.reverse(); // 元の向きに戻す
+
+    Ok(y[edge..y.len() - edge].to_vec())
+}
+
+/// 窓の中心に合わせて FIR を掛ける (対称な係数ならゼロ位相)
+pub fn FirZeroPhase(coeffs: &[f64], pulse: &Array1<f64>) -> Result<Vec<f64>, String> {
+    let x = pulse.to_vec();
+    let left = (coeffs.len().max(1) - 1) / 2;
+    let right = coeffs.len().max(1) - 1 - left;
+    if x.len() <= right.max(1) {
+        return Err("Data length is too short for the filter window".to_string());
+    }
+    let ext = OddExtension(&x, left, right);
+    Ok((0..x.len())
+        .map(|i| coeffs.iter().enumerate().map(|(k, c)| c * ext[i + k]).sum())
+        .collect())
+}
+
+#[derive(Debug, Clone)]
+pub enum PulseFilterS {
+    None,
+    Sections(Vec<Section>),
+    Fir(Vec<f64>),
+}
+
+impl PulseFilterS {
+    pub fn Design(
+        backend: &dyn NumericBackend,
+        PRConfig: &PulseReadoutConfig,
+        PAConfig: &PulseAnalysisConfig,
+    ) -> Result<Self, String> {
+        let rate = PRConfig.Rate;
+        let cutoff = PAConfig.CutoffFrequency;
+        let filter = match &PAConfig.Filter {
+            PulseFilterConfig::None => PulseFilterS::None,
+            PulseFilterConfig::Bessel { Order } => PulseFilterS::Sections(
+                backend.FilterSections(FilterKind::Bessel, *Order, rate, cutoff)?,
+            ),
+            PulseFilterConfig::Butterworth { Order } => PulseFilterS::Sections(
+                backend.FilterSections(FilterKind::Butterworth, *Order, rate, cutoff)?,
+            ),
+            PulseFilterConfig::Chebyshev { Order, RippleDb } => {
+                PulseFilterS::Sections(backend.FilterSections(
+                    FilterKind::Chebyshev {
+                        RippleDb: *RippleDb,
+                    },
+                    *Order,
+                    rate,
+                    cutoff,
+                )?)
+            }
+            PulseFilterConfig::MovingAverage { Window } => {
+                PulseFilterS::Fir(MovingAverageCoefficients(*Window)?)
+            }
+            PulseFilterConfig::SavitzkyGolay { Window, PolyOrder } => {
+                PulseFilterS::Fir(SavitzkyGolayCoefficients(*Window, *PolyOrder)?)
+            }
+        };
+        Ok(filter)
+    }
 
-    return Ok(filtered_bwd);
+    pub fn Apply(&self, pulse: &Array1<f64>) -> Result<Vec<f64>, String> {
+        match self {
+            PulseFilterS::None => Ok(pulse.to_vec()),
+            PulseFilterS::Sections(sos) => filtfilt(sos, pulse),
+            PulseFilterS::Fir(coeffs) => FirZeroPhase(coeffs, pulse),
+        }
+    }
+}
+
+/// 1 ファイルを読んで解析する (解析できなければ理由を返す)
+fn AnalyzeFile(
+    PRConfig: &PulseReadoutConfig,
+    PAConfig: &PulseAnalysisConfig,
+    Filter: &PulseFilterS,
+    path: &Path,
+    ExpectedLength: Option<usize>,
+) -> Result<PulseInfoS, RejectionS> {
+    let pulse = LoadBi(path).map_err(|e| RejectionS::new(RejectReason::Unreadable, e))?;
+    if pulse.is_empty() {
+        return Err(RejectionS::new(RejectReason::Unreadable, "No samples in file"));
+    }
+    if let Some(n) = ExpectedLength.filter(|n| *n != pulse.len()) {
+        return Err(RejectionS::new(
+            RejectReason::WrongLength,
+            format!("{} samples, expected {}", pulse.len(), n),
+        ));
+    }
+    let MinimumLength = PulseAnalysisHelperS::new(PRConfig, PAConfig).MinimumLength(PAConfig);
+    if (pulse.len() as u32) < MinimumLength {
+        return Err(RejectionS::new(
+            RejectReason::TooShort,
+            format!("{} samples, analysis needs {}", pulse.len(), MinimumLength),
+        ));
+    }
+    if let Some(i) = pulse.iter().position(|v| !v.is_finite()) {
+        return Err(RejectionS::new(
+            RejectReason::NaN,
+            format!("Non-finite value at sample {}", i),
+        ));
+    }
+    let filtered_pulse = Filter
+        .Apply(&pulse)
+        .map_err(|e| RejectionS::new(RejectReason::FilterFailed, e))?;
+    if let Some(i) = filtered_pulse.iter().position(|v| !v.is_finite()) {
+        return Err(RejectionS::new(
+            RejectReason::NaN,
+            format!("Non-finite value at sample {} after filtering", i),
+        ));
+    }
+    // 長さと NaN は確認済みなので、残るのはピークが取れない場合
+    GetPulseInfo(PRConfig, PAConfig, Array1::from(filtered_pulse))
+        .map(|(pi, _, _)| pi)
+        .map_err(|e| RejectionS::new(RejectReason::NoPeak, e))
 }
 
 pub fn GetPulseInfo(
@@ -40,7 +241,7 @@ pub fn GetPulseInfo(
     let mut PIH = PulseInfoHelperS::new();
     let mut PAH = PulseAnalysisHelperS::new(PRConfig, PAConfig);
 
-    if Pulse.len()as u32<=max(PAH.BaseEnd, max(PAH.PeakSearch, PAH.PeakAverageEnd)){
+    if (Pulse.len() as u32) < PAH.MinimumLength(PAConfig) {
         return Err("Data length is too short".to_string());
     }
 
@@ -50,6 +251,60 @@ pub fn GetPulseInfo(
         .ok_or("Failed to calculate mean of ndarray when calculate base")?;
     Pulse -= PI.Base;
 
+    // ベースラインの揺らぎと傾き
+    let BaseSlice = Pulse.slice(s![PAH.BaseStart as usize..PAH.BaseEnd as usize]);
+    PI.BaseRMS = BaseSlice.std(0.0);
+    if BaseSlice.len() >= 2 {
+        let BaseTime: Array1<f64> =
+            (PAH.BaseStart..PAH.BaseEnd).map(|i| i as f64 / PRConfig.Rate).collect();
+        PI.BaseSlope = LinerFit(&BaseTime, &BaseSlice.to_owned())?;
+    }
+
+    // 記録末尾のベースラインとプリトリガーの差
+    PAH.PostBaseEnd = Pulse.len() as u32;
+    PAH.PostBaseStart = PAH.PostBaseEnd - PAConfig.BaseLinePostSample.min(PAH.PostBaseEnd);
+    if PAH.PostBaseStart < PAH.PostBaseEnd {
+        PI.BaseDiff = Pulse
+            .slice(s![PAH.PostBaseStart as usize..])
+            .mean()
+            .ok_or("Failed to calculate mean of ndarray when calculate post-trigger base")?;
+    }
+
+    // 記録中の全パルスを探してパイルアップとプリトリガーへの混入を調べる
+    if PAConfig.Pileup.Enable {
+        // トリガーできなかった場合は PulseCount が 0 のまま
+        if let Ok(pulses) = FindPulses(
+            &PAConfig.Pileup,
+            PRConfig.Rate,
+            PRConfig.PreSample as usize,
+            PAConfig.PeakSearchSample as usize,
+            &Pulse.to_vec(),
+        ) {
+            let trigger_start =
+                PRConfig.PreSample.saturating_sub(PAConfig.Pileup.DeadTime) as f64;
+            let primary = pulses.iter().position(|p| p.Index >= trigger_start);
+            PI.PulseCount = pulses.len() as u32;
+            PI.PretriggerPulse = primary.unwrap_or(pulses.len()).min(1) as u32;
+            if let Some(second) = primary.and_then(|i| pulses.get(i + 1)) {
+                PI.Pileup = 1;
+                PI.SecondAmplitude = second.Amplitude;
+                PI.SecondTime = second.Time;
+            }
+            PIH.SubPulses = pulses;
+        }
+    }
+
+    // 面積 (V·s)
+    PAH.AreaEnd = match PAConfig.AreaLength {
+        0 => Pulse.len() as u32,
+        l => (PAConfig.AreaStart + l).min(Pulse.len() as u32),
+    };
+    PAH.AreaStart = PAConfig.AreaStart.min(PAH.AreaEnd);
+    PI.Area = Pulse
+        .slice(s![PAH.AreaStart as usize..PAH.AreaEnd as usize])
+        .sum()
+        / PRConfig.Rate;
+
     PIH.Peak = Pulse
         .slice(s![PRConfig.PreSample as usize..PAH.PeakSearch as usize])
         .iter()
@@ -60,9 +315,6 @@ pub fn GetPulseInfo(
 
     // NaN が含まれていないかチェック
     if PeakSearchSlice.iter().any(|v| v.is_nan()) {
-        println!("Pulse:{}",Pulse);
-        println!("Slice:{}",PeakSearchSlice);
-
         return Err(format!(
             "PeakIndex 計算中に NaN が検出されました (PreSample={}..PeakSearch={})",
             PRConfig.PreSample, PAH.PeakSearch
@@ -117,6 +369,72 @@ pub fn GetPulseInfo(
 
     PI.DecayTime = (PIH.DecayLowIndex as f64 - PIH.DecayHighIndex as f64) / PRConfig.Rate;
 
+    // 閾値を横切る位置をサンプル間で補間する (None なら整数のまま)
+    let method = PAConfig.Interpolation;
+    let y = Pulse.to_vec();
+    let Position = |index: usize, left: usize, ratio: f64| {
+        if method == CrossingInterpolation::None || index == 0 {
+            index as f64
+        } else {
+            Crossing(&y, left, PI.PeakAverage * ratio, method)
+        }
+    };
+    PIH.RiseHighPosition = Position(PIH.RiseHighIndex, PIH.RiseHighIndex, PAConfig.RiseHighRatio);
+    PIH.RiseLowPosition = Position(PIH.RiseLowIndex, PIH.RiseLowIndex.saturating_sub(1), PAConfig.RiseLowRatio);
+    PIH.DecayHighPosition = Position(PIH.DecayHighIndex, PIH.DecayHighIndex.saturating_sub(1), PAConfig.DecayHighRatio);
+    PIH.DecayLowPosition = Position(PIH.DecayLowIndex, PIH.DecayLowIndex.saturating_sub(1), PAConfig.DecayLowRatio);
+    PI.RiseHighTime = PIH.RiseHighPosition / PRConfig.Rate;
+    PI.RiseLowTime = PIH.RiseLowPosition / PRConfig.Rate;
+    PI.DecayHighTime = PIH.DecayHighPosition / PRConfig.Rate;
+    PI.DecayLowTime = PIH.DecayLowPosition / PRConfig.Rate;
+    if method != CrossingInterpolation::None {
+        PI.RiseTime = PI.RiseHighTime - PI.RiseLowTime;
+        PI.DecayTime = PI.DecayLowTime - PI.DecayHighTime;
+    }
+
+    if let Some(position) = CFDCrossing(
+        &y,
+        &PAConfig.CFD,
+        PRConfig.PreSample.saturating_sub(PAConfig.CFD.Delay) as usize,
+        PI.PeakIndex as usize,
+        method,
+    ) {
+        PIH.CFDPosition = position;
+        PI.CFDTime = position / PRConfig.Rate;
+    }
+
+    if PAConfig.Fit.Function != PulseFitFunction::None {
+        // 閾値の通過時間を指数関数の時定数に直して初期値にする
+        let Tau = |span: f64, ratio: f64| {
+            if span > 0.0 && ratio.ln() > 0.0 {
+                span / ratio.ln()
+            } else {
+                1.0 / PRConfig.Rate
+            }
+        };
+        let guess = PulseFitGuessS {
+            Amplitude: PI.PeakAverage,
+            Onset: PIH.RiseLowIndex as f64 / PRConfig.Rate,
+            RiseTau: Tau(
+                PI.RiseTime,
+                (1.0 - PAConfig.RiseLowRatio) / (1.0 - PAConfig.RiseHighRatio),
+            ),
+            DecayTau: Tau(PI.DecayTime, PAConfig.DecayHighRatio / PAConfig.DecayLowRatio),
+        };
+        match FitPulse(&PAConfig.Fit, PRConfig.Rate, &y, &guess) {
+            Ok(fit) => {
+                PI.FitAmplitude = fit.Amplitude;
+                PI.FitOnset = fit.Onset;
+                PI.FitRiseTau = fit.RiseTau;
+                PI.FitDecayTau = fit.DecayTau;
+                PI.FitDecayTau2 = fit.DecayTau2;
+                PI.FitDecayTauErr = fit.DecayTauErr;
+                PI.FitRSquared = fit.RSquared;
+            }
+            Err(_) => PI.FitError = 1,
+        }
+    }
+
     return Ok((PI, PIH, PAH));
 }
 
@@ -125,10 +443,58 @@ pub fn GetPulseInfo(
 #[derive(Debug)]
 pub struct PulseInfoS {
     pub(crate) Base: f64,
-    PeakAverage: f64,
-    PeakIndex: u32,
-    RiseTime: f64,
-    DecayTime: f64,
+    pub(crate) PeakAverage: f64,
+    pub(crate) PeakIndex: u32,
+    pub(crate) RiseTime: f64,
+    pub(crate) DecayTime: f64,
+    pub(crate) OFAmplitude: f64,
+    pub(crate) OFTimeShift: f64,
+    pub(crate) FitAmplitude: f64,
+    pub(crate) FitOnset: f64,
+    pub(crate) FitRiseTau: f64,
+    pub(crate) FitDecayTau: f64,
+    pub(crate) FitDecayTau2: f64,
+    pub(crate) FitDecayTauErr: f64,
+    pub(crate) FitRSquared: f64,
+    /// フィットに失敗したら 1
+    pub(crate) FitError: u32,
+    pub(crate) Area: f64,
+    pub(crate) BaseRMS: f64,
+    pub(crate) BaseSlope: f64,
+    /// 末尾のベースライン - プリトリガーのベースライン
+    pub(crate) BaseDiff: f64,
+    /// 校正済みエネルギー (keV)
+    pub(crate) Energy: f64,
+    /// トリガーしたパルスの数 (トリガーできなければ 0)
+    pub(crate) PulseCount: u32,
+    pub(crate) Pileup: u32,
+    pub(crate) PretriggerPulse: u32,
+    pub(crate) SecondAmplitude: f64,
+    pub(crate) SecondTime: f64,
+    /// 閾値を横切った時刻 (秒, 記録の先頭から)
+    pub(crate) RiseLowTime: f64,
+    pub(crate) RiseHighTime: f64,
+    pub(crate) DecayHighTime: f64,
+    pub(crate) DecayLowTime: f64,
+    pub(crate) CFDTime: f64,
+}
+
+/// Info.csv の列を名前で引く (古い Info.csv に無い列は None)
+fn InfoField<T: FromStr>(
+    record: &csv::StringRecord,
+    columns: &HashMap<String, usize>,
+    name: &str,
+) -> Result<Option<T>, String>
+where
+    T::Err: std::fmt::Display,
+{
+    match columns.get(name).and_then(|&i| record.get(i)) {
+        Some(v) => v
+            .parse()
+            .map(Some)
+            .map_err(|e| format!("Failed to parse {} '{}'\n{}", name, v, e)),
+        None => Ok(None),
+    }
 }
 
 impl PulseInfoS {
@@ -139,8 +505,155 @@ impl PulseInfoS {
             PeakIndex: 0,
             RiseTime: 0.0,
             DecayTime: 0.0,
+            OFAmplitude: 0.0,
+            OFTimeShift: 0.0,
+            FitAmplitude: 0.0,
+            FitOnset: 0.0,
+            FitRiseTau: 0.0,
+            FitDecayTau: 0.0,
+            FitDecayTau2: 0.0,
+            FitDecayTauErr: 0.0,
+            FitRSquared: 0.0,
+            FitError: 0,
+            Area: 0.0,
+            BaseRMS: 0.0,
+            BaseSlope: 0.0,
+            BaseDiff: 0.0,
+            Energy: 0.0,
+            PulseCount: 0,
+            Pileup: 0,
+            PretriggerPulse: 0,
+            SecondAmplitude: 0.0,
+            SecondTime: 0.0,
+            RiseLowTime: 0.0,
+            RiseHighTime: 0.0,
+            DecayHighTime: 0.0,
+            DecayLowTime: 0.0,
+            CFDTime: 0.0,
         }
     }
+
+    /// フィールドを名前で引く (整数のフィールドも f64 で返す)
+    pub fn Field(&self, name: &str) -> Option<f64> {
+        Some(match name {
+            "Base" => self.Base,
+            "PeakAverage" => self.PeakAverage,
+            "PeakIndex" => self.PeakIndex as f64,
+            "RiseTime" => self.RiseTime,
+            "DecayTime" => self.DecayTime,
+            "OFAmplitude" => self.OFAmplitude,
+            "OFTimeShift" => self.OFTimeShift,
+            "FitAmplitude" => self.FitAmplitude,
+            "FitOnset" => self.FitOnset,
+            "FitRiseTau" => self.FitRiseTau,
+            "FitDecayTau" => self.FitDecayTau,
+            "FitDecayTau2" => self.FitDecayTau2,
+            "FitDecayTauErr" => self.FitDecayTauErr,
+            "FitRSquared" => self.FitRSquared,
+            "FitError" => self.FitError as f64,
+            "Area" => self.Area,
+            "BaseRMS" => self.BaseRMS,
+            "BaseSlope" => self.BaseSlope,
+            "BaseDiff" => self.BaseDiff,
+            "Energy" => self.Energy,
+            "PulseCount" => self.PulseCount as f64,
+            "Pileup" => self.Pileup as f64,
+            "PretriggerPulse" => self.PretriggerPulse as f64,
+            "SecondAmplitude" => self.SecondAmplitude,
+            "SecondTime" => self.SecondTime,
+            "RiseLowTime" => self.RiseLowTime,
+            "RiseHighTime" => self.RiseHighTime,
+            "DecayHighTime" => self.DecayHighTime,
+            "DecayLowTime" => self.DecayLowTime,
+            "CFDTime" => self.CFDTime,
+            _ => return None,
+        })
+    }
+
+    /// Info.csv のヘッダ (key 列を含む)
+    pub const CSV_HEADER: &'static str =
+        "key,Base,PeakAverage,PeakIndex,RiseTime,DecayTime,OFAmplitude,OFTimeShift,\
+         FitAmplitude,FitOnset,FitRiseTau,FitDecayTau,FitDecayTau2,FitDecayTauErr,FitRSquared,FitError,\
+         Area,BaseRMS,BaseSlope,BaseDiff,Energy,\
+         PulseCount,Pileup,PretriggerPulse,SecondAmplitude,SecondTime,\
+         RiseLowTime,RiseHighTime,DecayHighTime,DecayLowTime,CFDTime";
+
+    pub fn ToCSVRow(&self, key: u32) -> String {
+        format!(
+            "{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{}",
+            key,
+            self.Base,
+            self.PeakAverage,
+            self.PeakIndex,
+            self.RiseTime,
+            self.DecayTime,
+            self.OFAmplitude,
+            self.OFTimeShift,
+            self.FitAmplitude,
+            self.FitOnset,
+            self.FitRiseTau,
+            self.FitDecayTau,
+            self.FitDecayTau2,
+            self.FitDecayTauErr,
+            self.FitRSquared,
+            self.FitError,
+            self.Area,
+            self.BaseRMS,
+            self.BaseSlope,
+            self.BaseDiff,
+            self.Energy,
+            self.PulseCount,
+            self.Pileup,
+            self.PretriggerPulse,
+            self.SecondAmplitude,
+            self.SecondTime,
+            self.RiseLowTime,
+            self.RiseHighTime,
+            self.DecayHighTime,
+            self.DecayLowTime,
+            self.CFDTime
+        )
+    }
+
+    fn FromCSVRecord(
+        record: &csv::StringRecord,
+        columns: &HashMap<String, usize>,
+    ) -> Result<(u32, Self), String> {
+        let missing = |name: &str| format!("Missing column {}", name);
+        let key: u32 = InfoField(record, columns, "key")?.ok_or_else(|| missing("key"))?;
+        let mut PI = Self::new();
+        PI.Base = InfoField(record, columns, "Base")?.ok_or_else(|| missing("Base"))?;
+        PI.PeakAverage = InfoField(record, columns, "PeakAverage")?.ok_or_else(|| missing("PeakAverage"))?;
+        PI.PeakIndex = InfoField(record, columns, "PeakIndex")?.ok_or_else(|| missing("PeakIndex"))?;
+        PI.RiseTime = InfoField(record, columns, "RiseTime")?.ok_or_else(|| missing("RiseTime"))?;
+        PI.DecayTime = InfoField(record, columns, "DecayTime")?.ok_or_else(|| missing("DecayTime"))?;
+        PI.OFAmplitude = InfoField(record, columns, "OFAmplitude")?.unwrap_or_default();
+        PI.OFTimeShift = InfoField(record, columns, "OFTimeShift")?.unwrap_or_default();
+        PI.FitAmplitude = InfoField(record, columns, "FitAmplitude")?.unwrap_or_default();
+        PI.FitOnset = InfoField(record, columns, "FitOnset")?.unwrap_or_default();
+        PI.FitRiseTau = InfoField(record, columns, "FitRiseTau")?.unwrap_or_default();
+        PI.FitDecayTau = InfoField(record, columns, "FitDecayTau")?.unwrap_or_default();
+        PI.FitDecayTau2 = InfoField(record, columns, "FitDecayTau2")?.unwrap_or_default();
+        PI.FitDecayTauErr = InfoField(record, columns, "FitDecayTauErr")?.unwrap_or_default();
+        PI.FitRSquared = InfoField(record, columns, "FitRSquared")?.unwrap_or_default();
+        PI.FitError = InfoField(record, columns, "FitError")?.unwrap_or_default();
+        PI.Area = InfoField(record, columns, "Area")?.unwrap_or_default();
+        PI.BaseRMS = InfoField(record, columns, "BaseRMS")?.unwrap_or_default();
+        PI.BaseSlope = InfoField(record, columns, "BaseSlope")?.unwrap_or_default();
+        PI.BaseDiff = InfoField(record, columns, "BaseDiff")?.unwrap_or_default();
+        PI.Energy = InfoField(record, columns, "Energy")?.unwrap_or_default();
+        PI.PulseCount = InfoField(record, columns, "PulseCount")?.unwrap_or_default();
+        PI.Pileup = InfoField(record, columns, "Pileup")?.unwrap_or_default();
+        PI.PretriggerPulse = InfoField(record, columns, "PretriggerPulse")?.unwrap_or_default();
+        PI.SecondAmplitude = InfoField(record, columns, "SecondAmplitude")?.unwrap_or_default();
+        PI.SecondTime = InfoField(record, columns, "SecondTime")?.unwrap_or_default();
+        PI.RiseLowTime = InfoField(record, columns, "RiseLowTime")?.unwrap_or_default();
+        PI.RiseHighTime = InfoField(record, columns, "RiseHighTime")?.unwrap_or_default();
+        PI.DecayHighTime = InfoField(record, columns, "DecayHighTime")?.unwrap_or_default();
+        PI.DecayLowTime = InfoField(record, columns, "DecayLowTime")?.unwrap_or_default();
+        PI.CFDTime = InfoField(record, columns, "CFDTime")?.unwrap_or_default();
+        Ok((key, PI))
+    }
 }
 
 #[derive(Serialize)]
@@ -150,6 +663,13 @@ pub struct PulseInfoHelperS {
     RiseLowIndex: usize,
     DecayHighIndex: usize,
     DecayLowIndex: usize,
+    /// 補間した位置 (サンプル単位の小数)
+    RiseHighPosition: f64,
+    RiseLowPosition: f64,
+    DecayHighPosition: f64,
+    DecayLowPosition: f64,
+    CFDPosition: f64,
+    SubPulses: Vec<SubPulseS>,
 }
 
 impl PulseInfoHelperS {
@@ -160,6 +680,12 @@ impl PulseInfoHelperS {
             RiseLowIndex: 0,
             DecayHighIndex: 0,
             DecayLowIndex: 0,
+            RiseHighPosition: 0.0,
+            RiseLowPosition: 0.0,
+            DecayHighPosition: 0.0,
+            DecayLowPosition: 0.0,
+            CFDPosition: 0.0,
+            SubPulses: Vec::new(),
         }
     }
 }
@@ -171,6 +697,10 @@ pub struct PulseAnalysisHelperS {
     PeakSearch: u32,
     PeakAverageStart: u32,
     PeakAverageEnd: u32,
+    PostBaseStart: u32,
+    PostBaseEnd: u32,
+    AreaStart: u32,
+    AreaEnd: u32,
 }
 
 impl PulseAnalysisHelperS {
@@ -181,8 +711,94 @@ impl PulseAnalysisHelperS {
             PeakSearch: PRConfig.PreSample + PAConfig.PeakSearchSample,
             PeakAverageStart: 0,
             PeakAverageEnd: 0,
+            PostBaseStart: 0,
+            PostBaseEnd: 0,
+            AreaStart: 0,
+            AreaEnd: 0,
         }
     }
+
+    /// ベースラインとピーク探索 (とその後の平均) に必要なレコード長
+    pub fn MinimumLength(&self, PAConfig: &PulseAnalysisConfig) -> u32 {
+        max(self.BaseEnd, self.PeakSearch + PAConfig.PeakAveragePostSample) + 1
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct CutCountS {
+    pub Name: String,
+    pub Rejected: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PulseSelectionS {
+    pub Total: usize,
+    pub Passed: Vec<u32>,
+    /// カットごとに独立に数えた除外数
+    pub Cuts: Vec<CutCountS>,
+}
+
+/// 解析待ちのファイル
+#[derive(Debug, Clone)]
+struct PendingPulseS {
+    Key: u32,
+    Path: PathBuf,
+    /// キャッシュに記録する名前
+    Name: String,
+    Entry: FileEntryS,
+}
+
+fn CacheName(path: &Path) -> String {
+    path.file_name()
+        .map_or_else(|| path.to_string_lossy(), |name| name.to_string_lossy())
+        .into_owned()
+}
+
+fn CSVErr(path: &Path, e: impl std::fmt::Display) -> TESErrorS {
+    TESErrorS::AtPath(TESErr::CSVParseErr, path, format!("Failed to parse CSV\n{}", e))
+}
+
+fn JsonErr(path: &Path, e: impl std::fmt::Display) -> TESErrorS {
+    TESErrorS::AtPath(TESErr::JsonParseErr, path, format!("Failed to parse JSON\n{}", e))
+}
+
+fn NoPulseInfo(Channel: &u32) -> TESErrorS {
+    TESErrorS::new(TESErr::NotAnalyzed, format!("No pulse info for channel {}", Channel))
+}
+
+fn UnknownField(Field: &str) -> TESErrorS {
+    TESErrorS::new(TESErr::ConfigErr, format!("Unknown field '{}'", Field))
+}
+
+/// ファイルサイズから最も多いレコード長 (サンプル数) を求める
+fn ModalLength<'a>(entries: impl Iterator<Item = &'a FileEntryS>) -> Option<usize> {
+    let mut counts: HashMap<u64, usize> = HashMap::new();
+    for entry in entries {
+        *counts.entry(entry.Size).or_insert(0) += 1;
+    }
+    // 同数なら大きい方 (途中で切れたファイルの方を外す)
+    let (size, _) = counts.into_iter().max_by_key(|(size, count)| (*count, *size))?;
+    // LoadBi と同じく先頭 4 バイトはヘッダ
+    Some((size.saturating_sub(4) / size_of::<f64>() as u64) as usize)
+}
+
+/// キャッシュに記録された除外ファイル (key 順)
+fn RejectedPulses(pulses: &[(u32, PathBuf)], cache: &FileCacheS) -> Vec<RejectedPulseS> {
+    let mut rejected: Vec<RejectedPulseS> = pulses
+        .iter()
+        .filter_map(|(key, path)| {
+            let File = CacheName(path);
+            let rejection = cache.Files.get(&File)?.Rejected.clone()?;
+            Some(RejectedPulseS {
+                Key: *key,
+                File,
+                Reason: rejection.Reason,
+                Detail: rejection.Detail,
+            })
+        })
+        .collect();
+    rejected.sort_by_key(|r| r.Key);
+    rejected
 }
 
 pub struct PulseProcessorS {
@@ -191,8 +807,17 @@ pub struct PulseProcessorS {
     pub(crate) PAConfig: PulseAnalysisConfig,
     pub Channels: HashSet<u32>,
     pub PulseInfosCH: HashMap<u32, HashMap<u32, PulseInfoS>>,
-    InfoCSVExist: HashMap<u32, bool>,
-    pub BesselCoeffs: Vec<Vec<f64>>,
+    /// Info.csv とキャッシュが rawdata と設定に追いついているか
+    UpToDate: HashMap<u32, bool>,
+    pub Filter: PulseFilterS,
+    pub(crate) Backend: BackendConfig,
+    pub OptimalFilters: HashMap<u32, OptimalFilterS>,
+    pub(crate) Cuts: Vec<PulseCutConfig>,
+    pub EnergyCalibrations: HashMap<u32, EnergyCalibrationS>,
+    pub AveragePulses: HashMap<u32, AveragePulseS>,
+    pub NoiseSpectra: HashMap<u32, NoiseSpectrumS>,
+    /// 解析できなかったファイル
+    pub Rejections: HashMap<u32, Vec<RejectedPulseS>>,
 }
 
 impl PulseProcessorS {
@@ -203,111 +828,159 @@ impl PulseProcessorS {
             PAConfig: PulseAnalysisConfig::new(),
             Channels: HashSet::new(),
             PulseInfosCH: HashMap::new(),
-            InfoCSVExist: HashMap::new(),
-            BesselCoeffs: Vec::new(),
+            UpToDate: HashMap::new(),
+            Filter: PulseFilterS::None,
+            Backend: BackendConfig::Native,
+            OptimalFilters: HashMap::new(),
+            Cuts: Vec::new(),
+            EnergyCalibrations: HashMap::new(),
+            AveragePulses: HashMap::new(),
+            NoiseSpectra: HashMap::new(),
+            Rejections: HashMap::new(),
         }
     }
 
-    pub fn SetDataPath(&mut self, path: &Path) {
-        self.DP.SetDataPath(path);
+    pub fn SetDataPath(&mut self, path: &Path) -> TESResult<()> {
+        self.DP.SetDataPath(path)
+    }
+
+    pub fn SetBackend(&mut self, backend: BackendConfig) {
+        self.Backend = backend;
     }
 
-    pub fn SavePulseInfos(&self, Channel: &u32) -> Result<(), String> {
+    pub fn SavePulseInfos(&self, Channel: &u32) -> TESResult<()> {
         let InfoPath = self
             .DP
             .DataPath
             .join(format!("CH{}_pulse", Channel))
             .join("Info.csv");
         let mut InfoFile = File::create(&InfoPath)
-            .map_err(|e| format!("Failed to create {:?}\n{}", InfoPath, e))?;
-        InfoFile
-            .write_all("key,Base,PeakAverage,PeakIndex,RiseTime,DecayTime\n".as_bytes())
-            .expect("Failed to write file");
-        for (key, value) in self.PulseInfosCH.get(Channel).unwrap() {
-            InfoFile
-                .write_all(
-                    format!(
-                        "{},{},{},{},{},{}\n",
-                        key,
-                        value.Base,
-                        value.PeakAverage,
-                        value.PeakIndex,
-                        value.RiseTime,
-                        value.DecayTime
-                    )
-                    .as_bytes(),
-                )
-                .expect("Failed to write file");
+            .map_err(|e| TESErrorS::AtPath(TESErr::FileCreateErr, &InfoPath, format!("Failed to create file\n{}", e)))?;
+        let mut lines = vec![PulseInfoS::CSV_HEADER.to_string()];
+        for (key, value) in self.PulseInfosCH.get(Channel).ok_or_else(|| NoPulseInfo(Channel))? {
+            lines.push(value.ToCSVRow(*key));
         }
+        InfoFile
+            .write_all((lines.join("\n") + "\n").as_bytes())
+            .map_err(|e| TESErrorS::AtPath(TESErr::FileWriteErr, &InfoPath, format!("Failed to write file\n{}", e)))?;
         return Ok(());
     }
 
-    pub fn SaveConfig(&mut self,new_config: serde_json::Value) -> Result<(), String> {
+    pub fn SaveRejected(&self, Channel: &u32) -> TESResult<()> {
+        let CsvPath = self
+            .DP
+            .DataPath
+            .join(format!("CH{}_pulse", Channel))
+            .join("Rejected.csv");
+        let mut lines = vec!["key,File,Reason,Detail".to_string()];
+        for r in self.Rejections.get(Channel).into_iter().flatten() {
+            lines.push(format!(
+                "{},{},{:?},{}",
+                r.Key,
+                CsvField(&r.File),
+                r.Reason,
+                CsvField(&r.Detail)
+            ));
+        }
+        WriteFile(&CsvPath, lines.join("\n") + "\n")
+    }
+
+    /// チャンネルごとの除外数と理由の内訳
+    pub fn RejectSummaries(&self) -> Vec<RejectSummaryS> {
+        let mut channels: Vec<u32> = self.Channels.iter().cloned().collect();
+        channels.sort();
+        channels
+            .into_iter()
+            .map(|ch| {
+                let rejected = self.Rejections.get(&ch).map_or(&[][..], |r| r.as_slice());
+                let accepted = self.PulseInfosCH.get(&ch).map_or(0, |infos| infos.len());
+                RejectSummaryS::new(ch, accepted + rejected.len(), rejected)
+            })
+            .collect()
+    }
+
+    pub fn SaveConfig(&mut self,new_config: serde_json::Value) -> TESResult<()> {
         let JsonPath = self.DP.DataPath.join("PulseConfig.json");
 
         // JSONをきれいに整形して文字列に変換
         let json_str = serde_json::to_string_pretty(&new_config)
-            .map_err(|e| format!("Failed to serialize config: {}", e))?;
+            .map_err(|e| TESErrorS::new(TESErr::SerializeErr, format!("Failed to serialize config: {}", e)))?;
 
         // ファイルに書き込む
-        std::fs::write(JsonPath, json_str)
-            .map_err(|e| format!("Failed to write config file: {}", e))?;
+        WriteFile(&JsonPath, json_str)?;
+
+        self.ApplyConfig(new_config)
+    }
+
+    /// PulseConfig.json を読む (無ければ既定の設定をコピーする)
+    pub fn LoadConfig(&self) -> TESResult<serde_json::Value> {
+        let JsonPath = self.DP.DataPath.join("PulseConfig.json");
+
+        if !JsonPath.exists() {
+            let JsonPathDefault = PathBuf::from("./Config/PulseConfig.json");
+            if JsonPathDefault.exists() {
+                std::fs::copy(&JsonPathDefault, &JsonPath).map_err(|e| {
+                    TESErrorS::AtPath(TESErr::FileCreateErr, &JsonPath, format!("Failed to copy the default config\n{}", e))
+                })?;
+            } else {
+                return Err(TESErrorS::AtPath(TESErr::FileNotFound, &JsonPathDefault, "Failed to find the default config"));
+            }
+        }
 
-        let PPC: PulseProcessorConfig = serde_json::from_value(new_config)
-            .map_err(|e| format!("Failed to parse \n{}",  e))?;
+        let JsonFile =
+            File::open(&JsonPath).map_err(|e| TESErrorS::Open(&JsonPath, &e))?;
+        serde_json::from_reader(JsonFile).map_err(|e| JsonErr(&JsonPath, e))
+    }
+
+    /// 設定を反映してフィルタを設計し直す (ファイルには書かない)
+    pub fn ApplyConfig(&mut self, config: serde_json::Value) -> TESResult<()> {
+        let PPC: PulseProcessorConfig = serde_json::from_value(config).map_err(|e| {
+            TESErrorS::AtPath(
+                TESErr::JsonParseErr,
+                &self.DP.DataPath.join("PulseConfig.json"),
+                format!("Failed to parse config\n{}", e),
+            )
+        })?;
         self.PRConfig = PPC.Readout;
         self.PAConfig = PPC.Analysis;
+        self.Backend = PPC.Backend;
+        self.Cuts = PPC.Cuts;
+        self.Filter =
+            PulseFilterS::Design(&*CreateBackend(&self.Backend), &self.PRConfig, &self.PAConfig)?;
 
         Ok(())
     }
 
-    pub fn LoadPulseInfos(&mut self, Channel: &u32) -> Result<(), String> {
+    pub fn LoadPulseInfos(&mut self, Channel: &u32) -> TESResult<()> {
         let InfoPath = self
             .DP
             .DataPath
             .join(format!("CH{}_pulse", Channel))
             .join("Info.csv");
         let InfoFile =
-            File::open(&InfoPath).map_err(|e| format!("Failed to open {:?}\n{}", InfoPath, e))?;
+            File::open(&InfoPath).map_err(|e| TESErrorS::Open(&InfoPath, &e))?;
         let mut InfoReader = csv::Reader::from_reader(InfoFile);
+        let columns: HashMap<String, usize> = InfoReader
+            .headers()
+            .map_err(|e| CSVErr(&InfoPath, e))?
+            .iter()
+            .enumerate()
+            .map(|(i, name)| (name.to_string(), i))
+            .collect();
         let mut InfoMap = HashMap::new();
         for record in InfoReader.records() {
-            let record = record.map_err(|e| format!("Failed to parse {:?}\n{}", InfoPath, e))?;
-            let key: u32 = record[0]
-                .parse()
-                .map_err(|e| format!("Failed to parse {:?}\n{}", InfoPath, e))?;
-            let Base: f64 = record[1]
-                .parse()
-                .map_err(|e| format!("Failed to parse {:?}\n{}", InfoPath, e))?;
-            let PeakAverage: f64 = record[2]
-                .parse()
-                .map_err(|e| format!("Failed to parse {:?}\n{}", InfoPath, e))?;
-            let PeakIndex: u32 = record[3]
-                .parse()
-                .map_err(|e| format!("Failed to parse {:?}\n{}", InfoPath, e))?;
-            let RiseTime: f64 = record[4]
-                .parse()
-                .map_err(|e| format!("Failed to parse {:?}\n{}", InfoPath, e))?;
-            let DecayTime: f64 = record[5]
-                .parse()
-                .map_err(|e| format!("Failed to parse {:?}\n{}", InfoPath, e))?;
-            InfoMap.insert(
-                key,
-                PulseInfoS {
-                    Base,
-                    PeakAverage,
-                    PeakIndex,
-                    RiseTime,
-                    DecayTime,
-                },
-            );
+            let record = record.map_err(|e| CSVErr(&InfoPath, e))?;
+            let (key, info) =
+                PulseInfoS::FromCSVRecord(&record, &columns).map_err(|e| CSVErr(&InfoPath, e))?;
+            InfoMap.insert(key, info);
         }
         self.PulseInfosCH.insert(*Channel, InfoMap);
         Ok(())
     }
 
 
-    pub fn AnalyzePulse<G: FnMut(u32)>(&mut self, Channel: &u32, mut progress_callback: G) -> Result<(), String> {
+    /// rawdata の (key, パス) の組
+    fn PulsePaths(&self, Channel: &u32) -> TESResult<Vec<(u32, PathBuf)>> {
         let pulse_pattern = Regex::new(r"CH\d+_(\d+)\.dat$").map_err(|e| format!("Regex Error\n{}", e))?;
 
         let pulse_paths = glob(&format!(
@@ -316,48 +989,151 @@ impl PulseProcessorS {
             Channel,
             Channel
         ))
-            .map_err(|e| format!("Failed to glob Pulse files: {}", e))?
+            .map_err(|e| TESErrorS::new(TESErr::PulseGlobErr, format!("Failed to glob Pulse files: {}", e)))?
             .filter_map(Result::ok)
             .collect::<Vec<_>>();
 
-        let total = pulse_paths.len() as u32;
-        let done = Arc::new(AtomicUsize::new(0));
-
-        let numbers: Vec<i32> = pulse_paths.iter()
+        // ファイル名から key を取り出して組にする
+        let pulses: Vec<(u32, PathBuf)> = pulse_paths
+            .into_iter()
             .filter_map(|path| {
-                pulse_pattern.captures(path.to_string_lossy().as_ref())?
-                    .get(1)?.as_str().parse::<i32>().ok()
+                let key = pulse_pattern
+                    .captures(path.to_string_lossy().as_ref())?
+                    .get(1)?
+                    .as_str()
+                    .parse::<u32>()
+                    .ok()?;
+                Some((key, path))
             })
             .collect();
+        Ok(pulses)
+    }
 
-        let pulse_infos_mutex = Arc::new(Mutex::new(HashMap::new()));
+    fn CachePath(&self, Channel: &u32) -> PathBuf {
+        self.DP
+            .DataPath
+            .join(format!("CH{}_pulse", Channel))
+            .join("Cache.json")
+    }
+
+    /// 前回から追加・変更されたファイルとキャッシュを返す (設定が変わっていれば全ファイル)
+    fn PendingPulses(
+        &self,
+        Channel: &u32,
+        pulses: &[(u32, PathBuf)],
+    ) -> TESResult<(FileCacheS, Vec<PendingPulseS>)> {
+        let hash = ConfigHash(&self.PRConfig, &self.PAConfig, &self.Backend)?;
+        let mut cache = FileCacheS::Load(&self.CachePath(Channel));
+        if cache.ConfigHash != hash || !self.PulseInfosCH.contains_key(Channel) {
+            cache = FileCacheS::new(hash);
+        }
+        let pending = pulses
+            .par_iter()
+            .map(|(key, path)| {
+                let entry = FileEntryS::Of(path)?;
+                let name = CacheName(path);
+                Ok((!cache.IsCurrent(&name, &entry)).then_some(PendingPulseS {
+                    Key: *key,
+                    Path: path.clone(),
+                    Name: name,
+                    Entry: entry,
+                }))
+            })
+            .collect::<TESResult<Vec<_>>>()?
+            .into_iter()
+            .flatten()
+            .collect();
+        Ok((cache, pending))
+    }
+
+    /// 追加・変更されたファイルだけ解析する (途中で止まっても CACHE_CHUNK ごとに保存した所から再開できる)
+    ///
+    /// cancel が立つと解析中のファイルを片付けて、そこまでの結果を保存してから Err を返す
+    pub fn AnalyzePulse<G: FnMut(u32)>(
+        &mut self,
+        Channel: &u32,
+        cancel: &Arc<AtomicBool>,
+        mut progress_callback: G,
+    ) -> TESResult<()> {
+        let pulses = self.PulsePaths(Channel)?;
+        if !self.PulseInfosCH.contains_key(Channel) {
+            // Info.csv が無ければ全部解析し直す
+            self.LoadPulseInfos(Channel).ok();
+        }
+        let (mut cache, pending) = self.PendingPulses(Channel, &pulses)?;
+
+        // 消えたファイルと解析し直すファイルの結果は捨てる
+        let current: HashSet<String> = pulses.iter().map(|(_, path)| CacheName(path)).collect();
+        let changed: HashSet<&str> = pending.iter().map(|p| p.Name.as_str()).collect();
+        cache
+            .Files
+            .retain(|name, _| current.contains(name) && !changed.contains(name.as_str()));
+        let kept: HashSet<u32> = pulses
+            .iter()
+            .filter(|(_, path)| cache.Files.contains_key(&CacheName(path)))
+            .map(|(key, _)| *key)
+            .collect();
+        let infos = self.PulseInfosCH.entry(*Channel).or_default();
+        infos.retain(|key, _| kept.contains(key));
+
+        // 大半のレコードと長さが違うファイルは除く
+        let ExpectedLength = ModalLength(
+            pending
+                .iter()
+                .map(|p| &p.Entry)
+                .chain(cache.Files.values()),
+        );
+
+        let total = pending.len() as u32;
+        let done = Arc::new(AtomicUsize::new(0));
         let done_clone = Arc::clone(&done);
 
         let PRConfig = self.PRConfig.clone();
         let PAConfig = self.PAConfig.clone();
 
-        // Rayon 並列処理を別スレッドで起動
-        let bessel_clone = self.BesselCoeffs.clone();
-        let pulse_infos_clone = Arc::clone(&pulse_infos_mutex);
-        let numbers_clone = numbers.clone();
-        let paths_clone = pulse_paths.clone();
+        // Rayon 並列処理を別スレッドで起動し、CACHE_CHUNK ごとに結果を送る
+        let filter_clone = self.Filter.clone();
+        let (sender, receiver) = mpsc::channel();
+        let cancel_clone = Arc::clone(cancel);
         let handle = std::thread::spawn(move || {
-            numbers_clone.par_iter()
-                .zip(paths_clone.par_iter())
-                .for_each(|(num, path)| {
-                    if let Ok(pulse) = LoadBi(path) {
-                        let filtered_pulse = filtfilt(&bessel_clone[0],&bessel_clone[1],&pulse).map_err(|e| format!("Filter error: {}", e)).unwrap();
-                        if let Ok((pi, _, _)) = GetPulseInfo(&PRConfig,&PAConfig,Array1::from(filtered_pulse)) {
-                            let mut map = pulse_infos_clone.lock().unwrap();
-                            map.insert(*num as u32, pi);
-                        }
-                    }
-                    done_clone.fetch_add(1, Ordering::SeqCst);
-                });
+            for chunk in pending.chunks(CACHE_CHUNK) {
+                // 中断されたファイルはキャッシュに載せず、次回に回す
+                let results: Vec<(PendingPulseS, Result<PulseInfoS, RejectionS>)> = chunk
+                    .par_iter()
+                    .filter(|_| !cancel_clone.load(Ordering::SeqCst))
+                    .map(|p| {
+                        let pi = AnalyzeFile(&PRConfig, &PAConfig, &filter_clone, &p.Path, ExpectedLength);
+                        done_clone.fetch_add(1, Ordering::SeqCst);
+                        (p.clone(), pi)
+                    })
+                    .collect();
+                if sender.send(results).is_err() || cancel_clone.load(Ordering::SeqCst) {
+                    return;
+                }
+            }
         });
 
-        // メインスレッドで進捗を監視してprogress_callbackを呼ぶ
-        while done.load(Ordering::SeqCst) < total as usize {
+        // メインスレッドで進捗を監視してprogress_callbackを呼び、届いた結果を保存する
+        let CachePath = self.CachePath(Channel);
+        let mut Merge = |this: &mut Self, results: Vec<(PendingPulseS, Result<PulseInfoS, RejectionS>)>| -> TESResult<()> {
+            let infos = this.PulseInfosCH.entry(*Channel).or_default();
+            for (p, pi) in results {
+                let mut entry = p.Entry;
+                match pi {
+                    Ok(pi) => {
+                        infos.insert(p.Key, pi);
+                    }
+                    Err(rejection) => entry.Rejected = Some(rejection),
+                }
+                cache.Files.insert(p.Name, entry);
+            }
+            this.SavePulseInfos(Channel)?;
+            cache.Save(&CachePath)
+        };
+        while done.load(Ordering::SeqCst) < total as usize && !handle.is_finished() {
+            while let Ok(results) = receiver.try_recv() {
+                Merge(self, results)?;
+            }
             let current = done.load(Ordering::SeqCst) as u32;
             let percent = (current * 100) / total;
             progress_callback(percent);
@@ -368,21 +1144,610 @@ impl PulseProcessorS {
         progress_callback(100);
 
         // 並列処理スレッドの終了を待つ
-        handle.join().map_err(|_| "Join thread failed".to_string())?;
+        handle
+            .join()
+            .map_err(|_| TESErrorS::new(TESErr::AnalysisErr, "Join thread failed"))?;
+        for results in receiver.try_iter() {
+            Merge(self, results)?;
+        }
+        // 新しいファイルが無くても消えたファイルの分を反映する
+        Merge(self, Vec::new())?;
+        self.Rejections.insert(*Channel, RejectedPulses(&pulses, &cache));
+        self.SaveRejected(Channel)?;
+        if cancel.load(Ordering::SeqCst) {
+            return Err(TESErrorS::new(
+                TESErr::Cancelled,
+                format!("Analysis of CH{} was cancelled", Channel),
+            ));
+        }
+
+        if self.PAConfig.OptimalFilter.Enable {
+            self.ApplyOptimalFilter(Channel, &pulses)?;
+        }
+        self.ApplyEnergyCalibration(Channel);
+        Ok(())
+    }
+
+    /// 最適フィルタ窓 (開始, 長さ) をサンプル単位で返す
+    ///
+    /// WindowLength が 0 の時は記録の最後まで。CH{n}_noise が無ければ雑音をプリトリガーから取るので、
+    /// プリトリガーの長さにして、WindowStart も 0 ならトリガーが窓の真ん中に来るように置く
+    fn OptimalFilterWindow(&self, Channel: &u32) -> TESResult<(usize, usize)> {
+        let OFConfig = &self.PAConfig.OptimalFilter;
+        let Sample = self.PRConfig.Sample as usize;
+        let PreSample = self.PRConfig.PreSample as usize;
+        let (start, length) = match (OFConfig.WindowStart as usize, OFConfig.WindowLength) {
+            (0, 0) if self.NoisePaths(Channel)?.is_empty() => (PreSample - PreSample / 2, PreSample),
+            (start, 0) if self.NoisePaths(Channel)?.is_empty() => (start, PreSample),
+            (start, 0) => (start, Sample.saturating_sub(start)),
+            (start, l) => (start, l as usize),
+        };
+        if !(start..start + length).contains(&PreSample) {
+            return Err(TESErrorS::new(TESErr::ConfigErr, format!(
+                "Optimal filter window {}..{} does not contain the trigger (PreSample={})",
+                start,
+                start + length,
+                PreSample
+            )));
+        }
+        if length < 2 || start + length > Sample {
+            return Err(TESErrorS::new(TESErr::ConfigErr, format!(
+                "Optimal filter window {}..{} is outside the record (Sample={})",
+                start,
+                start + length,
+                Sample
+            )));
+        }
+        Ok((start, length))
+    }
+
+    fn NoisePaths(&self, Channel: &u32) -> TESResult<Vec<PathBuf>> {
+        Ok(glob(&format!(
+            "{}/CH{}_noise/rawdata/CH{}_*.dat",
+            self.DP.DataPath.display(),
+            Channel,
+            Channel
+        ))
+        .map_err(|e| TESErrorS::new(TESErr::PulseGlobErr, format!("Failed to glob Noise files: {}", e)))?
+        .filter_map(Result::ok)
+        .collect())
+    }
 
-        // PulseInfos を取り出し
-        let pulse_infos = Arc::try_unwrap(pulse_infos_mutex)
-            .map_err(|_| "Arc still has multiple owners".to_string())?
-            .into_inner()
-            .map_err(|e| format!("Mutex poisoned: {}", e))?;
+    /// 雑音波形を集める (CH{n}_noise があればそれを、無ければプリトリガー部分を使う)
+    fn CollectNoise(
+        &self,
+        Channel: &u32,
+        pulses: &[(u32, PathBuf)],
+        length: usize,
+    ) -> TESResult<Vec<Vec<f64>>> {
+        let noise_paths = self.NoisePaths(Channel)?;
+
+        if !noise_paths.is_empty() {
+            // 長い雑音波形は WelchPSD で窓の長さごとに区切る
+            return Ok(noise_paths
+                .par_iter()
+                .filter_map(|path| LoadBi(path).ok())
+                .map(|noise| noise.to_vec())
+                .collect());
+        }
 
-        self.PulseInfosCH.insert(*Channel, pulse_infos);
+        if length > self.PRConfig.PreSample as usize {
+            return Err(TESErrorS::new(TESErr::ConfigErr, format!(
+                "CH{}_noise was not found and the pretrigger ({}) is shorter than the optimal filter window ({})",
+                Channel, self.PRConfig.PreSample, length
+            )));
+        }
+        Ok(pulses
+            .par_iter()
+            .filter_map(|(_, path)| LoadBi(path).ok())
+            .filter(|pulse| pulse.len() >= length)
+            .map(|pulse| pulse.slice(s![..length]).to_vec())
+            .collect())
+    }
+
+    /// 良いパルスの平均と雑音スペクトルから最適フィルタを作り、全パルスの振幅を求める
+    fn ApplyOptimalFilter(&mut self, Channel: &u32, pulses: &[(u32, PathBuf)]) -> TESResult<()> {
+        let OFConfig = self.PAConfig.OptimalFilter.clone();
+        let (start, length) = self.OptimalFilterWindow(Channel)?;
+        let infos = self
+            .PulseInfosCH
+            .get(Channel)
+            .ok_or_else(|| NoPulseInfo(Channel))?;
+
+        let IsTemplatePulse = |pi: &PulseInfoS| {
+            pi.PeakAverage >= OFConfig.TemplateMinHeight
+                && (OFConfig.TemplateMaxHeight <= OFConfig.TemplateMinHeight
+                    || pi.PeakAverage <= OFConfig.TemplateMaxHeight)
+        };
+        let TemplatePulses: Vec<Vec<f64>> = pulses
+            .par_iter()
+            .filter_map(|(key, path)| {
+                let pi = infos.get(key).filter(|pi| IsTemplatePulse(pi))?;
+                let pulse = LoadBi(path).ok()?;
+                if pulse.len() < start + length {
+                    return None;
+                }
+                Some(pulse.slice(s![start..start + length]).iter().map(|v| v - pi.Base).collect())
+            })
+            .collect();
+        let Rate = self.PRConfig.Rate;
+        let Template = AveragePulse(&TemplatePulses, None, Rate)
+            .Context(TESErr::AnalysisErr, format!("Failed to build template for CH{}", Channel))?;
+
+        let Noise = self.CollectNoise(Channel, pulses, length)?;
+        // 最適フィルタはパルスの窓をそのまま DFT するので、雑音も矩形窓で重ねずに区切る
+        let config = NoiseConfig {
+            SegmentLength: length as u32,
+            Overlap: 0.0,
+            Window: PSDWindow::Rectangular,
+            ..NoiseConfig::default()
+        };
+        let Spectrum = WelchPSD(&Noise, Rate, length, &config)
+            .Context(TESErr::AnalysisErr, format!("Failed to estimate noise for CH{}", Channel))?;
+        let OF = OptimalFilterS::new(Template.Mean, &Spectrum, OFConfig.MaxShift as usize)?;
+
+        let Results: Vec<(u32, f64, f64)> = pulses
+            .par_iter()
+            .filter(|(key, _)| infos.contains_key(key))
+            .filter_map(|(key, path)| {
+                let pulse = LoadBi(path).ok()?;
+                if pulse.len() < start + length {
+                    return None;
+                }
+                let window = pulse.slice(s![start..start + length]).to_vec();
+                let (amplitude, shift) = OF.Estimate(&window).ok()?;
+                Some((*key, amplitude, shift))
+            })
+            .collect();
+
+        if let Some(infos) = self.PulseInfosCH.get_mut(Channel) {
+            for (key, amplitude, shift) in Results {
+                if let Some(pi) = infos.get_mut(&key) {
+                    pi.OFAmplitude = amplitude;
+                    pi.OFTimeShift = shift / Rate;
+                }
+            }
+        }
+        self.OptimalFilters.insert(*Channel, OF);
         Ok(())
     }
 
-    pub fn ResetPreResult(&mut self)-> Result<(), String> {
+    /// カットを掛けて、通過した key とカットごとの除外数を返す
+    pub fn SelectPulses(&self, Channel: &u32) -> TESResult<PulseSelectionS> {
+        let infos = self
+            .PulseInfosCH
+            .get(Channel)
+            .ok_or_else(|| NoPulseInfo(Channel))?;
+
+        let cuts = self
+            .Cuts
+            .iter()
+            .filter(|c| c.Channels.is_empty() || c.Channels.contains(Channel))
+            .map(|c| {
+                CutExprS::Parse(&c.Expression)
+                    .map(|expr| (c.Name.clone(), expr))
+                    .Context(TESErr::ConfigErr, format!("Failed to parse cut {}", c.Name))
+            })
+            .collect::<TESResult<Vec<_>>>()?;
+
+        // 存在しないフィールドは評価前に弾く
+        for (name, expr) in cuts.iter() {
+            if let Some(field) = expr.UnknownField() {
+                return Err(TESErrorS::new(
+                    TESErr::ConfigErr,
+                    format!("Unknown field '{}' in cut {}", field, name),
+                ));
+            }
+        }
+
+        let mut keys: Vec<u32> = infos.keys().cloned().collect();
+        keys.sort();
+        let mut Rejected = vec![0; cuts.len()];
+        let mut Passed = Vec::new();
+        for key in keys.iter() {
+            let mut pass = true;
+            for (i, (_, expr)) in cuts.iter().enumerate() {
+                if !expr
+                    .Matches(*key, &infos[key])
+                    .Context(TESErr::ConfigErr, "Failed to evaluate cut")?
+                {
+                    Rejected[i] += 1;
+                    pass = false;
+                }
+            }
+            if pass {
+                Passed.push(*key);
+            }
+        }
+
+        Ok(PulseSelectionS {
+            Total: keys.len(),
+            Passed,
+            Cuts: cuts
+                .into_iter()
+                .zip(Rejected)
+                .map(|((Name, _), Rejected)| CutCountS { Name, Rejected })
+                .collect(),
+        })
+    }
+
+    /// 指定したフィールドの値を集める (UseCuts ならカットを通過したパルスのみ)
+    pub fn FieldValues(&self, Channel: &u32, Field: &str, UseCuts: bool) -> TESResult<Vec<f64>> {
+        if PulseInfoS::new().Field(Field).is_none() {
+            return Err(UnknownField(Field));
+        }
+        let infos = self
+            .PulseInfosCH
+            .get(Channel)
+            .ok_or_else(|| NoPulseInfo(Channel))?;
+        let keys: Vec<u32> = if UseCuts {
+            self.SelectPulses(Channel)?.Passed
+        } else {
+            infos.keys().cloned().collect()
+        };
+        Ok(keys
+            .iter()
+            .filter_map(|key| infos.get(key)?.Field(Field))
+            .collect())
+    }
+
+    pub fn Spectrum(&self, Channel: &u32, config: &SpectrumConfig) -> TESResult<HistogramS> {
+        let values = self.FieldValues(Channel, &config.Field, config.UseCuts)?;
+        Histogram(&values, config).Context(TESErr::AnalysisErr, format!("Failed to fill spectrum on CH{}", Channel))
+    }
+
+    /// 選び方に合う key を昇順で返す
+    pub fn SelectKeys(&self, Channel: &u32, selection: &PulseSelectionConfig) -> TESResult<Vec<u32>> {
+        let infos = self
+            .PulseInfosCH
+            .get(Channel)
+            .ok_or_else(|| NoPulseInfo(Channel))?;
+        let mut keys: Vec<u32> = match selection {
+            PulseSelectionConfig::All => infos.keys().cloned().collect(),
+            PulseSelectionConfig::Cuts => self.SelectPulses(Channel)?.Passed,
+            PulseSelectionConfig::Keys { Keys } => {
+                Keys.iter().cloned().filter(|key| infos.contains_key(key)).collect()
+            }
+            PulseSelectionConfig::Window { Field, Min, Max, UseCuts } => {
+                if PulseInfoS::new().Field(Field).is_none() {
+                    return Err(UnknownField(Field));
+                }
+                let candidates: Vec<u32> = if *UseCuts {
+                    self.SelectPulses(Channel)?.Passed
+                } else {
+                    infos.keys().cloned().collect()
+                };
+                candidates
+                    .into_iter()
+                    .filter(|key| {
+                        infos[key]
+                            .Field(Field)
+                            .is_some_and(|v| v >= *Min && v <= *Max)
+                    })
+                    .collect()
+            }
+        };
+        keys.sort();
+        Ok(keys)
+    }
+
+    /// 選んだパルスのベースラインを引いて (必要なら揃えて) 平均し、CH{n}_pulse/Template.{dat,csv} に保存する
+    pub fn BuildAveragePulse(&mut self, Channel: &u32, config: &TemplateConfig) -> TESResult<AveragePulseS> {
+        let keys = self.SelectKeys(Channel, &config.Selection)?;
+        if keys.is_empty() {
+            return Err(TESErrorS::new(TESErr::NotAnalyzed, format!("No pulses selected on CH{}", Channel)));
+        }
+        if config.Align == TemplateAlign::CFD && self.PAConfig.CFD.Delay == 0 {
+            return Err(TESErrorS::new(TESErr::ConfigErr, "CFD alignment needs a non-zero CFD delay"));
+        }
+        let infos = &self.PulseInfosCH[Channel];
+        let selected: HashSet<u32> = keys.iter().cloned().collect();
+        let paths: Vec<(u32, PathBuf)> = self
+            .PulsePaths(Channel)?
+            .into_iter()
+            .filter(|(key, _)| selected.contains(key))
+            .collect();
+
+        let Rate = self.PRConfig.Rate;
+        let Sample = self.PRConfig.Sample as usize;
+        let filter = &self.Filter;
+        let mut loaded: Vec<(u32, Vec<f64>, f64)> = paths
+            .par_iter()
+            .filter_map(|(key, path)| {
+                let info = &infos[key];
+                let raw = LoadBi(path).ok()?;
+                let pulse = if config.Filtered {
+                    filter.Apply(&raw).ok()?
+                } else {
+                    raw.to_vec()
+                };
+                if pulse.len() != Sample {
+                    return None;
+                }
+                let mark = match config.Align {
+                    TemplateAlign::None => 0.0,
+                    TemplateAlign::PeakIndex => info.PeakIndex as f64,
+                    TemplateAlign::CFD => info.CFDTime * Rate,
+                    TemplateAlign::RiseLow => info.RiseLowTime * Rate,
+                };
+                if !mark.is_finite() {
+                    return None;
+                }
+                Some((*key, pulse.iter().map(|v| v - info.Base).collect(), mark))
+            })
+            .collect();
+        loaded.sort_by_key(|(key, _, _)| *key);
+        if loaded.is_empty() {
+            return Err(TESErrorS::new(
+                TESErr::AnalysisErr,
+                format!("Failed to load any selected pulse on CH{}", Channel),
+            ));
+        }
+
+        let (Keys, rest): (Vec<u32>, Vec<(Vec<f64>, f64)>) =
+            loaded.into_iter().map(|(key, pulse, mark)| (key, (pulse, mark))).unzip();
+        let (pulses, marks): (Vec<Vec<f64>>, Vec<f64>) = rest.into_iter().unzip();
+        let marks = (config.Align != TemplateAlign::None).then_some(marks.as_slice());
+        let mut average = AveragePulse(&pulses, marks, Rate)
+            .Context(TESErr::AnalysisErr, format!("Failed to average pulses on CH{}", Channel))?;
+        average.Keys = Keys;
+        self.SaveAveragePulse(Channel, &average)?;
+        self.AveragePulses.insert(*Channel, average.clone());
+        Ok(average)
+    }
+
+    fn SaveAveragePulse(&self, Channel: &u32, average: &AveragePulseS) -> TESResult<()> {
+        let PulseDir = self.DP.DataPath.join(format!("CH{}_pulse", Channel));
+        SaveBi(&PulseDir.join("Template.dat"), &average.Mean)?;
+
+        let CsvPath = PulseDir.join("Template.csv");
+        let mut lines = vec!["Time,Mean,Std".to_string()];
+        for i in 0..average.Mean.len() {
+            lines.push(format!("{},{},{}", average.Time[i], average.Mean[i], average.Std[i]));
+        }
+        WriteFile(&CsvPath, lines.join("\n") + "\n")
+    }
+
+    /// チャンネル間で同じ key のパルスを突き合わせ、Coincidence.csv と CrossTalk.csv に保存する
+    pub fn AnalyzeCoincidence(&self, config: &CoincidenceConfig) -> TESResult<CoincidenceS> {
+        for field in [&config.AmplitudeField, &config.TimeField] {
+            if PulseInfoS::new().Field(field).is_none() {
+                return Err(UnknownField(field));
+            }
+        }
+        let mut channels: Vec<u32> = if config.Channels.is_empty() {
+            self.PulseInfosCH.keys().cloned().collect()
+        } else {
+            config.Channels.clone()
+        };
+        channels.sort();
+        channels.dedup();
+        if channels.len() < 2 {
+            return Err(TESErrorS::new(
+                TESErr::NotAnalyzed,
+                "Coincidence analysis needs at least two analysed channels",
+            ));
+        }
+
+        let mut events = HashMap::new();
+        for ch in channels.iter() {
+            let infos = self
+                .PulseInfosCH
+                .get(ch)
+                .ok_or_else(|| NoPulseInfo(ch))?;
+            let passed: HashSet<u32> = if config.UseCuts {
+                self.SelectPulses(ch)?.Passed.into_iter().collect()
+            } else {
+                infos.keys().cloned().collect()
+            };
+            let channel_events: ChannelEventsT = infos
+                .iter()
+                .map(|(key, info)| {
+                    let amplitude = info.Field(&config.AmplitudeField).unwrap_or(f64::NAN);
+                    let time = info.Field(&config.TimeField).unwrap_or(f64::NAN);
+                    (*key, (amplitude, time, passed.contains(key)))
+                })
+                .collect();
+            events.insert(*ch, channel_events);
+        }
+
+        let result = Coincidence::Coincidence(config, &channels, &events);
+        self.SaveCoincidence(&result)?;
+        Ok(result)
+    }
+
+    fn SaveCoincidence(&self, result: &CoincidenceS) -> TESResult<()> {
+        let mut header = vec!["key".to_string(), "Hits".to_string(), "Coincident".to_string()];
+        for ch in result.Channels.iter() {
+            header.push(format!("CH{}_Amplitude", ch));
+            header.push(format!("CH{}_Time", ch));
+        }
+        let mut lines = vec![header.join(",")];
+        for event in result.Events.iter() {
+            let mut row = vec![
+                event.Key.to_string(),
+                event.Hits.len().to_string(),
+                event.Coincident.len().to_string(),
+            ];
+            for (amplitude, time) in event.Amplitudes.iter().zip(&event.Times) {
+                row.push(amplitude.to_string());
+                row.push(time.to_string());
+            }
+            lines.push(row.join(","));
+        }
+        let CsvPath = self.DP.DataPath.join("Coincidence.csv");
+        WriteFile(&CsvPath, lines.join("\n") + "\n")?;
+
+        let mut lines = vec!["From,To,Ratio,RatioErr,Events".to_string()];
+        for c in result.CrossTalk.iter() {
+            lines.push(format!("{},{},{},{},{}", c.From, c.To, c.Ratio, c.RatioErr, c.Events));
+        }
+        let CsvPath = self.DP.DataPath.join("CrossTalk.csv");
+        WriteFile(&CsvPath, lines.join("\n") + "\n")
+    }
+
+    /// CH{n}_noise の波形から雑音スペクトルを求め、テンプレートがあれば NEP と分解能も出す
+    pub fn AnalyzeNoise(&mut self, Channel: &u32, config: &NoiseConfig) -> TESResult<NoiseSpectrumS> {
+        let noise_paths = self.NoisePaths(Channel)?;
+        if noise_paths.is_empty() {
+            return Err(TESErrorS::new(
+                TESErr::FolderNotAdequate,
+                format!("No noise records found in CH{}_noise/rawdata", Channel),
+            ));
+        }
+        let records: Vec<Vec<f64>> = noise_paths
+            .par_iter()
+            .map(|path| LoadBi(path).map(|r| r.to_vec()))
+            .collect::<TESResult<_>>()?;
+
+        let template = if config.TemplateEnergy > 0.0 {
+            Some(self.Template(Channel)?)
+        } else {
+            None
+        };
+        let length = match (config.SegmentLength, &template) {
+            (0, Some(t)) => t.len(),
+            (0, None) => self.PRConfig.Sample as usize,
+            (l, _) => l as usize,
+        };
+
+        let Rate = self.PRConfig.Rate;
+        let mut spectrum = WelchPSD(&records, Rate, length, config)
+            .Context(TESErr::AnalysisErr, format!("Failed to estimate noise PSD for CH{}", Channel))?;
+        if config.CurrentGain > 0.0 {
+            spectrum.CurrentNoise = spectrum
+                .VoltageNoise
+                .iter()
+                .map(|v| v * config.CurrentGain)
+                .collect();
+        }
+        if let Some(template) = template {
+            NEPAndResolution(&template, Rate, config.TemplateEnergy, &mut spectrum)
+                .Context(TESErr::AnalysisErr, format!("Failed to compute NEP for CH{}", Channel))?;
+        }
+
+        self.SaveNoiseSpectrum(Channel, &spectrum)?;
+        self.NoiseSpectra.insert(*Channel, spectrum.clone());
+        Ok(spectrum)
+    }
+
+    /// BuildAveragePulse で作った平均パルス (無ければ保存した Template.dat)
+    fn Template(&self, Channel: &u32) -> TESResult<Vec<f64>> {
+        if let Some(average) = self.AveragePulses.get(Channel) {
+            return Ok(average.Mean.clone());
+        }
+        let TemplatePath = self
+            .DP
+            .DataPath
+            .join(format!("CH{}_pulse", Channel))
+            .join("Template.dat");
+        if !TemplatePath.exists() {
+            return Err(TESErrorS::new(
+                TESErr::NotAnalyzed,
+                format!("No average pulse for CH{}. Build the template first.", Channel),
+            ));
+        }
+        Ok(LoadBi(&TemplatePath)?.to_vec())
+    }
+
+    fn SaveNoiseSpectrum(&self, Channel: &u32, spectrum: &NoiseSpectrumS) -> TESResult<()> {
+        let CsvPath = self
+            .DP
+            .DataPath
+            .join(format!("CH{}_noise", Channel))
+            .join("NoiseSpectrum.csv");
+        let column = |v: &Vec<f64>, i: usize| v.get(i).map_or(String::new(), |x| x.to_string());
+        let mut lines = vec!["Frequency,VoltageNoise,CurrentNoise,NEP".to_string()];
+        for i in 0..spectrum.Frequency.len() {
+            lines.push(format!(
+                "{},{},{},{}",
+                spectrum.Frequency[i],
+                spectrum.VoltageNoise[i],
+                column(&spectrum.CurrentNoise, i),
+                column(&spectrum.NEP, i)
+            ));
+        }
+        WriteFile(&CsvPath, lines.join("\n") + "\n")
+    }
+
+    /// 既知の輝線のピークをフィットしてゲインカーブを作り、Info.csv の Energy を更新する
+    pub fn CalibrateEnergy(
+        &mut self,
+        Channel: &u32,
+        config: &EnergyCalibrationConfig,
+    ) -> TESResult<EnergyCalibrationS> {
+        let hist = self.Spectrum(Channel, &config.Spectrum)?;
+        let mut Lines = Vec::new();
+        for line in config.Lines.iter() {
+            let Energy = line
+                .Energy
+                .or_else(|| KnownLineEnergy(&line.Name))
+                .ok_or_else(|| TESErrorS::new(TESErr::ConfigErr, format!("Unknown energy for line {}", line.Name)))?;
+            let peak = PeakFitConfig {
+                Min: line.Min,
+                Max: line.Max,
+                Tail: config.Tail,
+            };
+            let fit = FitPeak(&hist, &peak)
+                .Context(TESErr::AnalysisErr, format!("Failed to fit {} on CH{}", line.Name, Channel))?;
+            Lines.push(CalibrationLineS {
+                Name: line.Name.clone(),
+                Energy,
+                Centroid: fit.Centroid,
+                CentroidErr: fit.CentroidErr,
+            });
+        }
+        let calibration = EnergyCalibrationS::Fit(&config.Spectrum.Field, config.Model, Lines)
+            .Context(TESErr::AnalysisErr, format!("Failed to fit the gain curve on CH{}", Channel))?;
+
+        self.EnergyCalibrations.insert(*Channel, calibration.clone());
+        self.ApplyEnergyCalibration(Channel);
+        self.SaveEnergyCalibrations()?;
+        self.SavePulseInfos(Channel)?;
+        Ok(calibration)
+    }
+
+    fn ApplyEnergyCalibration(&mut self, Channel: &u32) {
+        let (Some(calibration), Some(infos)) = (
+            self.EnergyCalibrations.get(Channel),
+            self.PulseInfosCH.get_mut(Channel),
+        ) else {
+            return;
+        };
+        for info in infos.values_mut() {
+            info.Energy = info
+                .Field(&calibration.Field)
+                .map_or(f64::NAN, |x| calibration.Energy(x));
+        }
+    }
+
+    pub fn SaveEnergyCalibrations(&self) -> TESResult<()> {
+        let JsonPath = self.DP.DataPath.join("EnergyCalibration.json");
+        let json_str = serde_json::to_string_pretty(&self.EnergyCalibrations)
+            .map_err(|e| TESErrorS::new(TESErr::SerializeErr, format!("Failed to serialize energy calibration: {}", e)))?;
+        WriteFile(&JsonPath, json_str)
+    }
+
+    pub fn LoadEnergyCalibrations(&mut self) -> TESResult<()> {
+        let JsonPath = self.DP.DataPath.join("EnergyCalibration.json");
+        self.EnergyCalibrations = if JsonPath.exists() {
+            let JsonFile = File::open(&JsonPath).map_err(|e| TESErrorS::Open(&JsonPath, &e))?;
+            serde_json::from_reader(JsonFile).map_err(|e| JsonErr(&JsonPath, e))?
+        } else {
+            HashMap::new()
+        };
+        Ok(())
+    }
+
+    pub fn ResetPreResult(&mut self)-> TESResult<()> {
         for ch in self.Channels.iter() {
-            self.InfoCSVExist.insert(*ch, false);
+            self.UpToDate.insert(*ch, false);
+            // キャッシュを消して全ファイルを解析し直す
+            let CachePath = self.CachePath(ch);
+            if CachePath.exists() {
+                std::fs::remove_file(&CachePath)
+                    .map_err(|e| TESErrorS::AtPath(TESErr::FileRemoveErr, &CachePath, format!("Failed to remove file\n{}", e)))?;
+            }
         }
         Ok(())
     }
@@ -392,35 +1757,28 @@ impl PulseProcessorS {
         G: FnMut(u32, u32),
     >(
         &mut self,
+        cancel: &Arc<AtomicBool>,
         mut OnChannelDone: F,
         mut OnPulseProgress: G,
-    ) -> Result<(), String> {
+    ) -> TESResult<()> {
 
         let Total=self.Channels.len() as u32;
-        println!("Total: {}", self.Channels.len());
         let mut Done:u32=0;
 
-        if cfg!(debug_assertions) {
-            println!("Channels: {:?}", self.Channels);
-        }
-
-        let mut keys: Vec<u32> = self.InfoCSVExist.keys().cloned().collect();
+        let mut keys: Vec<u32> = self.UpToDate.keys().cloned().collect();
         keys.sort();
 
         OnChannelDone(Done, Total, keys[0]);
 
         for ch in keys {
-            let exist = self.InfoCSVExist.get(&ch).unwrap(); // 値を取得
+            let exist = self.UpToDate.get(&ch).unwrap(); // 値を取得
             let mut inner_progress = |progress_percent: u32| {
                 OnPulseProgress(progress_percent, ch);
             };
-            if *exist{
-                println!("continue: {}", ch);
-            }
-            else{
-                self.AnalyzePulse(&ch, &mut inner_progress)?;
+            // 解析済みのチャンネルは飛ばす
+            if !*exist {
+                self.AnalyzePulse(&ch, cancel, &mut inner_progress)?;
                 self.SavePulseInfos(&ch)?;
-                print!("Analyzed CH{}.\n", ch);
             }
             Done += 1;
             OnChannelDone(Done, Total, ch);
@@ -429,39 +1787,20 @@ impl PulseProcessorS {
         Ok(())
     }
 
-    pub fn AnalyzePulseFolderPre(&mut self)->Result<String,String>{
-        let JsonPath = self.DP.DataPath.join("PulseConfig.json");
-
-        if !JsonPath.exists() {
-            let JsonPathDefault = PathBuf::from("./Config/PulseConfig.json");
-            if JsonPathDefault.exists() {
-                std::fs::copy(&JsonPathDefault, &JsonPath).map_err(|e| {
-                    format!("Failed to copy.{}\n{}", JsonPathDefault.display(), e).to_string()
-                })?;
-            } else {
-                return Err(format!("Failed to find {}.\n", JsonPathDefault.display()).to_string());
-            }
-        }
-
-        let JsonFile =
-            File::open(&JsonPath).map_err(|e| format!("Failed to open {:?}\n{}", JsonPath, e))?;
-
-        let PPC: PulseProcessorConfig = serde_json::from_reader(JsonFile)
-            .map_err(|e| format!("Failed to parse {:?}\n{}", JsonPath, e))?;
-        self.PRConfig = PPC.Readout;
-        self.PAConfig = PPC.Analysis;
+    pub fn AnalyzePulseFolderPre(&mut self)-> TESResult<String> {
+        let config = self.LoadConfig()?;
+        self.AnalyzePulseFolderPreWith(config)
+    }
 
-        // Besselフィルタ係数の計算（同期）
-        let rt = tokio::runtime::Runtime::new().unwrap();
-        self.BesselCoeffs = rt.block_on(BesselCoefficients(
-            self.PRConfig.Rate,
-            self.PAConfig.CutoffFrequency,
-        ))?;
+    /// PulseConfig.json の代わりに config を使う (CLI で設定を上書きする時)
+    pub fn AnalyzePulseFolderPreWith(&mut self, config: serde_json::Value) -> TESResult<String> {
+        self.ApplyConfig(config)?;
+        self.LoadEnergyCalibrations()?;
 
         let ChannelPattern = format!("{}/CH*_pulse", self.DP.DataPath.display());
 
         self.Channels = glob(&ChannelPattern)
-            .expect("Failed to read glob pattern")
+            .map_err(|e| TESErrorS::new(TESErr::PulseGlobErr, format!("Failed to read glob pattern: {}", e)))?
             .filter_map(Result::ok) // PathBuf の結果を取り出す
             .filter(|path| path.is_dir()) // ディレクトリのみフィルタ
             .filter_map(|path| {
@@ -474,7 +1813,7 @@ impl PulseProcessorS {
             .collect();
 
         if self.Channels.is_empty() {
-            return Err("Pulse has no channels.".to_string());
+            return Err(TESErrorS::AtPath(TESErr::PulseChannelEmpty, &self.DP.DataPath, "Pulse has no channels."));
         }
 
         let Channels = self.Channels.clone();
@@ -489,9 +1828,18 @@ impl PulseProcessorS {
                 .join("Info.csv");
             if info_path.exists() {
                 self.LoadPulseInfos(&ch)?;
-                self.InfoCSVExist.insert(*ch, true);
-            } else {
-                self.InfoCSVExist.insert(*ch, false);
+            }
+            // 追加・変更されたファイルが無く、設定も変わっていなければ解析しなくてよい
+            let pulses = self.PulsePaths(ch)?;
+            let (cache, pending) = self.PendingPulses(ch, &pulses)?;
+            self.Rejections.insert(*ch, RejectedPulses(&pulses, &cache));
+            let RejectedPath = info_path.with_file_name("Rejected.csv");
+            let UpToDate = info_path.exists()
+                && RejectedPath.exists()
+                && pending.is_empty()
+                && cache.Files.len() == pulses.len();
+            self.UpToDate.insert(*ch, UpToDate);
+            if !UpToDate {
                 AllExist=false;
             }
         }
@@ -502,3 +1850,262 @@ impl PulseProcessorS {
         return Ok("Perfect".to_string());
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn OptimalFilterWindowContainsTrigger() {
+        let mut p = PulseProcessorS::new();
+        p.PRConfig.Sample = 10000;
+        p.PRConfig.PreSample = 1000;
+        // CH{n}_noise が無いので、プリトリガーの長さでトリガーを真ん中にする
+        assert_eq!(p.OptimalFilterWindow(&1).unwrap(), (500, 1000));
+
+        p.PAConfig.OptimalFilter.WindowStart = 900;
+        assert_eq!(p.OptimalFilterWindow(&1).unwrap(), (900, 1000));
+
+        // トリガーより後ろだけの窓はテンプレートにパルスが入らない
+        p.PAConfig.OptimalFilter.WindowStart = 1200;
+        let e = p.OptimalFilterWindow(&1).unwrap_err();
+        assert_eq!(e.Code, TESErr::ConfigErr);
+    }
+
+    // scipy.signal.sosfiltfilt と同じ手順 (odd 拡張, lfilter_zi を連立方程式で解く) を
+    // mpmath (40 桁) で別に実装して求めた値。Rate 1e5, CutoffFrequency 1e4
+    const BESSEL2_SOS: [f64; 6] = [
+        0.06327976405808242,
+        0.12655952811616483,
+        0.06327976405808242,
+        1.0,
+        -1.0722295286007402,
+        0.32534858483306983,
+    ];
+    const BESSEL2_ZI: [f64; 2] = [0.9367202359419176, -0.26206882077498744];
+    const BESSEL2_STEP: [f64; 30] = [
+        -0.0026278047040222186,
+        -0.0036594291129203108,
+        -0.003983245383830622,
+        -0.0018795969486058558,
+        0.006048546468020631,
+        0.02571096807144013,
+        0.06614294240169986,
+        0.13895695259715524,
+        0.2546524227847878,
+        0.412139166707034,
+        0.5878608332929293,
+        0.7453475772151724,
+        0.861043047402805,
+        0.9338570575982786,
+        0.9742890319286043,
+        0.9939514535321889,
+        1.0018795969491587,
+        1.0039832453850086,
+        1.003659429115103,
+        1.0026278047075956,
+        1.0016270197209305,
+        1.0008895860470102,
+        1.000424491862921,
+        1.0001657271401445,
+        1.000039589682369,
+        0.9999885300824581,
+        0.9999748210664057,
+        0.9999767339529979,
+        0.999983245133892,
+        0.999989604206201,
+    ];
+    const BESSEL2_PULSE: [f64; 30] = [
+        -0.0007832943833487678,
+        -0.001250218558257278,
+        -0.0017127072254063528,
+        -0.0018017496617421089,
+        -0.0006736834745182301,
+        0.0033176918473063013,
+        0.013004546007688446,
+        0.03266086555635766,
+        0.0676671099792635,
+        0.12261866114755422,
+        0.1961226888181218,
+        0.2750134409004494,
+        0.33826574666857906,
+        0.3707184427917582,
+        0.37084271763856075,
+        0.34661616480522917,
+        0.3084674801915105,
+        0.2652069180400034,
+        0.22273894710014808,
+        0.1842838533443155,
+        0.1511287328105424,
+        0.12338891639786219,
+        0.1005847229706497,
+        0.08200838585770963,
+        0.06692272233118451,
+        0.0546457165147643,
+        0.0445660577031157,
+        0.0361215981148669,
+        0.02876449473884161,
+        0.021938215480041948,
+    ];
+    const BUTTER4_SOS: [f64; 12] = [
+        0.07795634051646258,
+        0.15591268103292516,
+        0.07795634051646258,
+        1.0,
+        -1.3209134308194261,
+        0.6327387928852765,
+        0.061885195299764495,
+        0.12377039059952899,
+        0.061885195299764495,
+        1.0,
+        -1.0485995763626115,
+        0.2961403575616695,
+    ];
+    const BUTTER4_ZI: [f64; 4] = [
+        0.9220436594835374,
+        -0.554782452368814,
+        0.9381148047002354,
+        -0.234255162261905,
+    ];
+    const BUTTER4_STEP: [f64; 30] = [
+        -5.810054856207766e-09,
+        -0.012741110168765153,
+        -0.02958614542274355,
+        -0.05000545257273433,
+        -0.06606291232612876,
+        -0.06288043982670499,
+        -0.02274394607918401,
+        0.06812094852668978,
+        0.21253551046359012,
+        0.3984207086763354,
+        0.6005790424077753,
+        0.7882835120692736,
+        0.935415710241426,
+        1.028321081142816,
+        1.0681077344765815,
+        1.0674040520216939,
+        1.044085330068499,
+        1.0150126514390427,
+        0.9918864248308038,
+        0.979900737165338,
+        0.9787217891834276,
+        0.9847201986252295,
+        0.9933541283456231,
+        1.000934673756912,
+        1.0054654251412392,
+        1.006641847639556,
+        1.005322427835888,
+        1.0028316673019213,
+        1.000374341870391,
+        0.9987028344579065,
+    ];
+    const BUTTER4_PULSE: [f64; 30] = [
+        0.0007008521316180193,
+        0.002701265403777297,
+        0.0002925525563215271,
+        -0.008579395247772377,
+        -0.022071566342718785,
+        -0.033936581301228265,
+        -0.03461726645648018,
+        -0.014008151343911764,
+        0.034664936398615176,
+        0.11111515620630603,
+        0.20614600366307909,
+        0.30310442378120017,
+        0.382685008681817,
+        0.4292544215430328,
+        0.43582355938614464,
+        0.4055183359689752,
+        0.34933861058896104,
+        0.2817136111050632,
+        0.21593431446576125,
+        0.16104503280099602,
+        0.12080433579649409,
+        0.09444031646423104,
+        0.07842569410332717,
+        0.06842359995342956,
+        0.06077976239458261,
+        0.05328073219636331,
+        0.04520780092345011,
+        0.03690754553001869,
+        0.029158137676497664,
+        0.022569891109222372,
+    ];
+
+    fn Sections(flat: &[f64]) -> Vec<Section> {
+        flat.chunks(6)
+            .map(|c| [c[0], c[1], c[2], c[3], c[4], c[5]])
+            .collect()
+    }
+
+    fn Step() -> Array1<f64> {
+        Array1::from_iter((0..30).map(|i| if i < 10 { 0.0 } else { 1.0 }))
+    }
+
+    fn Pulse() -> Array1<f64> {
+        Array1::from_iter((0..30).map(|i| {
+            if i < 10 {
+                0.0
+            } else {
+                let t = (i - 10) as f64;
+                (-t / 5.0).exp() - (-t).exp()
+            }
+        }))
+    }
+
+    fn AssertClose(actual: &[f64], expected: &[f64]) {
+        assert_eq!(actual.len(), expected.len());
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() < 1e-10, "{:?} != {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn SosfiltZiMatchesScipy() {
+        let zi = sosfilt_zi(&Sections(&BESSEL2_SOS));
+        AssertClose(&zi.concat(), &BESSEL2_ZI);
+        // 2段目は1段目の直流ゲインを掛けた初期状態になる
+        let zi = sosfilt_zi(&Sections(&BUTTER4_SOS));
+        AssertClose(&zi.concat(), &BUTTER4_ZI);
+    }
+
+    #[test]
+    fn FiltfiltMatchesScipySosfiltfilt() {
+        let bessel = Sections(&BESSEL2_SOS);
+        AssertClose(&filtfilt(&bessel, &Step()).unwrap(), &BESSEL2_STEP);
+        AssertClose(&filtfilt(&bessel, &Pulse()).unwrap(), &BESSEL2_PULSE);
+        let butter = Sections(&BUTTER4_SOS);
+        AssertClose(&filtfilt(&butter, &Step()).unwrap(), &BUTTER4_STEP);
+        AssertClose(&filtfilt(&butter, &Pulse()).unwrap(), &BUTTER4_PULSE);
+    }
+
+    #[test]
+    fn FieldCoversEveryInfoColumn() {
+        let header: Vec<&str> = PulseInfoS::CSV_HEADER.split(',').collect();
+        let columns: HashMap<String, usize> = header
+            .iter()
+            .enumerate()
+            .map(|(i, name)| (name.to_string(), i))
+            .collect();
+        // 列ごとに違う値を入れて、名前と値の取り違えも見る
+        let record = csv::StringRecord::from(
+            (0..header.len()).map(|i| i.to_string()).collect::<Vec<_>>(),
+        );
+        let (key, info) = PulseInfoS::FromCSVRecord(&record, &columns).unwrap();
+        assert_eq!(key, 0);
+        for (i, name) in header.iter().enumerate().skip(1) {
+            assert_eq!(info.Field(name), Some(i as f64), "{}", name);
+        }
+        assert_eq!(info.Field("key"), None);
+        assert_eq!(info.Field("Unknown"), None);
+    }
+
+    #[test]
+    fn FiltfiltKeepsConstant() {
+        let flat = Array1::from_elem(20, 3.5);
+        AssertClose(
+            &filtfilt(&Sections(&BUTTER4_SOS), &flat).unwrap(),
+            &[3.5; 20],
+        );
+    }
+}
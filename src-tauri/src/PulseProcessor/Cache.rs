@@ -0,0 +1,114 @@
+#![allow(non_snake_case)]
+// rawdata の各ファイルを解析した時の状態を覚えておき、変わったファイルだけ解析し直す
+use super::Reject::RejectionS;
+use crate::Config::{BackendConfig, PulseAnalysisConfig, PulseReadoutConfig};
+use crate::DataProcessor::WriteFile;
+use crate::TES_Err::{TESErr, TESErrorS, TESResult};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+/// この数のファイルを解析するごとに Info.csv とキャッシュを保存する
+pub const CACHE_CHUNK: usize = 1000;
+
+// 形式の違う古いキャッシュは読まずに解析し直す
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileEntryS {
+    /// 更新時刻 (UNIX 時間, ns)
+    pub Modified: u64,
+    pub Size: u64,
+    /// 解析できなかった理由 (解析できたら None)
+    pub Rejected: Option<RejectionS>,
+}
+
+impl FileEntryS {
+    pub fn Of(path: &Path) -> TESResult<Self> {
+        let metadata = std::fs::metadata(path).map_err(|e| TESErrorS::Open(path, &e))?;
+        let Modified = metadata
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_nanos() as u64);
+        Ok(Self {
+            Modified,
+            Size: metadata.len(),
+            Rejected: None,
+        })
+    }
+
+    /// ファイルが前回の解析から変わっていないか
+    pub fn SameFile(&self, other: &Self) -> bool {
+        self.Modified == other.Modified && self.Size == other.Size
+    }
+}
+
+/// CH{n}_pulse/Cache.json の中身
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FileCacheS {
+    pub ConfigHash: String,
+    /// ファイル名ごとの状態
+    pub Files: HashMap<String, FileEntryS>,
+}
+
+impl FileCacheS {
+    pub fn new(ConfigHash: String) -> Self {
+        Self {
+            ConfigHash,
+            Files: HashMap::new(),
+        }
+    }
+
+    /// 読めなければ空のキャッシュ (全部解析し直す) にする
+    pub fn Load(path: &Path) -> Self {
+        File::open(path)
+            .ok()
+            .and_then(|f| serde_json::from_reader(f).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn Save(&self, path: &Path) -> TESResult<()> {
+        let json_str = serde_json::to_string(self).map_err(|e| {
+            TESErrorS::new(
+                TESErr::SerializeErr,
+                format!("Failed to serialize cache: {}", e),
+            )
+        })?;
+        // 途中で止まっても壊れたファイルが残らないよう書き込んでから置き換える
+        let tmp = path.with_extension("json.tmp");
+        WriteFile(&tmp, json_str)?;
+        std::fs::rename(&tmp, path).map_err(|e| {
+            TESErrorS::AtPath(
+                TESErr::FileWriteErr,
+                path,
+                format!("Failed to replace file\n{}", e),
+            )
+        })
+    }
+
+    /// 前回と同じ状態で解析済みなら true
+    pub fn IsCurrent(&self, name: &str, entry: &FileEntryS) -> bool {
+        self.Files.get(name).is_some_and(|e| e.SameFile(entry))
+    }
+}
+
+/// 1 ファイルの解析結果に効く設定のハッシュ
+pub fn ConfigHash(
+    Readout: &PulseReadoutConfig,
+    Analysis: &PulseAnalysisConfig,
+    Backend: &BackendConfig,
+) -> TESResult<String> {
+    let json_str = serde_json::to_string(&(Readout, Analysis, Backend)).map_err(|e| {
+        TESErrorS::new(
+            TESErr::SerializeErr,
+            format!("Failed to serialize config: {}", e),
+        )
+    })?;
+    Ok(Sha256::digest(json_str.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
+}
@@ -0,0 +1,114 @@
+#![allow(non_snake_case)]
+use crate::Config::CalibrationModel;
+use crate::TESAnalyzer::Fit::Solve;
+use serde::{Deserialize, Serialize};
+
+/// よく使う輝線のエネルギー (keV)
+pub fn KnownLineEnergy(name: &str) -> Option<f64> {
+    match name {
+        // Fe-55 (分解できない Kα は Kα1 : Kα2 = 2 : 1 の重み付き平均)
+        "MnKa" => Some((2.0 * 5.89875 + 5.88765) / 3.0),
+        "MnKa1" => Some(5.89875),
+        "MnKa2" => Some(5.88765),
+        // Kβ1 と Kβ3 は分けられないので、まとめた Kβ1,3 の値
+        "MnKb" => Some(6.49045),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CalibrationLineS {
+    pub Name: String,
+    /// keV
+    pub Energy: f64,
+    pub Centroid: f64,
+    pub CentroidErr: f64,
+}
+
+/// チャンネルごとのゲインカーブ (原点を通る)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EnergyCalibrationS {
+    /// 校正に使った PulseInfoS のフィールド
+    pub Field: String,
+    pub Model: CalibrationModel,
+    pub Params: Vec<f64>,
+    pub Lines: Vec<CalibrationLineS>,
+}
+
+impl EnergyCalibrationS {
+    /// 線形: E = a x, 2次: E = a x + b x^2, べき: E = a x^b
+    pub fn Fit(
+        Field: &str,
+        Model: CalibrationModel,
+        Lines: Vec<CalibrationLineS>,
+    ) -> Result<Self, String> {
+        let needed = match Model {
+            CalibrationModel::Linear => 1,
+            CalibrationModel::Quadratic | CalibrationModel::PowerLaw => 2,
+        };
+        if Lines.len() < needed {
+            return Err(format!(
+                "{:?} calibration needs at least {} lines, got {}",
+                Model,
+                needed,
+                Lines.len()
+            ));
+        }
+        let x: Vec<f64> = Lines.iter().map(|l| l.Centroid).collect();
+        let E: Vec<f64> = Lines.iter().map(|l| l.Energy).collect();
+
+        let Params = match Model {
+            CalibrationModel::Linear => {
+                let xx: f64 = x.iter().map(|v| v * v).sum();
+                let xE: f64 = x.iter().zip(&E).map(|(a, b)| a * b).sum();
+                vec![xE / xx]
+            }
+            CalibrationModel::Quadratic => {
+                // 正規方程式 [Σx², Σx³; Σx³, Σx⁴] [a, b] = [ΣxE, Σx²E]
+                let s = |k: i32| x.iter().map(|v| v.powi(k)).sum::<f64>();
+                let t = |k: i32| x.iter().zip(&E).map(|(v, e)| v.powi(k) * e).sum::<f64>();
+                Solve(&[vec![s(2), s(3)], vec![s(3), s(4)]], &[t(1), t(2)])
+                    .ok_or("Singular matrix in quadratic calibration")?
+            }
+            CalibrationModel::PowerLaw => {
+                if x.iter().chain(&E).any(|v| *v <= 0.0) {
+                    return Err(
+                        "Power-law calibration needs positive centroids and energies".to_string(),
+                    );
+                }
+                // log E = log a + b log x の直線フィット
+                let lx: Vec<f64> = x.iter().map(|v| v.ln()).collect();
+                let lE: Vec<f64> = E.iter().map(|v| v.ln()).collect();
+                let n = lx.len() as f64;
+                let (sx, sy) = (lx.iter().sum::<f64>(), lE.iter().sum::<f64>());
+                let sxx: f64 = lx.iter().map(|v| v * v).sum();
+                let sxy: f64 = lx.iter().zip(&lE).map(|(a, b)| a * b).sum();
+                let denom = n * sxx - sx * sx;
+                if denom.abs() < f64::EPSILON {
+                    return Err("Power-law calibration needs distinct centroids".to_string());
+                }
+                let b = (n * sxy - sx * sy) / denom;
+                vec![((sy - b * sx) / n).exp(), b]
+            }
+        };
+        if Params.iter().any(|p| !p.is_finite()) {
+            return Err("Energy calibration did not converge".to_string());
+        }
+        Ok(Self {
+            Field: Field.to_string(),
+            Model,
+            Params,
+            Lines,
+        })
+    }
+
+    /// パルスハイトをエネルギー (keV) に直す
+    pub fn Energy(&self, x: f64) -> f64 {
+        let p = &self.Params;
+        match self.Model {
+            CalibrationModel::Linear => p[0] * x,
+            CalibrationModel::Quadratic => p[0] * x + p[1] * x * x,
+            CalibrationModel::PowerLaw => p[0] * x.signum() * x.abs().powf(p[1]),
+        }
+    }
+}
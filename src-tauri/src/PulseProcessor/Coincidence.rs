@@ -0,0 +1,135 @@
+#![allow(non_snake_case)]
+use crate::Config::CoincidenceConfig;
+use serde::Serialize;
+use std::collections::{BTreeSet, HashMap};
+
+/// 1 チャンネル分の (振幅, 時刻, カットを通過したか)
+pub type ChannelEventsT = HashMap<u32, (f64, f64, bool)>;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CoincidenceEventS {
+    pub Key: u32,
+    /// パルスがあったチャンネル
+    pub Hits: Vec<u32>,
+    /// 最初のヒットから TimingWindow 以内のチャンネル
+    pub Coincident: Vec<u32>,
+    /// Channels の順 (レコードが無ければ NaN)
+    pub Amplitudes: Vec<f64>,
+    pub Times: Vec<f64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CrossTalkS {
+    /// パルスがあったチャンネル
+    pub From: u32,
+    /// ヒットしなかったチャンネル
+    pub To: u32,
+    /// 原点を通る直線 A_To = Ratio A_From の傾き
+    pub Ratio: f64,
+    pub RatioErr: f64,
+    pub Events: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CoincidenceS {
+    pub Channels: Vec<u32>,
+    pub Events: Vec<CoincidenceEventS>,
+    /// 2 チャンネル以上で同時にパルスがあったイベント数
+    pub CoincidentEvents: usize,
+    pub SingleEvents: usize,
+    /// 複数チャンネルにパルスがあるが TimingWindow より離れているイベント数
+    pub SeparatedEvents: usize,
+    pub EmptyEvents: usize,
+    pub CrossTalk: Vec<CrossTalkS>,
+}
+
+/// チャンネルごとのイベントを key で突き合わせる
+pub fn Coincidence(
+    config: &CoincidenceConfig,
+    channels: &[u32],
+    events: &HashMap<u32, ChannelEventsT>,
+) -> CoincidenceS {
+    let keys: BTreeSet<u32> = events.values().flat_map(|e| e.keys().cloned()).collect();
+
+    let mut Events = Vec::with_capacity(keys.len());
+    for key in keys {
+        let values: Vec<Option<(f64, f64, bool)>> = channels
+            .iter()
+            .map(|ch| events.get(ch).and_then(|e| e.get(&key)).cloned())
+            .collect();
+        let Hits: Vec<(u32, f64)> = channels
+            .iter()
+            .zip(&values)
+            .filter_map(|(ch, v)| {
+                let (amplitude, time, pass) = (*v)?;
+                (pass && amplitude >= config.Threshold).then_some((*ch, time))
+            })
+            .collect();
+        let first = Hits
+            .iter()
+            .map(|(_, t)| *t)
+            .filter(|t| t.is_finite())
+            .fold(f64::INFINITY, f64::min);
+        let Coincident: Vec<u32> = Hits
+            .iter()
+            .filter(|(_, t)| (t - first).abs() <= config.TimingWindow)
+            .map(|(ch, _)| *ch)
+            .collect();
+        Events.push(CoincidenceEventS {
+            Key: key,
+            Hits: Hits.iter().map(|(ch, _)| *ch).collect(),
+            Coincident,
+            Amplitudes: values.iter().map(|v| v.map_or(f64::NAN, |v| v.0)).collect(),
+            Times: values.iter().map(|v| v.map_or(f64::NAN, |v| v.1)).collect(),
+        });
+    }
+
+    let CoincidentEvents = Events.iter().filter(|e| e.Coincident.len() >= 2).count();
+    let SingleEvents = Events.iter().filter(|e| e.Hits.len() == 1).count();
+    let EmptyEvents = Events.iter().filter(|e| e.Hits.is_empty()).count();
+    let SeparatedEvents = Events.len() - CoincidentEvents - SingleEvents - EmptyEvents;
+    let CrossTalk = CrossTalkRatios(channels, &Events);
+    CoincidenceS {
+        Channels: channels.to_vec(),
+        Events,
+        CoincidentEvents,
+        SingleEvents,
+        SeparatedEvents,
+        EmptyEvents,
+        CrossTalk,
+    }
+}
+
+/// From だけにパルスがあったイベントで、To に漏れた振幅の比を求める
+fn CrossTalkRatios(channels: &[u32], events: &[CoincidenceEventS]) -> Vec<CrossTalkS> {
+    let mut result = Vec::new();
+    for (i, From) in channels.iter().enumerate() {
+        for (j, To) in channels.iter().enumerate() {
+            if i == j {
+                continue;
+            }
+            let pairs: Vec<(f64, f64)> = events
+                .iter()
+                .filter(|e| e.Hits.len() == 1 && e.Hits[0] == *From)
+                .map(|e| (e.Amplitudes[i], e.Amplitudes[j]))
+                .filter(|(x, y)| x.is_finite() && y.is_finite())
+                .collect();
+            let n = pairs.len();
+            let sxx: f64 = pairs.iter().map(|(x, _)| x * x).sum();
+            if n < 2 || sxx <= 0.0 {
+                continue;
+            }
+            let Ratio = pairs.iter().map(|(x, y)| x * y).sum::<f64>() / sxx;
+            let residual: f64 = pairs.iter().map(|(x, y)| (y - Ratio * x).powi(2)).sum();
+            let RatioErr = (residual / (n - 1) as f64 / sxx).sqrt();
+            result.push(CrossTalkS {
+                From: *From,
+                To: *To,
+                Ratio,
+                RatioErr,
+                Events: n,
+            });
+        }
+    }
+    result
+}
@@ -0,0 +1,375 @@
+#![allow(non_snake_case)]
+// PulseInfoS のフィールドに対するカット式
+// 例: PeakAverage > 0.03 && 0 < RiseTime <= 1e-4 || !(FitError != 0)
+use super::PulseInfoS;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    Ident(String),
+    Op(CompareOp),
+    And,
+    Or,
+    Not,
+    LParen,
+    RParen,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CompareOp {
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+}
+
+impl CompareOp {
+    fn Apply(self, a: f64, b: f64) -> bool {
+        match self {
+            CompareOp::Lt => a < b,
+            CompareOp::Le => a <= b,
+            CompareOp::Gt => a > b,
+            CompareOp::Ge => a >= b,
+            CompareOp::Eq => a == b,
+            CompareOp::Ne => a != b,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Operand {
+    Number(f64),
+    Field(String),
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    Or(Box<Expr>, Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    /// `a < x <= b` のような連続比較
+    Compare(Vec<Operand>, Vec<CompareOp>),
+}
+
+fn Tokenize(src: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = src.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        if c.is_whitespace() {
+            i += 1;
+        } else if c.is_ascii_digit()
+            || c == '.'
+            || (c == '-' && next.is_some_and(|n| n.is_ascii_digit() || n == '.'))
+        {
+            // 引き算は無いので数字の前の '-' は負号として読む
+            let start = i;
+            i += 1;
+            while i < chars.len() {
+                let d = chars[i];
+                let exponent_sign = (d == '-' || d == '+') && matches!(chars[i - 1], 'e' | 'E');
+                if d.is_ascii_digit() || d == '.' || d == 'e' || d == 'E' || exponent_sign {
+                    i += 1;
+                } else {
+                    break;
+                }
+            }
+            let text: String = chars[start..i].iter().collect();
+            let value = text
+                .parse::<f64>()
+                .map_err(|e| format!("Invalid number '{}': {}", text, e))?;
+            tokens.push(Token::Number(value));
+        } else if c.is_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            let word: String = chars[start..i].iter().collect();
+            tokens.push(match word.as_str() {
+                "and" => Token::And,
+                "or" => Token::Or,
+                "not" => Token::Not,
+                _ => Token::Ident(word),
+            });
+        } else {
+            let (token, len) = match (c, next) {
+                ('&', Some('&')) => (Token::And, 2),
+                ('|', Some('|')) => (Token::Or, 2),
+                ('<', Some('=')) => (Token::Op(CompareOp::Le), 2),
+                ('>', Some('=')) => (Token::Op(CompareOp::Ge), 2),
+                ('=', Some('=')) => (Token::Op(CompareOp::Eq), 2),
+                ('!', Some('=')) => (Token::Op(CompareOp::Ne), 2),
+                ('<', _) => (Token::Op(CompareOp::Lt), 1),
+                ('>', _) => (Token::Op(CompareOp::Gt), 1),
+                ('=', _) => (Token::Op(CompareOp::Eq), 1),
+                ('!', _) => (Token::Not, 1),
+                ('(', _) => (Token::LParen, 1),
+                (')', _) => (Token::RParen, 1),
+                _ => return Err(format!("Unexpected character '{}' at {}", c, i)),
+            };
+            tokens.push(token);
+            i += len;
+        }
+    }
+    Ok(tokens)
+}
+
+/// 再帰下降パーサ (優先順位は not > and > or)
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn Peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn Next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn ParseOr(&mut self) -> Result<Expr, String> {
+        let mut lhs = self.ParseAnd()?;
+        while self.Peek() == Some(&Token::Or) {
+            self.pos += 1;
+            lhs = Expr::Or(Box::new(lhs), Box::new(self.ParseAnd()?));
+        }
+        Ok(lhs)
+    }
+
+    fn ParseAnd(&mut self) -> Result<Expr, String> {
+        let mut lhs = self.ParseUnary()?;
+        while self.Peek() == Some(&Token::And) {
+            self.pos += 1;
+            lhs = Expr::And(Box::new(lhs), Box::new(self.ParseUnary()?));
+        }
+        Ok(lhs)
+    }
+
+    fn ParseUnary(&mut self) -> Result<Expr, String> {
+        match self.Peek() {
+            Some(Token::Not) => {
+                self.pos += 1;
+                Ok(Expr::Not(Box::new(self.ParseUnary()?)))
+            }
+            Some(Token::LParen) => {
+                self.pos += 1;
+                let inner = self.ParseOr()?;
+                match self.Next() {
+                    Some(Token::RParen) => Ok(inner),
+                    _ => Err("Missing ')'".to_string()),
+                }
+            }
+            _ => self.ParseCompare(),
+        }
+    }
+
+    fn ParseOperand(&mut self) -> Result<Operand, String> {
+        match self.Next() {
+            Some(Token::Number(v)) => Ok(Operand::Number(v)),
+            Some(Token::Ident(name)) => Ok(Operand::Field(name)),
+            Some(t) => Err(format!("Expected a field or number, found {:?}", t)),
+            None => Err("Unexpected end of expression".to_string()),
+        }
+    }
+
+    fn ParseCompare(&mut self) -> Result<Expr, String> {
+        let mut operands = vec![self.ParseOperand()?];
+        let mut ops = Vec::new();
+        while let Some(Token::Op(op)) = self.Peek() {
+            ops.push(*op);
+            self.pos += 1;
+            operands.push(self.ParseOperand()?);
+        }
+        if ops.is_empty() {
+            return Err("Expected a comparison".to_string());
+        }
+        Ok(Expr::Compare(operands, ops))
+    }
+}
+
+impl Expr {
+    fn Evaluate<F: Fn(&str) -> Option<f64>>(&self, field: &F) -> Result<bool, String> {
+        match self {
+            Expr::Or(a, b) => Ok(a.Evaluate(field)? || b.Evaluate(field)?),
+            Expr::And(a, b) => Ok(a.Evaluate(field)? && b.Evaluate(field)?),
+            Expr::Not(a) => Ok(!a.Evaluate(field)?),
+            Expr::Compare(operands, ops) => {
+                let values = operands
+                    .iter()
+                    .map(|o| match o {
+                        Operand::Number(v) => Ok(*v),
+                        Operand::Field(name) => {
+                            field(name).ok_or_else(|| format!("Unknown field '{}'", name))
+                        }
+                    })
+                    .collect::<Result<Vec<f64>, String>>()?;
+                Ok(ops
+                    .iter()
+                    .enumerate()
+                    .all(|(i, op)| op.Apply(values[i], values[i + 1])))
+            }
+        }
+    }
+
+    fn Fields<'a>(&'a self, out: &mut Vec<&'a str>) {
+        match self {
+            Expr::Or(a, b) | Expr::And(a, b) => {
+                a.Fields(out);
+                b.Fields(out);
+            }
+            Expr::Not(a) => a.Fields(out),
+            Expr::Compare(operands, _) => {
+                for o in operands {
+                    if let Operand::Field(name) = o {
+                        out.push(name);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// 解析済みのカット式
+#[derive(Debug, Clone)]
+pub struct CutExprS {
+    expr: Expr,
+}
+
+impl CutExprS {
+    pub fn Parse(src: &str) -> Result<Self, String> {
+        let mut parser = Parser {
+            tokens: Tokenize(src)?,
+            pos: 0,
+        };
+        let expr = parser.ParseOr()?;
+        if parser.pos < parser.tokens.len() {
+            return Err(format!(
+                "Unexpected {:?} in '{}'",
+                parser.tokens[parser.pos], src
+            ));
+        }
+        Ok(Self { expr })
+    }
+
+    /// 式で使われているフィールド名
+    pub fn Fields(&self) -> Vec<&str> {
+        let mut out = Vec::new();
+        self.expr.Fields(&mut out);
+        out
+    }
+
+    pub fn Evaluate<F: Fn(&str) -> Option<f64>>(&self, field: F) -> Result<bool, String> {
+        self.expr.Evaluate(&field)
+    }
+
+    /// PulseInfoS に無いフィールド (key は使える)
+    pub fn UnknownField(&self) -> Option<&str> {
+        let empty = PulseInfoS::new();
+        self.Fields()
+            .into_iter()
+            .find(|f| *f != "key" && empty.Field(f).is_none())
+    }
+
+    /// key のパルスがカットを通るか
+    pub fn Matches(&self, key: u32, info: &PulseInfoS) -> Result<bool, String> {
+        self.Evaluate(|name| match name {
+            "key" => Some(key as f64),
+            _ => info.Field(name),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn Eval(src: &str) -> Result<bool, String> {
+        let field = |name: &str| match name {
+            "PeakAverage" => Some(0.05),
+            "RiseTime" => Some(5e-5),
+            "FitError" => Some(0.0),
+            _ => None,
+        };
+        CutExprS::Parse(src)?.Evaluate(field)
+    }
+
+    #[test]
+    fn AndBindsTighterThanOr() {
+        // true || (false && false)
+        assert_eq!(
+            Eval("PeakAverage > 0.03 || RiseTime > 1 && FitError != 0"),
+            Ok(true)
+        );
+        // (true || false) && false
+        assert_eq!(
+            Eval("(PeakAverage > 0.03 or RiseTime > 1) and FitError != 0"),
+            Ok(false)
+        );
+        assert_eq!(Eval("not PeakAverage > 0.03 or FitError == 0"), Ok(true));
+        assert_eq!(Eval("!(FitError != 0) && !(PeakAverage < 0)"), Ok(true));
+    }
+
+    #[test]
+    fn ChainedComparisonIsRange() {
+        assert_eq!(Eval("0 < RiseTime <= 1e-4"), Ok(true));
+        assert_eq!(Eval("0 < RiseTime <= 1e-5"), Ok(false));
+        assert_eq!(Eval("-1 < -.5 < PeakAverage < 0.1"), Ok(true));
+        assert_eq!(Eval("1e-5 <= RiseTime < 1E+2"), Ok(true));
+    }
+
+    #[test]
+    fn Equality() {
+        assert_eq!(Eval("FitError == 0"), Ok(true));
+        assert_eq!(Eval("FitError = 0"), Ok(true));
+        assert_eq!(Eval("FitError != 0"), Ok(false));
+        assert_eq!(Eval("PeakAverage >= 0.05 && PeakAverage <= 0.05"), Ok(true));
+    }
+
+    #[test]
+    fn UnknownFieldIsAnError() {
+        let expr = CutExprS::Parse("Unknown > 0 && PeakAverage > 0").unwrap();
+        assert_eq!(expr.Fields(), vec!["Unknown", "PeakAverage"]);
+        assert!(Eval("Unknown > 0")
+            .unwrap_err()
+            .contains("Unknown field 'Unknown'"));
+    }
+
+    #[test]
+    fn MatchesPulseInfo() {
+        let mut info = PulseInfoS::new();
+        info.PeakAverage = 0.05;
+        info.FitError = 1;
+        let expr = CutExprS::Parse("key < 10 && PeakAverage > 0.03 && FitError == 1").unwrap();
+        assert_eq!(expr.UnknownField(), None);
+        assert_eq!(expr.Matches(3, &info), Ok(true));
+        assert_eq!(expr.Matches(12, &info), Ok(false));
+        let expr = CutExprS::Parse("PeakAverage > 0 || Peak > 0").unwrap();
+        assert_eq!(expr.UnknownField(), Some("Peak"));
+    }
+
+    #[test]
+    fn MalformedInputIsRejected() {
+        for src in [
+            "",
+            "PeakAverage",
+            "PeakAverage >",
+            "> 0.03",
+            "(PeakAverage > 0.03",
+            "PeakAverage > 0.03)",
+            "PeakAverage > 0.03 &&",
+            "PeakAverage > 0.03 & RiseTime < 1",
+            "PeakAverage > 1e",
+            "PeakAverage > 0.03 RiseTime < 1",
+        ] {
+            assert!(CutExprS::Parse(src).is_err(), "'{}' should not parse", src);
+        }
+    }
+}
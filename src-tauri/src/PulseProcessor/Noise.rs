@@ -0,0 +1,160 @@
+#![allow(non_snake_case)]
+use crate::Config::{NoiseConfig, PSDWindow};
+use crate::Fourier::RealFFT;
+use serde::Serialize;
+
+/// 1 keV (J)
+const JOULE_PER_KEV: f64 = 1.602176634e-16;
+/// FWHM = 2√(2 ln 2) σ
+const FWHM_PER_SIGMA: f64 = 2.354820045030949;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct NoiseSpectrumS {
+    pub Frequency: Vec<f64>,
+    /// V/√Hz (片側)
+    pub VoltageNoise: Vec<f64>,
+    /// A/√Hz (CurrentGain が無ければ空)
+    pub CurrentNoise: Vec<f64>,
+    /// W/√Hz (テンプレートが無ければ空)
+    pub NEP: Vec<f64>,
+    pub Records: usize,
+    pub Segments: usize,
+    pub RejectedSegments: usize,
+    /// テンプレートと組み合わせた最適フィルタの分解能 eV (テンプレートが無ければ NaN)
+    pub BaselineResolutionFWHM: f64,
+}
+
+fn Window(kind: PSDWindow, n: usize) -> Vec<f64> {
+    match kind {
+        PSDWindow::Rectangular => vec![1.0; n],
+        PSDWindow::Hann => (0..n)
+            .map(|i| 0.5 - 0.5 * (2.0 * std::f64::consts::PI * i as f64 / n as f64).cos())
+            .collect(),
+    }
+}
+
+fn Std(x: &[f64]) -> f64 {
+    let mean = x.iter().sum::<f64>() / x.len() as f64;
+    (x.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / x.len() as f64).sqrt()
+}
+
+/// Welch 法で片側の雑音スペクトル (V/√Hz) を求める (電流雑音と NEP は空のまま)
+pub fn WelchPSD(
+    records: &[Vec<f64>],
+    rate: f64,
+    length: usize,
+    config: &NoiseConfig,
+) -> Result<NoiseSpectrumS, String> {
+    if length < 2 {
+        return Err("PSD segment length must be at least 2".to_string());
+    }
+    if !(0.0..1.0).contains(&config.Overlap) {
+        return Err(format!("Overlap must be in 0..1, got {}", config.Overlap));
+    }
+    let step = (((1.0 - config.Overlap) * length as f64).round() as usize).max(1);
+    let segments: Vec<&[f64]> = records
+        .iter()
+        .flat_map(|r| {
+            (0..r.len().saturating_sub(length - 1))
+                .step_by(step)
+                .map(move |i| &r[i..i + length])
+        })
+        .collect();
+    if segments.is_empty() {
+        return Err(format!("No noise record is longer than {} samples", length));
+    }
+
+    // パルスなどが混ざった区間を捨てる
+    let stds: Vec<f64> = segments.iter().map(|s| Std(s)).collect();
+    let limit = if config.RejectSigma > 0.0 {
+        let mut sorted = stds.clone();
+        sorted.sort_by(|a, b| a.total_cmp(b));
+        config.RejectSigma * sorted[sorted.len() / 2]
+    } else {
+        f64::INFINITY
+    };
+    let accepted: Vec<&[f64]> = segments
+        .iter()
+        .zip(&stds)
+        .filter(|(_, s)| **s <= limit)
+        .map(|(seg, _)| *seg)
+        .collect();
+    if accepted.is_empty() {
+        return Err("All noise segments were rejected".to_string());
+    }
+
+    let w = Window(config.Window, length);
+    let scale = rate * w.iter().map(|v| v * v).sum::<f64>();
+    let bins = length / 2 + 1;
+    let mut psd = vec![0.0; bins];
+    for seg in accepted.iter() {
+        let mean = seg.iter().sum::<f64>() / length as f64;
+        let x: Vec<f64> = seg.iter().zip(&w).map(|(v, wi)| (v - mean) * wi).collect();
+        let X = RealFFT(&x);
+        for (k, p) in psd.iter_mut().enumerate() {
+            // DC と (偶数長の) ナイキスト以外は負の周波数の分を足す
+            let twice = k != 0 && !(length.is_multiple_of(2) && k == length / 2);
+            *p += X[k].norm_sqr() / scale * if twice { 2.0 } else { 1.0 };
+        }
+    }
+    let count = accepted.len() as f64;
+    psd.iter_mut().for_each(|p| *p /= count);
+    Ok(NoiseSpectrumS {
+        Frequency: (0..bins).map(|k| k as f64 * rate / length as f64).collect(),
+        VoltageNoise: psd.iter().map(|p| p.sqrt()).collect(),
+        CurrentNoise: Vec::new(),
+        NEP: Vec::new(),
+        Records: records.len(),
+        Segments: accepted.len(),
+        RejectedSegments: segments.len() - accepted.len(),
+        BaselineResolutionFWHM: f64::NAN,
+    })
+}
+
+/// NEP (W/√Hz) と、最適フィルタで得られるベースライン分解能 (eV, FWHM)
+///
+/// template はエネルギー energy (keV) のパルスの平均で、区間と同じ長さのもの。
+pub fn NEPAndResolution(
+    template: &[f64],
+    rate: f64,
+    energy: f64,
+    spectrum: &mut NoiseSpectrumS,
+) -> Result<(), String> {
+    if template.len() / 2 + 1 != spectrum.VoltageNoise.len() {
+        return Err(format!(
+            "Template length {} does not match the PSD segment length",
+            template.len()
+        ));
+    }
+    if energy.is_nan() || energy <= 0.0 {
+        return Err("Template energy must be positive".to_string());
+    }
+    let mean = template.iter().sum::<f64>() / template.len() as f64;
+    let x: Vec<f64> = template.iter().map(|v| v - mean).collect();
+    let S = RealFFT(&x);
+    let E = energy * JOULE_PER_KEV;
+    let df = rate / template.len() as f64;
+
+    // 応答 |S(f)| = |V(f)| / E (V/W)、V(f) は連続フーリエ変換 (V·s)
+    let mut inverse_variance = 0.0;
+    let NEP = spectrum
+        .VoltageNoise
+        .iter()
+        .enumerate()
+        .map(|(k, v)| {
+            let responsivity = S[k].norm() / rate / E;
+            let nep = v / responsivity;
+            if k > 0 && nep.is_finite() && nep > 0.0 {
+                inverse_variance += 4.0 * df / (nep * nep);
+            }
+            nep
+        })
+        .collect();
+    if inverse_variance <= 0.0 {
+        return Err("Template has no signal above DC".to_string());
+    }
+    let sigma_eV = 1.0 / inverse_variance.sqrt() / JOULE_PER_KEV * 1e3;
+    spectrum.NEP = NEP;
+    spectrum.BaselineResolutionFWHM = FWHM_PER_SIGMA * sigma_eV;
+    Ok(())
+}
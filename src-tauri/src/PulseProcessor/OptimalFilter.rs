@@ -0,0 +1,103 @@
+#![allow(non_snake_case)]
+use super::Noise::NoiseSpectrumS;
+use crate::Fourier::{RealFFT, IFFT};
+use num_complex::Complex64;
+
+/// 周波数領域の最適フィルタ
+#[derive(Debug, Clone)]
+pub struct OptimalFilterS {
+    /// ピークを 1 に規格化したテンプレート
+    pub Template: Vec<f64>,
+    /// DFT のビンごとの雑音パワー
+    pub NoisePSD: Vec<f64>,
+    pub MaxShift: usize,
+    /// conj(S_k)/J_k を Σ|S_k|^2/J_k で割ったもの (DC は 0)
+    Kernel: Vec<Complex64>,
+}
+
+impl OptimalFilterS {
+    /// Template はベースラインを差し引いた平均パルス、Noise は同じ長さの区間で求めた片側スペクトル
+    pub fn new(Template: Vec<f64>, Noise: &NoiseSpectrumS, MaxShift: usize) -> Result<Self, String> {
+        let n = Template.len();
+        if n < 2 || Noise.VoltageNoise.len() != n / 2 + 1 {
+            return Err(format!(
+                "Noise spectrum ({} bins) does not match the template length {}",
+                Noise.VoltageNoise.len(),
+                n
+            ));
+        }
+        let peak = Template.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+        if peak.is_nan() || peak <= 0.0 {
+            return Err("Template has no positive peak".to_string());
+        }
+        let Template: Vec<f64> = Template.into_iter().map(|v| v / peak).collect();
+        // 片側スペクトルを DFT のビンごとのパワーに戻す (全体の倍率は振幅に効かない)
+        let NoisePSD: Vec<f64> = (0..n)
+            .map(|k| {
+                let j = k.min(n - k);
+                let p = Noise.VoltageNoise[j].powi(2);
+                if j != 0 && !(n.is_multiple_of(2) && j == n / 2) {
+                    p / 2.0
+                } else {
+                    p
+                }
+            })
+            .collect();
+        let S = RealFFT(&Template);
+        let mut Kernel = vec![Complex64::new(0.0, 0.0); n];
+        let mut norm = 0.0;
+        // ベースラインの揺らぎを拾わないよう DC は使わない
+        for k in 1..n {
+            if NoisePSD[k] > 0.0 {
+                Kernel[k] = S[k].conj() / NoisePSD[k];
+                norm += S[k].norm_sqr() / NoisePSD[k];
+            }
+        }
+        if norm.is_nan() || norm <= 0.0 {
+            return Err("Optimal filter has no usable frequency bins".to_string());
+        }
+        for v in Kernel.iter_mut() {
+            *v /= norm;
+        }
+        Ok(Self {
+            MaxShift: MaxShift.min(n / 2),
+            Template,
+            NoisePSD,
+            Kernel,
+        })
+    }
+
+    /// 振幅 (テンプレートのピークを 1 とする) と時間シフト (サンプル) を返す
+    pub fn Estimate(&self, pulse: &[f64]) -> Result<(f64, f64), String> {
+        let n = self.Kernel.len();
+        if pulse.len() != n {
+            return Err(format!(
+                "Pulse length {} does not match the optimal filter length {}",
+                pulse.len(),
+                n
+            ));
+        }
+        let V = RealFFT(pulse);
+        let product: Vec<Complex64> = self.Kernel.iter().zip(&V).map(|(k, v)| k * v).collect();
+        // IFFT は 1/N で規格化されているので N 倍して戻す
+        let amplitude: Vec<f64> = IFFT(&product).iter().map(|c| c.re * n as f64).collect();
+        let at = |shift: i64| amplitude[shift.rem_euclid(n as i64) as usize];
+
+        let max_shift = self.MaxShift as i64;
+        let best = (-max_shift..=max_shift)
+            .max_by(|&a, &b| at(a).total_cmp(&at(b)))
+            .unwrap_or(0);
+        if max_shift == 0 || best.abs() == max_shift {
+            return Ok((at(best), best as f64));
+        }
+
+        // 3 点の放物線補間でサンプル間のピークを求める
+        let (y0, y1, y2) = (at(best - 1), at(best), at(best + 1));
+        let denom = y0 - 2.0 * y1 + y2;
+        if denom >= 0.0 {
+            return Ok((y1, best as f64));
+        }
+        let delta = 0.5 * (y0 - y2) / denom;
+        Ok((y1 - 0.25 * (y0 - y2) * delta, best as f64 + delta))
+    }
+}
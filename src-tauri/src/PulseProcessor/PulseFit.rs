@@ -0,0 +1,122 @@
+#![allow(non_snake_case)]
+use crate::Config::{PulseFitConfig, PulseFitFunction};
+use crate::TESAnalyzer::Fit::LevenbergMarquardt;
+use serde::Serialize;
+
+/// 閾値から求めた初期値 (時間はすべて秒)
+pub struct PulseFitGuessS {
+    pub Amplitude: f64,
+    pub Onset: f64,
+    pub RiseTau: f64,
+    pub DecayTau: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PulseFitS {
+    /// モデルの最大値
+    pub Amplitude: f64,
+    pub Onset: f64,
+    pub RiseTau: f64,
+    pub DecayTau: f64,
+    pub DecayTau2: f64,
+    pub DecayTauErr: f64,
+    pub RSquared: f64,
+    pub Params: Vec<f64>,
+}
+
+/// t は記録の先頭からの秒、start はフィット窓の開始時刻
+pub fn PulseModel(function: PulseFitFunction, start: f64, t: f64, p: &[f64]) -> f64 {
+    match function {
+        PulseFitFunction::None => 0.0,
+        PulseFitFunction::MonoExp => p[0] * (-(t - start) / p[1]).exp(),
+        PulseFitFunction::DoubleExp => {
+            let dt = t - p[1];
+            if dt < 0.0 {
+                0.0
+            } else {
+                p[0] * ((-dt / p[3]).exp() - (-dt / p[2]).exp())
+            }
+        }
+        PulseFitFunction::RiseTwoDecay => {
+            let dt = t - p[2];
+            if dt < 0.0 {
+                0.0
+            } else {
+                p[0] * (-dt / p[4]).exp() + p[1] * (-dt / p[5]).exp()
+                    - (p[0] + p[1]) * (-dt / p[3]).exp()
+            }
+        }
+    }
+}
+
+fn InitialParams(function: PulseFitFunction, guess: &PulseFitGuessS) -> Vec<f64> {
+    let (A, t0, tr, td) = (guess.Amplitude, guess.Onset, guess.RiseTau, guess.DecayTau);
+    match function {
+        PulseFitFunction::None => Vec::new(),
+        PulseFitFunction::MonoExp => vec![A, td],
+        PulseFitFunction::DoubleExp => vec![A, t0, tr, td],
+        PulseFitFunction::RiseTwoDecay => vec![0.7 * A, 0.3 * A, t0, tr, td, 5.0 * td],
+    }
+}
+
+/// ベースラインを差し引いたパルスを Fit 窓でフィットする
+pub fn FitPulse(
+    config: &PulseFitConfig,
+    rate: f64,
+    pulse: &[f64],
+    guess: &PulseFitGuessS,
+) -> Result<PulseFitS, String> {
+    let function = config.Function;
+    let start = config.WindowStart as usize;
+    let end = match config.WindowLength {
+        0 => pulse.len(),
+        l => (start + l as usize).min(pulse.len()),
+    };
+    if start >= end {
+        return Err(format!("Fit window {}..{} is empty", start, end));
+    }
+
+    let p0 = if config.InitialParams.is_empty() {
+        InitialParams(function, guess)
+    } else {
+        config.InitialParams.clone()
+    };
+    if p0.len() != InitialParams(function, guess).len() {
+        return Err(format!(
+            "{:?} needs {} initial parameters, got {}",
+            function,
+            InitialParams(function, guess).len(),
+            p0.len()
+        ));
+    }
+
+    let t_start = start as f64 / rate;
+    let t: Vec<f64> = (start..end).map(|i| i as f64 / rate).collect();
+    let y = &pulse[start..end];
+    let model = |t: f64, p: &[f64]| PulseModel(function, t_start, t, p);
+    let result = LevenbergMarquardt(model, &t, y, None, &p0)?;
+    let (p, e) = (&result.Params, &result.Errors);
+
+    let mean = y.iter().sum::<f64>() / y.len() as f64;
+    let ss_tot: f64 = y.iter().map(|v| (v - mean).powi(2)).sum();
+    let ss_res: f64 = t.iter().zip(y).map(|(&ti, &yi)| (yi - model(ti, p)).powi(2)).sum();
+    let RSquared = if ss_tot > 0.0 { 1.0 - ss_res / ss_tot } else { 0.0 };
+    let Amplitude = t.iter().map(|&ti| model(ti, p)).fold(f64::NEG_INFINITY, f64::max);
+
+    let (Onset, RiseTau, DecayTau, DecayTau2, DecayTauErr) = match function {
+        PulseFitFunction::None => (0.0, 0.0, 0.0, 0.0, 0.0),
+        PulseFitFunction::MonoExp => (t_start, 0.0, p[1], 0.0, e[1]),
+        PulseFitFunction::DoubleExp => (p[1], p[2], p[3], 0.0, e[3]),
+        PulseFitFunction::RiseTwoDecay => (p[2], p[3], p[4], p[5], e[4]),
+    };
+    Ok(PulseFitS {
+        Amplitude,
+        Onset,
+        RiseTau,
+        DecayTau,
+        DecayTau2,
+        DecayTauErr,
+        RSquared,
+        Params: p.clone(),
+    })
+}
@@ -0,0 +1,65 @@
+#![allow(non_snake_case)]
+// 解析できなかったファイルとその理由
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum RejectReason {
+    /// ファイルが読めない
+    Unreadable,
+    /// 他のレコードとサンプル数が違う
+    WrongLength,
+    /// NaN や無限大を含む
+    NaN,
+    /// ベースラインやピーク探索の範囲より短い
+    TooShort,
+    FilterFailed,
+    /// ピーク探索の範囲からピークが取れない
+    NoPeak,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RejectionS {
+    pub Reason: RejectReason,
+    pub Detail: String,
+}
+
+impl RejectionS {
+    pub fn new(Reason: RejectReason, Detail: impl Into<String>) -> Self {
+        Self {
+            Reason,
+            Detail: Detail.into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RejectedPulseS {
+    pub Key: u32,
+    pub File: String,
+    pub Reason: RejectReason,
+    pub Detail: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RejectSummaryS {
+    pub Channel: u32,
+    pub Total: usize,
+    pub Rejected: usize,
+    pub Counts: BTreeMap<RejectReason, usize>,
+}
+
+impl RejectSummaryS {
+    pub fn new(Channel: u32, Total: usize, rejected: &[RejectedPulseS]) -> Self {
+        let mut Counts = BTreeMap::new();
+        for r in rejected {
+            *Counts.entry(r.Reason).or_insert(0) += 1;
+        }
+        Self {
+            Channel,
+            Total,
+            Rejected: rejected.len(),
+            Counts,
+        }
+    }
+}
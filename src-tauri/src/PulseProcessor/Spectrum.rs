@@ -0,0 +1,182 @@
+#![allow(non_snake_case)]
+use crate::Config::{PeakFitConfig, SpectrumConfig};
+use crate::TESAnalyzer::Fit::LevenbergMarquardt;
+use serde::Serialize;
+use std::f64::consts::SQRT_2;
+
+/// FWHM = 2√(2 ln 2) σ
+const FWHM_PER_SIGMA: f64 = 2.354820045030949;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HistogramS {
+    pub Edges: Vec<f64>,
+    pub Centers: Vec<f64>,
+    pub Counts: Vec<u64>,
+    /// 範囲外で数えなかった数
+    pub Outside: u64,
+}
+
+/// Min >= Max ならデータの範囲を使う
+pub fn Histogram(values: &[f64], config: &SpectrumConfig) -> Result<HistogramS, String> {
+    let bins = config.Bins as usize;
+    if bins == 0 {
+        return Err("Number of bins must be positive".to_string());
+    }
+    let finite: Vec<f64> = values.iter().cloned().filter(|v| v.is_finite()).collect();
+    let (min, max) = if config.Min < config.Max {
+        (config.Min, config.Max)
+    } else {
+        let min = finite.iter().cloned().fold(f64::INFINITY, f64::min);
+        let max = finite.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+        if !min.is_finite() || !max.is_finite() {
+            return Err("No finite values to histogram".to_string());
+        }
+        if min == max {
+            (min - 0.5, max + 0.5)
+        } else {
+            (min, max)
+        }
+    };
+
+    let width = (max - min) / bins as f64;
+    let Edges: Vec<f64> = (0..=bins).map(|i| min + width * i as f64).collect();
+    let Centers: Vec<f64> = (0..bins).map(|i| min + width * (i as f64 + 0.5)).collect();
+    let mut Counts = vec![0u64; bins];
+    let mut Outside = (values.len() - finite.len()) as u64;
+    for v in finite {
+        if v < min || v > max {
+            Outside += 1;
+            continue;
+        }
+        // 右端は最後のビンに入れる
+        let i = (((v - min) / width) as usize).min(bins - 1);
+        Counts[i] += 1;
+    }
+    Ok(HistogramS {
+        Edges,
+        Centers,
+        Counts,
+        Outside,
+    })
+}
+
+/// exp(a) erfc(z) をオーバーフローしないように計算する (Numerical Recipes の erfcc)
+fn ExpErfc(a: f64, z: f64) -> f64 {
+    let x = z.abs();
+    let t = 1.0 / (1.0 + 0.5 * x);
+    let poly = -1.26551223
+        + t * (1.00002368
+            + t * (0.37409196
+                + t * (0.09678418
+                    + t * (-0.18628806
+                        + t * (0.27886807
+                            + t * (-1.13520398
+                                + t * (1.48851587 + t * (-0.82215223 + t * 0.17087277))))))));
+    let scaled = t * (a - x * x + poly).exp();
+    if z >= 0.0 {
+        scaled
+    } else {
+        2.0 * a.exp() - scaled
+    }
+}
+
+/// [A, μ, σ, B] のガウシアン、Tail なら [A, μ, σ, B, f, β] で低エネルギー側に指数テールを足す
+pub fn PeakModel(x: f64, p: &[f64]) -> f64 {
+    let (A, mu, sigma, B) = (p[0], p[1], p[2], p[3]);
+    let u = (x - mu) / sigma;
+    let gauss = (-0.5 * u * u).exp();
+    if p.len() < 6 {
+        return A * gauss + B;
+    }
+    let (f, beta) = (p[4], p[5]);
+    // 指数関数とガウシアンの畳み込み (ピーク付近で高さ 1 程度)
+    let tail = 0.5
+        * ExpErfc(
+            (x - mu) / beta + sigma * sigma / (2.0 * beta * beta),
+            (u + sigma / beta) / SQRT_2,
+        );
+    A * ((1.0 - f) * gauss + f * tail) + B
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PeakFitS {
+    pub Amplitude: f64,
+    pub Centroid: f64,
+    pub CentroidErr: f64,
+    pub Sigma: f64,
+    pub SigmaErr: f64,
+    pub FWHM: f64,
+    pub FWHMErr: f64,
+    pub Background: f64,
+    pub TailFraction: f64,
+    pub TailSlope: f64,
+    pub ReducedChi2: f64,
+    pub Params: Vec<f64>,
+    pub CurveX: Vec<f64>,
+    pub CurveY: Vec<f64>,
+}
+
+/// ヒストグラムの Min..Max の範囲にピークをフィットする (重みはポアソン誤差)
+pub fn FitPeak(hist: &HistogramS, config: &PeakFitConfig) -> Result<PeakFitS, String> {
+    let (x, y): (Vec<f64>, Vec<f64>) = hist
+        .Centers
+        .iter()
+        .zip(&hist.Counts)
+        .filter(|(c, _)| **c >= config.Min && **c <= config.Max)
+        .map(|(c, n)| (*c, *n as f64))
+        .unzip();
+    let total: f64 = y.iter().sum();
+    if total <= 0.0 {
+        return Err(format!(
+            "No counts in the fit range {}..{}",
+            config.Min, config.Max
+        ));
+    }
+
+    // 範囲内のモーメントから初期値を決める
+    let mean = x.iter().zip(&y).map(|(a, b)| a * b).sum::<f64>() / total;
+    let var = x
+        .iter()
+        .zip(&y)
+        .map(|(a, b)| (a - mean).powi(2) * b)
+        .sum::<f64>()
+        / total;
+    let bin_width = hist
+        .Edges
+        .get(1)
+        .zip(hist.Edges.first())
+        .map_or(1.0, |(b, a)| b - a);
+    let sigma0 = var.sqrt().max(bin_width);
+    let height = y.iter().cloned().fold(0.0, f64::max);
+    let mut p0 = vec![height, mean, sigma0, 0.0];
+    if config.Tail {
+        p0.extend([0.1, sigma0]);
+    }
+
+    let sigma: Vec<f64> = y.iter().map(|n| n.max(1.0).sqrt()).collect();
+    let result = LevenbergMarquardt(PeakModel, &x, &y, Some(&sigma), &p0)
+        .map_err(|e| format!("Failed to fit peak\n{}", e))?;
+    let (p, e) = (&result.Params, &result.Errors);
+
+    let points = 500;
+    let step = (config.Max - config.Min) / (points - 1) as f64;
+    let CurveX: Vec<f64> = (0..points).map(|i| config.Min + step * i as f64).collect();
+    let CurveY = CurveX.iter().map(|&xi| PeakModel(xi, p)).collect();
+
+    Ok(PeakFitS {
+        Amplitude: p[0],
+        Centroid: p[1],
+        CentroidErr: e[1],
+        Sigma: p[2].abs(),
+        SigmaErr: e[2],
+        FWHM: FWHM_PER_SIGMA * p[2].abs(),
+        FWHMErr: FWHM_PER_SIGMA * e[2],
+        Background: p[3],
+        TailFraction: p.get(4).copied().unwrap_or(0.0),
+        TailSlope: p.get(5).copied().unwrap_or(0.0),
+        ReducedChi2: result.ReducedChi2,
+        Params: p.clone(),
+        CurveX,
+        CurveY,
+    })
+}
@@ -0,0 +1,81 @@
+#![allow(non_snake_case)]
+use serde::Serialize;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AveragePulseS {
+    pub Time: Vec<f64>,
+    pub Mean: Vec<f64>,
+    /// 各サンプルでの標準偏差
+    pub Std: Vec<f64>,
+    pub Count: usize,
+    pub Keys: Vec<u32>,
+}
+
+/// y を shift サンプルずらした値 (線形補間、端は端の値)
+fn Shifted(y: &[f64], shift: f64) -> Vec<f64> {
+    let n = y.len();
+    (0..n)
+        .map(|i| {
+            let x = (i as f64 + shift).clamp(0.0, (n - 1) as f64);
+            let j = (x.floor() as usize).min(n - 1);
+            let frac = x - j as f64;
+            if j + 1 < n {
+                y[j] * (1.0 - frac) + y[j + 1] * frac
+            } else {
+                y[j]
+            }
+        })
+        .collect()
+}
+
+/// ベースラインを差し引いたパルスの平均と標準偏差
+///
+/// Keys は呼び出し側で埋める。marks を与えると各パルスの目印 (サンプル位置) が中央値に揃うようにずらしてから平均する。
+pub fn AveragePulse(
+    pulses: &[Vec<f64>],
+    marks: Option<&[f64]>,
+    rate: f64,
+) -> Result<AveragePulseS, String> {
+    let n = pulses.first().ok_or("No pulses to average")?.len();
+    if pulses.iter().any(|p| p.len() != n) {
+        return Err("Pulses have different lengths".to_string());
+    }
+    if marks.is_some_and(|m| m.len() != pulses.len()) {
+        return Err("Number of alignment marks does not match pulses".to_string());
+    }
+
+    let reference = marks.map(|m| {
+        let mut sorted = m.to_vec();
+        sorted.sort_by(|a, b| a.total_cmp(b));
+        sorted[sorted.len() / 2]
+    });
+
+    let mut sum = vec![0.0; n];
+    let mut sum2 = vec![0.0; n];
+    for (k, pulse) in pulses.iter().enumerate() {
+        let aligned = match (marks, reference) {
+            (Some(m), Some(r)) => Shifted(pulse, m[k] - r),
+            _ => pulse.clone(),
+        };
+        for i in 0..n {
+            sum[i] += aligned[i];
+            sum2[i] += aligned[i] * aligned[i];
+        }
+    }
+
+    let count = pulses.len() as f64;
+    let Mean: Vec<f64> = sum.iter().map(|s| s / count).collect();
+    let Std = sum2
+        .iter()
+        .zip(&Mean)
+        .map(|(s2, m)| (s2 / count - m * m).max(0.0).sqrt())
+        .collect();
+    let Time = (0..n).map(|i| i as f64 / rate).collect();
+    Ok(AveragePulseS {
+        Time,
+        Mean,
+        Std,
+        Count: pulses.len(),
+        Keys: Vec::new(),
+    })
+}
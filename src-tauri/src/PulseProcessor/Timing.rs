@@ -0,0 +1,85 @@
+#![allow(non_snake_case)]
+use crate::Config::{CFDConfig, CrossingInterpolation};
+
+/// y[i-1], y[i], y[i+1], y[i+2] を通る Catmull-Rom 曲線の i..i+1 の値
+fn CatmullRom(p: [f64; 4], t: f64) -> f64 {
+    let [p0, p1, p2, p3] = p;
+    0.5 * (2.0 * p1
+        + (-p0 + p2) * t
+        + (2.0 * p0 - 5.0 * p1 + 4.0 * p2 - p3) * t * t
+        + (-p0 + 3.0 * p1 - 3.0 * p2 + p3) * t * t * t)
+}
+
+/// y[i] と y[i+1] の間で level を横切る位置 (サンプル単位の小数)
+pub fn Crossing(y: &[f64], i: usize, level: f64, method: CrossingInterpolation) -> f64 {
+    if i + 1 >= y.len() {
+        return i as f64;
+    }
+    let (a, b) = (y[i], y[i + 1]);
+    let linear = if b != a {
+        ((level - a) / (b - a)).clamp(0.0, 1.0)
+    } else {
+        0.0
+    };
+    let frac = match method {
+        CrossingInterpolation::None => 0.0,
+        CrossingInterpolation::Linear => linear,
+        CrossingInterpolation::Spline => {
+            let p = [
+                y[i.saturating_sub(1)],
+                a,
+                b,
+                y[(i + 2).min(y.len() - 1)],
+            ];
+            // 区間内で符号が変わる点を二分法で探す
+            let f = |t: f64| CatmullRom(p, t) - level;
+            let (mut lo, mut hi) = (0.0, 1.0);
+            if f(lo) * f(hi) > 0.0 {
+                linear
+            } else {
+                for _ in 0..50 {
+                    let mid = 0.5 * (lo + hi);
+                    if f(lo) * f(mid) <= 0.0 {
+                        hi = mid;
+                    } else {
+                        lo = mid;
+                    }
+                }
+                0.5 * (lo + hi)
+            }
+        }
+    };
+    i as f64 + frac
+}
+
+/// 遅延させた波形と定数倍した波形の差 Fraction·y[i] - y[i-Delay] が
+/// 正から負に変わる点を立ち上がりの時刻とする
+pub fn CFDCrossing(
+    y: &[f64],
+    config: &CFDConfig,
+    start: usize,
+    peak: usize,
+    method: CrossingInterpolation,
+) -> Option<f64> {
+    let delay = config.Delay as usize;
+    if delay == 0 || peak >= y.len() {
+        return None;
+    }
+    let end = (peak + delay).min(y.len() - 1);
+    let begin = start.max(delay);
+    if begin >= end {
+        return None;
+    }
+    let c: Vec<f64> = (begin..=end)
+        .map(|i| config.Fraction * y[i] - y[i - delay])
+        .collect();
+
+    // 雑音で反応しないよう最大値の半分を超えてから探す
+    let max = c.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+    if max.is_nan() || max <= 0.0 {
+        return None;
+    }
+    let armed = c.iter().position(|v| *v > 0.5 * max)?;
+    let k = (armed..c.len() - 1).find(|&k| c[k] > 0.0 && c[k + 1] <= 0.0)?;
+    Some(begin as f64 + Crossing(&c, k, 0.0, method))
+}
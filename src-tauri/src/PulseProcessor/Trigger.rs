@@ -0,0 +1,133 @@
+#![allow(non_snake_case)]
+use crate::Config::{PileupConfig, TriggerMethod};
+use crate::Fourier::{RealFFT, IFFT};
+use crate::TESAnalyzer::Median;
+use serde::Serialize;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SubPulseS {
+    /// トリガーした位置 (閾値を横切った点を線形補間したサンプル位置)
+    pub Index: f64,
+    pub Time: f64,
+    /// 直前の値からの立ち上がり (EstimateSubPulses の時のみ)
+    pub Amplitude: f64,
+}
+
+/// 中央値絶対偏差から求めた頑健な標準偏差
+fn RobustSigma(values: &[f64]) -> f64 {
+    let med = Median(values).unwrap_or(0.0);
+    let dev: Vec<f64> = values.iter().map(|x| (x - med).abs()).collect();
+    1.4826 * Median(&dev).unwrap_or(0.0)
+}
+
+/// 差分 x[i+step] - x[i]
+fn Derivative(pulse: &[f64], step: usize) -> Vec<f64> {
+    (0..pulse.len().saturating_sub(step))
+        .map(|i| pulse[i + step] - pulse[i])
+        .collect()
+}
+
+/// 1 サンプル差分に、テンプレートの差分を相関させる (FFT で計算)
+fn MatchedFilter(pulse: &[f64], rate: f64, rise: f64, decay: f64) -> Result<Vec<f64>, String> {
+    if rise <= 0.0 || decay <= 0.0 {
+        return Err("Matched-filter trigger needs positive RiseTau and DecayTau".to_string());
+    }
+    let d = Derivative(pulse, 1);
+    let length = ((5.0 * decay * rate).ceil() as usize).clamp(2, d.len().max(2));
+    let shape = |i: usize| {
+        let t = i as f64 / rate;
+        (-t / decay).exp() - (-t / rise).exp()
+    };
+    let kernel: Vec<f64> = (0..length).map(|i| shape(i + 1) - shape(i)).collect();
+    let norm = kernel.iter().map(|k| k * k).sum::<f64>().sqrt();
+
+    // ゼロ詰めして巡回相関が回り込まないようにする
+    let n = (d.len() + length).next_power_of_two();
+    let mut a = d.clone();
+    a.resize(n, 0.0);
+    let mut b = kernel;
+    b.resize(n, 0.0);
+    let (A, B) = (RealFFT(&a), RealFFT(&b));
+    let product: Vec<_> = A.iter().zip(&B).map(|(x, y)| x * y.conj()).collect();
+    Ok(IFFT(&product)
+        .iter()
+        .take(d.len())
+        .map(|c| c.re / norm)
+        .collect())
+}
+
+/// 記録中のパルスを閾値トリガーで探す (pulse はベースラインを差し引いたもの)
+pub fn FindPulses(
+    config: &PileupConfig,
+    rate: f64,
+    pre_sample: usize,
+    peak_search: usize,
+    pulse: &[f64],
+) -> Result<Vec<SubPulseS>, String> {
+    let signal = match config.Method {
+        TriggerMethod::Derivative => Derivative(pulse, (config.DerivativeStep as usize).max(1)),
+        TriggerMethod::MatchedFilter => {
+            MatchedFilter(pulse, rate, config.RiseTau, config.DecayTau)?
+        }
+    };
+    if signal.len() < 2 {
+        return Err("Data length is too short to trigger".to_string());
+    }
+
+    // プリトリガー部分の揺らぎから閾値を決める (他のパルスが混ざっても効かないよう MAD)
+    let noise_end = pre_sample.min(signal.len());
+    let noise = if noise_end >= 10 {
+        &signal[..noise_end]
+    } else {
+        &signal[..]
+    };
+    let sigma = RobustSigma(noise);
+    if sigma.is_nan() || sigma <= 0.0 {
+        return Err("Trigger noise level is zero".to_string());
+    }
+    let threshold = config.Threshold * sigma;
+
+    let dead_time = config.DeadTime as usize;
+    let mut pulses = Vec::new();
+    let mut last: Option<usize> = None;
+    let mut armed = signal[0] < threshold;
+    for i in 1..signal.len() {
+        if signal[i] < threshold {
+            armed = true;
+            continue;
+        }
+        if !armed || last.is_some_and(|l| i - l < dead_time) {
+            continue;
+        }
+        armed = false;
+        last = Some(i);
+        let frac = (threshold - signal[i - 1]) / (signal[i] - signal[i - 1]);
+        let Index = (i - 1) as f64 + frac.clamp(0.0, 1.0);
+        pulses.push(SubPulseS {
+            Index,
+            Time: Index / rate,
+            Amplitude: 0.0,
+        });
+    }
+
+    if config.EstimateSubPulses {
+        // 次のトリガーまで (最大 peak_search) の最大値と直前の値の差
+        let starts: Vec<usize> = pulses.iter().map(|p| p.Index as usize).collect();
+        for (k, p) in pulses.iter_mut().enumerate() {
+            let start = starts[k];
+            let end = starts
+                .get(k + 1)
+                .copied()
+                .unwrap_or(pulse.len())
+                .min(start + peak_search.max(1))
+                .min(pulse.len());
+            let before = pulse[start.saturating_sub(1)];
+            let peak = pulse[start..end]
+                .iter()
+                .cloned()
+                .fold(f64::NEG_INFINITY, f64::max);
+            p.Amplitude = peak - before;
+        }
+    }
+    Ok(pulses)
+}
@@ -1,69 +1,381 @@
+#![allow(non_snake_case)]
+use crate::Config::BackendConfig;
+use crate::FilterDesign::{
+    FilterKind, LowPassCoefficients, LowPassSections, Section, TfToSections,
+};
+use crate::TESAnalyzer::RT::{FitRTCurve, RTFitS};
+use crate::TES_Err::{TESErr, TESErrorS};
 use reqwest::Client;
 use serde_json::json;
+use std::f64::consts::PI;
+use std::fmt;
+use std::sync::LazyLock;
 
-pub async fn BesselCoefficients(rate: f64, fs: f64) -> Result<Vec<Vec<f64>>, String> {
-    let client = Client::new();
-    let url = "https://tes-gui-pyhelper.onrender.com/Bessel";
+/// HTTP バックエンドで共有する tokio ランタイム
+static RUNTIME: LazyLock<Result<tokio::runtime::Runtime, String>> = LazyLock::new(|| {
+    tokio::runtime::Runtime::new().map_err(|e| format!("Failed to start tokio runtime: {}", e))
+});
 
-    let res = client
-        .post(url)
-        .json(&json!({ "rate": rate, "fs": fs }))
-        .send()
-        .await
-        .map_err(|e| format!("Failed to get response: {}", e))?;
+#[derive(Debug)]
+pub enum BackendErr {
+    Request(String),
+    Status(u16, String),
+    MalformedResponse(String),
+    Unsupported(String),
+    Compute(String),
+}
 
-    let status = res.status();
-    let text = res.text().await.map_err(|e| format!("Failed to read body: {}", e))?;
+impl fmt::Display for BackendErr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BackendErr::Request(e) => write!(f, "Failed to get response: {}", e),
+            BackendErr::Status(code, body) => {
+                write!(f, "Request failed with status {}: {}", code, body)
+            }
+            BackendErr::MalformedResponse(e) => write!(f, "Malformed response: {}", e),
+            BackendErr::Unsupported(e) => write!(f, "Unsupported by backend: {}", e),
+            BackendErr::Compute(e) => write!(f, "{}", e),
+        }
+    }
+}
 
-    println!("Status: {}, body: {}", status, text);
+impl From<BackendErr> for String {
+    fn from(e: BackendErr) -> Self {
+        e.to_string()
+    }
+}
 
-    // エラーハンドリング
-    if !status.is_success() {
-        return Err(format!("Request failed with status {}: {}", status, text));
+impl From<BackendErr> for TESErrorS {
+    fn from(e: BackendErr) -> Self {
+        let Code = match e {
+            BackendErr::Request(_) | BackendErr::Status(..) | BackendErr::MalformedResponse(_) => {
+                TESErr::PyErr
+            }
+            BackendErr::Unsupported(_) => TESErr::ConfigErr,
+            BackendErr::Compute(_) => TESErr::AnalysisErr,
+        };
+        TESErrorS::new(Code, e.to_string())
     }
+}
 
-    // JSONパース
-    let json: serde_json::Value = serde_json::from_str(&text)
-        .map_err(|e| format!("Failed to parse JSON: {}", e))?;
+/// フィルタ設計とカーブフィットを提供する数値計算バックエンド
+pub trait NumericBackend: Send + Sync {
+    /// ローパスフィルタ係数を [a, b] の順で返す
+    fn FilterCoefficients(
+        &self,
+        kind: FilterKind,
+        order: usize,
+        rate: f64,
+        cutoff: f64,
+    ) -> Result<Vec<Vec<f64>>, BackendErr>;
 
-    let a = json["a"]
-        .as_array()
-        .unwrap()
-        .iter()
-        .map(|v| v.as_f64().unwrap())
-        .collect::<Vec<f64>>();
+    /// ローパスフィルタを2次セクションの直列で返す
+    fn FilterSections(
+        &self,
+        kind: FilterKind,
+        order: usize,
+        rate: f64,
+        cutoff: f64,
+    ) -> Result<Vec<Section>, BackendErr> {
+        let coeffs = self.FilterCoefficients(kind, order, rate, cutoff)?;
+        if coeffs.len() != 2 {
+            return Err(BackendErr::MalformedResponse(
+                "Filter coefficients must be [a, b]".to_string(),
+            ));
+        }
+        TfToSections(&coeffs[1], &coeffs[0]).map_err(BackendErr::Compute)
+    }
 
-    let b = json["b"]
-        .as_array()
-        .unwrap()
-        .iter()
-        .map(|v| v.as_f64().unwrap())
-        .collect::<Vec<f64>>();
+    /// RT カーブをダブルフェルミ関数でフィットする
+    fn FitRT(&self, R: &[f64], T: &[f64]) -> Result<RTFitS, BackendErr>;
+}
+
+pub fn CreateBackend(config: &BackendConfig) -> Box<dyn NumericBackend> {
+    match config {
+        BackendConfig::Native => Box::new(NativeBackend),
+        BackendConfig::Http { Url } => Box::new(HttpBackend::new(Url)),
+        BackendConfig::Mock => Box::new(MockBackend),
+    }
+}
+
+pub struct NativeBackend;
+
+impl NumericBackend for NativeBackend {
+    fn FilterCoefficients(
+        &self,
+        kind: FilterKind,
+        order: usize,
+        rate: f64,
+        cutoff: f64,
+    ) -> Result<Vec<Vec<f64>>, BackendErr> {
+        LowPassCoefficients(kind, order, rate, cutoff).map_err(BackendErr::Compute)
+    }
+
+    fn FilterSections(
+        &self,
+        kind: FilterKind,
+        order: usize,
+        rate: f64,
+        cutoff: f64,
+    ) -> Result<Vec<Section>, BackendErr> {
+        LowPassSections(kind, order, rate, cutoff).map_err(BackendErr::Compute)
+    }
+
+    fn FitRT(&self, R: &[f64], T: &[f64]) -> Result<RTFitS, BackendErr> {
+        FitRTCurve(R, T).map_err(BackendErr::Compute)
+    }
+}
+
+/// TES-GUI-PyHelper (Flask) に問い合わせるバックエンド
+pub struct HttpBackend {
+    Url: String,
+    client: Client,
+}
 
-    return Ok(vec![a, b]);
+impl HttpBackend {
+    pub fn new(Url: &str) -> Self {
+        Self {
+            Url: Url.trim_end_matches('/').to_string(),
+            client: Client::new(),
+        }
+    }
+
+    fn Post(
+        &self,
+        endpoint: &str,
+        body: serde_json::Value,
+    ) -> Result<serde_json::Value, BackendErr> {
+        let runtime = RUNTIME
+            .as_ref()
+            .map_err(|e| BackendErr::Request(e.clone()))?;
+        let url = format!("{}/{}", self.Url, endpoint);
+        runtime.block_on(async {
+            let res = self
+                .client
+                .post(&url)
+                .json(&body)
+                .send()
+                .await
+                .map_err(|e| BackendErr::Request(e.to_string()))?;
+            let status = res.status();
+            let text = res
+                .text()
+                .await
+                .map_err(|e| BackendErr::Request(format!("Failed to read body: {}", e)))?;
+            if !status.is_success() {
+                return Err(BackendErr::Status(status.as_u16(), text));
+            }
+            serde_json::from_str(&text).map_err(|e| BackendErr::MalformedResponse(e.to_string()))
+        })
+    }
 }
 
-pub async fn RTFit(R: &Vec<f64>, T: &Vec<f64>) -> Result<Vec<f64>, String> {
-    let client = Client::new();
-    let url = "https://tes-gui-pyhelper.onrender.com/RTFit";
-
-    let res = client
-        .post(url)
-        .json(&json!({ "R": R, "T": T }))
-        .send()
-        .await
-        .map_err(|e| format!("Failed to get response: {}", e))?;
-
-    let json: serde_json::Value = res
-        .json()
-        .await
-        .map_err(|e| format!("Failed to execute Python command: {}", e))?;
-    let result = json
+fn ToF64Vec(value: &serde_json::Value, name: &str) -> Result<Vec<f64>, BackendErr> {
+    value
         .as_array()
-        .unwrap()
+        .ok_or_else(|| BackendErr::MalformedResponse(format!("'{}' is not an array", name)))?
         .iter()
-        .map(|v| v.as_f64().unwrap())
-        .collect::<Vec<f64>>();
+        .map(|v| {
+            v.as_f64().ok_or_else(|| {
+                BackendErr::MalformedResponse(format!("'{}' contains a non-number: {}", name, v))
+            })
+        })
+        .collect()
+}
+
+impl NumericBackend for HttpBackend {
+    fn FilterCoefficients(
+        &self,
+        kind: FilterKind,
+        order: usize,
+        rate: f64,
+        cutoff: f64,
+    ) -> Result<Vec<Vec<f64>>, BackendErr> {
+        if kind != FilterKind::Bessel || order != 2 {
+            return Err(BackendErr::Unsupported(format!(
+                "{:?} filter of order {}",
+                kind, order
+            )));
+        }
+        let json = self.Post("Bessel", json!({ "rate": rate, "fs": cutoff }))?;
+        let a = ToF64Vec(&json["a"], "a")?;
+        let b = ToF64Vec(&json["b"], "b")?;
+        Ok(vec![a, b])
+    }
+
+    fn FitRT(&self, R: &[f64], T: &[f64]) -> Result<RTFitS, BackendErr> {
+        if R.is_empty() || R.len() != T.len() {
+            return Err(BackendErr::Compute(
+                "R and T must have the same non-zero length".to_string(),
+            ));
+        }
+        let json = self.Post("RTFit", json!({ "R": R, "T": T }))?;
+        let p = ToF64Vec(&json, "result")?;
+        if p.len() != 4 {
+            return Err(BackendErr::MalformedResponse(format!(
+                "Expected 4 parameters, got {}",
+                p.len()
+            )));
+        }
+
+        // PyHelper は atan で範囲を制限したパラメータを返す
+        let (R_s, R_f) = (R[0], R[R.len() - 1]);
+        let (T_s, T_f) = (T[0], T[T.len() - 1]);
+        let T1 = (T_f / PI) * p[2].atan() + T_f / 2.0;
+        let T2 = (T_f / PI) * p[3].atan() + T_f / 2.0;
+        Ok(RTFitS {
+            RN: ((R_f - R_s) / PI) * p[0].atan() + (R_s + R_f) / 2.0,
+            Tc: ((T_f - T_s) / PI) * p[1].atan() + (T_s + T_f) / 2.0,
+            T1: T1.min(T2),
+            T2: T1.max(T2),
+            RN_err: f64::NAN,
+            Tc_err: f64::NAN,
+            T1_err: f64::NAN,
+            T2_err: f64::NAN,
+            ReducedChi2: f64::NAN,
+        })
+    }
+}
 
-    return Ok(result);
+/// テスト用の決定論的なバックエンド
+pub struct MockBackend;
+
+impl NumericBackend for MockBackend {
+    fn FilterCoefficients(
+        &self,
+        _kind: FilterKind,
+        order: usize,
+        _rate: f64,
+        _cutoff: f64,
+    ) -> Result<Vec<Vec<f64>>, BackendErr> {
+        // 素通しフィルタ
+        let mut a = vec![0.0; order + 1];
+        let mut b = vec![0.0; order + 1];
+        a[0] = 1.0;
+        b[0] = 1.0;
+        Ok(vec![a, b])
+    }
+
+    fn FilterSections(
+        &self,
+        _kind: FilterKind,
+        _order: usize,
+        _rate: f64,
+        _cutoff: f64,
+    ) -> Result<Vec<Section>, BackendErr> {
+        Ok(vec![[1.0, 0.0, 0.0, 1.0, 0.0, 0.0]])
+    }
+
+    fn FitRT(&self, R: &[f64], T: &[f64]) -> Result<RTFitS, BackendErr> {
+        if R.is_empty() || R.len() != T.len() {
+            return Err(BackendErr::Compute(
+                "R and T must have the same non-zero length".to_string(),
+            ));
+        }
+        let RN = R.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+        let Tc = T
+            .iter()
+            .zip(R)
+            .find(|(_, &r)| r >= RN / 2.0)
+            .map(|(&t, _)| t)
+            .unwrap_or(T[0]);
+        Ok(RTFitS {
+            RN,
+            Tc,
+            T1: 1.0,
+            T2: 1.0,
+            RN_err: 0.0,
+            Tc_err: 0.0,
+            T1_err: 0.0,
+            T2_err: 0.0,
+            ReducedChi2: 0.0,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Config::{PulseAnalysisConfig, PulseFilterConfig, PulseReadoutConfig};
+    use crate::PulseProcessor::PulseFilterS;
+    use ndarray::Array1;
+
+    fn PulseConfigs(Filter: PulseFilterConfig) -> (PulseReadoutConfig, PulseAnalysisConfig) {
+        let mut PRConfig = PulseReadoutConfig::new();
+        PRConfig.Rate = 1e6;
+        let mut PAConfig = PulseAnalysisConfig::new();
+        PAConfig.CutoffFrequency = 1e4;
+        PAConfig.Filter = Filter;
+        (PRConfig, PAConfig)
+    }
+
+    fn RTCurve() -> (Vec<f64>, Vec<f64>) {
+        let T: Vec<f64> = (0..41).map(|i| 195.0 + 0.25 * i as f64).collect();
+        let R = T
+            .iter()
+            .map(|t| 0.1 / ((1.0 + ((200.0 - t) / 0.5).exp()) * (1.0 + ((200.0 - t) / 1.5).exp())))
+            .collect();
+        (R, T)
+    }
+
+    #[test]
+    fn MockFilterPassesPulseThrough() {
+        let backend = CreateBackend(&BackendConfig::Mock);
+        for Filter in [
+            PulseFilterConfig::Bessel { Order: 2 },
+            PulseFilterConfig::Butterworth { Order: 4 },
+        ] {
+            let (PRConfig, PAConfig) = PulseConfigs(Filter);
+            let filter = PulseFilterS::Design(&*backend, &PRConfig, &PAConfig).unwrap();
+            assert!(matches!(filter, PulseFilterS::Sections(_)));
+            let pulse = Array1::from_iter((0..100).map(|i| {
+                if i < 30 {
+                    0.0
+                } else {
+                    (-(i as f64 - 30.0) / 20.0).exp()
+                }
+            }));
+            let filtered = filter.Apply(&pulse).unwrap();
+            for (a, b) in filtered.iter().zip(pulse.iter()) {
+                assert!((a - b).abs() < 1e-12);
+            }
+        }
+    }
+
+    #[test]
+    fn NativeFilterMatchesFilterDesign() {
+        let backend = CreateBackend(&BackendConfig::Native);
+        let (PRConfig, PAConfig) = PulseConfigs(PulseFilterConfig::Bessel { Order: 2 });
+        let expected = LowPassSections(FilterKind::Bessel, 2, 1e6, 1e4).unwrap();
+        match PulseFilterS::Design(&*backend, &PRConfig, &PAConfig).unwrap() {
+            PulseFilterS::Sections(sos) => assert_eq!(sos, expected),
+            other => panic!("Unexpected filter {:?}", other),
+        }
+    }
+
+    #[test]
+    fn MockFitRTIsDeterministic() {
+        let (R, T) = RTCurve();
+        let fit = CreateBackend(&BackendConfig::Mock).FitRT(&R, &T).unwrap();
+        let RN = R.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+        assert_eq!(fit.RN, RN);
+        // R が RN/2 を最初に超える温度
+        let i = R.iter().position(|r| *r >= RN / 2.0).unwrap();
+        assert_eq!(fit.Tc, T[i]);
+        assert!(CreateBackend(&BackendConfig::Mock)
+            .FitRT(&R, &T[1..])
+            .is_err());
+    }
+
+    #[test]
+    fn NativeFitRTMatchesFitRTCurve() {
+        let (R, T) = RTCurve();
+        let fit = CreateBackend(&BackendConfig::Native).FitRT(&R, &T).unwrap();
+        let expected = FitRTCurve(&R, &T).unwrap();
+        assert_eq!(
+            (fit.RN, fit.Tc, fit.T1, fit.T2),
+            (expected.RN, expected.Tc, expected.T1, expected.T2)
+        );
+        assert!((fit.Tc - 200.0).abs() < 1e-6);
+    }
 }
@@ -1,10 +1,32 @@
 #![allow(non_snake_case)]
+use crate::TES_Err::{TESErr, TESErrorS, TESResult};
 use ndarray::Array1;
 
+pub mod Branch;
+pub mod Electrothermal;
+pub mod Fit;
+pub mod FluxJump;
+pub mod History;
 pub mod IV;
 pub mod RT;
+pub mod Thermal;
 
-pub(crate) fn LinerFit(x: &Array1<f64>, y: &Array1<f64>) -> Result<f64, String> {
+/// 有限な値の中央値 (偶数個なら真ん中 2 つの平均, 無ければ None)
+pub(crate) fn Median(values: &[f64]) -> Option<f64> {
+    let mut v: Vec<f64> = values.iter().cloned().filter(|x| x.is_finite()).collect();
+    if v.is_empty() {
+        return None;
+    }
+    v.sort_by(|a, b| a.total_cmp(b));
+    let mid = v.len() / 2;
+    Some(if v.len().is_multiple_of(2) {
+        (v[mid - 1] + v[mid]) / 2.0
+    } else {
+        v[mid]
+    })
+}
+
+pub(crate) fn LinerFit(x: &Array1<f64>, y: &Array1<f64>) -> TESResult<f64> {
     let n = x.len() as f64;
 
     let sum_x = x.sum();
@@ -18,7 +40,7 @@ pub(crate) fn LinerFit(x: &Array1<f64>, y: &Array1<f64>) -> Result<f64, String>
 
     let denominator = n * sum_xx - sum_x * sum_x;
     if denominator.abs() < 1e-10 {
-        return Err("Failed to compute LinerFit".into());
+        return Err(TESErrorS::new(TESErr::LinerFitErr, "Failed to compute LinerFit"));
     }
 
     let a = (n * sum_xy - sum_x * sum_y) / denominator;
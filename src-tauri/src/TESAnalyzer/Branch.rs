@@ -0,0 +1,123 @@
+#![allow(non_snake_case)]
+// IV の各点を超伝導・転移・常伝導に分ける (傾きと曲率で判定する)
+use crate::TESAnalyzer::Median;
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum Branch {
+    Superconducting,
+    Transition,
+    Normal,
+}
+
+/// 1 温度分の分類結果 (傾きは枝の中の dV_out/dI_bias で V/uA)
+#[derive(Debug, Clone, Serialize)]
+pub struct IVBranchS {
+    /// I_bias と同じ並び
+    pub Points: Vec<Branch>,
+    pub SCSlope: Option<f64>,
+    pub NormalSlope: Option<f64>,
+    /// 常伝導抵抗 (Ω, 常伝導側が無い温度は他の温度の中央値)
+    pub R_n: Option<f64>,
+}
+
+/// 端から傾き slope の直線が続く点の数 (傾きか曲率が tolerance を超えたら止める)
+fn LinearRun(local: &[f64], curvature: &[f64], slope: f64, tolerance: f64) -> usize {
+    let limit = tolerance * slope.abs();
+    local
+        .iter()
+        .zip(curvature)
+        .take_while(|(m, c)| (**m - slope).abs() <= limit && c.abs() <= limit)
+        .count()
+}
+
+/// I_bias は昇順、V_out はオフセットを引いたものを渡す
+///
+/// 局所的な傾きは Window 区間の中央値なので、補正していない飛びが 1 つあっても枝は切れない。
+/// ReferenceSC は最低温度の超伝導側の傾きで、転移の無い温度が超伝導か常伝導かを決めるのに使う
+pub fn SegmentIV(
+    I_bias: &[f64],
+    V_out: &[f64],
+    Window: usize,
+    Tolerance: f64,
+    ReferenceSC: Option<f64>,
+) -> IVBranchS {
+    let n = I_bias.len().min(V_out.len());
+    let Unclassified = IVBranchS {
+        Points: vec![Branch::Transition; n],
+        SCSlope: None,
+        NormalSlope: None,
+        R_n: None,
+    };
+    let Window = Window.max(3);
+    if n < 2 * Window {
+        return Unclassified;
+    }
+
+    // 区間ごとの傾き
+    let d: Vec<f64> = (0..n - 1)
+        .map(|j| (V_out[j + 1] - V_out[j]) / (I_bias[j + 1] - I_bias[j]))
+        .collect();
+    let h = Window / 2;
+    // 点 i の局所的な傾き (i をはさむ区間の中央値) と、その変化 (曲率)
+    let local: Vec<f64> = (0..n)
+        .map(|i| Median(&d[i.saturating_sub(h)..(i + h).min(d.len())]).unwrap_or(f64::NAN))
+        .collect();
+    let curvature: Vec<f64> = (0..n)
+        .map(|i| (local[(i + 1).min(n - 1)] - local[i.saturating_sub(1)]) / 2.0)
+        .collect();
+
+    let (Some(SC), Some(Normal)) = (Median(&d[..Window]), Median(&d[d.len() - Window..])) else {
+        return Unclassified;
+    };
+
+    // 転移が無く 1 本の直線になっている
+    if (SC - Normal).abs() <= Tolerance * SC.abs() {
+        let slope = Median(&d).unwrap_or(SC);
+        let IsSC = ReferenceSC.is_none_or(|r| (slope - r).abs() <= Tolerance * r.abs());
+        return IVBranchS {
+            Points: vec![
+                if IsSC {
+                    Branch::Superconducting
+                } else {
+                    Branch::Normal
+                };
+                n
+            ],
+            SCSlope: IsSC.then_some(slope),
+            NormalSlope: (!IsSC).then_some(slope),
+            R_n: None,
+        };
+    }
+
+    let SCEnd = LinearRun(&local, &curvature, SC, Tolerance);
+    let reversed = |v: &[f64]| v.iter().rev().cloned().collect::<Vec<_>>();
+    let NormalStart = n - LinearRun(&reversed(&local), &reversed(&curvature), Normal, Tolerance);
+    // 常伝導側は正の傾きで、3 点以上続く時だけ認める
+    let HasNormal = Normal > 0.0 && n - NormalStart >= 3 && NormalStart >= SCEnd;
+
+    // 傾きは枝全体の区間の中央値にする (飛びの区間は外れ値として落ちる)
+    let SCSlope = Median(&d[..SCEnd.saturating_sub(1).max(1)]);
+    let NormalSlope = if HasNormal {
+        Median(&d[NormalStart..])
+    } else {
+        None
+    };
+    let Points = (0..n)
+        .map(|i| {
+            if i < SCEnd {
+                Branch::Superconducting
+            } else if HasNormal && i >= NormalStart {
+                Branch::Normal
+            } else {
+                Branch::Transition
+            }
+        })
+        .collect();
+    IVBranchS {
+        Points,
+        SCSlope,
+        NormalSlope,
+        R_n: None,
+    }
+}
@@ -0,0 +1,121 @@
+#![allow(non_snake_case)]
+// IV の各バイアス点での電熱フィードバックの量 (ループゲイン・時定数・電流応答度) を求める
+use serde::Serialize;
+
+/// RT から求めた α の R_TES / R_n 依存性
+#[derive(Debug, Clone, Serialize)]
+pub struct AlphaCurveS {
+    /// α を求めた RT の電流 (uA)
+    pub Current: u32,
+    /// 100 * R / R_n (昇順)
+    pub BiasPoint: Vec<f64>,
+    pub Alpha: Vec<f64>,
+}
+
+impl AlphaCurveS {
+    /// 100 * R / R_n での α を線形補間する (RT の範囲外なら None)
+    pub fn At(&self, BiasPoint: f64) -> Option<f64> {
+        let (x, y) = (&self.BiasPoint, &self.Alpha);
+        let i = x
+            .windows(2)
+            .position(|w| w[0] <= BiasPoint && BiasPoint <= w[1])?;
+        let t = if x[i + 1] > x[i] {
+            (BiasPoint - x[i]) / (x[i + 1] - x[i])
+        } else {
+            0.0
+        };
+        Some(y[i] + t * (y[i + 1] - y[i]))
+    }
+}
+
+/// ループゲインを求める時の熱浴側の量
+#[derive(Debug, Clone, Serialize)]
+pub struct ThermalLinkS {
+    /// 熱伝導度 (pW/K)
+    pub G: f64,
+    /// 転移中の TES の温度 (mK)
+    pub Tc: f64,
+    /// 熱容量 (pJ/K, 分からなければ None)
+    pub C: Option<f64>,
+    /// TES から見た負荷抵抗 R_sh + R_par (Ω)
+    pub R_L: f64,
+}
+
+/// 1 つのバイアス点 (β = 0 として計算する)
+#[derive(Debug, Clone, Serialize)]
+pub struct BiasPointS {
+    /// uA
+    pub I_bias: f64,
+    /// uA
+    pub I_TES: f64,
+    /// Ω
+    pub R_TES: f64,
+    /// ジュール熱 (pW)
+    pub P_J: f64,
+    /// R_TES / R_n
+    pub RFraction: f64,
+    pub Alpha: Option<f64>,
+    /// ループゲイン L = αP/(GT)
+    pub LoopGain: Option<f64>,
+    /// τ_eff / τ0 (τ0 = C/G)
+    pub TauRatio: Option<f64>,
+    /// 実効的な時定数 (s, C が分かる時だけ)
+    pub Tau_eff: Option<f64>,
+    /// 直流の電流応答度 dI/dP (A/W)
+    pub S_I: Option<f64>,
+}
+
+impl BiasPointS {
+    pub const CSV_HEADER: &'static str =
+        "Temp,I_bias,I_TES,R_TES,P_J,RFraction,Alpha,LoopGain,TauRatio,Tau_eff,S_I";
+
+    /// 求まらなかった値は空欄にする
+    pub fn ToCSVRow(&self, Temp: u32) -> String {
+        let Blank = |v: Option<f64>| v.map_or(String::new(), |v| v.to_string());
+        format!(
+            "{},{},{},{},{},{},{},{},{},{},{}",
+            Temp,
+            self.I_bias,
+            self.I_TES,
+            self.R_TES,
+            self.P_J,
+            self.RFraction,
+            Blank(self.Alpha),
+            Blank(self.LoopGain),
+            Blank(self.TauRatio),
+            Blank(self.Tau_eff),
+            Blank(self.S_I)
+        )
+    }
+}
+
+/// 転移中の点 (α が RT の範囲にある点) だけループゲインなどを求める
+pub fn BiasPoint(
+    I_bias: f64,
+    I_TES: f64,
+    R_TES: f64,
+    R_n: f64,
+    Alpha: &AlphaCurveS,
+    Link: &ThermalLinkS,
+) -> BiasPointS {
+    let P_J = I_TES * I_TES * R_TES;
+    let RFraction = R_TES / R_n;
+    let alpha = Alpha.At(100.0 * RFraction);
+    let LoopGain = alpha.map(|a| a * P_J / (Link.G * Link.Tc * 1e-3));
+    // 電気的な帰還の強さ (R - R_L)/(R + R_L)
+    let feedback = (R_TES - Link.R_L) / (R_TES + Link.R_L);
+    let TauRatio = LoopGain.map(|L| 1.0 / (1.0 + L * feedback));
+    let S_I = LoopGain.map(|L| -L / (I_TES * 1e-6 * ((R_TES + Link.R_L) + L * (R_TES - Link.R_L))));
+    BiasPointS {
+        I_bias,
+        I_TES,
+        R_TES,
+        P_J,
+        RFraction,
+        Alpha: alpha,
+        LoopGain,
+        TauRatio,
+        Tau_eff: TauRatio.zip(Link.C).map(|(r, C)| r * C / Link.G),
+        S_I,
+    }
+}
@@ -0,0 +1,279 @@
+#![allow(non_snake_case)]
+use serde::Serialize;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FitResultS {
+    pub Params: Vec<f64>,
+    pub Errors: Vec<f64>,
+    pub Covariance: Vec<Vec<f64>>,
+    pub ReducedChi2: f64,
+    pub Iterations: usize,
+}
+
+/// ガウスの消去法で逆行列を求める (特異なら None)
+pub(crate) fn Invert(mat: &[Vec<f64>]) -> Option<Vec<Vec<f64>>> {
+    let n = mat.len();
+    let mut a: Vec<Vec<f64>> = mat.to_vec();
+    let mut inv: Vec<Vec<f64>> = (0..n)
+        .map(|i| (0..n).map(|j| if i == j { 1.0 } else { 0.0 }).collect())
+        .collect();
+
+    for col in 0..n {
+        let pivot = (col..n).max_by(|&i, &j| a[i][col].abs().total_cmp(&a[j][col].abs()))?;
+        if a[pivot][col].abs() < 1e-300 {
+            return None;
+        }
+        a.swap(col, pivot);
+        inv.swap(col, pivot);
+
+        let d = a[col][col];
+        for j in 0..n {
+            a[col][j] /= d;
+            inv[col][j] /= d;
+        }
+        for row in 0..n {
+            if row != col {
+                let f = a[row][col];
+                if f != 0.0 {
+                    for j in 0..n {
+                        a[row][j] -= f * a[col][j];
+                        inv[row][j] -= f * inv[col][j];
+                    }
+                }
+            }
+        }
+    }
+    Some(inv)
+}
+
+/// 連立一次方程式 A x = b を解く
+pub(crate) fn Solve(a: &[Vec<f64>], b: &[f64]) -> Option<Vec<f64>> {
+    let inv = Invert(a)?;
+    Some(
+        inv.iter()
+            .map(|row| row.iter().zip(b).map(|(r, v)| r * v).sum())
+            .collect(),
+    )
+}
+
+fn Chi2<F: Fn(f64, &[f64]) -> f64>(model: &F, x: &[f64], y: &[f64], w: &[f64], p: &[f64]) -> f64 {
+    x.iter()
+        .zip(y)
+        .zip(w)
+        .map(|((&xi, &yi), &wi)| {
+            let r = (yi - model(xi, p)) * wi;
+            r * r
+        })
+        .sum()
+}
+
+/// Levenberg-Marquardt 法による非線形最小二乗フィット
+///
+/// ヤコビアンは数値微分で求める。sigma を与えた場合は 1/sigma で重み付けする。
+pub fn LevenbergMarquardt<F: Fn(f64, &[f64]) -> f64>(
+    model: F,
+    x: &[f64],
+    y: &[f64],
+    sigma: Option<&[f64]>,
+    p0: &[f64],
+) -> Result<FitResultS, String> {
+    let n = x.len();
+    let m = p0.len();
+    if n != y.len() {
+        return Err("x and y must have the same length".to_string());
+    }
+    if n <= m {
+        return Err(format!("Too few data points ({}) for {} parameters", n, m));
+    }
+    let w: Vec<f64> = match sigma {
+        Some(s) if s.len() == n => s
+            .iter()
+            .map(|v| if *v > 0.0 { 1.0 / v } else { 0.0 })
+            .collect(),
+        Some(_) => return Err("sigma must have the same length as y".to_string()),
+        None => vec![1.0; n],
+    };
+
+    let jacobian = |p: &[f64]| -> Vec<Vec<f64>> {
+        let mut J = vec![vec![0.0; m]; n];
+        let mut pp = p.to_vec();
+        for k in 0..m {
+            let h = 1e-6 * p[k].abs().max(1e-8);
+            pp[k] = p[k] + h;
+            let f_plus: Vec<f64> = x.iter().map(|&xi| model(xi, &pp)).collect();
+            pp[k] = p[k] - h;
+            let f_minus: Vec<f64> = x.iter().map(|&xi| model(xi, &pp)).collect();
+            pp[k] = p[k];
+            for i in 0..n {
+                J[i][k] = w[i] * (f_plus[i] - f_minus[i]) / (2.0 * h);
+            }
+        }
+        J
+    };
+
+    let mut p = p0.to_vec();
+    let mut chi2 = Chi2(&model, x, y, &w, &p);
+    if !chi2.is_finite() {
+        return Err("Initial parameters give a non-finite residual".to_string());
+    }
+    let mut lambda = 1e-3;
+    let mut iterations = 0;
+
+    while iterations < 500 {
+        iterations += 1;
+        let J = jacobian(&p);
+        let r: Vec<f64> = (0..n).map(|i| (y[i] - model(x[i], &p)) * w[i]).collect();
+
+        let mut JtJ = vec![vec![0.0; m]; m];
+        let mut Jtr = vec![0.0; m];
+        for i in 0..n {
+            for a in 0..m {
+                Jtr[a] += J[i][a] * r[i];
+                for b in 0..m {
+                    JtJ[a][b] += J[i][a] * J[i][b];
+                }
+            }
+        }
+
+        let mut improved = false;
+        while lambda < 1e12 {
+            let mut A = JtJ.clone();
+            for k in 0..m {
+                A[k][k] += lambda * JtJ[k][k].max(1e-12);
+            }
+            if let Some(delta) = Solve(&A, &Jtr) {
+                let p_new: Vec<f64> = p.iter().zip(&delta).map(|(a, d)| a + d).collect();
+                let chi2_new = Chi2(&model, x, y, &w, &p_new);
+                if chi2_new.is_finite() && chi2_new < chi2 {
+                    let converged = (chi2 - chi2_new) <= 1e-12 * chi2.max(1e-300);
+                    p = p_new;
+                    chi2 = chi2_new;
+                    lambda = (lambda / 10.0).max(1e-12);
+                    improved = !converged;
+                    break;
+                }
+            }
+            lambda *= 10.0;
+        }
+        if !improved {
+            break;
+        }
+    }
+
+    if p.iter().any(|v| !v.is_finite()) {
+        return Err("Fit diverged".to_string());
+    }
+
+    let dof = (n - m) as f64;
+    let ReducedChi2 = chi2 / dof;
+
+    // 共分散行列 (重みなしの場合は残差分散でスケール)
+    let J = jacobian(&p);
+    let mut JtJ = vec![vec![0.0; m]; m];
+    for row in J.iter() {
+        for a in 0..m {
+            for b in 0..m {
+                JtJ[a][b] += row[a] * row[b];
+            }
+        }
+    }
+    let scale = if sigma.is_some() { 1.0 } else { ReducedChi2 };
+    let Covariance = match Invert(&JtJ) {
+        Some(inv) => inv
+            .into_iter()
+            .map(|row| row.into_iter().map(|v| v * scale).collect())
+            .collect(),
+        None => vec![vec![f64::NAN; m]; m],
+    };
+    let Errors = (0..m).map(|k| Covariance[k][k].abs().sqrt()).collect();
+
+    Ok(FitResultS {
+        Params: p,
+        Errors,
+        Covariance,
+        ReducedChi2,
+        Iterations: iterations,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn DoubleFermi(T: f64, p: &[f64]) -> f64 {
+        p[0] / ((1.0 + ((p[1] - T) / p[2]).exp()) * (1.0 + ((p[1] - T) / p[3]).exp()))
+    }
+
+    /// 再現できるように線形合同法 + Box-Muller で作る正規乱数
+    fn Gaussian(n: usize, seed: u64) -> Vec<f64> {
+        let mut state = seed;
+        let mut uniform = || {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            ((state >> 11) as f64 + 0.5) / (1u64 << 53) as f64
+        };
+        (0..n)
+            .map(|_| {
+                let (u1, u2) = (uniform(), uniform());
+                (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+            })
+            .collect()
+    }
+
+    const TRUTH: [f64; 4] = [0.1, 200.0, 0.5, 1.5];
+
+    fn Temperatures() -> Vec<f64> {
+        (0..81).map(|i| 190.0 + 0.25 * i as f64).collect()
+    }
+
+    #[test]
+    fn RecoversExactDoubleFermi() {
+        let T = Temperatures();
+        let R: Vec<f64> = T.iter().map(|&t| DoubleFermi(t, &TRUTH)).collect();
+        let result =
+            LevenbergMarquardt(DoubleFermi, &T, &R, None, &[0.12, 199.0, 1.0, 2.0]).unwrap();
+        for (p, t) in result.Params.iter().zip(TRUTH) {
+            assert!((p - t).abs() < 1e-6 * t.abs(), "{:?}", result.Params);
+        }
+        assert!(result.ReducedChi2 < 1e-20);
+    }
+
+    #[test]
+    fn RecoversParametersAndErrorsWithNoise() {
+        let T = Temperatures();
+        let sigma = 1e-3;
+        let R: Vec<f64> = T
+            .iter()
+            .zip(Gaussian(T.len(), 42))
+            .map(|(&t, n)| DoubleFermi(t, &TRUTH) + sigma * n)
+            .collect();
+        let s = vec![sigma; T.len()];
+        let result =
+            LevenbergMarquardt(DoubleFermi, &T, &R, Some(&s), &[0.12, 199.0, 1.0, 2.0]).unwrap();
+        for ((p, e), t) in result.Params.iter().zip(&result.Errors).zip(TRUTH) {
+            assert!(e.is_finite() && *e > 0.0);
+            // 誤差は真値からのずれを 4σ 以内で説明する
+            assert!((p - t).abs() < 4.0 * e, "{} +- {} vs {}", p, e, t);
+            assert!(*e < 0.05 * t, "{} +- {}", p, e);
+        }
+        // 正しい sigma を与えたので χ²/dof は 1 前後
+        assert!(
+            (0.6..1.5).contains(&result.ReducedChi2),
+            "{}",
+            result.ReducedChi2
+        );
+
+        // 重み無しでも残差分散でスケールした誤差はほぼ同じになる
+        let unweighted =
+            LevenbergMarquardt(DoubleFermi, &T, &R, None, &[0.12, 199.0, 1.0, 2.0]).unwrap();
+        for (a, b) in unweighted.Errors.iter().zip(&result.Errors) {
+            assert!((a / b - 1.0).abs() < 0.5, "{} vs {}", a, b);
+        }
+    }
+
+    #[test]
+    fn RejectsTooFewPoints() {
+        assert!(LevenbergMarquardt(DoubleFermi, &[1.0, 2.0], &[1.0, 2.0], None, &TRUTH).is_err());
+    }
+}
@@ -0,0 +1,108 @@
+#![allow(non_snake_case)]
+// SQUID の磁束の飛びを全温度から自動で探して補正する
+use crate::TESAnalyzer::Median;
+use serde::Serialize;
+
+/// 補正した 1 つの飛び (Index から続けて外れた区間を 1 つにまとめる)
+#[derive(Debug, Clone, Serialize)]
+pub struct FluxJumpS {
+    pub Index: usize,
+    /// 外れた区間の数
+    pub Intervals: usize,
+    /// 飛びの手前の I_bias (uA)
+    pub I_bias: f64,
+    /// Index + 1 以降に足した量 (V)
+    pub Step: f64,
+}
+
+/// 1 温度分の補正結果 (Jumps が空なら変更していない)
+#[derive(Debug, Clone, Serialize)]
+pub struct FluxJumpReportS {
+    pub Temp: u32,
+    pub Jumps: Vec<FluxJumpS>,
+    /// 補正を記録した履歴のインデックス
+    pub HistoryIndex: Option<usize>,
+}
+
+/// 区間 j の傾きの前後 Window 区間の中央値 (j 自身は除く)
+fn Neighbors(d: &[f64], j: usize, Window: usize) -> (Option<f64>, Option<f64>) {
+    let before = Median(&d[j.saturating_sub(Window)..j]);
+    let after = Median(&d[(j + 1).min(d.len())..(j + 1 + Window).min(d.len())]);
+    (before, after)
+}
+
+/// 区間の傾きが周りの傾きから MAD の Threshold 倍以上、かつ MinSlopeChange の割合以上外れていて、
+/// 前後の傾きが揃っている所を飛びとする
+///
+/// 前後で傾きが変わる所 (超伝導から転移への折れ曲がり) は飛びとは見なさない。
+/// 戻り値は補正した V_out と、補正した飛び
+pub fn CorrectFluxJumps(
+    I_bias: &[f64],
+    V_out: &[f64],
+    Window: usize,
+    Threshold: f64,
+    MinSlopeChange: f64,
+    SlopeTolerance: f64,
+) -> (Vec<f64>, Vec<FluxJumpS>) {
+    let n = I_bias.len().min(V_out.len());
+    let mut V_out = V_out[..n].to_vec();
+    let Window = Window.max(2);
+    if n < 2 * Window + 2 {
+        return (V_out, vec![]);
+    }
+
+    let d: Vec<f64> = (0..n - 1)
+        .map(|j| (V_out[j + 1] - V_out[j]) / (I_bias[j + 1] - I_bias[j]))
+        .collect();
+    // 周りの傾きで予想した傾きとの差
+    let expected: Vec<Option<f64>> = (0..d.len())
+        .map(|j| match Neighbors(&d, j, Window) {
+            (Some(b), Some(a)) => Median(&[b, a]),
+            (b, a) => b.or(a),
+        })
+        .collect();
+    let residual: Vec<f64> = d
+        .iter()
+        .zip(&expected)
+        .map(|(d, e)| e.map_or(f64::NAN, |e| d - e))
+        .collect();
+    let Some(center) = Median(&residual) else {
+        return (V_out, vec![]);
+    };
+    let deviation: Vec<f64> = residual.iter().map(|r| (r - center).abs()).collect();
+    // 正規分布なら 1.4826 * MAD が標準偏差になる
+    let sigma = 1.4826 * Median(&deviation).unwrap_or(0.0);
+    let scale = Median(&d.iter().map(|v| v.abs()).collect::<Vec<_>>()).unwrap_or(0.0);
+    let sigma = sigma.max(scale * 1e-6).max(f64::MIN_POSITIVE);
+
+    let mut Jumps: Vec<FluxJumpS> = vec![];
+    for j in 0..d.len() {
+        let Some(e) = expected[j] else { continue };
+        if ((residual[j] - center) / sigma).abs() < Threshold
+            || residual[j].abs() < MinSlopeChange * e.abs()
+        {
+            continue;
+        }
+        if let (Some(b), Some(a)) = Neighbors(&d, j, Window) {
+            if (b - a).abs() > SlopeTolerance * b.abs().max(a.abs()) {
+                continue;
+            }
+        }
+        // 飛びの区間の傾きを周りの傾きに合わせて、それ以降を平行移動する
+        let Step = e * (I_bias[j + 1] - I_bias[j]) - (V_out[j + 1] - V_out[j]);
+        V_out[j + 1..].iter_mut().for_each(|v| *v += Step);
+        match Jumps.last_mut() {
+            Some(last) if last.Index + last.Intervals == j => {
+                last.Intervals += 1;
+                last.Step += Step;
+            }
+            _ => Jumps.push(FluxJumpS {
+                Index: j,
+                Intervals: 1,
+                I_bias: I_bias[j],
+                Step,
+            }),
+        }
+    }
+    (V_out, Jumps)
+}
@@ -0,0 +1,173 @@
+#![allow(non_snake_case)]
+// IV のキャリブレーション履歴を Calibration/History.json に残し、開き直した時に戻す
+use crate::DataProcessor::WriteFile;
+use crate::TES_Err::{TESErr, TESErrorS, TESResult};
+use ndarray::Array1;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// 温度ごとの V_out の履歴
+type VoutHistory = HashMap<u32, Vec<Array1<f64>>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CalibrationOp {
+    /// 読み込んだままの V_out
+    Raw,
+    /// 履歴の無い古い Calibration/{T}mk.dat から読んだ
+    Loaded,
+    SingleJump,
+    MultipleJump,
+    AutoJump,
+}
+
+/// 履歴の 1 段 (V_out_history の同じインデックスをどう作ったか)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CalibrationStepS {
+    pub Op: CalibrationOp,
+    /// 補正した温度 (mK, 全温度なら None)
+    pub Temp: Option<u32>,
+    /// 補正範囲の I_bias (uA)
+    pub BiasWindow: Option<(f64, f64)>,
+    /// UNIX 時間 (s)
+    pub Timestamp: u64,
+    pub Label: Option<String>,
+}
+
+impl CalibrationStepS {
+    pub fn new(Op: CalibrationOp, Temp: Option<u32>, BiasWindow: Option<(f64, f64)>) -> Self {
+        let Timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        Self {
+            Op,
+            Temp,
+            BiasWindow,
+            Timestamp,
+            Label: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct HistoryEntryS {
+    #[serde(flatten)]
+    Step: CalibrationStepS,
+    /// 前の段から変わった温度の V_out (最初の段は生データから作り直すので空)
+    Changes: HashMap<u32, Vec<f64>>,
+}
+
+/// Calibration/History.json の中身
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CalibrationHistoryS {
+    CurrentIndex: usize,
+    Entries: Vec<HistoryEntryS>,
+}
+
+impl CalibrationHistoryS {
+    /// 各段の V_out は前の段との差分だけ残す
+    pub fn Encode(
+        Steps: &[CalibrationStepS],
+        V_out_history: &VoutHistory,
+        CurrentIndex: usize,
+    ) -> Self {
+        let Entries = Steps
+            .iter()
+            .enumerate()
+            .map(|(i, step)| HistoryEntryS {
+                Step: step.clone(),
+                Changes: V_out_history
+                    .iter()
+                    .filter(|(_, history)| {
+                        i > 0 && i < history.len() && history[i] != history[i - 1]
+                    })
+                    .map(|(temp, history)| (*temp, history[i].to_vec()))
+                    .collect(),
+            })
+            .collect();
+        Self {
+            CurrentIndex,
+            Entries,
+        }
+    }
+
+    /// 生データ (V_out_history の最初の段) に差分を順に当てて履歴を作り直す
+    ///
+    /// 生データと合わない (温度や点数が違う) 履歴はエラーにする
+    pub fn Decode(
+        &self,
+        Raw: &HashMap<u32, Array1<f64>>,
+    ) -> Result<(Vec<CalibrationStepS>, VoutHistory, usize), String> {
+        match self.Entries.first() {
+            Some(e) if e.Step.Op == CalibrationOp::Raw => {}
+            _ => return Err("The first calibration step is not the raw data".to_string()),
+        }
+        if self.CurrentIndex >= self.Entries.len() {
+            return Err(format!(
+                "Calibration index {} is out of {} steps",
+                self.CurrentIndex,
+                self.Entries.len()
+            ));
+        }
+        let mut V_out_history: VoutHistory = Raw
+            .iter()
+            .map(|(temp, V_out)| (*temp, vec![V_out.clone()]))
+            .collect();
+        for entry in self.Entries.iter().skip(1) {
+            for (temp, V_out) in entry.Changes.iter() {
+                match Raw.get(temp) {
+                    Some(raw) if raw.len() == V_out.len() => {}
+                    Some(_) => {
+                        return Err(format!(
+                            "Calibrated V_out at {}mK has a different length",
+                            temp
+                        ))
+                    }
+                    None => {
+                        return Err(format!(
+                            "No IV data at {}mK for the calibration history",
+                            temp
+                        ))
+                    }
+                }
+            }
+            for (temp, history) in V_out_history.iter_mut() {
+                let next = match entry.Changes.get(temp) {
+                    Some(V_out) => Array1::from(V_out.clone()),
+                    None => history[history.len() - 1].clone(),
+                };
+                history.push(next);
+            }
+        }
+        let Steps = self.Entries.iter().map(|e| e.Step.clone()).collect();
+        Ok((Steps, V_out_history, self.CurrentIndex))
+    }
+
+    /// 無いか読めなければ None
+    pub fn Load(path: &Path) -> Option<Self> {
+        File::open(path)
+            .ok()
+            .and_then(|f| serde_json::from_reader(f).ok())
+    }
+
+    pub fn Save(&self, path: &Path) -> TESResult<()> {
+        let json_str = serde_json::to_string(self).map_err(|e| {
+            TESErrorS::new(
+                TESErr::SerializeErr,
+                format!("Failed to serialize calibration history: {}", e),
+            )
+        })?;
+        // 途中で止まっても壊れたファイルが残らないよう書き込んでから置き換える
+        let tmp = path.with_extension("json.tmp");
+        WriteFile(&tmp, json_str)?;
+        std::fs::rename(&tmp, path).map_err(|e| {
+            TESErrorS::AtPath(
+                TESErr::FileWriteErr,
+                path,
+                format!("Failed to replace file\n{}", e),
+            )
+        })
+    }
+}
@@ -1,7 +1,15 @@
 #![allow(non_snake_case)]
-use crate::Config::TESAnalysisConfig;
+use crate::Config::{
+    BackendConfig, BranchConfig, ElectrothermalConfig, FluxJumpConfig, TESAnalysisConfig, ThermalConfig,
+};
 use crate::DataProcessor::{DataProcessorS, DataProcessorT, LoadTxt, SaveTxt};
-use crate::TESAnalyzer::LinerFit;
+use crate::TESAnalyzer::Branch::{Branch, IVBranchS, SegmentIV};
+use crate::TESAnalyzer::Electrothermal::{AlphaCurveS, BiasPoint, BiasPointS, ThermalLinkS};
+use crate::TESAnalyzer::FluxJump::{CorrectFluxJumps, FluxJumpReportS};
+use crate::TESAnalyzer::History::{CalibrationHistoryS, CalibrationOp, CalibrationStepS};
+use crate::TESAnalyzer::{LinerFit, Median};
+use crate::TESAnalyzer::Thermal::{FitPsat, PsatS, SaturationPower, ThermalFitS};
+use crate::TES_Err::{ContextT, Severity, TESErr, TESErrorS, TESResult};
 use glob::glob;
 use ndarray::{s, Array1};
 use plotters::prelude::*;
@@ -9,15 +17,31 @@ use regex::Regex;
 use std::collections::HashMap;
 use std::fs::{self};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::vec;
 
 pub struct IVProcessorS {
     pub DP: DataProcessorS,
     pub I_bias_temps: HashMap<u32, Array1<f64>>,
     pub V_out_history_temps: HashMap<u32, Vec<Array1<f64>>>,
+    /// V_out_history と同じ並びで、各段をどう作ったか
+    pub History: Vec<CalibrationStepS>,
     pub R_tes_temps: HashMap<u32, Array1<f64>>,
+    /// P_TES = I_TES * V_TES (pW, I_bias と同じ長さ)
+    pub P_tes_temps: HashMap<u32, Array1<f64>>,
+    pub Psat: Vec<PsatS>,
+    /// P_sat の温度依存性のフィット (温度が足りなければエラー)
+    pub Thermal: Result<ThermalFitS, TESErrorS>,
+    /// 温度ごとの超伝導・転移・常伝導の分類
+    pub Branches: HashMap<u32, IVBranchS>,
+    /// 寄生抵抗 (Ω, Eta を設定した時だけ求まる)
+    pub R_par: f64,
+    /// 温度ごとの各バイアス点のループゲインなど (CalculateElectrothermal で求める)
+    pub Electrothermal: HashMap<u32, Vec<BiasPointS>>,
     pub Temps: Vec<u32>,
     pub CurrentIndex:usize,
+    /// History.json が IV データと合わずに History.json.bak に退避した時の警告
+    pub HistoryWarning: Option<TESErrorS>,
     TESAConfig: TESAnalysisConfig,
 }
 
@@ -51,41 +75,125 @@ impl Offsettable for Array1<f64> {
             .expect("Array1 should have a mutable slice")
     }
 }
+/// 温度ごとのデータが無い時のエラー
+fn Missing(what: &str, temp: u32) -> TESErrorS {
+    TESErrorS::new(
+        TESErr::NotAnalyzed,
+        format!("{} at Temperature:{}mk is not found.", what, temp),
+    )
+}
+
+/// I_bias から校正範囲の端のインデックスを探す
+fn BiasIndex(I_bias: &Array1<f64>, value: f64, name: &str) -> TESResult<usize> {
+    I_bias.iter().position(|&x| x >= value).ok_or_else(|| {
+        TESErrorS::new(TESErr::ConfigErr, format!("{} ({}) is out of range", name, value))
+    })
+}
+
 impl IVProcessorS {
     pub fn new() -> Self {
         Self {
             DP: DataProcessorS::new(),
             I_bias_temps: HashMap::new(),
             V_out_history_temps: HashMap::new(),
+            History: vec![],
             R_tes_temps: HashMap::new(),
+            P_tes_temps: HashMap::new(),
+            Psat: vec![],
+            Thermal: Err(TESErrorS::new(TESErr::NotAnalyzed, "P_sat is not fitted yet.")),
+            Branches: HashMap::new(),
+            R_par: 0.0,
+            Electrothermal: HashMap::new(),
             Temps: vec![],
             CurrentIndex: 0,
+            HistoryWarning: None,
             TESAConfig: TESAnalysisConfig {
                 R_sh: 3.9e-3,
                 LinerFitSample: 10,
+                Backend: BackendConfig::Native,
+                Thermal: ThermalConfig::default(),
+                Eta: None,
+                Branch: BranchConfig::default(),
+                FluxJump: FluxJumpConfig::default(),
+                Electrothermal: ElectrothermalConfig::default(),
             },
         }
     }
-    pub fn SetDataPath(&mut self, path: &Path) {
-        self.DP.SetDataPath(path);
+    /// IVConfig.json があれば設定も読む
+    pub fn SetDataPath(&mut self, path: &Path) -> TESResult<()> {
+        self.DP.SetDataPath(path)?;
+        if let Some(config) = TESAnalysisConfig::Load(&self.ConfigPath())? {
+            self.TESAConfig = config;
+        }
+        Ok(())
+    }
+
+    fn ConfigPath(&self) -> PathBuf {
+        self.DP.DataPath.join("IVConfig.json")
+    }
+
+    /// データのフォルダが決まっていれば IVConfig.json にも残す
+    pub fn SetBackend(&mut self, backend: BackendConfig) -> TESResult<()> {
+        self.TESAConfig.Backend = backend;
+        if self.DP.DataPath.as_os_str().is_empty() {
+            return Ok(());
+        }
+        self.TESAConfig.Save(&self.ConfigPath())
+    }
+
+    pub fn Config(&self) -> &TESAnalysisConfig {
+        &self.TESAConfig
+    }
+
+    pub fn SetConfig(&mut self, config: TESAnalysisConfig) {
+        self.TESAConfig = config;
+    }
+
+    /// 設定を IVConfig.json に書き出し、読み込み済みなら R_TES から求め直す
+    pub fn SaveConfig(&mut self, config: TESAnalysisConfig) -> TESResult<()> {
+        config.Save(&self.ConfigPath())?;
+        self.TESAConfig = config;
+        if self.Temps.is_empty() {
+            return Ok(());
+        }
+        self.CalculateR_TES()
+    }
+
+    /// 設定した読み出しゲインか、最低温度の超伝導側の傾きの逆数
+    pub fn GetEta(&self) -> TESResult<f64> {
+        match self.TESAConfig.Eta {
+            Some(Eta) => Ok(Eta),
+            None => Ok(1.0 / self.SCSlope()?),
+        }
     }
 
-    pub fn GetEta(&self) -> Result<f64, String> {
-        let LeastTemp = self.Temps.iter().min().ok_or("Failed to Find iter min.")?;
+    /// 最低温度の超伝導側の傾き dV_out/dI_bias (分類できなければ先頭の LinerFitSample 点の直線)
+    fn SCSlope(&self) -> TESResult<f64> {
+        let LeastTemp = self
+            .Temps
+            .iter()
+            .min()
+            .ok_or(TESErrorS::new(TESErr::NotAnalyzed, "No IV data is loaded."))?;
+        if let Some(slope) = self.Branches.get(LeastTemp).and_then(|b| b.SCSlope) {
+            return Ok(slope);
+        }
         let I_bias = self
             .I_bias_temps
             .get(LeastTemp)
-            .ok_or(format!("I_bias at Temperature:{}mk is not found.", LeastTemp).to_string())?;
+            .ok_or_else(|| Missing("I_bias", *LeastTemp))?;
         let V_out = self
             .V_out_history_temps
             .get(LeastTemp)
-            .ok_or(format!("V_out at Temperature:{}mk is not found.", LeastTemp).to_string())?
+            .ok_or_else(|| Missing("V_out", *LeastTemp))?
             [self.CurrentIndex]
             .clone();
         if self.TESAConfig.LinerFitSample > I_bias.len() as u32
             || self.TESAConfig.LinerFitSample > V_out.len() as u32
         {
-            return Err("Too few data points for linear fit.".to_string());
+            return Err(TESErrorS::new(
+                TESErr::TooBigLinerFitSample,
+                "Too few data points for linear fit.",
+            ));
         }
         let I_bias_sample = I_bias
             .slice(s![0..self.TESAConfig.LinerFitSample as usize])
@@ -93,59 +201,96 @@ impl IVProcessorS {
         let V_out_sample = V_out
             .slice(s![0..self.TESAConfig.LinerFitSample as usize])
             .to_owned();
-        let Eta = 1f64 / LinerFit(&I_bias_sample, &V_out_sample)?;
-        return Ok(Eta);
+        LinerFit(&I_bias_sample, &V_out_sample)
     }
 
-    pub fn SaveCalibrated(&self) -> Result<(), String> {
+    /// 出力電圧から TES 電流への換算係数 (A/V)。I_bias は uA なので Eta を換算する
+    pub fn ReadoutGain(&self) -> TESResult<f64> {
+        Ok(self.GetEta()? * 1e-6)
+    }
+
+    pub fn SaveCalibrated(&self) -> TESResult<()> {
         let CalibPath = self.DP.DataPath.join("Calibration");
         if !CalibPath.exists() {
-            fs::create_dir_all(&CalibPath)
-                .map_err(|e| format!("Failed to Create {:?}\n{}", CalibPath, e))?;
+            fs::create_dir_all(&CalibPath).map_err(|e| {
+                TESErrorS::AtPath(TESErr::FolderCreateErr, &CalibPath, format!("Failed to create folder\n{}", e))
+            })?;
             // 存在しない場合ディレクトリを作成
         }
         for temp in self.Temps.iter() {
             if let Some(V_out_history) = self.V_out_history_temps.get(temp) {
                 let SavePath = CalibPath.join(format!("{}mk.dat", temp));
-                SaveTxt(SavePath.as_path(), &V_out_history[self.CurrentIndex].to_vec())?;
-                
+                let V_out = V_out_history
+                    .get(self.CurrentIndex)
+                    .ok_or_else(|| Missing("Calibrated V_out", *temp))?;
+                SaveTxt(SavePath.as_path(), &V_out.to_vec())?;
             }
         }
+        self.SaveHistory()?;
         return Ok(());
     }
 
+    fn SaveHistory(&self) -> TESResult<()> {
+        CalibrationHistoryS::Encode(&self.History, &self.V_out_history_temps, self.CurrentIndex)
+            .Save(&self.DP.DataPath.join("Calibration").join("History.json"))
+    }
+
+    /// 履歴の index 段目に移る (次に開いた時もそこから始まる)
+    pub fn JumpToStep(&mut self, index: usize) -> TESResult<()> {
+        // 全ての温度の V_out の履歴にその段が無ければ移れない
+        let steps = self
+            .V_out_history_temps
+            .values()
+            .map(|history| history.len())
+            .fold(self.History.len(), usize::min);
+        if index >= steps {
+            return Err(TESErrorS::new(
+                TESErr::ConfigErr,
+                format!("Calibration step {} is out of {} steps", index, steps),
+            ));
+        }
+        self.CurrentIndex = index;
+        self.SaveCalibrated()?;
+        self.CalculateR_TES()
+    }
+
+    /// 履歴の index 段目に名前を付ける (空なら外す)
+    pub fn LabelStep(&mut self, index: usize, Label: &str) -> TESResult<()> {
+        let len = self.History.len();
+        let step = self.History.get_mut(index).ok_or_else(|| {
+            TESErrorS::new(TESErr::ConfigErr, format!("Calibration step {} is out of {} steps", index, len))
+        })?;
+        step.Label = (!Label.trim().is_empty()).then(|| Label.trim().to_string());
+        self.SaveHistory()
+    }
+
     pub fn CalibrateSingleJump(
         &mut self,
         temp: u32,
         CalibStartI_bias: f64,
         CalibEndI_bias: f64,
-    ) -> Result<(), String> {
+    ) -> TESResult<()> {
         // 1. 選択された温度の履歴をトリミング（CurrentIndex以降を削除）
         if let Some(history) = self.V_out_history_temps.get_mut(&temp) {
             history.truncate(self.CurrentIndex + 1);
         }
+        self.History.truncate(self.CurrentIndex + 1);
 
         // 2. 現在のV_outを取得
         let mut V_out = self
             .V_out_history_temps
             .get(&temp)
-            .ok_or(format!("V_out at temperature:{} is not found", temp))?
+            .ok_or_else(|| Missing("V_out", temp))?
             [self.CurrentIndex]
             .clone();
 
         let I_bias = self
             .I_bias_temps
             .get(&temp)
-            .ok_or(format!("I_bias at temperature:{} is not found", temp))?;
+            .ok_or_else(|| Missing("I_bias", temp))?;
 
-        let CalibStartIndex = I_bias
-            .iter()
-            .position(|&x| x >= CalibStartI_bias)
-            .ok_or("CalibStartI_bias is out of range")?;
-        let CalibEndIndex = I_bias
-            .iter()
-            .position(|&x| x >= CalibEndI_bias)
-            .ok_or("CalibEndI_bias is out of range")?;
+        let CalibStartIndex = BiasIndex(I_bias, CalibStartI_bias, "CalibStartI_bias")?;
+        let CalibEndIndex = BiasIndex(I_bias, CalibEndI_bias, "CalibEndI_bias")?;
 
         let V_out_Target = V_out.slice(s![CalibStartIndex..CalibEndIndex]).to_owned();
 
@@ -204,6 +349,11 @@ impl IVProcessorS {
         }
 
         // 4. 共通のインデックスをインクリメント
+        self.History.push(CalibrationStepS::new(
+            CalibrationOp::SingleJump,
+            Some(temp),
+            Some((CalibStartI_bias, CalibEndI_bias)),
+        ));
         self.CurrentIndex += 1;
 
         self.SaveCalibrated()?;
@@ -218,22 +368,22 @@ impl IVProcessorS {
         temp: u32,
         CalibStartI_bias: f64,
         CalibEndI_bias: f64,
-    ) -> Result<(), String> {
+    ) -> TESResult<()> {
         // 選択された温度の現在のV_outを取得
         let mut V_out = self
             .V_out_history_temps
             .get(&temp)
-            .ok_or(format!("V_out at Temperature:{}mk is not found.", temp))?
+            .ok_or_else(|| Missing("V_out", temp))?
             [self.CurrentIndex]
             .clone();
         let I_bias = self
             .I_bias_temps
             .get(&temp)
-            .ok_or(format!("I_bias at Temperature:{}mk is not found.", temp))?;
+            .ok_or_else(|| Missing("I_bias", temp))?;
 
         // 校正範囲のインデックスを計算
-        let CalibStartIndex = I_bias.iter().position(|&x| x >= CalibStartI_bias).ok_or("")?;
-        let CalibEndIndex = I_bias.iter().position(|&x| x >= CalibEndI_bias).ok_or("")?;
+        let CalibStartIndex = BiasIndex(I_bias, CalibStartI_bias, "CalibStartI_bias")?;
+        let CalibEndIndex = BiasIndex(I_bias, CalibEndI_bias, "CalibEndI_bias")?;
 
         let V_out_Target = V_out.slice(s![CalibStartIndex..CalibEndIndex]).to_owned();
         let I_bias_Target = I_bias.slice(s![CalibStartIndex..CalibEndIndex]).to_owned();
@@ -289,6 +439,7 @@ impl IVProcessorS {
                 history.truncate(self.CurrentIndex + 1);
             }
         }
+        self.History.truncate(self.CurrentIndex + 1);
 
         // 選択温度の履歴に今回の校正結果を追加
         if let Some(V_out_history) = self.V_out_history_temps.get_mut(&temp) {
@@ -304,6 +455,11 @@ impl IVProcessorS {
             }
         }
 
+        self.History.push(CalibrationStepS::new(
+            CalibrationOp::MultipleJump,
+            Some(temp),
+            Some((CalibStartI_bias, CalibEndI_bias)),
+        ));
         self.CurrentIndex += 1;
 
         self.SaveCalibrated()?;
@@ -312,34 +468,345 @@ impl IVProcessorS {
         Ok(())
     }
 
+    /// 全温度の飛びを自動で補正する (補正した温度ごとに履歴を 1 つ追加する)
+    pub fn CalibrateAutoJump(&mut self) -> TESResult<Vec<FluxJumpReportS>> {
+        let config = self.TESAConfig.FluxJump.clone();
+        let mut Reports = vec![];
+        let mut temps = self.Temps.clone();
+        temps.sort();
+        for temp in temps {
+            let I_bias = self.I_bias_temps.get(&temp).ok_or_else(|| Missing("I_bias", temp))?;
+            let V_out = self
+                .V_out_history_temps
+                .get(&temp)
+                .ok_or_else(|| Missing("V_out", temp))?
+                [self.CurrentIndex]
+                .to_vec();
+            let (mut V_out, Jumps) = CorrectFluxJumps(
+                &I_bias.to_vec(),
+                &V_out,
+                config.Window as usize,
+                config.Threshold,
+                config.MinSlopeChange,
+                config.SlopeTolerance,
+            );
+            if Jumps.is_empty() {
+                Reports.push(FluxJumpReportS { Temp: temp, Jumps, HistoryIndex: None });
+                continue;
+            }
+            Offset(&mut V_out);
+
+            // 最初に補正した時だけ、今より先の履歴を捨てる (何も補正しなければ履歴はそのまま)
+            if Reports.iter().all(|r| r.HistoryIndex.is_none()) {
+                for history in self.V_out_history_temps.values_mut() {
+                    history.truncate(self.CurrentIndex + 1);
+                }
+                self.History.truncate(self.CurrentIndex + 1);
+            }
+
+            // 補正した温度に結果を、それ以外には今の値を追加する
+            for (&other_temp, history) in self.V_out_history_temps.iter_mut() {
+                let next = if other_temp == temp {
+                    Array1::from(V_out.clone())
+                } else {
+                    history[self.CurrentIndex].clone()
+                };
+                history.push(next);
+            }
+            self.History.push(CalibrationStepS::new(CalibrationOp::AutoJump, Some(temp), None));
+            self.CurrentIndex += 1;
+            Reports.push(FluxJumpReportS { Temp: temp, Jumps, HistoryIndex: Some(self.CurrentIndex) });
+        }
+
+        if Reports.iter().any(|r| r.HistoryIndex.is_some()) {
+            self.SaveCalibrated()?;
+            self.CalculateR_TES()?;
+        }
+        self.SaveFluxJumps(&Reports)?;
+        Ok(Reports)
+    }
+
+    fn SaveFluxJumps(&self, Reports: &[FluxJumpReportS]) -> TESResult<()> {
+        let mut Data = vec!["Temp,Index,Intervals,I_bias,Step,HistoryIndex".to_string()];
+        for report in Reports {
+            for jump in report.Jumps.iter() {
+                Data.push(format!(
+                    "{},{},{},{},{},{}",
+                    report.Temp,
+                    jump.Index,
+                    jump.Intervals,
+                    jump.I_bias,
+                    jump.Step,
+                    report.HistoryIndex.map_or(String::new(), |i| i.to_string())
+                ));
+            }
+        }
+        SaveTxt(&self.DP.DataPath.join("output").join("FluxJump.csv"), &Data)
+    }
+
+    /// 温度ごとに IV を分類して、寄生抵抗と R_n を求める
+    pub fn SegmentBranches(&mut self) -> TESResult<()> {
+        let config = &self.TESAConfig.Branch;
+        let mut ReferenceSC = None;
+        self.Branches.clear();
+        // 最低温度から順に分類し、その超伝導側の傾きを基準にする
+        for temp in self.Temps.iter() {
+            let I_bias = self.I_bias_temps.get(temp).ok_or_else(|| Missing("I_bias", *temp))?;
+            let V_out = self
+                .V_out_history_temps
+                .get(temp)
+                .ok_or_else(|| Missing("V_out", *temp))?
+                [self.CurrentIndex]
+                .to_vec();
+            let branch = SegmentIV(
+                &I_bias.to_vec(),
+                &V_out,
+                config.Window as usize,
+                config.SlopeTolerance,
+                ReferenceSC,
+            );
+            ReferenceSC = ReferenceSC.or(branch.SCSlope);
+            self.Branches.insert(*temp, branch);
+        }
+
+        // 超伝導側の傾き R_sh / ((R_sh + R_par) Eta) から寄生抵抗、常伝導側の傾きから R_n
+        let Eta = self.GetEta()?;
+        let R_sh = self.TESAConfig.R_sh;
+        self.R_par = match self.TESAConfig.Eta {
+            Some(_) => R_sh * (1.0 / (self.SCSlope()? * Eta) - 1.0),
+            None => 0.0,
+        };
+        for branch in self.Branches.values_mut() {
+            branch.R_n = branch.NormalSlope.map(|s| R_sh / (s * Eta) - R_sh - self.R_par);
+        }
+        // 常伝導まで振っていない温度は他の温度の中央値
+        let known: Vec<f64> = self.Branches.values().filter_map(|b| b.R_n).collect();
+        if let Some(median) = Median(&known) {
+            for branch in self.Branches.values_mut() {
+                branch.R_n.get_or_insert(median);
+            }
+        }
+        Ok(())
+    }
 
-    pub fn CalculateR_TES(&mut self) -> Result<(), String> {
+    pub fn CalculateR_TES(&mut self) -> TESResult<()> {
+        self.SegmentBranches()?;
         let Eta = self.GetEta()?;
         for temp in self.Temps.iter() {
             let I_bias = self
                 .I_bias_temps
                 .get(temp)
-                .ok_or(format!("I_bias at Temperature:{}mk is not found.", temp).to_string())?;
+                .ok_or_else(|| Missing("I_bias", *temp))?;
             let V_out = self
                 .V_out_history_temps
                 .get(temp)
-                .ok_or(format!("V_out at Temperature:{}mk is not found.", temp).to_string())?
+                .ok_or_else(|| Missing("V_out", *temp))?
                 [self.CurrentIndex]
                 .clone();
             let I_TES = V_out * Eta;
             let I_sh = I_bias - &I_TES;
-            let V_TES = I_sh * self.TESAConfig.R_sh;
+            // 寄生抵抗での電圧降下を除いた TES の電圧
+            let V_TES = I_sh * self.TESAConfig.R_sh - &I_TES * self.R_par;
             let R_TES = V_TES.slice(s![1..]).to_owned() / I_TES.slice(s![1..]).to_owned();
             self.R_tes_temps.insert(*temp, R_TES);
+            // I_bias は uA, V_TES は uV なので pW
+            self.P_tes_temps.insert(*temp, &I_TES * &V_TES);
         }
+        self.CalculatePsat()?;
+        self.SaveThermal()?;
+        self.SaveBranches()?;
         return Ok(());
     }
 
+    /// 温度ごとの P_sat を求めて K, n, Tc, G をフィットする
+    pub fn CalculatePsat(&mut self) -> TESResult<()> {
+        let Fraction = self.TESAConfig.Thermal.PsatFraction;
+        self.Psat = self
+            .Temps
+            .iter()
+            .map(|temp| {
+                let I_bias = self.I_bias_temps.get(temp).ok_or_else(|| Missing("I_bias", *temp))?;
+                let R_TES = self.R_tes_temps.get(temp).ok_or_else(|| Missing("R_TES", *temp))?;
+                let P_TES = self.P_tes_temps.get(temp).ok_or_else(|| Missing("P_TES", *temp))?;
+                // 超伝導のままの温度は横切る点が無い
+                let R_n = self
+                    .Branches
+                    .get(temp)
+                    .filter(|b| b.Points.iter().any(|p| *p != Branch::Superconducting))
+                    .and_then(|b| b.R_n);
+                // R_TES は 2 点目からなので揃える
+                let I_bias: Vec<f64> = I_bias.iter().skip(1).cloned().collect();
+                let P_TES: Vec<f64> = P_TES.iter().skip(1).cloned().collect();
+                let crossing = R_n.and_then(|R_n| {
+                    SaturationPower(&I_bias, &R_TES.to_vec(), &P_TES, R_n, Fraction)
+                });
+                Ok(PsatS {
+                    Temp: *temp,
+                    R_n,
+                    P_sat: crossing.map(|(_, P)| P),
+                    I_bias: crossing.map(|(I, _)| I),
+                })
+            })
+            .collect::<TESResult<Vec<_>>>()?;
+
+        let (Temps, P_sat): (Vec<f64>, Vec<f64>) = self
+            .Psat
+            .iter()
+            .filter_map(|p| Some((p.Temp as f64, p.P_sat?)))
+            .unzip();
+        self.Thermal = FitPsat(&Temps, &P_sat).Context(TESErr::AnalysisErr, "Failed to fit P_sat(T_bath)");
+        Ok(())
+    }
+
+    /// output/ に P_TES, P_sat, フィット結果を書き出す
+    pub fn SaveThermal(&self) -> TESResult<()> {
+        let OutputPath = self.DP.DataPath.join("output");
+        let mut PData = vec!["Temp,I_bias,R_TES,P_TES".to_string()];
+        for temp in self.Temps.iter() {
+            let I_bias = self.I_bias_temps.get(temp).ok_or_else(|| Missing("I_bias", *temp))?;
+            let R_TES = self.R_tes_temps.get(temp).ok_or_else(|| Missing("R_TES", *temp))?;
+            let P_TES = self.P_tes_temps.get(temp).ok_or_else(|| Missing("P_TES", *temp))?;
+            for i in 0..I_bias.len() {
+                let R = if i == 0 { String::new() } else { R_TES[i - 1].to_string() };
+                PData.push(format!("{},{},{},{}", temp, I_bias[i], R, P_TES[i]));
+            }
+        }
+        SaveTxt(&OutputPath.join("P_TES.csv"), &PData)?;
+
+        let mut PsatData = vec!["Temp,R_n,I_bias,P_sat".to_string()];
+        let Blank = |v: Option<f64>| v.map_or(String::new(), |v| v.to_string());
+        for p in self.Psat.iter() {
+            PsatData.push(format!("{},{},{},{}", p.Temp, Blank(p.R_n), Blank(p.I_bias), Blank(p.P_sat)));
+        }
+        SaveTxt(&OutputPath.join("Psat.csv"), &PsatData)?;
+
+        // フィットできなかった時は古い結果を残さない
+        let FitPath = OutputPath.join("ThermalFit.csv");
+        match &self.Thermal {
+            Ok(f) => SaveTxt(
+                &FitPath,
+                &[
+                    "PsatFraction,K,K_err,n,n_err,Tc,Tc_err,G,G_err,ReducedChi2".to_string(),
+                    format!(
+                        "{},{},{},{},{},{},{},{},{},{}",
+                        self.TESAConfig.Thermal.PsatFraction,
+                        f.K, f.K_err, f.n, f.n_err, f.Tc, f.Tc_err, f.G, f.G_err, f.ReducedChi2
+                    ),
+                ],
+            ),
+            Err(_) if FitPath.exists() => fs::remove_file(&FitPath).map_err(|e| {
+                TESErrorS::AtPath(TESErr::FileRemoveErr, &FitPath, format!("Failed to remove file\n{}", e))
+            }),
+            Err(_) => Ok(()),
+        }
+    }
+
+    /// 各点の分類と、温度ごとの傾き・R_n を書き出す
+    pub fn SaveBranches(&self) -> TESResult<()> {
+        let OutputPath = self.DP.DataPath.join("output");
+        let Eta = self.GetEta()?;
+        let Blank = |v: Option<f64>| v.map_or(String::new(), |v| v.to_string());
+        let mut PointData = vec!["Temp,I_bias,Branch".to_string()];
+        let mut SummaryData = vec!["Temp,SCSlope,NormalSlope,R_n,R_par,Eta".to_string()];
+        for temp in self.Temps.iter() {
+            let I_bias = self.I_bias_temps.get(temp).ok_or_else(|| Missing("I_bias", *temp))?;
+            let branch = self.Branches.get(temp).ok_or_else(|| Missing("Branch", *temp))?;
+            for (I, b) in I_bias.iter().zip(branch.Points.iter()) {
+                PointData.push(format!("{},{},{:?}", temp, I, b));
+            }
+            SummaryData.push(format!(
+                "{},{},{},{},{},{}",
+                temp,
+                Blank(branch.SCSlope),
+                Blank(branch.NormalSlope),
+                Blank(branch.R_n),
+                self.R_par,
+                Eta
+            ));
+        }
+        SaveTxt(&OutputPath.join("Branch.csv"), &PointData)?;
+        SaveTxt(&OutputPath.join("IVBranch.csv"), &SummaryData)?;
+        Ok(())
+    }
+
+    /// 熱伝導度と転移温度は設定があればそれを、無ければ P_sat のフィットの値を使う
+    pub fn ThermalLink(&self) -> TESResult<ThermalLinkS> {
+        let config = &self.TESAConfig.Electrothermal;
+        let fit = self.Thermal.as_ref().ok();
+        let NoFit = |what: &str| {
+            TESErrorS::new(
+                TESErr::NotAnalyzed,
+                format!("{} is unknown. Fit P_sat with at least 4 temperatures or set it in the config.", what),
+            )
+        };
+        Ok(ThermalLinkS {
+            G: config.G.or(fit.map(|f| f.G)).ok_or_else(|| NoFit("Thermal conductance G"))?,
+            Tc: config.Tc.or(fit.map(|f| f.Tc)).ok_or_else(|| NoFit("Tc"))?,
+            C: config.C,
+            R_L: self.TESAConfig.R_sh + self.R_par,
+        })
+    }
+
+    /// RT の α から温度ごとに各バイアス点のループゲイン・時定数・電流応答度を求めて書き出す
+    pub fn CalculateElectrothermal(&mut self, Alpha: &AlphaCurveS) -> TESResult<ThermalLinkS> {
+        let Link = self.ThermalLink()?;
+        let Eta = self.GetEta()?;
+        self.Electrothermal.clear();
+        for temp in self.Temps.iter() {
+            let I_bias = self.I_bias_temps.get(temp).ok_or_else(|| Missing("I_bias", *temp))?;
+            let V_out = &self
+                .V_out_history_temps
+                .get(temp)
+                .ok_or_else(|| Missing("V_out", *temp))?
+                [self.CurrentIndex];
+            let R_TES = self.R_tes_temps.get(temp).ok_or_else(|| Missing("R_TES", *temp))?;
+            let Some(R_n) = self.Branches.get(temp).and_then(|b| b.R_n) else {
+                continue;
+            };
+            // R_TES は 2 点目からなので揃える
+            let points = (1..I_bias.len())
+                .map(|i| BiasPoint(I_bias[i], V_out[i] * Eta, R_TES[i - 1], R_n, Alpha, &Link))
+                .collect();
+            self.Electrothermal.insert(*temp, points);
+        }
+        self.SaveElectrothermal(Alpha, &Link)?;
+        Ok(Link)
+    }
+
+    fn SaveElectrothermal(&self, Alpha: &AlphaCurveS, Link: &ThermalLinkS) -> TESResult<()> {
+        let OutputPath = self.DP.DataPath.join("output");
+        let mut Data = vec![BiasPointS::CSV_HEADER.to_string()];
+        for temp in self.Temps.iter() {
+            for p in self.Electrothermal.get(temp).into_iter().flatten() {
+                Data.push(p.ToCSVRow(*temp));
+            }
+        }
+        SaveTxt(&OutputPath.join("Electrothermal.csv"), &Data)?;
+        // 計算に使った熱伝導度などは別に残す
+        SaveTxt(
+            &OutputPath.join("ElectrothermalLink.csv"),
+            &[
+                "AlphaCurrent,G,Tc,C,R_L".to_string(),
+                format!(
+                    "{},{},{},{},{}",
+                    Alpha.Current,
+                    Link.G,
+                    Link.Tc,
+                    Link.C.map_or(String::new(), |v| v.to_string()),
+                    Link.R_L
+                ),
+            ],
+        )
+    }
+
     #[cfg(debug_assertions)]
-    pub fn SaveFig(&self, FileName: &String) -> Result<String, String> {
+    pub fn SaveFig(&self, FileName: &String) -> TESResult<String> {
         let FilePath = self.DP.DataPath.join(FileName);
+        let DrawErr = |e: &dyn std::fmt::Display| {
+            TESErrorS::AtPath(TESErr::FileWriteErr, &FilePath, format!("Failed to draw IV curve\n{}", e))
+        };
         let root = BitMapBackend::new(&FilePath, (800, 600)).into_drawing_area();
-        root.fill(&WHITE).map_err(|e| e.to_string())?;
+        root.fill(&WHITE).map_err(|e| DrawErr(&e))?;
 
         // 全温度のデータを集めて範囲を自動設定
         let mut all_i_vals = Vec::new();
@@ -358,7 +825,7 @@ impl IVProcessorS {
         }
 
         if all_i_vals.is_empty() || all_v_vals.is_empty() {
-            return Err("プロット可能なデータが見つかりませんでした。".into());
+            return Err(TESErrorS::new(TESErr::NotAnalyzed, "プロット可能なデータが見つかりませんでした。"));
         }
 
         let get_range = |data: &Vec<f64>| {
@@ -379,14 +846,14 @@ impl IVProcessorS {
             .margin(20)
             .set_all_label_area_size(40)
             .build_cartesian_2d(i_min..i_max, v_min..v_max)
-            .map_err(|e| e.to_string())?;
+            .map_err(|e| DrawErr(&e))?;
 
         chart
             .configure_mesh()
             .x_desc("Bias Current [A]")
             .y_desc("Output Voltage [V]")
             .draw()
-            .map_err(|e| e.to_string())?;
+            .map_err(|e| DrawErr(&e))?;
 
         for temp in &self.Temps {
             if let (Some(i), Some(v_vec)) = (
@@ -397,7 +864,7 @@ impl IVProcessorS {
                     let points = i.iter().zip(v.iter()).map(|(&x, &y)| (x, y));
                     chart
                         .draw_series(LineSeries::new(points, &Palette99::pick(*temp as usize)))
-                        .map_err(|e| e.to_string())?
+                        .map_err(|e| DrawErr(&e))?
                         .label(format!("{} mK", temp))
                         .legend(move |(x, y)| {
                             PathElement::new(
@@ -414,141 +881,61 @@ impl IVProcessorS {
             .background_style(&WHITE.mix(0.8))
             .border_style(&BLACK)
             .draw()
-            .map_err(|e| e.to_string())?;
+            .map_err(|e| DrawErr(&e))?;
 
         Ok("IV.png に保存されました".to_string())
     }
 
-    pub(crate) fn AnalyzeIVFolder(&mut self) -> Result<(), String> {
-        self.Temps = glob(&format!("{}/*mK", self.DP.DataPath.display()))
-            .map_err(|e| {
-                format!(
-                    "Failed to glob Temperature folders at {:?}\n{}",
-                    self.DP.DataPath, e
-                )
-            })?
-            .filter_map(Result::ok) // 結果を取り出す
-            .filter(|path| path.is_dir()) // ディレクトリのみをフィルタ
-            .filter_map(|path| {
-                path.file_name()
-                    .and_then(|name| name.to_str()) // OsStr を &str に変換
-                    .and_then(|name| {
-                        // "mK" を削除して数値部分をパース
-                        name.trim_end_matches("mK").parse::<u32>().ok() // 数値としてパースできる場合のみ抽出
-                    })
-            })
+    /// Calibration/History.json があれば履歴を戻す (戻せたら true)
+    ///
+    /// 生データと合わない History.json は History.json.bak に退避して HistoryWarning に残す
+    fn RestoreHistory(&mut self, CalibPath: &Path) -> TESResult<bool> {
+        let HistoryPath = CalibPath.join("History.json");
+        let Some(history) = CalibrationHistoryS::Load(&HistoryPath) else {
+            return Ok(false);
+        };
+        let Raw = self
+            .V_out_history_temps
+            .iter()
+            .map(|(temp, history)| (*temp, history[0].clone()))
             .collect();
-        self.Temps.sort();
-
-        if self.Temps.len() == 0 {
-            return Err(format!(
-                "No temperature folders found in [{}].",
-                self.DP.DataPath.display()
-            )
-                .to_string());
-        }
-
-        if cfg!(debug_assertions) {
-            println!("Temps: {:?}", self.Temps);
-        }
-
-        let IVPattern = Regex::new(r"(\d+)uA\.dat$").map_err(|e| format!("Regex Error\n{}", e))?;
-
-        for temp in self.Temps.iter() {
-            let IVFiles = glob(&format!(
-                "{}/*.dat",
-                self.DP.DataPath.join(format!("{}mk", temp)).display()
-            ))
-                .map_err(|e| format!("Failed to glob IV files at {:?}\n{}", self.DP.DataPath, e))?
-                .filter_map(Result::ok)
-                .collect::<Vec<PathBuf>>();
-            let mut V_out: Vec<f64> = Vec::new();
-            let mut I_bias: Vec<f64> = Vec::new();
-            for IVFile in IVFiles {
-                let V_out_data = LoadTxt(IVFile.as_path())?;
-                V_out.push(
-                    V_out_data
-                        .mean()
-                        .ok_or("Failed to calculate mean of ndarray".to_string())?,
-                );
-                let IVFile_str = IVFile.to_string_lossy();
-                // `\d+` で数字部分をキャプチャ
-                let I = IVPattern
-                    .captures(&IVFile_str)
-                    .and_then(|caps| caps.get(1)) // 1つ目のキャプチャグループを取得
-                    .and_then(|m| m.as_str().parse::<u32>().ok())
-                    .ok_or("Failed to parse I_bias".to_string())?; // `u32` に変換
-                I_bias.push(I as f64);
+        match history.Decode(&Raw) {
+            Ok((Steps, V_out_history, CurrentIndex)) => {
+                self.History = Steps;
+                self.V_out_history_temps = V_out_history;
+                self.CurrentIndex = CurrentIndex;
+                Ok(true)
             }
-            // I_bias と V_out をペアにする
-            let mut paired: Vec<(f64, f64)> =
-                I_bias.iter().cloned().zip(V_out.iter().cloned()).collect();
-
-            // I_bias に基づいてソート
-            paired.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap()); // I_bias（a.0）でソート
-
-            // ソート後に I_bias と V_out を再度分ける
-            I_bias = paired.iter().map(|(i, _)| *i).collect();
-            V_out = paired.iter().map(|(_, v)| *v).collect();
-            if cfg!(debug_assertions) {
-                //println!("V_out: {:?}", V_out);
-                //println!("I_bias: {:?}", I_bias);
-                //println!();
+            Err(e) => {
+                let BakPath = HistoryPath.with_extension("json.bak");
+                fs::rename(&HistoryPath, &BakPath).map_err(|e| {
+                    TESErrorS::AtPath(
+                        TESErr::FileWriteErr,
+                        &HistoryPath,
+                        format!("Failed to move the calibration history aside\n{}", e),
+                    )
+                })?;
+                self.HistoryWarning = Some(
+                    TESErrorS::AtPath(
+                        TESErr::CalibrationHistoryErr,
+                        &BakPath,
+                        format!("The calibration history does not match the IV data\n{}", e),
+                    )
+                    .WithSeverity(Severity::Warning),
+                );
+                Ok(false)
             }
-            Offset(&mut V_out);
-            self.V_out_history_temps
-                .insert(*temp, vec![Array1::from(V_out)]);
-            self.I_bias_temps.insert(*temp, Array1::from(I_bias));
         }
-
-        let CalibPath = self.DP.DataPath.join("Calibration");
-        if !CalibPath.exists() {
-            fs::create_dir_all(&CalibPath)
-                .map_err(|_| format!("Failed to crate {}.", CalibPath.display()))?;
-        // 存在しない場合ディレクトリを作成
-        } else {
-            let mut Calibrated=false;
-            for temp in self.Temps.iter() {
-                let calib_file = CalibPath.join(format!("{}mk.dat", temp));
-                if calib_file.exists() {
-                    let V_out = LoadTxt(calib_file.as_path())?;
-                    if let Some(V_out_history) = self.V_out_history_temps.get_mut(temp) {
-                        if V_out.len()
-                            == self
-                            .I_bias_temps
-                            .get(temp)
-                            .ok_or(format!("{}", temp).to_string())?
-                            .len()
-                        {
-                            V_out_history.push(V_out);
-                            Calibrated=true;
-                        }
-                    }
-                } else {
-                    // キャリブレーションファイルがない場合、現在のVを履歴に追加
-                    if let Some(V_out_history) = self.V_out_history_temps.get_mut(temp) {
-                        let V_out_init=V_out_history[0].clone();
-                        V_out_history.push(V_out_init);
-                    }
-                }
-            }
-            if Calibrated {
-                self.CurrentIndex=1;
-            }
-
-        }
-        self.CalculateR_TES()?;
-        Ok(())
     }
-}
 
-impl DataProcessorT for IVProcessorS {
-    fn AnalyzeFolder(&mut self) -> Result<(), String> {
-        self.Temps = glob(&format!("{}/*mK", self.DP.DataPath.display()))
+    /// cancel が立ったら温度の間で止める (途中までの結果は反映しない)
+    pub fn AnalyzeIVFolder(&mut self, cancel: &AtomicBool) -> TESResult<()> {
+        let mut Temps: Vec<u32> = glob(&format!("{}/*mK", self.DP.DataPath.display()))
             .map_err(|e| {
-                format!(
-                    "Failed to glob Temperature folders at {:?}\n{}",
-                    self.DP.DataPath, e
+                TESErrorS::AtPath(
+                    TESErr::IVGlobErr,
+                    &self.DP.DataPath,
+                    format!("Failed to glob Temperature folders\n{}", e),
                 )
             })?
             .filter_map(Result::ok) // 結果を取り出す
@@ -562,30 +949,39 @@ impl DataProcessorT for IVProcessorS {
                     })
             })
             .collect();
-        self.Temps.sort();
-
-        if self.Temps.len() == 0 {
-            return Err(format!(
-                "No temperature folders found in [{}].",
-                self.DP.DataPath.display()
-            )
-            .to_string());
-        }
-
-        if cfg!(debug_assertions) {
-            println!("Temps: {:?}", self.Temps);
+        Temps.sort();
+
+        if Temps.len() == 0 {
+            return Err(TESErrorS::AtPath(
+                TESErr::FolderNotAdequate,
+                &self.DP.DataPath,
+                "No temperature folders found.",
+            ));
         }
 
         let IVPattern = Regex::new(r"(\d+)uA\.dat$").map_err(|e| format!("Regex Error\n{}", e))?;
 
-        for temp in self.Temps.iter() {
+        let mut Loaded: Vec<(u32, Array1<f64>, Array1<f64>)> = Vec::new();
+        for temp in Temps.iter() {
+            if cancel.load(Ordering::SeqCst) {
+                return Err(TESErrorS::new(TESErr::Cancelled, "IV analysis was cancelled"));
+            }
             let IVFiles = glob(&format!(
                 "{}/*.dat",
-                self.DP.DataPath.join(format!("{}mk", temp)).display()
+                self.DP.DataPath.join(format!("{}mK", temp)).display()
             ))
-            .map_err(|e| format!("Failed to glob IV files at {:?}\n{}", self.DP.DataPath, e))?
-            .filter_map(Result::ok)
-            .collect::<Vec<PathBuf>>();
+                .map_err(|e| {
+                    TESErrorS::AtPath(TESErr::IVGlobErr, &self.DP.DataPath, format!("Failed to glob IV files\n{}", e))
+                })?
+                .filter_map(Result::ok)
+                .collect::<Vec<PathBuf>>();
+            if IVFiles.is_empty() {
+                return Err(TESErrorS::AtPath(
+                    TESErr::FolderNotAdequate,
+                    &self.DP.DataPath.join(format!("{}mK", temp)),
+                    "No IV files in the temperature folder",
+                ));
+            }
             let mut V_out: Vec<f64> = Vec::new();
             let mut I_bias: Vec<f64> = Vec::new();
             for IVFile in IVFiles {
@@ -593,7 +989,7 @@ impl DataProcessorT for IVProcessorS {
                 V_out.push(
                     V_out_data
                         .mean()
-                        .ok_or("Failed to calculate mean of ndarray".to_string())?,
+                        .ok_or_else(|| TESErrorS::AtPath(TESErr::IVParseErr, &IVFile, "No values in IV file"))?,
                 );
                 let IVFile_str = IVFile.to_string_lossy();
                 // `\d+` で数字部分をキャプチャ
@@ -601,7 +997,7 @@ impl DataProcessorT for IVProcessorS {
                     .captures(&IVFile_str)
                     .and_then(|caps| caps.get(1)) // 1つ目のキャプチャグループを取得
                     .and_then(|m| m.as_str().parse::<u32>().ok())
-                    .ok_or("Failed to parse I_bias".to_string())?; // `u32` に変換
+                    .ok_or_else(|| TESErrorS::AtPath(TESErr::IVParseErr, &IVFile, "Failed to parse I_bias"))?; // `u32` に変換
                 I_bias.push(I as f64);
             }
             // I_bias と V_out をペアにする
@@ -614,54 +1010,56 @@ impl DataProcessorT for IVProcessorS {
             // ソート後に I_bias と V_out を再度分ける
             I_bias = paired.iter().map(|(i, _)| *i).collect();
             V_out = paired.iter().map(|(_, v)| *v).collect();
-            if cfg!(debug_assertions) {
-                //println!("V_out: {:?}", V_out);
-                //println!("I_bias: {:?}", I_bias);
-                //println!();
-            }
             Offset(&mut V_out);
-            self.V_out_history_temps
-                .insert(*temp, vec![Array1::from(V_out)]);
-            self.I_bias_temps.insert(*temp, Array1::from(I_bias));
+            Loaded.push((*temp, Array1::from(I_bias), Array1::from(V_out)));
+        }
+        self.Temps = Temps;
+        for (temp, I_bias, V_out) in Loaded {
+            self.V_out_history_temps.insert(temp, vec![V_out]);
+            self.I_bias_temps.insert(temp, I_bias);
         }
 
+        self.CurrentIndex = 0;
+        self.History = vec![CalibrationStepS::new(CalibrationOp::Raw, None, None)];
+        self.HistoryWarning = None;
         let CalibPath = self.DP.DataPath.join("Calibration");
         if !CalibPath.exists() {
             fs::create_dir_all(&CalibPath)
-                .map_err(|_| format!("Failed to crate {}.", CalibPath.display()))?;
+                .map_err(|e| {
+                    TESErrorS::AtPath(TESErr::FolderCreateErr, &CalibPath, format!("Failed to create folder\n{}", e))
+                })?;
         // 存在しない場合ディレクトリを作成
-        } else {
-            let mut Calibrated=false;
+        } else if !self.RestoreHistory(&CalibPath)? {
+            // 履歴が無ければ前回の校正結果だけを読む (点数が合わない温度は使わない)
+            let mut Calibrated = HashMap::new();
             for temp in self.Temps.iter() {
                 let calib_file = CalibPath.join(format!("{}mk.dat", temp));
-                if calib_file.exists() {
-                    let V_out = LoadTxt(calib_file.as_path())?;
-                    if let Some(V_out_history) = self.V_out_history_temps.get_mut(temp) {
-                        if V_out.len()
-                            == self
-                            .I_bias_temps
-                            .get(temp)
-                            .ok_or(format!("{}", temp).to_string())?
-                            .len()
-                        {
-                            V_out_history.push(V_out);
-                            Calibrated=true;
-                        }
-                    }
-                } else {
-                    // キャリブレーションファイルがない場合、現在のVを履歴に追加
-                    if let Some(V_out_history) = self.V_out_history_temps.get_mut(temp) {
-                        let V_out_init=V_out_history[0].clone();
-                        V_out_history.push(V_out_init);
-                    }
+                if !calib_file.exists() {
+                    continue;
+                }
+                let V_out = LoadTxt(calib_file.as_path())?;
+                let len = self.I_bias_temps.get(temp).ok_or_else(|| Missing("I_bias", *temp))?.len();
+                if V_out.len() == len {
+                    Calibrated.insert(*temp, V_out);
                 }
             }
-            if Calibrated {
-                self.CurrentIndex=1;
+            // 読めた温度が 1 つでもあれば、全ての温度に 1 段足す (読めなかった温度は生データのまま)
+            if !Calibrated.is_empty() {
+                for (temp, V_out_history) in self.V_out_history_temps.iter_mut() {
+                    let next = Calibrated.remove(temp).unwrap_or_else(|| V_out_history[0].clone());
+                    V_out_history.push(next);
+                }
+                self.History.push(CalibrationStepS::new(CalibrationOp::Loaded, None, None));
+                self.CurrentIndex = 1;
             }
-
         }
         self.CalculateR_TES()?;
         Ok(())
     }
 }
+
+impl DataProcessorT for IVProcessorS {
+    fn AnalyzeFolder(&mut self) -> TESResult<()> {
+        self.AnalyzeIVFolder(&AtomicBool::new(false))
+    }
+}
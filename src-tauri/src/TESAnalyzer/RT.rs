@@ -1,20 +1,152 @@
 #![allow(non_upper_case_globals)]
 
-use crate::Config::TESAnalysisConfig;
+use crate::Config::{BackendConfig, TESAnalysisConfig};
 use crate::DataProcessor::{DataProcessorS};
 use crate::DataProcessor::{LoadTxt, SaveTxt};
-use crate::PyMod::RTFit;
+use crate::PyMod::CreateBackend;
+use crate::TESAnalyzer::Electrothermal::AlphaCurveS;
+use crate::TESAnalyzer::Fit::LevenbergMarquardt;
 use crate::TESAnalyzer::LinerFit;
+use crate::TES_Err::{ContextT, TESErr, TESErrorS, TESResult};
 use glob::glob;
 use ndarray::Array1;
 use regex::Regex;
-use serde_json::to_string_pretty;
+use serde::Serialize;
 use std::collections::{HashMap, HashSet};
-use std::f64::consts::PI;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 const sup_R: f64 = 90.0;
 const inf_R: f64 = 10.0;
 
+/// ダブルフェルミ関数 RN/((1+exp((Tc-T)/T1))(1+exp((Tc-T)/T2)))
+fn RTModel(T: f64, p: &[f64]) -> f64 {
+    p[0] / ((1.0 + ((p[1] - T) / p[2]).exp()) * (1.0 + ((p[1] - T) / p[3]).exp()))
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RTFitS {
+    pub RN: f64,
+    pub Tc: f64,
+    pub T1: f64,
+    pub T2: f64,
+    pub RN_err: f64,
+    pub Tc_err: f64,
+    pub T1_err: f64,
+    pub T2_err: f64,
+    pub ReducedChi2: f64,
+}
+
+/// フィットした RT から転移の 10%〜90% の間の α と R/RN (%) を求める (BiasPoint, Alpha)
+fn AlphaFromFit(Fit: &RTFitS, T: &[f64]) -> (Vec<f64>, Vec<f64>) {
+    let (RN, T_c, T_1, T_2) = (Fit.RN, Fit.Tc, Fit.T1, Fit.T2);
+    // T はファイルの並び (100mK が 95mK より先など) なので最小・最大を取る
+    let T_min = T.iter().cloned().fold(f64::INFINITY, f64::min);
+    let T_max = T.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+    let T_fit: Array1<f64> = Array1::linspace(T_min - 2.0, T_max + 2.0, 1000);
+    let R_fit = RN
+        / ((1.0 + ((-&T_fit + T_c) / T_1).exp()) * (1.0 + ((-&T_fit + T_c) / T_2).exp()));
+
+    let RN_sup = RN * sup_R * 0.01;
+    let RN_inf = RN * inf_R * 0.01;
+
+    let mut T_inf: f64 = 0.0;
+    let mut T_sup: f64 = 0.0;
+
+    for i in 0..T_fit.len() {
+        if R_fit[i] > RN_sup {
+            T_sup = T_fit[i];
+            break;
+        }
+    }
+
+    for i in 0..T_fit.len() {
+        if R_fit[i] > RN_inf {
+            T_inf = T_fit[i];
+            break;
+        }
+    }
+
+    let T_Alpha = Array1::linspace(T_inf, T_sup, 1000);
+
+    let mut Alpha: Vec<f64> = Vec::new();
+    let mut RAlpha: Vec<f64> = Vec::new();
+    let mut TAlpha: Vec<f64> = Vec::new();
+    let mut diff_R: Vec<f64> = Vec::new();
+    let mut BiasPoint: Vec<f64> = Vec::new();
+
+    for i in 0..T_Alpha.len() - 1 {
+        TAlpha.push(T_Alpha[i]);
+        RAlpha.push(
+            RN / ((1.0 + ((-T_Alpha[i] + T_c) / T_1).exp())
+                * (1.0 + ((-T_Alpha[i] + T_c) / T_2).exp())),
+        );
+        diff_R.push(
+            (RN / ((1.0 + ((-T_Alpha[i + 1] + T_c) / T_1).exp())
+                * (1.0 + ((-T_Alpha[i + 1] + T_c) / T_2).exp()))
+                - RAlpha[i])
+                / (T_Alpha[i + 1] - T_Alpha[i]),
+        );
+        Alpha.push((TAlpha[i] * diff_R[i]) / RAlpha[i]);
+        BiasPoint.push(100. * RAlpha[i] / RN);
+    }
+    (BiasPoint, Alpha)
+}
+
+/// RT カーブから初期値を推定してフィットする
+///
+/// 初期値の転移温度は T の昇順に R を見て探すので、先に T で並べ替える
+pub fn FitRTCurve(R: &[f64], T: &[f64]) -> Result<RTFitS, String> {
+    if R.len() != T.len() || R.len() < 5 {
+        return Err("Too few RT points to fit".to_string());
+    }
+    let mut points: Vec<(f64, f64)> = T.iter().cloned().zip(R.iter().cloned()).collect();
+    points.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let (T, R): (Vec<f64>, Vec<f64>) = points.into_iter().unzip();
+    let (T, R) = (T.as_slice(), R.as_slice());
+    let RN0 = R.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+    let cross = |ratio: f64| {
+        T.iter()
+            .zip(R)
+            .find(|(_, &r)| r >= RN0 * ratio)
+            .map(|(&t, _)| t)
+            .unwrap_or(T[T.len() / 2])
+    };
+    let Tc0 = cross(0.5);
+    let width = (cross(0.9) - cross(0.1))
+        .abs()
+        .max((T[T.len() - 1] - T[0]).abs() / (10.0 * T.len() as f64));
+
+    let result = LevenbergMarquardt(RTModel, T, R, None, &[RN0, Tc0, width / 4.0, width / 2.0])
+        .map_err(|e| format!("Failed to fit RT\n{}", e))?;
+
+    let (p, e) = (&result.Params, &result.Errors);
+    // T1 と T2 は対称なので T1 <= T2 となるように並べる
+    let (T1, T1_err, T2, T2_err) = if p[2].abs() <= p[3].abs() {
+        (p[2].abs(), e[2], p[3].abs(), e[3])
+    } else {
+        (p[3].abs(), e[3], p[2].abs(), e[2])
+    };
+    Ok(RTFitS {
+        RN: p[0],
+        Tc: p[1],
+        T1,
+        T2,
+        RN_err: e[0],
+        Tc_err: e[1],
+        T1_err,
+        T2_err,
+        ReducedChi2: result.ReducedChi2,
+    })
+}
+
+/// 電流ごとのデータが無い時のエラー
+fn Missing(what: &str, current: u32) -> TESErrorS {
+    TESErrorS::new(
+        TESErr::NotAnalyzed,
+        format!("Failed to get {} at {}microA", what, current),
+    )
+}
+
 pub struct RTProcessorS {
     DP: DataProcessorS,
     TESAConfig: TESAnalysisConfig,
@@ -24,6 +156,7 @@ pub struct RTProcessorS {
     eta: f64,
     pub BiasPoints_Current:HashMap<u32, Vec<f64>>,
     pub Alpha_Current:HashMap<u32, Vec<f64>>,
+    pub Fit_Current: HashMap<u32, RTFitS>,
 }
 
 impl RTProcessorS {
@@ -37,23 +170,57 @@ impl RTProcessorS {
             eta: 104.0,
             BiasPoints_Current:HashMap::new(),
             Alpha_Current: HashMap::new(),
+            Fit_Current: HashMap::new(),
+        }
+    }
+
+    /// RTConfig.json があれば設定も読む
+    pub fn SetDataPath(&mut self, path: &Path) -> TESResult<()> {
+        self.DP.SetDataPath(path)?;
+        if let Some(config) = TESAnalysisConfig::Load(&self.ConfigPath())? {
+            self.TESAConfig = config;
         }
+        Ok(())
     }
 
-    pub fn SetDataPath(&mut self, path: &Path) {
-        self.DP.SetDataPath(path);
+    fn ConfigPath(&self) -> PathBuf {
+        self.DP.DataPath.join("RTConfig.json")
     }
 
-    pub fn SaveRT(&self) -> Result<(), String> {
+    /// データのフォルダが決まっていれば RTConfig.json にも残す
+    pub fn SetBackend(&mut self, backend: BackendConfig) -> TESResult<()> {
+        self.TESAConfig.Backend = backend;
+        if self.DP.DataPath.as_os_str().is_empty() {
+            return Ok(());
+        }
+        self.TESAConfig.Save(&self.ConfigPath())
+    }
+
+    pub fn Config(&self) -> &TESAnalysisConfig {
+        &self.TESAConfig
+    }
+
+    pub fn SetConfig(&mut self, config: TESAnalysisConfig) {
+        self.TESAConfig = config;
+    }
+
+    /// 設定を RTConfig.json に書き出す (結果には AnalyzeRTFolder をやり直すと反映される)
+    pub fn SaveConfig(&mut self, config: TESAnalysisConfig) -> TESResult<()> {
+        config.Save(&self.ConfigPath())?;
+        self.TESAConfig = config;
+        Ok(())
+    }
+
+    pub fn SaveRT(&self) -> TESResult<()> {
         for ch in self.Currents.iter() {
             let R = self
                 .R_tes_Current
                 .get(ch)
-                .ok_or(format!("Failed to get R at {}microA", ch))?;
+                .ok_or_else(|| Missing("R", *ch))?;
             let T = self
                 .Temp_Current
                 .get(ch)
-                .ok_or(format!("Failed to get T at {}microA", ch))?;
+                .ok_or_else(|| Missing("T", *ch))?;
             let mut RTData = Vec::new();
             RTData.push("T,R".to_string());
             for i in 0..R.len() {
@@ -62,11 +229,25 @@ impl RTProcessorS {
             let RTFileName = format!("{}/output/RT_{}uA.csv", self.DP.DataPath.display(), ch);
             SaveTxt(Path::new(&RTFileName), &RTData)?;
         }
+
+        let mut currents: Vec<&u32> = self.Fit_Current.keys().collect();
+        currents.sort();
+        let mut FitData = Vec::new();
+        FitData.push("Current,RN,RN_err,Tc,Tc_err,T1,T1_err,T2,T2_err,ReducedChi2".to_string());
+        for crt in currents {
+            let f = &self.Fit_Current[crt];
+            FitData.push(format!(
+                "{},{},{},{},{},{},{},{},{},{}",
+                crt, f.RN, f.RN_err, f.Tc, f.Tc_err, f.T1, f.T1_err, f.T2, f.T2_err, f.ReducedChi2
+            ));
+        }
+        let FitFileName = format!("{}/output/RTFit.csv", self.DP.DataPath.display());
+        SaveTxt(Path::new(&FitFileName), &FitData)?;
         return Ok(());
     }
 
-    pub fn FitRT(&mut self) -> Result<(), String> {
-        let rt = tokio::runtime::Runtime::new().unwrap();
+    pub fn FitRT(&mut self) -> TESResult<()> {
+        let backend = CreateBackend(&self.TESAConfig.Backend);
         for crt in self.Currents.iter() {
             if *crt == 0 {
                 continue;
@@ -74,104 +255,83 @@ impl RTProcessorS {
             let R = self
                 .R_tes_Current
                 .get(crt)
-                .ok_or(format!("Failed to get R at {}microA", crt))?;
+                .ok_or_else(|| Missing("R", *crt))?;
             let T = self
                 .Temp_Current
                 .get(crt)
-                .ok_or(format!("Failed to get R at {}microA", crt))?;
-
-            let FitParams = rt.block_on(RTFit(R, T))?;
+                .ok_or_else(|| Missing("T", *crt))?;
 
-            let R_n_s = R[0];
-            let R_n_f = R[R.len() - 1];
-            let T_c_s = T[0];
-            let T_c_f = T[R.len() - 1];
-
-            let RN = ((R_n_f - R_n_s) / PI) * FitParams[0].atan() + (R_n_s + R_n_f) / 2.0;
-            let T_c = ((T_c_f - T_c_s) / PI) * FitParams[1].atan() + (T_c_s + T_c_f) / 2.0;
-            let T_1 = (T_c_f / PI) * FitParams[2].atan() + T_c_f / 2.0;
-            let T_2 = (T_c_f / PI) * FitParams[3].atan() + T_c_f / 2.0;
-
-            let T_fit: Array1<f64> = Array1::linspace(T[0] - 2.0, T[T.len() - 1] + 2.0, 1000);
-            let R_fit = RN
-                / ((1.0 + ((-&T_fit + T_c) / T_1).exp()) * (1.0 + ((-&T_fit + T_c) / T_2).exp()));
-
-            let RN_sup = RN * sup_R * 0.01;
-            let RN_inf = RN * inf_R * 0.01;
-
-            let mut T_inf: f64 = 0.0;
-            let mut T_sup: f64 = 0.0;
-
-            for i in 0..T_fit.len() {
-                if R_fit[i] > RN_sup {
-                    T_sup = T_fit[i];
-                    break;
-                }
-            }
-
-            for i in 0..T_fit.len() {
-                if R_fit[i] > RN_inf {
-                    T_inf = T_fit[i];
-                    break;
-                }
-            }
-
-            let T_Alpha = Array1::linspace(T_inf, T_sup, 1000);
-
-            let mut Alpha: Vec<f64> = Vec::new();
-            let mut RAlpha: Vec<f64> = Vec::new();
-            let mut TAlpha: Vec<f64> = Vec::new();
-            let mut diff_R: Vec<f64> = Vec::new();
-            let mut BiasPoint: Vec<f64> = Vec::new();
-
-            for i in 0..T_Alpha.len() - 1 {
-                TAlpha.push(T_Alpha[i]);
-                RAlpha.push(
-                    RN / ((1.0 + ((-T_Alpha[i] + T_c) / T_1).exp())
-                        * (1.0 + ((-T_Alpha[i] + T_c) / T_2).exp())),
-                );
-                diff_R.push(
-                    (RN / ((1.0 + ((-T_Alpha[i + 1] + T_c) / T_1).exp())
-                        * (1.0 + ((-T_Alpha[i + 1] + T_c) / T_2).exp()))
-                        - RAlpha[i])
-                        / (T_Alpha[i + 1] - T_Alpha[i]),
-                );
-                Alpha.push((TAlpha[i] * diff_R[i]) / RAlpha[i]);
-                BiasPoint.push(100. * RAlpha[i] / RN);
-            }
+            let Fit = backend
+                .FitRT(R, T)
+                .Context(TESErr::AnalysisErr, format!("Failed to fit RT at {}uA", crt))?;
+            let (BiasPoint, Alpha) = AlphaFromFit(&Fit, T);
             
             self.BiasPoints_Current.insert(*crt, BiasPoint);
             self.Alpha_Current.insert(*crt, Alpha);
+            self.Fit_Current.insert(*crt, Fit);
         }
         return Ok(());
     }
     
-    pub fn AnalyzeRTFolder(&mut self)->Result<(), String>{
-        let RTFiles = glob(&format!("{}/rawdata/CH*.dat", self.DP.DataPath.display()))
-            .map_err(|e| format!("Failed to glob RT files at {:?}\n{}", self.DP.DataPath, e))?
+    /// 指定した電流 (無ければフィットした中で一番小さい電流) の α を取り出す
+    pub fn AlphaCurve(&self, Current: Option<u32>) -> TESResult<AlphaCurveS> {
+        let Current = match Current {
+            Some(c) => c,
+            None => *self.Alpha_Current.keys().min().ok_or_else(|| {
+                TESErrorS::new(TESErr::NotAnalyzed, "RT is not fitted yet. Run FitRT first.")
+            })?,
+        };
+        Ok(AlphaCurveS {
+            Current,
+            BiasPoint: self
+                .BiasPoints_Current
+                .get(&Current)
+                .ok_or_else(|| Missing("BiasPoint", Current))?
+                .clone(),
+            Alpha: self
+                .Alpha_Current
+                .get(&Current)
+                .ok_or_else(|| Missing("Alpha", Current))?
+                .clone(),
+        })
+    }
+
+    /// cancel が立ったらファイルの間とフィットの前で止める
+    pub fn AnalyzeRTFolder(&mut self, cancel: &AtomicBool)->TESResult<()>{
+        let RawPath = self.DP.DataPath.join("rawdata");
+        let RTFiles = glob(&format!("{}/CH*.dat", RawPath.display()))
+            .map_err(|e| TESErrorS::AtPath(TESErr::FolderNotAdequate, &RawPath, format!("Failed to glob RT files\n{}", e)))?
             .filter_map(Result::ok)
             .collect::<Vec<PathBuf>>();
 
         if RTFiles.is_empty() {
-            return Err("RTFiles is empty".to_string());
+            return Err(TESErrorS::AtPath(TESErr::FolderNotAdequate, &RawPath, "No RT files (CH*.dat) found"));
         }
 
         let RTPattern =
             Regex::new(r"_(\d+)mK_(\d+)uA\.dat").map_err(|e| format!("Regex Error\n{}", e))?;
 
         let mut V_out_current: HashMap<u32, Vec<f64>> = HashMap::new();
+        let Cancelled = || {
+            if cancel.load(Ordering::SeqCst) {
+                Err(TESErrorS::new(TESErr::Cancelled, "RT analysis was cancelled"))
+            } else {
+                Ok(())
+            }
+        };
         for file in RTFiles {
+            Cancelled()?;
             let V_out = LoadTxt(file.as_path())?
                 .mean()
-                .ok_or("Failed to calculate mean of ndarray.")?;
+                .ok_or_else(|| TESErrorS::AtPath(TESErr::RTParseErr, &file, "No values in RT file"))?;
             let file_str = file.to_string_lossy();
             if let Some(captures) = RTPattern.captures(&file_str) {
-                let temp = captures[1]
-                    .parse::<f64>()
-                    .map_err(|e| format!("Regex Error\n{}", e))?;
-                let current = captures[2]
-                    .parse::<u32>()
-                    .map_err(|e| format!("Regex Error\n{}", e))?;
+                let temp = captures[1].parse::<f64>().map_err(|e| {
+                    TESErrorS::AtPath(TESErr::RTParseErr, &file, format!("Failed to parse temperature\n{}", e))
+                })?;
+                let current = captures[2].parse::<u32>().map_err(|e| {
+                    TESErrorS::AtPath(TESErr::RTParseErr, &file, format!("Failed to parse current\n{}", e))
+                })?;
 
                 self.Currents.insert(current);
                 self.Temp_Current
@@ -194,11 +354,22 @@ impl RTProcessorS {
         V_out_sample.sort_by(|a, b| a.partial_cmp(b).unwrap());
 
         self.eta = 1.0 / LinerFit(&Array1::from(I_bias_sample), &Array1::from(V_out_sample))?;
-        println!("eta: {}", self.eta);
 
+        // 0uA の出力をオフセットとして引く
+        let Offset = V_out_current
+            .get(&0)
+            .ok_or_else(|| TESErrorS::AtPath(TESErr::RTParseErr, &RawPath, "No 0uA RT data for the offset"))?;
         for cur in self.Currents.iter() {
-            for i in 0..V_out_current.get(cur).unwrap().len() {
-                let V_out = V_out_current.get(cur).unwrap()[i] - V_out_current.get(&0).unwrap()[i];
+            let V_outs = &V_out_current[cur];
+            if V_outs.len() > Offset.len() {
+                return Err(TESErrorS::AtPath(
+                    TESErr::RTParseErr,
+                    &RawPath,
+                    format!("{}uA has more temperatures than 0uA", cur),
+                ));
+            }
+            for i in 0..V_outs.len() {
+                let V_out = V_outs[i] - Offset[i];
                 let R_tes = self.TESAConfig.R_sh * (*cur as f64 / (self.eta * V_out) - 1.0);
                 self.R_tes_Current
                     .entry(*cur)
@@ -211,15 +382,7 @@ impl RTProcessorS {
         self.Temp_Current.remove(&0);
         self.R_tes_Current.remove(&0);
 
-        if cfg!(debug_assertions) {
-            println!("Currents: {:?}", self.Currents);
-            let R_TES = to_string_pretty(&self.R_tes_Current).unwrap();
-            //let temp = to_string_pretty(&self.Temp_Current).unwrap();
-            println!("R_tes_Current: {}", R_TES);
-            //println!("Temp_Current: {}", temp);
-            println!("eta: {}", self.eta);
-        }
-
+        Cancelled()?;
         self.FitRT()?;
         self.SaveRT()?;
         return Ok(());
@@ -227,3 +390,52 @@ impl RTProcessorS {
 }
 
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn FitRTCurveDoesNotDependOnOrder() {
+        let truth = [0.1, 200.0, 0.5, 1.5];
+        let T: Vec<f64> = (0..81).map(|i| 190.0 + 0.25 * i as f64).collect();
+        let R: Vec<f64> = T.iter().map(|&t| RTModel(t, &truth)).collect();
+        let sorted = FitRTCurve(&R, &T).unwrap();
+        // 降順に測った RT でも同じ初期値から同じ結果になる
+        let Tr: Vec<f64> = T.iter().rev().cloned().collect();
+        let Rr: Vec<f64> = R.iter().rev().cloned().collect();
+        let reversed = FitRTCurve(&Rr, &Tr).unwrap();
+        for (a, b) in [
+            (sorted.Tc, reversed.Tc),
+            (sorted.T1, reversed.T1),
+            (sorted.T2, reversed.T2),
+        ] {
+            assert!((a - b).abs() < 1e-9, "{} vs {}", a, b);
+        }
+        assert!((sorted.Tc - 200.0).abs() < 1e-6);
+        assert!((sorted.T1 - 0.5).abs() < 1e-6 && (sorted.T2 - 1.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn AlphaDoesNotDependOnTemperatureOrder() {
+        let Fit = RTFitS {
+            RN: 0.1,
+            Tc: 100.0,
+            T1: 0.5,
+            T2: 1.5,
+            RN_err: 0.0,
+            Tc_err: 0.0,
+            T1_err: 0.0,
+            T2_err: 0.0,
+            ReducedChi2: 0.0,
+        };
+        // glob の順 (100mK..105mK の後に 95mK..99mK)
+        let unsorted: Vec<f64> = (100..=105).chain(95..=99).map(f64::from).collect();
+        let sorted: Vec<f64> = (95..=105).map(f64::from).collect();
+        let (BiasPoint, Alpha) = AlphaFromFit(&Fit, &unsorted);
+        assert_eq!(Alpha.len(), 999);
+        assert!(Alpha.iter().chain(&BiasPoint).all(|v| v.is_finite()));
+        assert!((BiasPoint[0] - 10.0).abs() < 1.0 && (BiasPoint[998] - 90.0).abs() < 1.0);
+        assert_eq!((BiasPoint, Alpha), AlphaFromFit(&Fit, &sorted));
+    }
+}
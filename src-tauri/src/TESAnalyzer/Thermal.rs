@@ -0,0 +1,116 @@
+#![allow(non_snake_case)]
+// IV から求めた飽和パワーの温度依存性 P_sat = K(Tc^n - Tbath^n) を求める
+use crate::TESAnalyzer::Fit::LevenbergMarquardt;
+use serde::Serialize;
+
+/// 温度ごとの飽和パワー (R_TES が PsatFraction * R_n を横切る点)
+#[derive(Debug, Clone, Serialize)]
+pub struct PsatS {
+    /// 熱浴温度 (mK)
+    pub Temp: u32,
+    /// 常伝導抵抗が分からなければ None
+    pub R_n: Option<f64>,
+    /// pW (転移しなければ None)
+    pub P_sat: Option<f64>,
+    /// P_sat を求めた I_bias (uA)
+    pub I_bias: Option<f64>,
+}
+
+/// P_sat = K(Tc^n - Tbath^n) のフィット結果 (K は pW/K^n、Tc は mK、G は Tc での値で pW/K)
+#[derive(Debug, Clone, Serialize)]
+pub struct ThermalFitS {
+    pub K: f64,
+    pub K_err: f64,
+    pub n: f64,
+    pub n_err: f64,
+    pub Tc: f64,
+    pub Tc_err: f64,
+    pub G: f64,
+    pub G_err: f64,
+    pub ReducedChi2: f64,
+}
+
+/// 常伝導側から下げていき、R_TES が Fraction * R_n を下回る所の P_TES を線形補間する
+///
+/// R_TES と P_TES は I_bias と同じ並び (I_bias が増える順)
+pub fn SaturationPower(
+    I_bias: &[f64],
+    R_TES: &[f64],
+    P_TES: &[f64],
+    R_n: f64,
+    Fraction: f64,
+) -> Option<(f64, f64)> {
+    let target = R_n * Fraction;
+    (0..R_TES.len().saturating_sub(1)).rev().find_map(|i| {
+        let (r0, r1) = (R_TES[i], R_TES[i + 1]);
+        if !(r0.is_finite() && r1.is_finite()) || r1 < target || r0 >= target {
+            return None;
+        }
+        let t = (target - r0) / (r1 - r0);
+        Some((
+            I_bias[i] + t * (I_bias[i + 1] - I_bias[i]),
+            P_TES[i] + t * (P_TES[i + 1] - P_TES[i]),
+        ))
+    })
+}
+
+fn PsatModel(T: f64, p: &[f64]) -> f64 {
+    p[0] * (p[2].powf(p[1]) - T.powf(p[1]))
+}
+
+/// 熱浴温度 (mK) と P_sat (pW) から K, n, Tc, G を求める (フィットは K 単位で行う)
+pub fn FitPsat(Temps: &[f64], P_sat: &[f64]) -> Result<ThermalFitS, String> {
+    if Temps.len() != P_sat.len() || Temps.len() < 4 {
+        return Err(format!(
+            "At least 4 temperatures with P_sat are needed to fit K, n and Tc (got {})",
+            Temps.len()
+        ));
+    }
+    let T: Vec<f64> = Temps.iter().map(|t| t * 1e-3).collect();
+
+    // n = 4 として P_sat と Tbath^4 の直線から初期値を決める
+    let n0 = 4.0;
+    let x: Vec<f64> = T.iter().map(|t| t.powf(n0)).collect();
+    let len = x.len() as f64;
+    let (mx, my) = (x.iter().sum::<f64>() / len, P_sat.iter().sum::<f64>() / len);
+    let sxx: f64 = x.iter().map(|v| (v - mx) * (v - mx)).sum();
+    let sxy: f64 = x.iter().zip(P_sat).map(|(v, p)| (v - mx) * (p - my)).sum();
+    let Tmax = T.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+    let (K0, Tc0) = if sxx > 0.0 && sxy < 0.0 {
+        let K = -sxy / sxx;
+        let intercept = my + K * mx;
+        (K, (intercept / K).powf(1.0 / n0).max(Tmax * 1.01))
+    } else {
+        let Tc = Tmax * 1.1;
+        (my / (Tc.powf(n0) - mx).max(1e-30), Tc)
+    };
+
+    let result = LevenbergMarquardt(PsatModel, &T, P_sat, None, &[K0, n0, Tc0])
+        .map_err(|e| format!("Failed to fit P_sat\n{}", e))?;
+    let (p, e, C) = (&result.Params, &result.Errors, &result.Covariance);
+    let (K, n, Tc) = (p[0], p[1], p[2]);
+
+    // G = nK Tc^(n-1) の誤差は共分散から伝搬する
+    let G = n * K * Tc.powf(n - 1.0);
+    let J = [
+        n * Tc.powf(n - 1.0),
+        K * Tc.powf(n - 1.0) * (1.0 + n * Tc.ln()),
+        n * (n - 1.0) * K * Tc.powf(n - 2.0),
+    ];
+    let G_var: f64 = (0..3)
+        .flat_map(|a| (0..3).map(move |b| (a, b)))
+        .map(|(a, b)| J[a] * C[a][b] * J[b])
+        .sum();
+
+    Ok(ThermalFitS {
+        K,
+        K_err: e[0],
+        n,
+        n_err: e[1],
+        Tc: Tc * 1e3,
+        Tc_err: e[2] * 1e3,
+        G,
+        G_err: G_var.abs().sqrt(),
+        ReducedChi2: result.ReducedChi2,
+    })
+}
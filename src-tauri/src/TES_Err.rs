@@ -1,37 +1,189 @@
-use serde::{Deserialize, Serialize};
-
-#[derive(Debug,Serialize)]
-pub(crate) enum TESErr{
-    FolderNotFound(String),
-    FolderCreateErr(String),
-    FolderNotAdequate(String),
-    FileCreateErr(String),
-    FileOpenErr(String),
-    FileNotFound(String),
-    FileWriteErr(String),
-    NdarrayMeanErr,
-    IterMapErr,
-    IterMinErr,
-    RegexErr,
-    RegexCaptureErr,
-    RegexGetErr,
-    BinarySeekErr,
-    BinaryReadErr(String),
-    BinaryBufferSizeErr(String),
-    CSVReadErr(String),
-    CSVParseErr(String),
+#![allow(non_snake_case)]
+// エラーの種類 (コード)、重大度、対象のパス、原因の連鎖を持つエラー型
+// フロントエンドには JSON で渡し、コードに応じて対処法を表示する
+use serde::Serialize;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum TESErr {
+    FolderNotFound,
+    FolderCreateErr,
+    FolderNotAdequate,
+    FileCreateErr,
+    FileOpenErr,
+    FileNotFound,
+    FileWriteErr,
+    FileRemoveErr,
+    BinaryReadErr,
+    BinaryBufferSizeErr,
+    CSVReadErr,
+    CSVParseErr,
+    JsonParseErr,
+    /// 設定の値がおかしい
+    ConfigErr,
     PulseChannelEmpty,
     PulseGlobErr,
-    LinerFitErr,
-    MapGetErr(String),
-    FindMaxJumpErr,
+    PulseFileNameErr,
     IVGlobErr,
     IVParseErr,
+    RTParseErr,
+    /// Calibration/History.json が IV データと合わない
+    CalibrationHistoryErr,
+    /// 先に別の解析が必要
+    NotAnalyzed,
+    /// 数値計算に失敗した
+    AnalysisErr,
+    LinerFitErr,
+    FindMaxJumpErr,
     TooBigLinerFitSample,
-    JsonParseErr(String),
-    PyErr(String),
-    CurrentEnvErr,
-    ToStrErr,
-    SortErr,
-    PulseFileNameErr(String),
-}
\ No newline at end of file
+    TabNotFound,
+    TabTypeErr,
+    /// 他のジョブがタブを使っている
+    TabBusy,
+    LockErr,
+    JobNotFound,
+    Cancelled,
+    PyErr,
+    SerializeErr,
+    /// String のエラーから変換したもの
+    Other,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub enum Severity {
+    /// 利用者の操作による中断など
+    Info,
+    /// やり直せば解決するもの
+    Warning,
+    Error,
+    /// アプリの再起動が必要なもの
+    Fatal,
+}
+
+impl TESErr {
+    pub fn DefaultSeverity(self) -> Severity {
+        match self {
+            TESErr::Cancelled => Severity::Info,
+            TESErr::NotAnalyzed | TESErr::JobNotFound | TESErr::TabBusy => Severity::Warning,
+            TESErr::LockErr => Severity::Fatal,
+            _ => Severity::Error,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TESErrorS {
+    pub Code: TESErr,
+    pub Severity: Severity,
+    pub Message: String,
+    /// 問題のあったファイルやフォルダ
+    pub Path: Option<PathBuf>,
+    /// このエラーの原因となったエラー
+    pub Source: Option<Box<TESErrorS>>,
+}
+
+pub type TESResult<T> = Result<T, TESErrorS>;
+
+impl TESErrorS {
+    pub fn new(Code: TESErr, Message: impl Into<String>) -> Self {
+        Self {
+            Code,
+            Severity: Code.DefaultSeverity(),
+            Message: Message.into(),
+            Path: None,
+            Source: None,
+        }
+    }
+
+    pub fn AtPath(Code: TESErr, path: &Path, Message: impl Into<String>) -> Self {
+        Self::new(Code, Message).WithPath(path)
+    }
+
+    /// ファイルを開けなかった時のエラー (無いのか読めないのかを分ける)
+    pub fn Open(path: &Path, e: &std::io::Error) -> Self {
+        let Code = match e.kind() {
+            std::io::ErrorKind::NotFound => TESErr::FileNotFound,
+            _ => TESErr::FileOpenErr,
+        };
+        Self::AtPath(Code, path, format!("Failed to open file\n{}", e))
+    }
+
+    pub fn WithPath(mut self, path: &Path) -> Self {
+        self.Path = Some(path.to_path_buf());
+        self
+    }
+
+    pub fn WithSeverity(mut self, Severity: Severity) -> Self {
+        self.Severity = Severity;
+        self
+    }
+
+    /// self を原因とする新しいエラーを作る (重大度は重い方)
+    pub fn Context(self, Code: TESErr, Message: impl Into<String>) -> Self {
+        let mut outer = Self::new(Code, Message);
+        outer.Severity = outer.Severity.max(self.Severity);
+        outer.Source = Some(Box::new(self));
+        outer
+    }
+
+    /// 一番元の原因
+    pub fn Root(&self) -> &TESErrorS {
+        let mut e = self;
+        while let Some(source) = &e.Source {
+            e = source;
+        }
+        e
+    }
+}
+
+impl fmt::Display for TESErrorS {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{:?}] {}", self.Code, self.Message)?;
+        if let Some(path) = &self.Path {
+            write!(f, "\n  at {:?}", path)?;
+        }
+        if let Some(source) = &self.Source {
+            write!(f, "\ncaused by: {}", source)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for TESErrorS {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.Source
+            .as_deref()
+            .map(|e| e as &(dyn std::error::Error + 'static))
+    }
+}
+
+// String を返す関数とも ? で混ぜて使えるようにする
+impl From<String> for TESErrorS {
+    fn from(Message: String) -> Self {
+        Self::new(TESErr::Other, Message)
+    }
+}
+
+impl From<&str> for TESErrorS {
+    fn from(Message: &str) -> Self {
+        Self::new(TESErr::Other, Message)
+    }
+}
+
+impl From<TESErrorS> for String {
+    fn from(e: TESErrorS) -> Self {
+        e.to_string()
+    }
+}
+
+/// Result のエラーに説明を足す
+pub trait ContextT<T> {
+    fn Context(self, Code: TESErr, Message: impl Into<String>) -> TESResult<T>;
+}
+
+impl<T, E: Into<TESErrorS>> ContextT<T> for Result<T, E> {
+    fn Context(self, Code: TESErr, Message: impl Into<String>) -> TESResult<T> {
+        self.map_err(|e| e.into().Context(Code, Message))
+    }
+}
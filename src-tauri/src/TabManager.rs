@@ -1,15 +1,23 @@
 #![allow(non_snake_case)]
-use crate::DataProcessor::{LoadBi};
+use crate::Config::{BackendConfig, CoincidenceConfig, TESAnalysisConfig, EnergyCalibrationConfig, NoiseConfig, PeakFitConfig, SpectrumConfig, TemplateConfig};
+use crate::DataProcessor::{LoadBi, WriteFile};
 use crate::PulseProcessor::PulseProcessorS;
+use crate::PulseProcessor::Spectrum::FitPeak;
+use crate::TESAnalyzer::FluxJump::FluxJumpReportS;
 use crate::TESAnalyzer::IV::IVProcessorS;
 use crate::TESAnalyzer::RT::RTProcessorS;
+use crate::TES_Err::{ContextT, TESErr, TESErrorS, TESResult};
 use ndarray::Array1;
+use serde::{de::DeserializeOwned, Serialize};
 use std::collections::HashMap;
 use std::fs;
 use std::path::{Path, PathBuf};
-use std::sync::{LazyLock, Mutex};
+use std::cell::Cell;
+use std::sync::{Arc, LazyLock, Mutex, MutexGuard, TryLockError};
 use tauri::Emitter;
-use crate::PulseProcessor;
+
+pub mod Job;
+use Job::JobHandleS;
 
 pub enum TabProcessor {
     IV(IVProcessorS),
@@ -17,56 +25,145 @@ pub enum TabProcessor {
     Pulse(PulseProcessorS),
 }
 
-pub static PROCESSORS: LazyLock<Mutex<HashMap<String, TabProcessor>>> =
+fn LockErr(what: &str) -> TESErrorS {
+    TESErrorS::new(TESErr::LockErr, format!("Failed to lock {}", what))
+}
+
+fn WrongTab(Message: &str) -> TESErrorS {
+    TESErrorS::new(TESErr::TabTypeErr, Message)
+}
+
+/// コマンドからタブを使う (ジョブが使っている間は待たずに TabBusy を返す)
+fn TryLock<'a>(
+    processor: &'a Mutex<TabProcessor>,
+    TabName: &str,
+) -> TESResult<MutexGuard<'a, TabProcessor>> {
+    processor.try_lock().map_err(|e| match e {
+        TryLockError::WouldBlock => TESErrorS::new(
+            TESErr::TabBusy,
+            format!("Tab '{}' is busy with another job", TabName),
+        ),
+        TryLockError::Poisoned(_) => LockErr("processor"),
+    })
+}
+
+fn TabNotFound(TabName: &str) -> TESErrorS {
+    TESErrorS::new(TESErr::TabNotFound, format!("Tab '{}' not found", TabName))
+}
+
+/// まだ解析していないデータを求められた
+fn NoData(Message: impl Into<String>) -> TESErrorS {
+    TESErrorS::new(TESErr::NotAnalyzed, Message)
+}
+
+fn ToJson<T: Serialize>(value: T) -> TESResult<serde_json::Value> {
+    serde_json::to_value(value)
+        .map_err(|e| TESErrorS::new(TESErr::SerializeErr, format!("Serialization error: {}", e)))
+}
+
+/// フロントエンドから受け取った設定を読む
+fn ParseConfig<T: DeserializeOwned>(value: serde_json::Value, what: &str) -> TESResult<T> {
+    serde_json::from_value(value).map_err(|e| {
+        TESErrorS::new(TESErr::JsonParseErr, format!("Failed to parse {} config\n{}", what, e))
+    })
+}
+
+/// タブごとにロックする (全体のロックはタブの出し入れの間だけ持つ)
+pub static PROCESSORS: LazyLock<Mutex<HashMap<String, Arc<Mutex<TabProcessor>>>>> =
     LazyLock::new(|| Mutex::new(HashMap::new()));
 
+pub fn GetProcessor(TabName: &str) -> TESResult<Arc<Mutex<TabProcessor>>> {
+    let map = PROCESSORS
+        .lock()
+        .map_err(|_| LockErr("processor map"))?;
+    map.get(TabName)
+        .cloned()
+        .ok_or_else(|| TabNotFound(TabName))
+}
+
+/// ジョブとして別スレッドで実行する (タブのロックはそのジョブの間だけ持つ)
+async fn RunJob<T, F>(window: tauri::Window, TabName: String, Kind: &str, f: F) -> TESResult<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&mut TabProcessor, &JobHandleS) -> TESResult<T> + Send + 'static,
+{
+    let processor = GetProcessor(&TabName)?;
+    let handle = Job::StartJob(&TabName, Kind);
+    let _ = window.emit(
+        "job-started",
+        serde_json::json!({ "id": handle.Id, "tab": TabName, "kind": Kind }),
+    );
+    let job = handle.clone();
+    let result = tokio::task::spawn_blocking(move || {
+        let mut tab = processor.lock().map_err(|_| LockErr("processor"))?;
+        // 他のジョブを待っている間に中断された
+        if job.IsCancelled() {
+            return Err(TESErrorS::new(TESErr::Cancelled, "Job was cancelled"));
+        }
+        f(&mut tab, &job)
+    })
+        .await
+        .map_err(|e| TESErrorS::new(TESErr::AnalysisErr, format!("Join error: {}", e)))
+        .and_then(|result| result);
+    if let Some(job) = Job::FinishJob(&handle, &result) {
+        let _ = window.emit("job-finished", job);
+    }
+    result
+}
+
 #[tauri::command]
-pub fn RegisterProcessor(TabName: String, ProcessorType: String) -> Result<(), String> {
+pub fn RegisterProcessor(TabName: String, ProcessorType: String) -> TESResult<()> {
     let mut map = PROCESSORS
         .lock()
-        .map_err(|_| "Failed to lock processor map")?;
+        .map_err(|_| LockErr("processor map"))?;
 
     if map.contains_key(&TabName) {
-        return Err(format!("Tab '{}' already registered", TabName));
+        return Err(TESErrorS::new(
+            TESErr::TabTypeErr,
+            format!("Tab '{}' already registered", TabName),
+        ));
     }
 
     let processor = match ProcessorType.as_str() {
         "IV" => TabProcessor::IV(IVProcessorS::new()),
         "RT" => TabProcessor::RT(RTProcessorS::new()),
         "Pulse" => TabProcessor::Pulse(PulseProcessorS::new()),
-        _ => return Err(format!("Unknown processor type: {}", ProcessorType)),
+        _ => return Err(WrongTab(&format!("Unknown processor type: {}", ProcessorType))),
     };
 
-    map.insert(TabName, processor);
+    map.insert(TabName, Arc::new(Mutex::new(processor)));
     Ok(())
 }
 
 #[tauri::command]
-pub fn UnregisterProcessor(TabName: String) -> Result<(), String> {
+pub fn UnregisterProcessor(TabName: String) -> TESResult<()> {
     let mut map = PROCESSORS
         .lock()
-        .map_err(|_| "Failed to lock processor map")?;
+        .map_err(|_| LockErr("processor map"))?;
 
     if map.remove(&TabName).is_some() {
+        Job::CancelTabJobs(&TabName);
         Ok(())
     } else {
-        Err(format!("Tab '{}' not found", TabName))
+        Err(TabNotFound(&TabName))
     }
 }
 
 impl TabProcessor {
-    pub fn SetDataPath(&mut self, path: String) -> Result<(), String> {
+    pub fn SetDataPath(&mut self, path: String) -> TESResult<()> {
         match self {
-            TabProcessor::IV(p) => {
-                p.SetDataPath(Path::new(&path));
-                Ok(())
-            }
-            TabProcessor::RT(p) => {
-                p.SetDataPath(Path::new(&path));
-                Ok(())
-            }
+            TabProcessor::IV(p) => p.SetDataPath(Path::new(&path)),
+            TabProcessor::RT(p) => p.SetDataPath(Path::new(&path)),
+            TabProcessor::Pulse(p) => p.SetDataPath(Path::new(&path)),
+        }
+    }
+
+    pub fn SetBackend(&mut self, backend: BackendConfig) -> TESResult<()> {
+        match self {
+            TabProcessor::IV(p) => p.SetBackend(backend),
+            TabProcessor::RT(p) => p.SetBackend(backend),
             TabProcessor::Pulse(p) => {
-                p.SetDataPath(Path::new(&path));
+                p.SetBackend(backend);
                 Ok(())
             }
         }
@@ -74,13 +171,13 @@ impl TabProcessor {
 }
 
 #[tauri::command]
-pub fn FindFolderType(folder: String) -> Result<String, String> {
+pub fn FindFolderType(folder: String) -> TESResult<String> {
     let path = Path::new(&folder);
     if !path.exists() {
-        return Err(path.to_string_lossy().to_string());
+        return Err(TESErrorS::AtPath(TESErr::FolderNotFound, path, "Folder not found."));
     }
     if !path.is_dir() {
-        return Err("Not a folder.".to_string());
+        return Err(TESErrorS::AtPath(TESErr::FolderNotAdequate, path, "Not a folder."));
     }
 
     let IsIV = fs::read_dir(path)
@@ -121,41 +218,72 @@ pub fn FindFolderType(folder: String) -> Result<String, String> {
         return Ok("Pulse".to_string());
     }
 
-    return Err(path.to_string_lossy().to_string());
+    return Err(TESErrorS::AtPath(
+        TESErr::FolderNotAdequate,
+        path,
+        "Not an IV, RT or Pulse folder.",
+    ));
 }
 
 #[tauri::command]
-pub fn SetDataPathCommand(TabName: String, path: String) -> Result<(), String> {
-    let mut map = PROCESSORS
-        .lock()
-        .map_err(|_| "Failed to lock processor map")?;
-    let processor = map.get_mut(&TabName).ok_or("Tab not found")?;
-    processor.SetDataPath(path)
+pub fn SetDataPathCommand(TabName: String, path: String) -> TESResult<()> {
+    let processor = GetProcessor(&TabName)?;
+    let mut tab = TryLock(&processor, &TabName)?;
+    tab.SetDataPath(path)
+}
+
+#[tauri::command]
+pub fn SetBackendCommand(TabName: String, backend: serde_json::Value) -> TESResult<()> {
+    let backend: BackendConfig = ParseConfig(backend, "backend")?;
+    let processor = GetProcessor(&TabName)?;
+    let mut tab = TryLock(&processor, &TabName)?;
+    tab.SetBackend(backend)
 }
 
 
 
+/// IV・RT の解析設定
 #[tauri::command]
-pub async fn AnalyzeIVFolderCommand(tab_name: String) -> Result<(), String> {
-    let result = tokio::task::spawn_blocking(move || {
-        let mut map = PROCESSORS.lock().map_err(|_| "Failed to lock processor map")?;
-        match map.get_mut(&tab_name) {
-            Some(TabProcessor::IV(iv)) => iv.AnalyzeIVFolder(),
-            _ => Err("Tab is not an IV Processor".to_string()),
-        }
+pub fn GetTESConfigCommand(TabName: String) -> TESResult<serde_json::Value> {
+    let processor = GetProcessor(&TabName)?;
+    let tab = TryLock(&processor, &TabName)?;
+    match &*tab {
+        TabProcessor::IV(iv) => ToJson(iv.Config()),
+        TabProcessor::RT(rt) => ToJson(rt.Config()),
+        _ => Err(WrongTab("Tab is not an IV or RT Processor")),
+    }
+}
+
+/// IVConfig.json / RTConfig.json に保存する
+#[tauri::command]
+pub fn SaveTESConfigCommand(TabName: String, json: serde_json::Value) -> TESResult<()> {
+    let config: TESAnalysisConfig = ParseConfig(json, "TES analysis")?;
+    let processor = GetProcessor(&TabName)?;
+    let mut tab = TryLock(&processor, &TabName)?;
+    match &mut *tab {
+        TabProcessor::IV(iv) => iv.SaveConfig(config),
+        TabProcessor::RT(rt) => rt.SaveConfig(config),
+        _ => Err(WrongTab("Tab is not an IV or RT Processor")),
+    }
+}
+
+#[tauri::command]
+pub async fn AnalyzeIVFolderCommand(window: tauri::Window, tab_name: String) -> TESResult<()> {
+    RunJob(window, tab_name, "AnalyzeIVFolder", |tab, job| match tab {
+        TabProcessor::IV(iv) => iv.AnalyzeIVFolder(&job.Cancel),
+        _ => Err(WrongTab("Tab is not an IV Processor")),
     })
-        .await
-        .map_err(|e| format!("Join error: {}", e))?;  // 二重Resultのflatten
-    result
+    .await
 }
 
 
 #[tauri::command]
-pub fn SaveCalibratedCommand(TabName: String) -> Result<(), String> {
-    let mut map = PROCESSORS.lock().map_err(|_| "Lock error")?;
-    match map.get_mut(&TabName) {
-        Some(TabProcessor::IV(iv)) => iv.SaveCalibrated(),
-        _ => Err("Tab is not an IV Processor".to_string()),
+pub fn SaveCalibratedCommand(TabName: String) -> TESResult<()> {
+    let processor = GetProcessor(&TabName)?;
+    let mut tab = TryLock(&processor, &TabName)?;
+    match &mut *tab {
+        TabProcessor::IV(iv) => iv.SaveCalibrated(),
+        _ => Err(WrongTab("Tab is not an IV Processor")),
     }
 }
 
@@ -165,15 +293,14 @@ pub fn CalibrateSingleJumpCommand(
     temp: u32,
     CalibStartIbias: f64,
     CalibEndIbias: f64,
-) -> Result<(), String> {
-    let mut map = PROCESSORS
-        .lock()
-        .map_err(|_| "Failed to lock processor map")?;
-    match map.get_mut(&TabName) {
-        Some(TabProcessor::IV(iv)) => {
+) -> TESResult<()> {
+    let processor = GetProcessor(&TabName)?;
+    let mut tab = TryLock(&processor, &TabName)?;
+    match &mut *tab {
+        TabProcessor::IV(iv) => {
             iv.CalibrateSingleJump(temp, CalibStartIbias, CalibEndIbias)
         }
-        _ => Err("Tab is not an IV Processor".to_string()),
+        _ => Err(WrongTab("Tab is not an IV Processor")),
     }
 }
 
@@ -183,41 +310,56 @@ pub fn CalibrateMultipleJumpCommand(
     temp: u32,
     CalibStartIbias: f64,
     CalibEndIbias: f64,
-) -> Result<(), String> {
-    let mut map = PROCESSORS
-        .lock()
-        .map_err(|_| "Failed to lock processor map")?;
-    match map.get_mut(&TabName) {
-        Some(TabProcessor::IV(iv)) => {
+) -> TESResult<()> {
+    let processor = GetProcessor(&TabName)?;
+    let mut tab = TryLock(&processor, &TabName)?;
+    match &mut *tab {
+        TabProcessor::IV(iv) => {
             iv.CalibrateMultipleJump(temp, CalibStartIbias, CalibEndIbias)
         }
-        _ => Err("Tab is not an IV Processor".to_string()),
+        _ => Err(WrongTab("Tab is not an IV Processor")),
+    }
+}
+
+#[tauri::command]
+pub fn CalibrateAutoJumpCommand(TabName: String) -> TESResult<Vec<FluxJumpReportS>> {
+    let processor = GetProcessor(&TabName)?;
+    let mut tab = TryLock(&processor, &TabName)?;
+    match &mut *tab {
+        TabProcessor::IV(iv) => iv.CalibrateAutoJump(),
+        _ => Err(WrongTab("Tab is not an IV Processor")),
     }
 }
 
 #[tauri::command]
-pub fn GetIVCommand(TabName: String) -> Result<serde_json::Value, String> {
-    let map = PROCESSORS.lock().unwrap();
-    match map.get(&TabName) {
-        Some(TabProcessor::IV(p)) => {
+pub fn GetIVCommand(TabName: String) -> TESResult<serde_json::Value> {
+    let processor = GetProcessor(&TabName)?;
+    let tab = TryLock(&processor, &TabName)?;
+    match &*tab {
+        TabProcessor::IV(p) => {
             let mut result = serde_json::Map::new();
 
             for &temp in &p.Temps {
                 let I_bias = p
                     .I_bias_temps
                     .get(&temp)
-                    .ok_or(format!("No I_bias data for temp {}", temp))?
+                    .ok_or_else(|| NoData(format!("No I_bias data for temp {}", temp)))?
                     .to_vec();
                 let v_out_vec = p
                     .V_out_history_temps
                     .get(&temp)
-                    .ok_or(format!("No V_out data for temp {}", temp))?;
+                    .ok_or_else(|| NoData(format!("No V_out data for temp {}", temp)))?;
                 let v_out = v_out_vec[p.CurrentIndex]
                     .to_vec();
                 let R_tes=p
                     .R_tes_temps
                     .get(&temp)
-                    .ok_or(format!("No R_tes data for temp {}", temp))?
+                    .ok_or_else(|| NoData(format!("No R_tes data for temp {}", temp)))?
+                    .to_vec();
+                let P_tes = p
+                    .P_tes_temps
+                    .get(&temp)
+                    .ok_or_else(|| NoData(format!("No P_tes data for temp {}", temp)))?
                     .to_vec();
                 result.insert(
                     temp.to_string(),
@@ -225,55 +367,152 @@ pub fn GetIVCommand(TabName: String) -> Result<serde_json::Value, String> {
                         "I_bias": I_bias,
                         "V_out": v_out,
                         "R_tes": R_tes,
+                        "P_tes": P_tes,
                     }),
                 );
             }
 
             Ok(serde_json::Value::Object(result))
         }
-        _ => Err("Invalid tab or processor type".into()),
+        _ => Err(WrongTab("Invalid tab or processor type")),
+    }
+}
+
+/// 温度ごとの P_sat と K, n, Tc, G のフィット結果
+#[tauri::command]
+pub fn GetThermalCommand(TabName: String) -> TESResult<serde_json::Value> {
+    let processor = GetProcessor(&TabName)?;
+    let tab = TryLock(&processor, &TabName)?;
+    match &*tab {
+        TabProcessor::IV(p) => Ok(serde_json::json!({
+            "Psat": p.Psat,
+            "Fit": p.Thermal.as_ref().ok(),
+            "FitError": p.Thermal.as_ref().err(),
+        })),
+        _ => Err(WrongTab("Invalid tab or processor type")),
+    }
+}
+
+#[tauri::command]
+pub fn GetIVBranchCommand(TabName: String) -> TESResult<serde_json::Value> {
+    let processor = GetProcessor(&TabName)?;
+    let tab = TryLock(&processor, &TabName)?;
+    match &*tab {
+        TabProcessor::IV(p) => Ok(serde_json::json!({
+            "Eta": p.GetEta()?,
+            "R_par": p.R_par,
+            "Temps": p.Branches,
+        })),
+        _ => Err(WrongTab("Invalid tab or processor type")),
+    }
+}
+
+/// RT タブの α を使って IV タブの各バイアス点のループゲインなどを求める
+#[tauri::command]
+pub fn GetElectrothermalCommand(
+    TabName: String,
+    RTTabName: String,
+    current: Option<u32>,
+) -> TESResult<serde_json::Value> {
+    // 2 つのタブを同時にロックしないよう先に α を取り出す
+    let Alpha = {
+        let processor = GetProcessor(&RTTabName)?;
+        let tab = TryLock(&processor, &RTTabName)?;
+        match &*tab {
+            TabProcessor::RT(rt) => rt.AlphaCurve(current)?,
+            _ => return Err(WrongTab("Tab is not an RT Processor")),
+        }
+    };
+    let processor = GetProcessor(&TabName)?;
+    let mut tab = TryLock(&processor, &TabName)?;
+    match &mut *tab {
+        TabProcessor::IV(iv) => {
+            let Link = iv.CalculateElectrothermal(&Alpha)?;
+            Ok(serde_json::json!({
+                "AlphaCurrent": Alpha.Current,
+                "Link": Link,
+                "Temps": iv.Electrothermal,
+            }))
+        }
+        _ => Err(WrongTab("Tab is not an IV Processor")),
     }
 }
+
 #[tauri::command]
-pub fn IVIncrementCommand(TabName: String) -> Result<(), String> {
-    let mut map = PROCESSORS.lock().unwrap();
-    match map.get_mut(&TabName) {
-        Some(TabProcessor::IV(p)) => {
+pub fn IVIncrementCommand(TabName: String) -> TESResult<()> {
+    let processor = GetProcessor(&TabName)?;
+    let mut tab = TryLock(&processor, &TabName)?;
+    match &mut *tab {
+        TabProcessor::IV(p) => {
             // 任意の温度の履歴長さを取得（ここでは最初の温度の履歴長さを使う例）
             return if let Some((&_temp, history)) = p.V_out_history_temps.iter().next() {
                 if p.CurrentIndex + 1 < history.len() {
-                    p.CurrentIndex += 1;
-                    Ok(())
+                    p.JumpToStep(p.CurrentIndex + 1)
                 } else {
-                    Err("Cannot increment CurrentIndex: already at latest history".into())
+                    Err(NoData("Cannot increment CurrentIndex: already at latest history"))
                 }
             } else {
-                Err("No V_out_history_temps found".into())
+                Err(NoData("No V_out_history_temps found"))
             }
         }
-        _ => Err("Invalid tab or processor type".into()),
+        _ => Err(WrongTab("Invalid tab or processor type")),
     }
 }
 #[tauri::command]
-pub fn IVDecrementCommand(TabName: String) -> Result<(), String> {
-    let mut map = PROCESSORS.lock().unwrap();
-    match map.get_mut(&TabName) {
-        Some(TabProcessor::IV(p)) => {
+pub fn IVDecrementCommand(TabName: String) -> TESResult<()> {
+    let processor = GetProcessor(&TabName)?;
+    let mut tab = TryLock(&processor, &TabName)?;
+    match &mut *tab {
+        TabProcessor::IV(p) => {
             if p.CurrentIndex > 0 {
-                p.CurrentIndex -= 1;
-                Ok(())
+                p.JumpToStep(p.CurrentIndex - 1)
             } else {
-                Err("Cannot decrement CurrentIndex: already at earliest history".into())
+                Err(NoData("Cannot decrement CurrentIndex: already at earliest history"))
             }
         }
-        _ => Err("Invalid tab or processor type".into()),
+        _ => Err(WrongTab("Invalid tab or processor type")),
+    }
+}
+#[tauri::command]
+pub fn GetIVHistoryCommand(TabName: String) -> TESResult<serde_json::Value> {
+    let processor = GetProcessor(&TabName)?;
+    let tab = TryLock(&processor, &TabName)?;
+    match &*tab {
+        TabProcessor::IV(p) => Ok(serde_json::json!({
+            "CurrentIndex": p.CurrentIndex,
+            "Steps": p.History,
+            "Warning": p.HistoryWarning,
+        })),
+        _ => Err(WrongTab("Invalid tab or processor type")),
+    }
+}
+
+#[tauri::command]
+pub fn JumpIVHistoryCommand(TabName: String, index: usize) -> TESResult<()> {
+    let processor = GetProcessor(&TabName)?;
+    let mut tab = TryLock(&processor, &TabName)?;
+    match &mut *tab {
+        TabProcessor::IV(p) => p.JumpToStep(index),
+        _ => Err(WrongTab("Invalid tab or processor type")),
     }
 }
+
+#[tauri::command]
+pub fn LabelIVHistoryCommand(TabName: String, index: usize, label: String) -> TESResult<()> {
+    let processor = GetProcessor(&TabName)?;
+    let mut tab = TryLock(&processor, &TabName)?;
+    match &mut *tab {
+        TabProcessor::IV(p) => p.LabelStep(index, &label),
+        _ => Err(WrongTab("Invalid tab or processor type")),
+    }
+}
+
 #[tauri::command]
-pub fn GetIVIndexInfoCommand(TabName: String) -> Result<serde_json::Value, String> {
-    let map = PROCESSORS.lock().unwrap();
-    match map.get(&TabName) {
-        Some(TabProcessor::IV(p)) => {
+pub fn GetIVIndexInfoCommand(TabName: String) -> TESResult<serde_json::Value> {
+    let processor = GetProcessor(&TabName)?;
+    let tab = TryLock(&processor, &TabName)?;
+    match &*tab {
+        TabProcessor::IV(p) => {
             // どれか一つの温度の履歴長を取得（全温度は同じ長さなので）
             return if let Some((_temp, history)) = p.V_out_history_temps.iter().next() {
                 let max = history.len();
@@ -284,66 +523,62 @@ pub fn GetIVIndexInfoCommand(TabName: String) -> Result<serde_json::Value, Strin
                 result.insert("max_history".to_string(), serde_json::json!(max));
                 Ok(serde_json::Value::Object(result))
             } else {
-                Err("No calibration history found".into())
+                Err(NoData("No calibration history found"))
             }
         }
-        _ => Err("Invalid tab or processor type".into()),
+        _ => Err(WrongTab("Invalid tab or processor type")),
     }
 }
 
 #[tauri::command]
-pub async fn AnalyzeRTFolderCommand(tab_name: String) -> Result<(), String> {
-    let result = tokio::task::spawn_blocking(move || {
-        let mut map = PROCESSORS.lock().map_err(|_| "Failed to lock processor map")?;
-        match map.get_mut(&tab_name) {
-            Some(TabProcessor::RT(rt)) => rt.AnalyzeRTFolder(),
-            _ => Err("Tab is not an RT Processor".to_string()),
-        }
+pub async fn AnalyzeRTFolderCommand(window: tauri::Window, tab_name: String) -> TESResult<()> {
+    RunJob(window, tab_name, "AnalyzeRTFolder", |tab, job| match tab {
+        TabProcessor::RT(rt) => rt.AnalyzeRTFolder(&job.Cancel),
+        _ => Err(WrongTab("Tab is not an RT Processor")),
     })
-        .await
-        .map_err(|e| format!("Join error: {}", e))?;  // 二重Resultのflatten
-    result
+    .await
 }
 
 #[tauri::command]
-pub fn FitRTCommand(TabName: String) -> Result<(), String> {
-    let mut map = PROCESSORS
-        .lock()
-        .map_err(|_| "Failed to lock processor map")?;
-    match map.get_mut(&TabName) {
-        Some(TabProcessor::RT(rt)) => rt.FitRT(),
-        _ => Err("Tab is not an RT Processor".to_string()),
+pub fn FitRTCommand(TabName: String) -> TESResult<()> {
+    let processor = GetProcessor(&TabName)?;
+    let mut tab = TryLock(&processor, &TabName)?;
+    match &mut *tab {
+        TabProcessor::RT(rt) => rt.FitRT(),
+        _ => Err(WrongTab("Tab is not an RT Processor")),
     }
 }
 
 #[tauri::command]
-pub fn GetRTCommand(TabName: String) -> Result<serde_json::Value, String> {
-    let map = PROCESSORS.lock().unwrap();
-    match map.get(&TabName) {
-        Some(TabProcessor::RT(p)) => {
+pub fn GetRTCommand(TabName: String) -> TESResult<serde_json::Value> {
+    let processor = GetProcessor(&TabName)?;
+    let tab = TryLock(&processor, &TabName)?;
+    match &*tab {
+        TabProcessor::RT(p) => {
             let mut result = serde_json::Map::new();
 
             for &crt in &p.Currents {
                 let Temp = p
                     .Temp_Current
                     .get(&crt)
-                    .ok_or(format!("No Temp Data for Current {}", crt))?
+                    .ok_or_else(|| NoData(format!("No Temp Data for Current {}", crt)))?
                     .to_vec();
                 let I_bias = p
                     .R_tes_Current
                     .get(&crt)
-                    .ok_or(format!("No I_bias Data for Current {}", crt))?
+                    .ok_or_else(|| NoData(format!("No I_bias Data for Current {}", crt)))?
                     .to_vec();
                 let Alpha=p
                     .Alpha_Current
                     .get(&crt)
-                    .ok_or(format!("No Alpha Data for Current {}", crt))?
+                    .ok_or_else(|| NoData(format!("No Alpha Data for Current {}", crt)))?
                     .to_vec();
                 let BiasPoint=p
                     .BiasPoints_Current
                     .get(&crt)
-                    .ok_or(format!("No BiasPoint Data for Current {}", crt))?
+                    .ok_or_else(|| NoData(format!("No BiasPoint Data for Current {}", crt)))?
                     .to_vec();
+                let Fit = p.Fit_Current.get(&crt);
                     result.insert(
                     crt.to_string(),
                     serde_json::json!({
@@ -351,73 +586,106 @@ pub fn GetRTCommand(TabName: String) -> Result<serde_json::Value, String> {
                         "R_tes": I_bias,
                         "Alpha": Alpha,
                         "BiasPoint": BiasPoint,
+                        "Fit": Fit,
                     }),
                 );
             }
             Ok(serde_json::Value::Object(result))
         }
-        _ => Err("Invalid tab or processor type".into()),
+        _ => Err(WrongTab("Invalid tab or processor type")),
     }
 }
 
 #[tauri::command]
-pub async fn AnalyzePulseFolderPreCommand(tabName: String) -> Result<String, String> {
+pub async fn AnalyzePulseFolderPreCommand(tabName: String) -> TESResult<String> {
     let result = tokio::task::spawn_blocking(move || {
-        let mut map = PROCESSORS.lock().map_err(|_| "Failed to lock processor map")?;
-        match map.get_mut(&tabName) {
-            Some(TabProcessor::Pulse(p)) => p.AnalyzePulseFolderPre(),
-            _ => Err("Tab is not an Pulse Processor".to_string()),
+        let processor = GetProcessor(&tabName)?;
+        let mut tab = processor.lock().map_err(|_| LockErr("processor"))?;
+        match &mut *tab {
+            TabProcessor::Pulse(p) => p.AnalyzePulseFolderPre(),
+            _ => Err(WrongTab("Tab is not an Pulse Processor")),
         }
     })
         .await
-        .map_err(|e| format!("Join error: {}", e))?;  // 二重Resultのflatten
+        .map_err(|e| TESErrorS::new(TESErr::AnalysisErr, format!("Join error: {}", e)))?;  // 二重Resultのflatten
     result
 }
 
 #[tauri::command]
-pub async fn AnalyzePulseFolderCommand(window: tauri::Window, tab_name: String) -> Result<(), String> {
-    let result = tokio::task::spawn_blocking(move || {
-        let mut map = PROCESSORS.lock().map_err(|_| "Failed to lock processor map")?;
-        match map.get_mut(&tab_name) {
-            Some(TabProcessor::Pulse(p)) => {
-                // クロージャを定義
-                let on_channel_done = |done: u32, total: u32, ch: u32| {
-                    let _ = window.emit(
-                        "pulse-channel-done",
-                        serde_json::json!({ "done": done, "total": total, "channel": ch })
-                    );
-                };
-                let on_pulse_progress = |progress: u32, ch: u32| {
-                    if let Err(e) = window.emit("pulse-progress", serde_json::json!({ "progress": progress, "channel": ch })) {
-                        eprintln!("Failed to emit pulse-progress: {}", e);
-                    }
-                };
-                p.AnalyzePulseFolder(on_channel_done, on_pulse_progress)
-            },
-            _ => Err("Tab is not an IV Processor".to_string()),
-        }
-    }).await.map_err(|e| format!("Join error: {}", e))?;
-    result
+pub async fn AnalyzePulseFolderCommand(window: tauri::Window, tab_name: String) -> TESResult<()> {
+    let emitter = window.clone();
+    let tab_id = tab_name.clone();
+    RunJob(window, tab_name, "AnalyzePulseFolder", move |tab, job| match tab {
+        TabProcessor::Pulse(p) => {
+            let done_channels = Cell::new(0u32);
+            let total_channels = Cell::new(1u32);
+            // クロージャを定義
+            let on_channel_done = |done: u32, total: u32, ch: u32| {
+                done_channels.set(done);
+                total_channels.set(total.max(1));
+                job.SetProgress(done as f64 * 100.0 / total.max(1) as f64);
+                let _ = emitter.emit(
+                    "pulse-channel-done",
+                    serde_json::json!({ "tab": tab_id, "done": done, "total": total, "channel": ch })
+                );
+            };
+            let on_pulse_progress = |progress: u32, ch: u32| {
+                job.SetProgress(
+                    (done_channels.get() as f64 + progress as f64 / 100.0) * 100.0
+                        / total_channels.get() as f64,
+                );
+                if let Err(e) = emitter.emit("pulse-progress", serde_json::json!({ "tab": tab_id, "progress": progress, "channel": ch })) {
+                    eprintln!("Failed to emit pulse-progress: {}", e);
+                }
+            };
+            let result = p.AnalyzePulseFolder(&job.Cancel, on_channel_done, on_pulse_progress);
+            // 途中で止まっても、そこまでの除外数は知らせる
+            let _ = emitter.emit(
+                "pulse-rejected-summary",
+                serde_json::json!({ "tab": tab_id, "summaries": p.RejectSummaries() })
+            );
+            result
+        },
+        _ => Err(WrongTab("Tab is not an Pulse Processor")),
+    })
+    .await
+}
+
+#[tauri::command]
+pub fn GetJobCommand(JobId: u64) -> TESResult<serde_json::Value> {
+    let job = Job::GetJob(JobId)
+        .ok_or_else(|| TESErrorS::new(TESErr::JobNotFound, format!("Job {} not found", JobId)))?;
+    ToJson(job)
+}
+
+#[tauri::command]
+pub fn GetJobsCommand(TabName: Option<String>) -> TESResult<serde_json::Value> {
+    ToJson(Job::ListJobs(TabName.as_deref()))
+}
+
+#[tauri::command]
+pub fn CancelJobCommand(JobId: u64) -> TESResult<()> {
+    Job::CancelJob(JobId)
 }
 
 
 #[tauri::command]
-pub fn GetPulseInfoCommand(TabName: String) -> Result<serde_json::Value, String> {
-    let map = PROCESSORS.lock().unwrap();
-    match map.get(&TabName) {
-        Some(TabProcessor::Pulse(p)) => {
+pub fn GetPulseInfoCommand(TabName: String) -> TESResult<serde_json::Value> {
+    let processor = GetProcessor(&TabName)?;
+    let tab = TryLock(&processor, &TabName)?;
+    match &*tab {
+        TabProcessor::Pulse(p) => {
             let mut outer = serde_json::Map::new();
 
             for &ch in &p.Channels {
                 let infos = p
                     .PulseInfosCH
                     .get(&ch)
-                    .ok_or(format!("No pulse info for channel {}", ch))?;
+                    .ok_or_else(|| NoData(format!("No pulse info for channel {}", ch)))?;
 
                 let mut channel_map = serde_json::Map::new();
                 for (&index, info) in infos {
-                    let json_value = serde_json::to_value(info)
-                        .map_err(|e| format!("Serialization error: {}", e))?;
+                    let json_value = ToJson(info)?;
                     channel_map.insert(index.to_string(), json_value);
                 }
 
@@ -426,7 +694,189 @@ pub fn GetPulseInfoCommand(TabName: String) -> Result<serde_json::Value, String>
 
             Ok(serde_json::Value::Object(outer))
         }
-        _ => Err("Invalid tab or processor type".into()),
+        _ => Err(WrongTab("Invalid tab or processor type")),
+    }
+}
+
+#[tauri::command]
+pub fn SelectPulsesCommand(TabName: String, Channel: Option<u32>) -> TESResult<serde_json::Value> {
+    let processor = GetProcessor(&TabName)?;
+    let tab = TryLock(&processor, &TabName)?;
+    match &*tab {
+        TabProcessor::Pulse(p) => {
+            let mut channels: Vec<u32> = match Channel {
+                Some(ch) => vec![ch],
+                None => p.Channels.iter().cloned().collect(),
+            };
+            channels.sort();
+
+            let mut outer = serde_json::Map::new();
+            for ch in channels {
+                let selection = p.SelectPulses(&ch)?;
+                outer.insert(
+                    ch.to_string(),
+                    ToJson(selection)?,
+                );
+            }
+            Ok(serde_json::Value::Object(outer))
+        }
+        _ => Err(WrongTab("Invalid tab or processor type")),
+    }
+}
+
+#[tauri::command]
+pub fn GetSpectrumCommand(TabName: String, Channel: u32, spectrum: serde_json::Value) -> TESResult<serde_json::Value> {
+    let config: SpectrumConfig = ParseConfig(spectrum, "spectrum")?;
+    let processor = GetProcessor(&TabName)?;
+    let tab = TryLock(&processor, &TabName)?;
+    match &*tab {
+        TabProcessor::Pulse(p) => {
+            let hist = p.Spectrum(&Channel, &config)?;
+            ToJson(hist)
+        }
+        _ => Err(WrongTab("Invalid tab or processor type")),
+    }
+}
+
+#[tauri::command]
+pub fn FitSpectrumPeakCommand(
+    TabName: String,
+    Channel: u32,
+    spectrum: serde_json::Value,
+    peak: serde_json::Value,
+) -> TESResult<serde_json::Value> {
+    let config: SpectrumConfig = ParseConfig(spectrum, "spectrum")?;
+    let peak: PeakFitConfig = ParseConfig(peak, "peak")?;
+    let processor = GetProcessor(&TabName)?;
+    let tab = TryLock(&processor, &TabName)?;
+    match &*tab {
+        TabProcessor::Pulse(p) => {
+            let hist = p.Spectrum(&Channel, &config)?;
+            let fit = FitPeak(&hist, &peak).Context(TESErr::AnalysisErr, "Failed to fit the peak")?;
+            Ok(serde_json::json!({ "Histogram": hist, "Fit": fit }))
+        }
+        _ => Err(WrongTab("Invalid tab or processor type")),
+    }
+}
+
+#[tauri::command]
+pub fn CalibrateEnergyCommand(TabName: String, Channel: u32, calibration: serde_json::Value) -> TESResult<serde_json::Value> {
+    let config: EnergyCalibrationConfig = ParseConfig(calibration, "energy calibration")?;
+    let processor = GetProcessor(&TabName)?;
+    let mut tab = TryLock(&processor, &TabName)?;
+    match &mut *tab {
+        TabProcessor::Pulse(p) => {
+            let result = p.CalibrateEnergy(&Channel, &config)?;
+            ToJson(result)
+        }
+        _ => Err(WrongTab("Invalid tab or processor type")),
+    }
+}
+
+#[tauri::command]
+pub fn GetEnergyCalibrationCommand(TabName: String) -> TESResult<serde_json::Value> {
+    let processor = GetProcessor(&TabName)?;
+    let tab = TryLock(&processor, &TabName)?;
+    match &*tab {
+        TabProcessor::Pulse(p) => ToJson(&p.EnergyCalibrations),
+        _ => Err(WrongTab("Invalid tab or processor type")),
+    }
+}
+
+#[tauri::command]
+pub fn BuildAveragePulseCommand(TabName: String, Channel: u32, template: serde_json::Value) -> TESResult<serde_json::Value> {
+    let config: TemplateConfig = ParseConfig(template, "template")?;
+    let processor = GetProcessor(&TabName)?;
+    let mut tab = TryLock(&processor, &TabName)?;
+    match &mut *tab {
+        TabProcessor::Pulse(p) => {
+            let result = p.BuildAveragePulse(&Channel, &config)?;
+            ToJson(result)
+        }
+        _ => Err(WrongTab("Invalid tab or processor type")),
+    }
+}
+
+#[tauri::command]
+pub fn AnalyzeCoincidenceCommand(TabName: String, coincidence: serde_json::Value) -> TESResult<serde_json::Value> {
+    let config: CoincidenceConfig = ParseConfig(coincidence, "coincidence")?;
+    let processor = GetProcessor(&TabName)?;
+    let tab = TryLock(&processor, &TabName)?;
+    match &*tab {
+        TabProcessor::Pulse(p) => {
+            let result = p.AnalyzeCoincidence(&config)?;
+            ToJson(result)
+        }
+        _ => Err(WrongTab("Invalid tab or processor type")),
+    }
+}
+
+/// IVTabName を渡すとその IV タブの換算係数で電流雑音を出す
+#[tauri::command]
+pub fn AnalyzeNoiseCommand(
+    TabName: String,
+    Channel: u32,
+    noise: serde_json::Value,
+    IVTabName: Option<String>,
+) -> TESResult<serde_json::Value> {
+    let mut config: NoiseConfig = ParseConfig(noise, "noise")?;
+    if let Some(IVTabName) = IVTabName {
+        // 2 つのタブを同時にロックしないよう、先に IV タブから換算係数を取り出す
+        let processor = GetProcessor(&IVTabName)?;
+        let tab = TryLock(&processor, &IVTabName)?;
+        config.CurrentGain = match &*tab {
+            TabProcessor::IV(iv) => iv.ReadoutGain()?,
+            _ => return Err(WrongTab("Tab is not an IV Processor")),
+        };
+    }
+    let processor = GetProcessor(&TabName)?;
+    let mut tab = TryLock(&processor, &TabName)?;
+    match &mut *tab {
+        TabProcessor::Pulse(p) => {
+            let result = p.AnalyzeNoise(&Channel, &config)?;
+            ToJson(result)
+        }
+        _ => Err(WrongTab("Invalid tab or processor type")),
+    }
+}
+
+#[tauri::command]
+pub fn GetNoiseSpectrumCommand(TabName: String, Channel: u32) -> TESResult<serde_json::Value> {
+    let processor = GetProcessor(&TabName)?;
+    let tab = TryLock(&processor, &TabName)?;
+    match &*tab {
+        TabProcessor::Pulse(p) => {
+            let spectrum = p
+                .NoiseSpectra
+                .get(&Channel)
+                .ok_or_else(|| NoData(format!("No noise spectrum for CH{}", Channel)))?;
+            ToJson(spectrum)
+        }
+        _ => Err(WrongTab("Invalid tab or processor type")),
+    }
+}
+
+/// Channel が None なら全チャンネル
+#[tauri::command]
+pub fn GetRejectedPulsesCommand(TabName: String, Channel: Option<u32>) -> TESResult<serde_json::Value> {
+    let processor = GetProcessor(&TabName)?;
+    let tab = TryLock(&processor, &TabName)?;
+    match &*tab {
+        TabProcessor::Pulse(p) => {
+            let mut channels: Vec<u32> = p
+                .Rejections
+                .keys()
+                .cloned()
+                .filter(|ch| Channel.is_none_or(|c| c == *ch))
+                .collect();
+            channels.sort();
+            let rejected: serde_json::Map<String, serde_json::Value> = channels
+                .into_iter()
+                .map(|ch| (ch.to_string(), serde_json::json!(p.Rejections[&ch])))
+                .collect();
+            Ok(serde_json::json!({ "Summary": p.RejectSummaries(), "Rejected": rejected }))
+        }
+        _ => Err(WrongTab("Invalid tab or processor type")),
     }
 }
 
@@ -435,10 +885,11 @@ pub fn GetPulseAnalysisCommand(
     TabName: String,
     key: u32,
     Channel: u32,
-    ) -> Result<serde_json::Value, String> {
-    let map = PROCESSORS.lock().unwrap();
-    match map.get(&TabName) {
-        Some(TabProcessor::Pulse(p)) => {
+    ) -> TESResult<serde_json::Value> {
+    let processor = GetProcessor(&TabName)?;
+    let tab = TryLock(&processor, &TabName)?;
+    match &*tab {
+        TabProcessor::Pulse(p) => {
             let mut result = serde_json::Map::new();
 
             let path = PathBuf::from(format!(
@@ -456,45 +907,52 @@ pub fn GetPulseAnalysisCommand(
 
             let mut Pulse = LoadBi(Path::new(&path))?;
 
-            let FilteredPulse =PulseProcessor::filtfilt(&p.BesselCoeffs[0],&p.BesselCoeffs[0],&Pulse).map_err(|e| format!("Filter error: {}", e))?;
-            let (PI, PIH, PAH) = crate::PulseProcessor::GetPulseInfo(&PRConfig, &PAConfig,Array1::from( FilteredPulse.clone()))?;
+            let FilteredPulse = p.Filter.Apply(&Pulse).Context(TESErr::AnalysisErr, "Filter error")?;
+            let (mut PI, PIH, PAH) = crate::PulseProcessor::GetPulseInfo(&PRConfig, &PAConfig,Array1::from( FilteredPulse.clone()))
+                .map_err(|e| TESErrorS::AtPath(TESErr::AnalysisErr, &path, e))?;
+            // 最適フィルタの結果は解析済みの値を使う
+            if let Some(stored) = p.PulseInfosCH.get(&Channel).and_then(|infos| infos.get(&key)) {
+                PI.OFAmplitude = stored.OFAmplitude;
+                PI.OFTimeShift = stored.OFTimeShift;
+                PI.Energy = stored.Energy;
+            }
 
             Pulse-=PI.Base;
             let FilteredPulseArray1=Array1::from(FilteredPulse)-PI.Base;
 
             result.insert(
                 "Time".to_string(),
-                serde_json::to_value(Time).map_err(|e| e.to_string())?,
+                ToJson(Time)?,
             );
 
             // Pulse と FilteredPulse を JSON に変換して挿入
             result.insert(
                 "Pulse".to_string(),
-                serde_json::to_value(Pulse.to_vec()).map_err(|e| e.to_string())?,
+                ToJson(Pulse.to_vec())?,
             );
             result.insert(
                 "FilteredPulse".to_string(),
-                serde_json::to_value(FilteredPulseArray1.to_vec()).map_err(|e| e.to_string())?,
+                ToJson(FilteredPulseArray1.to_vec())?,
             );
 
             // PI, PIH, PAH は Serialize を derive している前提
             result.insert(
                 "PI".to_string(),
-                serde_json::to_value(PI).map_err(|e| e.to_string())?,
+                ToJson(PI)?,
             );
             result.insert(
                 "PIH".to_string(),
-                serde_json::to_value(PIH).map_err(|e| e.to_string())?,
+                ToJson(PIH)?,
             );
             result.insert(
                 "PAH".to_string(),
-                serde_json::to_value(PAH).map_err(|e| e.to_string())?,
+                ToJson(PAH)?,
             );
 
             // result をファイルに書き込む
             let json_value = serde_json::Value::Object(result.clone()); // clone は書き込む用
             let json_string = serde_json::to_string_pretty(&json_value)
-                .map_err(|e| format!("Failed to serialize JSON: {}", e))?;
+                .map_err(|e| TESErrorS::new(TESErr::SerializeErr, format!("Failed to serialize JSON: {}", e)))?;
 
             // 保存先のパスを決める（例: 同じディレクトリに CH{channel}_{key}.json で保存）
             let json_path = PathBuf::from(format!(
@@ -506,49 +964,53 @@ pub fn GetPulseAnalysisCommand(
             ));
 
             // ファイルに書き込む
-            fs::write(&json_path, json_string)
-                .map_err(|e| format!("Failed to write JSON file: {}", e))?;
+            WriteFile(&json_path, json_string)?;
 
             Ok(serde_json::Value::Object(result))
         }
-        _ => Err("Invalid tab or processor type".into()),
+        _ => Err(WrongTab("Invalid tab or processor type")),
     }
 }
 
 #[tauri::command]
-pub fn SaveConfigCommand(TabName:String,json:serde_json::Value) -> Result<(), String> {
-    let mut map = PROCESSORS.lock().unwrap();
-    match map.get_mut(&TabName) {
-        Some(TabProcessor::Pulse(p)) => {
+pub fn SaveConfigCommand(TabName:String,json:serde_json::Value) -> TESResult<()> {
+    let processor = GetProcessor(&TabName)?;
+    let mut tab = TryLock(&processor, &TabName)?;
+    match &mut *tab {
+        TabProcessor::Pulse(p) => {
             p.SaveConfig(json)?;
             Ok(())
         }
-        _ => Err("Invalid tab or processor type".into()),
+        _ => Err(WrongTab("Invalid tab or processor type")),
     }
 }
 #[tauri::command]
-pub fn GetConfigCommand(TabName:String)-> Result<serde_json::Value, String> {
-    let map = PROCESSORS.lock().unwrap();
-    match map.get(&TabName) {
-        Some(TabProcessor::Pulse(p)) => {
+pub fn GetConfigCommand(TabName:String)-> TESResult<serde_json::Value> {
+    let processor = GetProcessor(&TabName)?;
+    let tab = TryLock(&processor, &TabName)?;
+    match &*tab {
+        TabProcessor::Pulse(p) => {
             let json_value = serde_json::json!({
             "Readout": p.PRConfig,
-            "Analysis": p.PAConfig
+            "Analysis": p.PAConfig,
+            "Backend": p.Backend,
+            "Cuts": p.Cuts
         });
             Ok(json_value)
         }
-        _=> Err("Invalid tab or processor type".into()),
+        _=> Err(WrongTab("Invalid tab or processor type")),
     }
 }
 
 #[tauri::command]
-pub fn ResetPreResultCommand(TabName:String) -> Result<(), String> {
-    let mut map = PROCESSORS.lock().unwrap();
-    match map.get_mut(&TabName) {
-        Some(TabProcessor::Pulse(p)) => {
+pub fn ResetPreResultCommand(TabName:String) -> TESResult<()> {
+    let processor = GetProcessor(&TabName)?;
+    let mut tab = TryLock(&processor, &TabName)?;
+    match &mut *tab {
+        TabProcessor::Pulse(p) => {
             p.ResetPreResult()?;
             Ok(())
         }
-        _ => Err("Invalid tab or processor type".into()),
+        _ => Err(WrongTab("Invalid tab or processor type")),
     }
 }
\ No newline at end of file
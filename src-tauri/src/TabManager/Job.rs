@@ -0,0 +1,134 @@
+#![allow(non_snake_case)]
+// 時間のかかる解析をジョブとして登録し、進捗の確認と中断をできるようにする
+use crate::TES_Err::{TESErr, TESErrorS, TESResult};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq)]
+pub enum JobStatus {
+    Running,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct JobS {
+    pub Id: u64,
+    pub TabName: String,
+    pub Kind: String,
+    pub Status: JobStatus,
+    /// 0..100
+    pub Progress: f64,
+    /// 失敗した時のエラー
+    pub Message: String,
+    pub Error: Option<TESErrorS>,
+    #[serde(skip)]
+    Cancel: Arc<AtomicBool>,
+}
+
+static JOBS: LazyLock<Mutex<HashMap<u64, JobS>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+static NEXT_JOB_ID: AtomicU64 = AtomicU64::new(1);
+
+/// 実行中のジョブから進捗の報告と中断の確認をするためのもの
+#[derive(Debug, Clone)]
+pub struct JobHandleS {
+    pub Id: u64,
+    pub Cancel: Arc<AtomicBool>,
+}
+
+impl JobHandleS {
+    pub fn SetProgress(&self, Progress: f64) {
+        if let Ok(mut jobs) = JOBS.lock() {
+            if let Some(job) = jobs.get_mut(&self.Id) {
+                job.Progress = Progress.clamp(0.0, 100.0);
+            }
+        }
+    }
+
+    pub fn IsCancelled(&self) -> bool {
+        self.Cancel.load(Ordering::SeqCst)
+    }
+}
+
+pub fn StartJob(TabName: &str, Kind: &str) -> JobHandleS {
+    let Id = NEXT_JOB_ID.fetch_add(1, Ordering::SeqCst);
+    let Cancel = Arc::new(AtomicBool::new(false));
+    let job = JobS {
+        Id,
+        TabName: TabName.to_string(),
+        Kind: Kind.to_string(),
+        Status: JobStatus::Running,
+        Progress: 0.0,
+        Message: String::new(),
+        Error: None,
+        Cancel: Arc::clone(&Cancel),
+    };
+    if let Ok(mut jobs) = JOBS.lock() {
+        jobs.insert(Id, job);
+    }
+    JobHandleS { Id, Cancel }
+}
+
+/// 結果に応じた最後の状態を返し、一覧から外す (中断を頼まれた後のエラーは Cancelled とする)
+///
+/// 終わったジョブの状態は job-finished で知らせるので、残しておかない
+pub fn FinishJob<T>(handle: &JobHandleS, result: &TESResult<T>) -> Option<JobS> {
+    let mut job = JOBS.lock().ok()?.remove(&handle.Id)?;
+    match result {
+        Ok(_) => {
+            job.Status = JobStatus::Completed;
+            job.Progress = 100.0;
+        }
+        Err(e) if handle.IsCancelled() || e.Code == TESErr::Cancelled => {
+            job.Status = JobStatus::Cancelled
+        }
+        Err(e) => {
+            job.Status = JobStatus::Failed;
+            job.Message = e.to_string();
+            job.Error = Some(e.clone());
+        }
+    }
+    Some(job)
+}
+
+pub fn GetJob(Id: u64) -> Option<JobS> {
+    JOBS.lock().ok()?.get(&Id).cloned()
+}
+
+/// 実行中のジョブ (TabName が None なら全てのタブ, Id 順)
+pub fn ListJobs(TabName: Option<&str>) -> Vec<JobS> {
+    let Ok(jobs) = JOBS.lock() else {
+        return Vec::new();
+    };
+    let mut list: Vec<JobS> = jobs
+        .values()
+        .filter(|job| TabName.is_none_or(|name| job.TabName == name))
+        .cloned()
+        .collect();
+    list.sort_by_key(|job| job.Id);
+    list
+}
+
+pub fn CancelJob(Id: u64) -> TESResult<()> {
+    let jobs = JOBS
+        .lock()
+        .map_err(|_| TESErrorS::new(TESErr::LockErr, "Failed to lock job map"))?;
+    // 終わったジョブは一覧に残らない
+    let job = jobs
+        .get(&Id)
+        .ok_or_else(|| TESErrorS::new(TESErr::JobNotFound, format!("Job {} is not running", Id)))?;
+    job.Cancel.store(true, Ordering::SeqCst);
+    Ok(())
+}
+
+/// タブを閉じる時などに、そのタブの実行中のジョブを全て止める
+pub fn CancelTabJobs(TabName: &str) {
+    if let Ok(jobs) = JOBS.lock() {
+        jobs.values()
+            .filter(|job| job.TabName == TabName && job.Status == JobStatus::Running)
+            .for_each(|job| job.Cancel.store(true, Ordering::SeqCst));
+    }
+}
@@ -1,83 +1,668 @@
-use std::io::{self, Write};
-use tes_gui_lib::TabManager;
-fn main() {
-    println!("Welcome to Processor CLI! Type 'help' to see commands.");
-
-    loop {
-        print!("> ");
-        io::stdout().flush().unwrap();
-
-        let mut input = String::new();
-        if io::stdin().read_line(&mut input).is_err() {
-            println!("Failed to read input");
-            continue;
+#![allow(non_snake_case)]
+// GUI なしで解析するためのコマンドライン (結果は stdout、進捗とエラーは stderr)
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use tes_gui_lib::Config::{PulseProcessorConfig, TESAnalysisConfig};
+use tes_gui_lib::DataProcessor::CsvField;
+use tes_gui_lib::PulseProcessor::{PulseInfoS, PulseProcessorS};
+use tes_gui_lib::TESAnalyzer::Electrothermal::BiasPointS;
+use tes_gui_lib::TESAnalyzer::IV::IVProcessorS;
+use tes_gui_lib::TESAnalyzer::RT::RTProcessorS;
+use tes_gui_lib::TES_Err::{TESErr, TESErrorS, TESResult};
+use tes_gui_lib::TabManager::FindFolderType;
+
+#[derive(Parser)]
+#[command(
+    name = "tes-cli",
+    about = "TES の IV / RT / Pulse フォルダを GUI なしで解析する",
+    after_help = "終了コード:\n  0  成功\n  1  解析に失敗\n  2  引数や設定の誤り\n  3  入力のフォルダやファイルが無い・不正\n  4  結果を書き込めない\n  130  中断"
+)]
+struct CliS {
+    #[command(subcommand)]
+    Command: CommandS,
+    /// 出力形式
+    #[arg(long, value_enum, default_value_t = Format::Json, global = true)]
+    format: Format,
+    /// 結果を書き込むファイル (省略すると stdout)
+    #[arg(short, long, global = true)]
+    output: Option<PathBuf>,
+    /// 進捗を表示しない
+    #[arg(short, long, global = true)]
+    quiet: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum Format {
+    Json,
+    Csv,
+}
+
+#[derive(Subcommand)]
+enum CommandS {
+    /// フォルダの種類 (IV / RT / Pulse) を判定する
+    Detect { folder: PathBuf },
+    #[command(subcommand)]
+    Iv(IvCommandS),
+    #[command(subcommand)]
+    Rt(RtCommandS),
+    #[command(subcommand)]
+    Pulse(PulseCommandS),
+}
+
+#[derive(Subcommand)]
+enum IvCommandS {
+    /// IV を読み込んで R_TES を計算する
+    Analyze {
+        folder: PathBuf,
+        #[command(flatten)]
+        overrides: OverrideS,
+    },
+    /// 飛びを補正して Calibration/ に保存する
+    Calibrate {
+        folder: PathBuf,
+        /// 補正する温度 (mK)
+        #[arg(long)]
+        temp: u32,
+        /// 補正範囲の I_bias (uA)
+        #[arg(long)]
+        start: f64,
+        #[arg(long)]
+        end: f64,
+        /// 範囲内の飛びをすべて補正する
+        #[arg(long)]
+        multiple: bool,
+        #[command(flatten)]
+        overrides: OverrideS,
+    },
+    /// 全温度の飛びを自動で探して補正し、Calibration/ に保存する
+    AutoCalibrate {
+        folder: PathBuf,
+        #[command(flatten)]
+        overrides: OverrideS,
+    },
+    /// キャリブレーション履歴を表示する (--jump でその段に戻す)
+    History {
+        folder: PathBuf,
+        /// 移る段のインデックス
+        #[arg(long)]
+        jump: Option<usize>,
+        /// 今の段に付ける名前 (空文字で外す)
+        #[arg(long)]
+        label: Option<String>,
+        #[command(flatten)]
+        overrides: OverrideS,
+    },
+    /// RT の α から各バイアス点のループゲイン・時定数・電流応答度を求める
+    Electrothermal {
+        folder: PathBuf,
+        /// α を取る RT フォルダ
+        #[arg(long)]
+        rt: PathBuf,
+        /// α を取る RT の電流 (uA, 省略するとフィットした中で一番小さい電流)
+        #[arg(long)]
+        current: Option<u32>,
+        #[command(flatten)]
+        overrides: OverrideS,
+    },
+}
+
+#[derive(Subcommand)]
+enum RtCommandS {
+    /// RT を読み込んで電流ごとにフィットする
+    Fit {
+        folder: PathBuf,
+        #[command(flatten)]
+        overrides: OverrideS,
+    },
+}
+
+#[derive(Subcommand)]
+enum PulseCommandS {
+    /// 追加・変更されたパルスを解析して Info.csv を更新する
+    Analyze {
+        folder: PathBuf,
+        #[command(flatten)]
+        overrides: OverrideS,
+    },
+    /// 解析済みの Info.csv を書き出す
+    Export {
+        folder: PathBuf,
+        /// 書き出すチャンネル (省略すると全チャンネル)
+        #[arg(long = "channel")]
+        channels: Vec<u32>,
+        /// カットを通ったパルスだけ書き出す
+        #[arg(long)]
+        selected: bool,
+    },
+}
+
+/// 設定の上書き (--config で丸ごと置き換えてから --set を当てる)
+#[derive(Args)]
+struct OverrideS {
+    /// 設定の JSON ファイル
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// 設定の一部を上書きする (例: --set Analysis.BaseLinePreSample=500 --set Backend.Type=Mock)
+    #[arg(long = "set", value_name = "KEY=VALUE")]
+    sets: Vec<String>,
+}
+
+/// 進捗を stderr に出す
+struct ProgressS {
+    quiet: bool,
+}
+
+impl ProgressS {
+    fn Step(&self, message: &str) {
+        if !self.quiet {
+            eprintln!("{}", message);
+        }
+    }
+}
+
+/// 出力する結果 (JSON と CSV の両方の形を持つ)
+struct OutputS {
+    Json: Value,
+    Csv: String,
+}
+
+fn ConfigErr(message: impl Into<String>) -> TESErrorS {
+    TESErrorS::new(TESErr::ConfigErr, message)
+}
+
+/// 値は JSON として読めればその型、読めなければ文字列にする
+fn SetValue(config: &mut Value, assignment: &str) -> TESResult<Vec<String>> {
+    let (key, raw) = assignment
+        .split_once('=')
+        .ok_or_else(|| ConfigErr(format!("--set '{}' is not KEY=VALUE", assignment)))?;
+    let value = serde_json::from_str(raw).unwrap_or_else(|_| Value::String(raw.to_string()));
+    let keys: Vec<String> = key.split('.').map(str::to_string).collect();
+    let (last, parents) = keys
+        .split_last()
+        .ok_or_else(|| ConfigErr(format!("--set '{}' has no key", assignment)))?;
+    let mut target = config;
+    for k in parents {
+        target = target
+            .as_object_mut()
+            .ok_or_else(|| ConfigErr(format!("'{}' is not a section", key)))?
+            .entry(k.clone())
+            .or_insert_with(|| Value::Object(Map::new()));
+    }
+    target
+        .as_object_mut()
+        .ok_or_else(|| ConfigErr(format!("'{}' is not a section", key)))?
+        .insert(last.clone(), value);
+    Ok(keys)
+}
+
+/// --config と --set を当てた設定を T として読む (綴り間違いのキーはエラーにする)
+fn Override<T>(base: Value, overrides: &OverrideS) -> TESResult<(T, Value)>
+where
+    T: serde::de::DeserializeOwned + serde::Serialize,
+{
+    let mut config = match &overrides.config {
+        Some(path) => {
+            let file = std::fs::File::open(path).map_err(|e| TESErrorS::Open(path, &e))?;
+            serde_json::from_reader(file).map_err(|e| {
+                TESErrorS::AtPath(
+                    TESErr::JsonParseErr,
+                    path,
+                    format!("Failed to parse config\n{}", e),
+                )
+            })?
+        }
+        None => base,
+    };
+    let mut keys = vec![];
+    for assignment in &overrides.sets {
+        keys.push(SetValue(&mut config, assignment)?);
+    }
+    let parsed: T = serde_json::from_value(config)
+        .map_err(|e| TESErrorS::new(TESErr::JsonParseErr, format!("Invalid config\n{}", e)))?;
+    // 知らないキーは読む時に捨てられるので、書き戻して残っているか確かめる
+    let normalized = serde_json::to_value(&parsed)
+        .map_err(|e| TESErrorS::new(TESErr::SerializeErr, format!("Serialization error: {}", e)))?;
+    for key in keys {
+        if key.iter().try_fold(&normalized, |v, k| v.get(k)).is_none() {
+            return Err(ConfigErr(format!("Unknown config key '{}'", key.join("."))));
         }
+    }
+    Ok((parsed, normalized))
+}
+
+fn OverrideTES(current: &TESAnalysisConfig, overrides: &OverrideS) -> TESResult<TESAnalysisConfig> {
+    let base = serde_json::to_value(current)
+        .map_err(|e| TESErrorS::new(TESErr::SerializeErr, format!("Serialization error: {}", e)))?;
+    Ok(Override(base, overrides)?.0)
+}
+
+fn Join<T: ToString>(values: &[T]) -> String {
+    values
+        .iter()
+        .map(T::to_string)
+        .collect::<Vec<_>>()
+        .join(",")
+}
 
-        let args: Vec<_> = input.trim().split_whitespace().collect();
-        if args.is_empty() {
-            continue;
+fn Detect(folder: &Path) -> TESResult<OutputS> {
+    let Type = FindFolderType(folder.to_string_lossy().to_string())?;
+    Ok(OutputS {
+        Json: json!({ "Folder": folder, "Type": Type }),
+        Csv: format!("Folder,Type\n{},{}\n", folder.display(), Type),
+    })
+}
+
+/// 温度ごとの I_bias, V_out, R_tes, P_tes と分類 (R_tes は 2 点目から)
+fn IVOutput(iv: &IVProcessorS) -> TESResult<OutputS> {
+    let mut temps = Map::new();
+    let mut lines = vec!["Temp,I_bias,V_out,R_tes,P_tes,Branch".to_string()];
+    for temp in &iv.Temps {
+        let missing = |what: &str| {
+            TESErrorS::new(
+                TESErr::NotAnalyzed,
+                format!("No {} data for temp {}", what, temp),
+            )
+        };
+        let I_bias = iv.I_bias_temps.get(temp).ok_or_else(|| missing("I_bias"))?;
+        let V_out = iv
+            .V_out_history_temps
+            .get(temp)
+            .and_then(|history| history.get(iv.CurrentIndex))
+            .ok_or_else(|| missing("V_out"))?;
+        let R_tes = iv.R_tes_temps.get(temp).ok_or_else(|| missing("R_tes"))?;
+        let P_tes = iv.P_tes_temps.get(temp).ok_or_else(|| missing("P_tes"))?;
+        let branch = iv.Branches.get(temp).ok_or_else(|| missing("Branch"))?;
+        for (i, (I, V)) in I_bias.iter().zip(V_out.iter()).enumerate() {
+            let R = i.checked_sub(1).and_then(|j| R_tes.get(j));
+            lines.push(format!(
+                "{},{},{},{},{},{:?}",
+                temp,
+                I,
+                V,
+                R.map_or(String::new(), f64::to_string),
+                P_tes[i],
+                branch.Points[i]
+            ));
         }
+        temps.insert(
+            temp.to_string(),
+            json!({
+                "I_bias": I_bias.to_vec(),
+                "V_out": V_out.to_vec(),
+                "R_tes": R_tes.to_vec(),
+                "P_tes": P_tes.to_vec(),
+                "Branch": branch,
+            }),
+        );
+    }
+    Ok(OutputS {
+        Json: json!({
+            "Eta": iv.GetEta()?,
+            "R_par": iv.R_par,
+            "CalibrationIndex": iv.CurrentIndex,
+            "CalibrationHistory": iv.History,
+            "CalibrationHistoryWarning": iv.HistoryWarning,
+            "Temps": temps,
+            "Psat": iv.Psat,
+            "Thermal": iv.Thermal.as_ref().ok(),
+        }),
+        Csv: lines.join("\n") + "\n",
+    })
+}
+
+fn LoadIV(folder: &Path, overrides: &OverrideS, progress: &ProgressS) -> TESResult<IVProcessorS> {
+    let mut iv = IVProcessorS::new();
+    iv.SetDataPath(folder)?;
+    iv.SetConfig(OverrideTES(iv.Config(), overrides)?);
+    progress.Step(&format!("Analyzing IV folder {}", folder.display()));
+    iv.AnalyzeIVFolder(&AtomicBool::new(false))?;
+    if let Some(warning) = &iv.HistoryWarning {
+        progress.Step(&warning.to_string());
+    }
+    progress.Step(&format!("Loaded {} temperatures", iv.Temps.len()));
+    Ok(iv)
+}
 
-        match args[0] {
-            "exit" => break,
-            "help" => {
-                println!("Available commands:");
-                println!("  register <tab> <type>");
-                println!("  setpath <tab> <path>");
-                println!("  analyze <tab>");
-                println!("  getiv <tab>");
-                println!("  getpulseinfo <tab>");
-                println!("  getpulseana <tab> <key> <ch>");
-                println!("  savefig <tab> <path>");
+fn RunIV(command: &IvCommandS, progress: &ProgressS) -> TESResult<OutputS> {
+    match command {
+        IvCommandS::Analyze { folder, overrides } => {
+            IVOutput(&LoadIV(folder, overrides, progress)?)
+        }
+        IvCommandS::Calibrate {
+            folder,
+            temp,
+            start,
+            end,
+            multiple,
+            overrides,
+        } => {
+            let mut iv = LoadIV(folder, overrides, progress)?;
+            if !iv.Temps.contains(temp) {
+                return Err(ConfigErr(format!("No IV data at {}mK", temp)));
             }
-            "rg" if args.len() == 3 => {
-                match TabManager::RegisterProcessor(args[1].into(), args[2].into()) {
-                    Ok(_) => println!("Registered."),
-                    Err(e) => println!("Error: {}", e),
-                }
+            progress.Step(&format!(
+                "Calibrating {}mK between {}uA and {}uA",
+                temp, start, end
+            ));
+            if *multiple {
+                iv.CalibrateMultipleJump(*temp, *start, *end)?;
+            } else {
+                iv.CalibrateSingleJump(*temp, *start, *end)?;
             }
-            "sp" if args.len() == 3 => {
-                match TabManager::SetDataPathCommand(args[1].into(), args[2].into()) {
-                    Ok(_) => println!("Path set."),
-                    Err(e) => println!("Error: {}", e),
-                }
+            progress.Step(&format!("Saved {}", folder.join("Calibration").display()));
+            IVOutput(&iv)
+        }
+        IvCommandS::AutoCalibrate { folder, overrides } => {
+            let mut iv = LoadIV(folder, overrides, progress)?;
+            progress.Step("Searching flux jumps in all temperatures");
+            let reports = iv.CalibrateAutoJump()?;
+            for report in reports.iter() {
+                progress.Step(&format!("{}mK: corrected {} jumps", report.Temp, report.Jumps.len()));
             }
-            /*"anl" if args.len() == 2 => {
-                match TabManager::AnalyzeFolderCommand(args[1].into()) {
-                    Ok(_) => println!("Analysis done."),
-                    Err(e) => println!("Error: {}", e),
-                }
+            let mut output = IVOutput(&iv)?;
+            output.Json["FluxJumps"] = json!(reports);
+            Ok(output)
+        }
+        IvCommandS::History {
+            folder,
+            jump,
+            label,
+            overrides,
+        } => {
+            let mut iv = LoadIV(folder, overrides, progress)?;
+            if let Some(index) = jump {
+                iv.JumpToStep(*index)?;
+                progress.Step(&format!("Moved to calibration step {}", index));
             }
-            */
-            "getiv" if args.len() == 2 => {
-                match TabManager::GetIVCommand(args[1].into()) {
-                    Ok(json) => println!("{}", serde_json::to_string_pretty(&json).unwrap()),
-                    Err(e) => println!("Error: {}", e),
-                }
+            if let Some(label) = label {
+                iv.LabelStep(iv.CurrentIndex, label)?;
             }
-            "getrt" if args.len() == 2 => {
-                match TabManager::GetRTCommand(args[1].into()) {
-                    Ok(json) => println!("{}", serde_json::to_string_pretty(&json).unwrap()),
-                    Err(e) => println!("Error: {}", e),
+            let mut lines = vec!["Index,Op,Temp,CalibStart,CalibEnd,Timestamp,Label,Current".to_string()];
+            let Blank = |v: Option<String>| v.unwrap_or_default();
+            for (i, step) in iv.History.iter().enumerate() {
+                lines.push(format!(
+                    "{},{:?},{},{},{},{},{},{}",
+                    i,
+                    step.Op,
+                    Blank(step.Temp.map(|t| t.to_string())),
+                    Blank(step.BiasWindow.map(|w| w.0.to_string())),
+                    Blank(step.BiasWindow.map(|w| w.1.to_string())),
+                    step.Timestamp,
+                    Blank(step.Label.as_ref().map(|l| CsvField(l))),
+                    i == iv.CurrentIndex
+                ));
+            }
+            Ok(OutputS {
+                Json: json!({
+                    "CurrentIndex": iv.CurrentIndex,
+                    "Steps": iv.History,
+                }),
+                Csv: lines.join("\n") + "\n",
+            })
+        }
+        IvCommandS::Electrothermal {
+            folder,
+            rt,
+            current,
+            overrides,
+        } => {
+            let mut rtp = RTProcessorS::new();
+            rtp.SetDataPath(rt)?;
+            rtp.SetConfig(OverrideTES(rtp.Config(), overrides)?);
+            progress.Step(&format!("Analyzing RT folder {}", rt.display()));
+            rtp.AnalyzeRTFolder(&AtomicBool::new(false))?;
+            let Alpha = rtp.AlphaCurve(*current)?;
+            let mut iv = LoadIV(folder, overrides, progress)?;
+            progress.Step(&format!("Using alpha at {}uA", Alpha.Current));
+            let Link = iv.CalculateElectrothermal(&Alpha)?;
+            let mut lines = vec![BiasPointS::CSV_HEADER.to_string()];
+            for temp in iv.Temps.iter() {
+                for p in iv.Electrothermal.get(temp).into_iter().flatten() {
+                    lines.push(p.ToCSVRow(*temp));
                 }
             }
-            "getpulseinfo" if args.len() == 2 => {
-                match TabManager::GetPulseInfoCommand(args[1].into()) {
-                    Ok(json) => println!("{}", serde_json::to_string_pretty(&json).unwrap()),
-                    Err(e) => println!("Error: {}", e),
+            Ok(OutputS {
+                Json: json!({
+                    "AlphaCurrent": Alpha.Current,
+                    "Link": Link,
+                    "Temps": iv.Electrothermal,
+                }),
+                Csv: lines.join("\n") + "\n",
+            })
+        }
+    }
+}
+
+fn RunRT(command: &RtCommandS, progress: &ProgressS) -> TESResult<OutputS> {
+    match command {
+        RtCommandS::Fit { folder, overrides } => {
+            let mut rt = RTProcessorS::new();
+            rt.SetDataPath(folder)?;
+            rt.SetConfig(OverrideTES(rt.Config(), overrides)?);
+            progress.Step(&format!("Analyzing RT folder {}", folder.display()));
+            rt.AnalyzeRTFolder(&AtomicBool::new(false))?;
+            let mut currents: Vec<u32> = rt.Currents.iter().cloned().collect();
+            currents.sort();
+            let mut result = Map::new();
+            let mut lines =
+                vec!["Current,RN,Tc,T1,T2,RN_err,Tc_err,T1_err,T2_err,ReducedChi2".to_string()];
+            for crt in currents {
+                let Fit = rt.Fit_Current.get(&crt);
+                match Fit {
+                    Some(f) => lines.push(format!(
+                        "{},{}",
+                        crt,
+                        Join(&[
+                            f.RN,
+                            f.Tc,
+                            f.T1,
+                            f.T2,
+                            f.RN_err,
+                            f.Tc_err,
+                            f.T1_err,
+                            f.T2_err,
+                            f.ReducedChi2
+                        ])
+                    )),
+                    None => progress.Step(&format!("Fit failed at {}uA", crt)),
                 }
+                result.insert(
+                    crt.to_string(),
+                    json!({
+                        "Temp": rt.Temp_Current.get(&crt),
+                        "R_tes": rt.R_tes_Current.get(&crt),
+                        "Alpha": rt.Alpha_Current.get(&crt),
+                        "BiasPoint": rt.BiasPoints_Current.get(&crt),
+                        "Fit": Fit,
+                    }),
+                );
             }
-            "getpulseana" if args.len() == 4 => {
-                let key: u32 = args[2].parse().unwrap_or(0);
-                let ch: u32 = args[3].parse().unwrap_or(0);
-                match TabManager::GetPulseAnalysisCommand(args[1].into(), key, ch) {
-                    Ok(json) => println!("{}", serde_json::to_string_pretty(&json).unwrap()),
-                    Err(e) => println!("Error: {}", e),
+            Ok(OutputS {
+                Json: Value::Object(result),
+                Csv: lines.join("\n") + "\n",
+            })
+        }
+    }
+}
+
+/// PulseConfig.json に上書きを当てて、解析が必要なチャンネルを調べる
+fn PreparePulse(
+    folder: &Path,
+    overrides: Option<&OverrideS>,
+    progress: &ProgressS,
+) -> TESResult<PulseProcessorS> {
+    let mut p = PulseProcessorS::new();
+    p.SetDataPath(folder)?;
+    let mut config = p.LoadConfig()?;
+    if let Some(overrides) = overrides {
+        config = Override::<PulseProcessorConfig>(config, overrides)?.1;
+    }
+    let state = p.AnalyzePulseFolderPreWith(config)?;
+    let mut channels: Vec<u32> = p.Channels.iter().cloned().collect();
+    channels.sort();
+    progress.Step(&format!("Channels {:?}: {}", channels, state));
+    Ok(p)
+}
+
+fn RunPulse(command: &PulseCommandS, progress: &ProgressS) -> TESResult<OutputS> {
+    match command {
+        PulseCommandS::Analyze { folder, overrides } => {
+            let mut p = PreparePulse(folder, Some(overrides), progress)?;
+            // Ctrl-C はプロセスごと止めるので中断フラグは使わない (CACHE_CHUNK ごとの保存から再開できる)
+            let cancel = Arc::new(AtomicBool::new(false));
+            let mut last = BTreeMap::new();
+            p.AnalyzePulseFolder(
+                &cancel,
+                |done, total, ch| progress.Step(&format!("[{}/{}] CH{}", done, total, ch)),
+                |percent, ch| {
+                    // 10% ごとに表示する
+                    if last.insert(ch, percent / 10) != Some(percent / 10) {
+                        progress.Step(&format!("CH{}: {}%", ch, percent));
+                    }
+                },
+            )?;
+            let summaries = p.RejectSummaries();
+            let mut lines = vec!["Channel,Total,Accepted,Rejected".to_string()];
+            for s in &summaries {
+                lines.push(format!(
+                    "{},{},{},{}",
+                    s.Channel,
+                    s.Total,
+                    s.Total - s.Rejected,
+                    s.Rejected
+                ));
+            }
+            Ok(OutputS {
+                Json: json!({ "Channels": summaries }),
+                Csv: lines.join("\n") + "\n",
+            })
+        }
+        PulseCommandS::Export {
+            folder,
+            channels,
+            selected,
+        } => {
+            let p = PreparePulse(folder, None, progress)?;
+            let mut targets: Vec<u32> = if channels.is_empty() {
+                p.Channels.iter().cloned().collect()
+            } else {
+                channels.clone()
+            };
+            targets.sort();
+            let mut result = Map::new();
+            let mut lines = vec![format!("Channel,{}", PulseInfoS::CSV_HEADER)];
+            for ch in targets {
+                let infos = p.PulseInfosCH.get(&ch).ok_or_else(|| {
+                    TESErrorS::new(
+                        TESErr::NotAnalyzed,
+                        format!(
+                            "CH{} has no Info.csv. Run `tes-cli pulse analyze` first.",
+                            ch
+                        ),
+                    )
+                })?;
+                let mut keys: Vec<u32> = if *selected {
+                    p.SelectPulses(&ch)?.Passed
+                } else {
+                    infos.keys().cloned().collect()
+                };
+                keys.sort();
+                let mut rows = vec![];
+                for key in keys {
+                    let Some(info) = infos.get(&key) else {
+                        continue;
+                    };
+                    lines.push(format!("{},{}", ch, info.ToCSVRow(key)));
+                    let mut row = serde_json::to_value(info).map_err(|e| {
+                        TESErrorS::new(TESErr::SerializeErr, format!("Serialization error: {}", e))
+                    })?;
+                    if let Some(row) = row.as_object_mut() {
+                        row.insert("key".to_string(), json!(key));
+                    }
+                    rows.push(row);
                 }
+                progress.Step(&format!("CH{}: {} pulses", ch, rows.len()));
+                result.insert(ch.to_string(), Value::Array(rows));
             }
-            _ => println!("Unknown or malformed command. Type 'help'."),
+            Ok(OutputS {
+                Json: Value::Object(result),
+                Csv: lines.join("\n") + "\n",
+            })
         }
     }
+}
+
+/// エラーの種類ごとの終了コード (after_help と合わせる)
+fn ErrorExitCode(e: &TESErrorS) -> u8 {
+    match e.Code {
+        TESErr::ConfigErr | TESErr::JsonParseErr => 2,
+        TESErr::FolderNotFound
+        | TESErr::FolderNotAdequate
+        | TESErr::FileNotFound
+        | TESErr::FileOpenErr
+        | TESErr::PulseChannelEmpty
+        | TESErr::NotAnalyzed => 3,
+        TESErr::FolderCreateErr
+        | TESErr::FileCreateErr
+        | TESErr::FileWriteErr
+        | TESErr::FileRemoveErr => 4,
+        TESErr::Cancelled => 130,
+        _ => 1,
+    }
+}
 
-    println!("Bye!");
-}
\ No newline at end of file
+fn Write(cli: &CliS, output: OutputS) -> TESResult<()> {
+    let text = match cli.format {
+        Format::Json => {
+            serde_json::to_string_pretty(&output.Json).map_err(|e| {
+                TESErrorS::new(TESErr::SerializeErr, format!("Serialization error: {}", e))
+            })? + "\n"
+        }
+        Format::Csv => output.Csv,
+    };
+    match &cli.output {
+        Some(path) => std::fs::write(path, text).map_err(|e| {
+            TESErrorS::AtPath(
+                TESErr::FileWriteErr,
+                path,
+                format!("Failed to write file\n{}", e),
+            )
+        }),
+        None => {
+            print!("{}", text);
+            Ok(())
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let cli = CliS::parse();
+    let progress = ProgressS { quiet: cli.quiet };
+    let result = match &cli.Command {
+        CommandS::Detect { folder } => Detect(folder),
+        CommandS::Iv(command) => RunIV(command, &progress),
+        CommandS::Rt(command) => RunRT(command, &progress),
+        CommandS::Pulse(command) => RunPulse(command, &progress),
+    }
+    .and_then(|output| Write(&cli, output));
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            // JSON の時はスクリプトから読めるようにエラーも JSON で出す
+            match cli.format {
+                Format::Json => eprintln!(
+                    "{}",
+                    serde_json::to_string(&e).unwrap_or_else(|_| e.to_string())
+                ),
+                Format::Csv => eprintln!("error: {}", e),
+            }
+            ExitCode::from(ErrorExitCode(&e))
+        }
+    }
+}
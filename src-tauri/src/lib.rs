@@ -1,13 +1,19 @@
 #![allow(nonstandard_style)]
 
-use crate::TabManager::{CalibrateMultipleJumpCommand, CalibrateSingleJumpCommand, FindFolderType, FitRTCommand, GetIVCommand, GetIVIndexInfoCommand, GetPulseAnalysisCommand, GetPulseInfoCommand, GetRTCommand, IVDecrementCommand, IVIncrementCommand, RegisterProcessor, SaveCalibratedCommand, SetDataPathCommand, UnregisterProcessor};
+use crate::TabManager::{CalibrateAutoJumpCommand, CalibrateMultipleJumpCommand, CalibrateSingleJumpCommand, FindFolderType, FitRTCommand, GetIVCommand, GetElectrothermalCommand, GetIVBranchCommand, GetIVHistoryCommand, GetIVIndexInfoCommand, JumpIVHistoryCommand, LabelIVHistoryCommand, GetThermalCommand, GetPulseAnalysisCommand, GetPulseInfoCommand, GetRTCommand, IVDecrementCommand, IVIncrementCommand, RegisterProcessor, SaveCalibratedCommand, SelectPulsesCommand, SetBackendCommand, SetDataPathCommand, UnregisterProcessor};
 use crate::TabManager::{AnalyzeRTFolderCommand,AnalyzeIVFolderCommand, AnalyzePulseFolderPreCommand,AnalyzePulseFolderCommand};
 use crate::TabManager::{GetConfigCommand,SaveConfigCommand,ResetPreResultCommand};
+use crate::TabManager::{GetTESConfigCommand, SaveTESConfigCommand};
+use crate::TabManager::{CancelJobCommand, GetJobCommand, GetJobsCommand};
+use crate::TabManager::{AnalyzeCoincidenceCommand, AnalyzeNoiseCommand, BuildAveragePulseCommand, CalibrateEnergyCommand, FitSpectrumPeakCommand, GetEnergyCalibrationCommand, GetNoiseSpectrumCommand, GetRejectedPulsesCommand, GetSpectrumCommand};
 pub mod Config;
 pub mod DataProcessor;
+pub mod FilterDesign;
+pub mod Fourier;
 pub mod PulseProcessor;
 pub mod PyMod;
 pub mod TESAnalyzer;
+pub mod TES_Err;
 pub mod TabManager;
 
 // Tauri コマンドの例
@@ -27,14 +33,22 @@ pub fn run() {
             RegisterProcessor,
             UnregisterProcessor,
             SetDataPathCommand,
+            SetBackendCommand,
             AnalyzeIVFolderCommand,
             SaveCalibratedCommand,
             CalibrateSingleJumpCommand,
             CalibrateMultipleJumpCommand,
+            CalibrateAutoJumpCommand,
             GetIVCommand,
             IVIncrementCommand,
             IVDecrementCommand,
             GetIVIndexInfoCommand,
+            GetIVHistoryCommand,
+            JumpIVHistoryCommand,
+            LabelIVHistoryCommand,
+            GetThermalCommand,
+            GetIVBranchCommand,
+            GetElectrothermalCommand,
             AnalyzeRTFolderCommand,
             FitRTCommand,
             GetRTCommand,
@@ -42,7 +56,22 @@ pub fn run() {
             AnalyzePulseFolderCommand,
             GetPulseInfoCommand,
             GetPulseAnalysisCommand,
+            SelectPulsesCommand,
+            GetSpectrumCommand,
+            FitSpectrumPeakCommand,
+            CalibrateEnergyCommand,
+            BuildAveragePulseCommand,
+            AnalyzeNoiseCommand,
+            GetNoiseSpectrumCommand,
+            GetRejectedPulsesCommand,
+            AnalyzeCoincidenceCommand,
+            GetJobCommand,
+            GetJobsCommand,
+            CancelJobCommand,
+            GetEnergyCalibrationCommand,
             FindFolderType,
+            GetTESConfigCommand,
+            SaveTESConfigCommand,
                 SaveConfigCommand,
                 GetConfigCommand,
             ResetPreResultCommand
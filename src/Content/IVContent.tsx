@@ -4,18 +4,52 @@ import {invoke} from "@tauri-apps/api/core";
 import {TESGraphRef} from "@/Graph/TESGraph.tsx";
 import {Shape} from "plotly.js"; // TESAGraph本体のimport想定
 import {Button,Description, Dialog, DialogPanel, DialogTitle} from '@headlessui/react'
+import { formatError } from "@/lib/tesError.ts";
+import TESConfig from "@/Graph/TESConfig.tsx";
 
 const ivTabs = [
     { id: "IV", label: "IV", xKey: "I_bias", yKey: "V_out", defaultTitle: "IV Title", defaultXaxis: "$I_{bias}$", defaultYaxis: "$V_{out}$" },
     { id: "IR", label: "IR", xKey: "I_bias", yKey: "R_tes", defaultTitle: "IR Title", defaultXaxis: "$I_{bias}$", defaultYaxis: "$R_{tes}$" },
+    { id: "PI", label: "PI", xKey: "I_bias", yKey: "P_tes", defaultTitle: "PI Title", defaultXaxis: "$I_{bias}$", defaultYaxis: "$P_{tes}[pW]$" },
 ];
 
+type FluxJumpReport = {
+    Temp: number;
+    Jumps: { Index: number; Intervals: number; I_bias: number; Step: number }[];
+    HistoryIndex: number | null;
+};
+
+type CalibrationStep = {
+    Op: "Raw" | "Loaded" | "SingleJump" | "MultipleJump" | "AutoJump";
+    Temp: number | null;
+    BiasWindow: [number, number] | null;
+    Timestamp: number;
+    Label: string | null;
+};
+
+type CalibrationHistory = {
+    CurrentIndex: number;
+    Steps: CalibrationStep[];
+    // History.json を使えずに退避した時の警告
+    Warning: unknown | null;
+};
+
+const opLabels: Record<CalibrationStep["Op"], string> = {
+    Raw: "生データ",
+    Loaded: "読み込み",
+    SingleJump: "単一補正",
+    MultipleJump: "複数補正",
+    AutoJump: "自動補正",
+};
+
 const IVContent = ({ tabId }: { tabId: string }) => {
     const [ivSelecting, setIVSelecting] = useState(false);
     const [ivSelectedRange, setIVSelectedRange] = useState<[number, number] | null>(null);
     const [ivSelectedKeyValue, setIVSelectedKeyValue] = useState<string | null>(null);
     const [sidebarOpen, setSidebarOpen] = useState(true);
     const [ivModalOpen, setIVModalOpen] = useState(false);
+    const [history, setHistory] = useState<CalibrationHistory | null>(null);
+    const [configOpen, setConfigOpen] = useState(false);
 
     const [isLoading, setIsLoading] = useState(true);
     const [IVData, setIVData] = useState<TESAData | null>(null);
@@ -32,7 +66,12 @@ const IVContent = ({ tabId }: { tabId: string }) => {
                         setIVData(res);
                         setIsLoading(false);
                     })
-                    .catch((e) => alert(e));
+                    .catch((e) => alert(formatError(e)));
+                invoke<CalibrationHistory>("GetIVHistoryCommand", { tabName: tabId })
+                    .then((res) => {
+                        if (res.Warning) alert(formatError(res.Warning));
+                    })
+                    .catch((e) => console.error(e));
             })
     }, [tabId]);
 
@@ -75,7 +114,7 @@ const IVContent = ({ tabId }: { tabId: string }) => {
                         .catch((e) => console.error(e));
                 })
                 .catch((e) => {
-                    alert("キャリブレーション中にエラーが発生しました。\n" + e);
+                    alert("キャリブレーション中にエラーが発生しました。\n" + formatError(e));
                 });
         }
         // IV選択モード解除＆全表示復帰
@@ -84,6 +123,58 @@ const IVContent = ({ tabId }: { tabId: string }) => {
         setIVSelectedRange(null);
     };
 
+    const autoCalibrate = () => {
+        invoke<FluxJumpReport[]>("CalibrateAutoJumpCommand", { tabName: tabId })
+            .then((reports) => {
+                const summary = reports
+                    .map((r) => `${r.Temp} mK: ${r.Jumps.length === 0 ? "変更なし" : r.Jumps.map((j) => `${j.I_bias} uA`).join(", ")}`)
+                    .join("\n");
+                alert("自動キャリブレーションの結果\n" + summary);
+                invoke<TESAData>("GetIVCommand", { tabName: tabId })
+                    .then((res) => {
+                        setIVData(res);
+                    })
+                    .catch((e) => console.error(e));
+            })
+            .catch((e) => {
+                alert("キャリブレーション中にエラーが発生しました。\n" + formatError(e));
+            });
+    };
+
+    const openHistory = () => {
+        invoke<CalibrationHistory>("GetIVHistoryCommand", { tabName: tabId })
+            .then((res) => setHistory(res))
+            .catch((e) => alert(formatError(e)));
+    };
+
+    const jumpToStep = (index: number) => {
+        invoke("JumpIVHistoryCommand", { tabName: tabId, index })
+            .then(() => {
+                setHistory((prev) => prev && { ...prev, CurrentIndex: index });
+                invoke<TESAData>("GetIVCommand", { tabName: tabId })
+                    .then((res) => {
+                        setIVData(res);
+                    })
+                    .catch((e) => console.error(e));
+            })
+            .catch((e) => alert(formatError(e)));
+    };
+
+    const labelStep = (index: number, label: string) => {
+        invoke("LabelIVHistoryCommand", { tabName: tabId, index, label })
+            .catch((e) => alert(formatError(e)));
+    };
+
+    // 設定を保存するとバックエンドで R_TES から求め直している
+    const onConfigChange = () => {
+        setConfigOpen(false);
+        invoke<TESAData>("GetIVCommand", { tabName: tabId })
+            .then((res) => {
+                setIVData(res);
+            })
+            .catch((e) => console.error(e));
+    };
+
     const cancelIVSelect = () => {
         setIVSelecting(false);
         setIVSelectedKeyValue(null);
@@ -185,6 +276,75 @@ const IVContent = ({ tabId }: { tabId: string }) => {
                         </div>
                     </Dialog>
 
+                    <Dialog
+                        open={history !== null}
+                        as="div"
+                        onClose={() => setHistory(null)}
+                        className="z-11 focus:outline-none"
+                    >
+                        <div className="fixed inset-0  z-11 bg-opacity-60 backdrop-blur-sm" aria-hidden="true" />
+                        <div className="fixed inset-0 z-11 flex items-center justify-center p-4">
+                            <DialogPanel className="w-full max-w-2xl rounded-xl bg-white/5 p-6 backdrop-blur-2xl">
+                                <DialogTitle className="text-base/7 font-medium text-gray-600 mb-2">
+                                    キャリブレーション履歴
+                                </DialogTitle>
+                                <div className="max-h-96 overflow-y-auto">
+                                    {history?.Steps.map((step, index) => (
+                                        <div
+                                            key={`${index}-${step.Timestamp}`}
+                                            className={`flex items-center gap-2 mb-1 rounded px-2 py-1 text-sm text-gray-600 ${index === history.CurrentIndex ? "bg-blue-100" : ""}`}
+                                        >
+                                            <span className="w-6">{index}</span>
+                                            <span className="w-20">{opLabels[step.Op]}</span>
+                                            <span className="w-16">{step.Temp !== null ? `${step.Temp} mK` : ""}</span>
+                                            <span className="w-28">{step.BiasWindow ? `${step.BiasWindow[0]}-${step.BiasWindow[1]} uA` : ""}</span>
+                                            <span className="w-40">{new Date(step.Timestamp * 1000).toLocaleString()}</span>
+                                            <input
+                                                className="flex-1 border rounded px-2 py-0.5"
+                                                defaultValue={step.Label ?? ""}
+                                                placeholder="名前"
+                                                onBlur={(e) => labelStep(index, e.target.value)}
+                                            />
+                                            <Button
+                                                className="bg-blue-600 text-white px-2 py-0.5 rounded-md hover:bg-blue-700 disabled:bg-gray-400"
+                                                disabled={index === history.CurrentIndex}
+                                                onClick={() => jumpToStep(index)}
+                                            >
+                                                移動
+                                            </Button>
+                                        </div>
+                                    ))}
+                                </div>
+                                <div className="flex justify-end mt-4">
+                                    <Button
+                                        type="button"
+                                        onClick={() => setHistory(null)}
+                                        className="bg-gray-400 text-white px-3 py-1.5 rounded-md hover:bg-gray-500"
+                                    >
+                                        閉じる
+                                    </Button>
+                                </div>
+                            </DialogPanel>
+                        </div>
+                    </Dialog>
+
+                    <Dialog
+                        open={configOpen}
+                        as="div"
+                        onClose={() => setConfigOpen(false)}
+                        className="z-11 focus:outline-none"
+                    >
+                        <div className="fixed inset-0  z-11 bg-opacity-60 backdrop-blur-sm" aria-hidden="true" />
+                        <div className="fixed inset-0 z-11 flex items-center justify-center p-4">
+                            <DialogPanel className="w-full max-w-md rounded-xl bg-white p-6 text-gray-600">
+                                <DialogTitle className="text-base/7 font-medium mb-2">
+                                    解析設定
+                                </DialogTitle>
+                                <TESConfig tabId={tabId} onConfigChange={onConfigChange} />
+                            </DialogPanel>
+                        </div>
+                    </Dialog>
+
                     <div className="relative flex-1 min-h-0">
                         <TESAGraph
                             ref={graphRef}
@@ -207,6 +367,33 @@ const IVContent = ({ tabId }: { tabId: string }) => {
                             </Button>
                         )}
 
+                        {!ivSelecting && (
+                            <Button
+                                className="inline-flex w-auto items-center gap-2 rounded-md bg-zinc-500 px-3 py-1.5 text-sm/6 font-semibold text-white hover:bg-gray-600"
+                                onClick={autoCalibrate}
+                            >
+                                自動キャリブレーション
+                            </Button>
+                        )}
+
+                        {!ivSelecting && (
+                            <Button
+                                className="inline-flex w-auto items-center gap-2 rounded-md bg-zinc-500 px-3 py-1.5 text-sm/6 font-semibold text-white hover:bg-gray-600"
+                                onClick={openHistory}
+                            >
+                                履歴
+                            </Button>
+                        )}
+
+                        {!ivSelecting && (
+                            <Button
+                                className="inline-flex w-auto items-center gap-2 rounded-md bg-zinc-500 px-3 py-1.5 text-sm/6 font-semibold text-white hover:bg-gray-600"
+                                onClick={() => setConfigOpen(true)}
+                            >
+                                設定
+                            </Button>
+                        )}
+
                         {ivSelecting && (
                             <div className="flex gap-1">
                                 <Button
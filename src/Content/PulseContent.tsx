@@ -11,6 +11,7 @@ import {Progress} from "@/components/ui/progress.tsx";
 import { TESGraphRef } from "@/Graph/TESGraph";
 import PrintPopover from "@/Graph/TESGraphPrintPopover.tsx";
 import {RefreshCw, Settings} from "lucide-react";
+import { formatError, isCancelled } from "@/lib/tesError.ts";
 
 // 型定義はそのままでOK
 type PulseInfo = { Base: number; PeakAverage: number; PeakIndex: number; RiseTime: number; DecayTime: number; };
@@ -31,6 +32,9 @@ export const PulseContent = ({ tabId }: { tabId: string }) => {
     const [progress, setProgress] = useState(0);
     const [channelsDone, setChannelsDone] = useState(0);
     const [totalChannels, setTotalChannels] = useState(0);
+    const [jobId, setJobId] = useState<number | null>(null);
+    const cancelledRef = useRef(false);
+    const [rejectSummary, setRejectSummary] = useState<{ Channel: number; Total: number; Rejected: number }[]>([]);
 
     const [activeTab, setActiveTab] = useState<TabKey | null>(null);
     const [settings, setSettings] = useState<TabSettings>({});
@@ -62,7 +66,7 @@ export const PulseContent = ({ tabId }: { tabId: string }) => {
                 }
             } catch (e) {
                 console.error(e);
-                alert("初期ロードエラー\n" + e);
+                alert("初期ロードエラー\n" + formatError(e));
             }
         };
         init();
@@ -80,7 +84,7 @@ export const PulseContent = ({ tabId }: { tabId: string }) => {
             await startAnalyzeFolder();
         } catch (e) {
             console.error(e);
-            alert("リセットエラー\n" + e);
+            alert("リセットエラー\n" + formatError(e));
         }
     }
 
@@ -89,14 +93,28 @@ export const PulseContent = ({ tabId }: { tabId: string }) => {
         setProgress(0);
         setChannelsDone(0);
         setTotalChannels(0);
+        setJobId(null);
+        cancelledRef.current = false;
         let unlistenProgress: (() => void) | null = null;
         let unlistenChannelDone: (() => void) | null = null;
+        let unlistenJob: (() => void) | null = null;
+        let unlistenRejected: (() => void) | null = null;
         try {
-            unlistenProgress = await listen<{ progress: number; channel: number }>(
-                "pulse-progress", e => { setProgress(e.payload.progress);  }
+            unlistenJob = await listen<{ id: number; tab: string; kind: string }>(
+                "job-started", e => { if (e.payload.tab === tabId) setJobId(e.payload.id); }
             );
-            unlistenChannelDone = await listen<{ done: number; total: number; channel: number }>(
-                "pulse-channel-done", e => { setChannelsDone(e.payload.done); setTotalChannels(e.payload.total); }
+            unlistenProgress = await listen<{ tab: string; progress: number; channel: number }>(
+                "pulse-progress", e => { if (e.payload.tab === tabId) setProgress(e.payload.progress); }
+            );
+            unlistenChannelDone = await listen<{ tab: string; done: number; total: number; channel: number }>(
+                "pulse-channel-done", e => {
+                    if (e.payload.tab !== tabId) return;
+                    setChannelsDone(e.payload.done);
+                    setTotalChannels(e.payload.total);
+                }
+            );
+            unlistenRejected = await listen<{ tab: string; summaries: { Channel: number; Total: number; Rejected: number }[] }>(
+                "pulse-rejected-summary", e => { if (e.payload.tab === tabId) setRejectSummary(e.payload.summaries); }
             );
             await invoke("AnalyzePulseFolderCommand", { tabName: tabId });
             const data = await invoke<PulseData>("GetPulseInfoCommand", { tabName: tabId });
@@ -104,11 +122,25 @@ export const PulseContent = ({ tabId }: { tabId: string }) => {
             setStatus("Finished");
         } catch (e) {
             console.error(e);
-            alert("解析エラー\n" + e);
+            if (!cancelledRef.current && !isCancelled(e)) alert("解析エラー\n" + formatError(e));
             setStatus("Ready");
         } finally {
             if (unlistenProgress) unlistenProgress();
             if (unlistenChannelDone) unlistenChannelDone();
+            if (unlistenJob) unlistenJob();
+            if (unlistenRejected) unlistenRejected();
+            setJobId(null);
+        }
+    };
+
+    // 解析を中断する (そこまでの結果は保存され、次回はその続きから解析する)
+    const cancelAnalyze = async () => {
+        if (jobId === null) return;
+        try {
+            cancelledRef.current = true;
+            await invoke("CancelJobCommand", { jobId });
+        } catch (e) {
+            console.error(e);
         }
     };
 
@@ -192,6 +224,10 @@ export const PulseContent = ({ tabId }: { tabId: string }) => {
                             </div>
                             <Progress value={totalChannels ? (channelsDone / totalChannels) * 100 : 0} />
                         </div>
+
+                        <Button variant="outline" onClick={cancelAnalyze} disabled={jobId === null}>
+                            中断
+                        </Button>
                     </div>
                 </div>
 
@@ -452,6 +488,11 @@ export const PulseContent = ({ tabId }: { tabId: string }) => {
                                 </PopoverTrigger>
                                 <PopoverContent>
                                     <div className="flex flex-col space-y-1">
+                                        {rejectSummary.filter(r => r.Rejected > 0).map(r => (
+                                            <div key={r.Channel} className="text-sm text-muted-foreground">
+                                                CH{r.Channel}: 除外 {r.Rejected} / {r.Total} 件 (Rejected.csv)
+                                            </div>
+                                        ))}
                                         <Button variant="outline" onClick={resetPreresult}>再計算</Button>
                                     </div>
                                 </PopoverContent>
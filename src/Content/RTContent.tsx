@@ -2,6 +2,9 @@ import  {useEffect, useRef, useState} from "react";
 import TESAGraph, { TESAData } from "@/Graph/TESAGraph.tsx";
 import {invoke} from "@tauri-apps/api/core";
 import {TESGraphRef} from "@/Graph/TESGraph.tsx";
+import { formatError } from "@/lib/tesError.ts";
+import {Button, Dialog, DialogPanel, DialogTitle} from '@headlessui/react'
+import TESConfig from "@/Graph/TESConfig.tsx";
 
 const rtTabs = [
     { label: "RT", xKey: "Temp", yKey: "R_tes", defaultTitle: "RT", defaultXaxis: "$Temp[mK]$", defaultYaxis: "$R_{tes}[\\Omega]$" },
@@ -11,6 +14,9 @@ const rtTabs = [
 const RTContent = ({ tabId }: { tabId: string }) => {
     const [sidebarOpen, setSidebarOpen] = useState(true);
     const [isLoading, setIsLoading] = useState(true);
+    const [configOpen, setConfigOpen] = useState(false);
+    // 設定を保存したら解析し直す
+    const [configVersion, setConfigVersion] = useState(0);
 
     const [RTData, setRTData] = useState<TESAData | null>(null);
     //const safeData: TESAData = IVData ?? defaultData;
@@ -27,9 +33,9 @@ const RTContent = ({ tabId }: { tabId: string }) => {
                         setRTData(res);
                         setIsLoading(false);
                     })
-                    .catch((e) => alert(e));
+                    .catch((e) => alert(formatError(e)));
             })
-    }, [tabId]);
+    }, [tabId, configVersion]);
 
     // TESAGraphへのprops
     const graphProps = {
@@ -50,6 +56,29 @@ const RTContent = ({ tabId }: { tabId: string }) => {
                 </div>
             ) : (
                 <div className="flex-1 flex h-full">
+                    <Dialog
+                        open={configOpen}
+                        as="div"
+                        onClose={() => setConfigOpen(false)}
+                        className="z-11 focus:outline-none"
+                    >
+                        <div className="fixed inset-0  z-11 bg-opacity-60 backdrop-blur-sm" aria-hidden="true" />
+                        <div className="fixed inset-0 z-11 flex items-center justify-center p-4">
+                            <DialogPanel className="w-full max-w-md rounded-xl bg-white p-6 text-gray-600">
+                                <DialogTitle className="text-base/7 font-medium mb-2">
+                                    解析設定
+                                </DialogTitle>
+                                <TESConfig
+                                    tabId={tabId}
+                                    onConfigChange={() => {
+                                        setConfigOpen(false);
+                                        setConfigVersion((v) => v + 1);
+                                    }}
+                                />
+                            </DialogPanel>
+                        </div>
+                    </Dialog>
+
                     {/* TESAGraph */}
                     <div className="flex-1 min-h-0">
                         <TESAGraph
@@ -57,6 +86,15 @@ const RTContent = ({ tabId }: { tabId: string }) => {
                             {...graphProps}
                         />
                     </div>
+
+                    <div className="absolute bottom-2 right-2 flex gap-2 z-11">
+                        <Button
+                            className="inline-flex w-auto items-center gap-2 rounded-md bg-zinc-500 px-3 py-1.5 text-sm/6 font-semibold text-white hover:bg-gray-600"
+                            onClick={() => setConfigOpen(true)}
+                        >
+                            設定
+                        </Button>
+                    </div>
                 </div>
             )}
         </div>
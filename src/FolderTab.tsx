@@ -8,6 +8,7 @@ import { listen } from "@tauri-apps/api/event";
 import IVContent from "@/Content/IVContent.tsx";
 import RTContent from "@/Content/RTContent.tsx";
 import {PulseContent} from "@/Content/PulseContent.tsx";
+import { formatError } from "@/lib/tesError.ts";
 
 enum TargetEnum {
     IV,RT,Pulse
@@ -173,7 +174,7 @@ const DynamicTabs = () => {
             );
         } catch (e) {
             console.error("フォルダ判定エラー:", e);
-            alert("フォルダの種類を判定できませんでした。\n" + e);
+            alert("フォルダの種類を判定できませんでした。\n" + formatError(e));
         }
     };
 
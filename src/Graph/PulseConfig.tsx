@@ -1,5 +1,6 @@
 import  { useEffect, useState } from "react";
 import { invoke } from "@tauri-apps/api/core";
+import { formatError } from "@/lib/tesError.ts";
 
 type ConfigType = {
     Readout: {
@@ -33,7 +34,7 @@ export default function PulseConfig({ tabId,onConfigChange }: Props) {
                 const res = await invoke<ConfigType>("GetConfigCommand", { tabName: tabId });
                 setConfig(res);
             } catch (e) {
-                alert("設定の取得に失敗: " + e);
+                alert("設定の取得に失敗: " + formatError(e));
             }
         };
         loadConfig();
@@ -48,7 +49,7 @@ export default function PulseConfig({ tabId,onConfigChange }: Props) {
         };
         setConfig(updated);
         invoke("SaveConfigCommand", { tabName: tabId, json: updated }).catch((e) =>
-            alert("設定の保存に失敗: " + e)
+            alert("設定の保存に失敗: " + formatError(e))
         );
 
         onConfigChange();
@@ -13,6 +13,7 @@ import { PlotData } from "plotly.js";
 import { Checkbox } from "@/components/ui/checkbox";
 import { Slider } from "@/components/ui/slider";
 import {autoWrapLatex} from "@/Graph/TESAGraph.tsx";  // shadcn slider
+import { formatError } from "@/lib/tesError.ts";
 
 type PulseAnalysisResult = {
     Time: number[];
@@ -89,7 +90,7 @@ export function PulsePulse({ tabId, pulseIndex, channel, pulseConfigVer, graphRe
                 });
                 setAnalysis(result);
             } catch (e) {
-                alert("データ取得失敗:" + e);
+                alert("データ取得失敗:" + formatError(e));
                 setAnalysis(null);
             }
         };
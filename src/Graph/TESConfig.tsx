@@ -0,0 +1,136 @@
+import { useEffect, useState } from "react";
+import { invoke } from "@tauri-apps/api/core";
+import { Button } from "@headlessui/react";
+import { formatError } from "@/lib/tesError.ts";
+
+// フィットなどの数値計算を行うバックエンド
+type BackendType = { Type: "Native" } | { Type: "Http"; Url: string } | { Type: "Mock" };
+
+// IVConfig.json / RTConfig.json の中身 (ここで変えない項目もそのまま保存し直す)
+type TESConfigType = {
+    Backend: BackendType;
+    Thermal: { PsatFraction: number };
+    // null なら超伝導側の傾きから求める
+    Eta: number | null;
+    Branch: { Window: number; SlopeTolerance: number };
+    // null なら P_sat のフィットの値を使う
+    Electrothermal: { G: number | null; Tc: number | null; C: number | null };
+    [key: string]: unknown;
+};
+
+type Props = { tabId: string; onConfigChange: () => void };
+
+export default function TESConfig({ tabId, onConfigChange }: Props) {
+    const [config, setConfig] = useState<TESConfigType | null>(null);
+
+    useEffect(() => {
+        invoke<TESConfigType>("GetTESConfigCommand", { tabName: tabId })
+            .then((res) => setConfig(res))
+            .catch((e) => alert("設定の取得に失敗: " + formatError(e)));
+    }, [tabId]);
+
+    const saveConfig = () => {
+        if (!config) return;
+        invoke("SaveTESConfigCommand", { tabName: tabId, json: config })
+            .then(() => onConfigChange())
+            .catch((e) => alert("設定の保存に失敗: " + formatError(e)));
+    };
+
+    const numberField = (label: string, value: number, onChange: (value: number) => void) => (
+        <div key={label}>
+            <label className="block text-xs font-medium">{label}</label>
+            <input
+                type="number"
+                className="w-full border px-2 py-1 text-sm"
+                value={value}
+                onChange={(e) => onChange(Number(e.target.value))}
+            />
+        </div>
+    );
+
+    // 空欄は未設定 (null)
+    const optionalField = (label: string, value: number | null, onChange: (value: number | null) => void) => (
+        <div key={label}>
+            <label className="block text-xs font-medium">{label}</label>
+            <input
+                type="number"
+                className="w-full border px-2 py-1 text-sm"
+                value={value ?? ""}
+                placeholder="自動"
+                onChange={(e) => onChange(e.target.value === "" ? null : Number(e.target.value))}
+            />
+        </div>
+    );
+
+    return (
+        <div>
+            {config ? (
+                <div className="space-y-4 max-h-96 overflow-auto">
+                    <h2 className="font-semibold">Thermal 設定</h2>
+                    {numberField("PsatFraction (R_TES / R_n)", config.Thermal.PsatFraction, (v) =>
+                        setConfig({ ...config, Thermal: { ...config.Thermal, PsatFraction: v } })
+                    )}
+
+                    <h2 className="font-semibold mt-2">読み出し</h2>
+                    {optionalField("Eta (uA/V)", config.Eta, (v) => setConfig({ ...config, Eta: v }))}
+
+                    <h2 className="font-semibold mt-2">Branch 設定</h2>
+                    {numberField("Window", config.Branch.Window, (v) =>
+                        setConfig({ ...config, Branch: { ...config.Branch, Window: v } })
+                    )}
+                    {numberField("SlopeTolerance", config.Branch.SlopeTolerance, (v) =>
+                        setConfig({ ...config, Branch: { ...config.Branch, SlopeTolerance: v } })
+                    )}
+
+                    <h2 className="font-semibold mt-2">Electrothermal 設定</h2>
+                    {optionalField("G (pW/K)", config.Electrothermal.G, (v) =>
+                        setConfig({ ...config, Electrothermal: { ...config.Electrothermal, G: v } })
+                    )}
+                    {optionalField("Tc (mK)", config.Electrothermal.Tc, (v) =>
+                        setConfig({ ...config, Electrothermal: { ...config.Electrothermal, Tc: v } })
+                    )}
+                    {optionalField("C (pJ/K)", config.Electrothermal.C, (v) =>
+                        setConfig({ ...config, Electrothermal: { ...config.Electrothermal, C: v } })
+                    )}
+
+                    <h2 className="font-semibold mt-2">バックエンド</h2>
+                    <select
+                        className="w-full border px-2 py-1 text-sm"
+                        value={config.Backend.Type}
+                        onChange={(e) => {
+                            const Type = e.target.value as BackendType["Type"];
+                            const Backend: BackendType =
+                                Type === "Http" ? { Type, Url: "http://localhost:5000" } : { Type };
+                            setConfig({ ...config, Backend });
+                        }}
+                    >
+                        <option value="Native">Native</option>
+                        <option value="Http">Http</option>
+                        <option value="Mock">Mock</option>
+                    </select>
+                    {config.Backend.Type === "Http" && (
+                        <div>
+                            <label className="block text-xs font-medium">Url</label>
+                            <input
+                                type="text"
+                                className="w-full border px-2 py-1 text-sm"
+                                value={config.Backend.Url}
+                                onChange={(e) => setConfig({ ...config, Backend: { Type: "Http", Url: e.target.value } })}
+                            />
+                        </div>
+                    )}
+                    <div className="flex justify-end">
+                        <Button
+                            onClick={saveConfig}
+                            className="bg-blue-600 text-white px-3 py-1.5 rounded-md hover:bg-blue-700"
+                        >
+                            保存
+                        </Button>
+                    </div>
+                </div>
+            ) : (
+                <div>Loading config...</div>
+            )}
+        </div>
+    );
+}
@@ -0,0 +1,67 @@
+// バックエンドの TESErrorS (JSON) を表示用の文字列にする
+
+export type TESErrorSeverity = "Info" | "Warning" | "Error" | "Fatal";
+
+export interface TESError {
+    Code: string;
+    Severity: TESErrorSeverity;
+    Message: string;
+    Path: string | null;
+    Source: TESError | null;
+}
+
+// コードごとの対処法
+const guidance: Record<string, string> = {
+    FolderNotFound: "フォルダが存在しません。パスを確認してください。",
+    FolderNotAdequate: "IV / RT / Pulse のどのフォルダとも判定できません。フォルダ構成を確認してください。",
+    FolderCreateErr: "出力フォルダを作成できません。書き込み権限を確認してください。",
+    FileNotFound: "ファイルがありません。先に解析を実行したか確認してください。",
+    FileOpenErr: "ファイルを開けません。他のアプリで開いていないか確認してください。",
+    FileWriteErr: "ファイルを書き込めません。書き込み権限と空き容量を確認してください。",
+    FileRemoveErr: "ファイルを削除できません。他のアプリで開いていないか確認してください。",
+    BinaryReadErr: "バイナリファイルが壊れている可能性があります。",
+    BinaryBufferSizeErr: "バイナリファイルの長さが不正です。ファイルが途中で切れていないか確認してください。",
+    CSVReadErr: "CSV を読めません。ファイルの形式を確認してください。",
+    CSVParseErr: "CSV に数値として読めない値があります。",
+    JsonParseErr: "設定ファイルの形式が不正です。",
+    ConfigErr: "設定値が不正です。設定を見直してください。",
+    PulseChannelEmpty: "rawdata にパルスファイルがありません。",
+    PulseGlobErr: "パルスファイルを列挙できません。",
+    PulseFileNameErr: "パルスファイル名が CH{n}_{番号}.dat の形式ではありません。",
+    IVGlobErr: "IV ファイルを列挙できません。",
+    IVParseErr: "IV ファイル名から温度を読めません。",
+    RTParseErr: "RT ファイル名から電流値を読めません。",
+    CalibrationHistoryErr: "キャリブレーション履歴が IV データと合わないため使いませんでした。元の履歴は History.json.bak に残しています。",
+    NotAnalyzed: "先に解析を実行してください。",
+    AnalysisErr: "解析に失敗しました。設定やデータを確認してください。",
+    LinerFitErr: "直線フィットに失敗しました。フィットに使う点数を確認してください。",
+    TooBigLinerFitSample: "フィットに使う点数がデータ点数より多くなっています。",
+    TabNotFound: "タブが閉じられています。もう一度フォルダを開いてください。",
+    TabTypeErr: "このタブでは実行できない操作です。",
+    TabBusy: "このタブは解析中です。終わるまで待つか、中断してから操作してください。",
+    LockErr: "内部状態が壊れています。アプリを再起動してください。",
+    PyErr: "Python バックエンドに接続できません。設定の URL とサーバーを確認してください。",
+};
+
+export function isTESError(e: unknown): e is TESError {
+    return typeof e === "object" && e !== null && "Code" in e && "Message" in e;
+}
+
+export function isCancelled(e: unknown): boolean {
+    return isTESError(e) && e.Code === "Cancelled";
+}
+
+// メッセージ、対象のパス、原因の連鎖、対処法の順に並べる
+export function formatError(e: unknown): string {
+    if (!isTESError(e)) return String(e);
+    const lines = [e.Message];
+    if (e.Path) lines.push(`対象: ${e.Path}`);
+    let root = e;
+    for (let s = e.Source; s; s = s.Source) {
+        lines.push(`原因: ${s.Message}` + (s.Path ? ` (${s.Path})` : ""));
+        root = s;
+    }
+    const hint = guidance[root.Code] ?? guidance[e.Code];
+    if (hint) lines.push("", hint);
+    return lines.join("\n");
+}